@@ -1,7 +1,128 @@
+use std::fmt;
 use typed_arena::Arena;
 
-#[derive(Debug, PartialEq)]
-pub enum AstNode<'a> {
+/// Tells a node apart from every other node of the same parse. Nodes are numbered in the
+/// order the parser builds them, so the same source always gets the same ids.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub u32);
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Where a node's source is: the bytes from `start` up to `end`, and the zero-based lines and
+/// columns they are at. Columns count characters, not bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    /// The source this span covers.
+    pub fn text<'s>(&self, source: &'s str) -> &'s str {
+        source.get(self.start..self.end).unwrap_or_default()
+    }
+}
+
+/// A node of the tree: what it is, where its source is and its id.
+pub struct AstNode<'a> {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: NodeKind<'a>,
+}
+
+impl<'a> AstNode<'a> {
+    pub fn new(id: NodeId, span: Span, kind: NodeKind<'a>) -> Self {
+        Self { id, span, kind }
+    }
+
+    /// The node whose span holds `offset` most tightly: this one or one inside it.
+    pub fn find(&'a self, offset: usize) -> Option<&'a AstNode<'a>> {
+        if !self.span.contains(offset) && !matches!(self.kind, NodeKind::Root { .. }) {
+            return None;
+        }
+        let inner = self
+            .children()
+            .into_iter()
+            .find_map(|child| child.find(offset));
+        inner.or(Some(self).filter(|node| node.span.contains(offset)))
+    }
+
+    /// The nodes directly inside this one, in source order.
+    pub fn children(&self) -> Vec<&'a AstNode<'a>> {
+        match &self.kind {
+            NodeKind::BinaryExpr { left, right, .. } => vec![left, right],
+            NodeKind::UnaryExpr { expr: node, .. }
+            | NodeKind::Return { value: node }
+            | NodeKind::Declaration { value: node, .. }
+            | NodeKind::Spill { value: node, .. } => vec![node],
+            NodeKind::Assignment { identifier, value } => vec![identifier, value],
+            NodeKind::Root { children: nodes }
+            | NodeKind::Call { args: nodes, .. }
+            | NodeKind::BangCall { args: nodes, .. }
+            | NodeKind::Catch { body: nodes, .. } => nodes.clone(),
+            NodeKind::Function { params, body, .. } | NodeKind::BottleCall { params, body, .. } => {
+                params.iter().chain(body).copied().collect()
+            }
+            NodeKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => [*cond]
+                .into_iter()
+                .chain(then_body.iter().chain(else_body).copied())
+                .collect(),
+            NodeKind::While { cond, body } => {
+                [*cond].into_iter().chain(body.iter().copied()).collect()
+            }
+            NodeKind::Try {
+                body,
+                handlers,
+                finally,
+            } => body
+                .iter()
+                .chain(handlers)
+                .chain(finally)
+                .copied()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Nodes are equal when they have the same shape, wherever their source is.
+impl PartialEq for AstNode<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/// Shows only the kind, so a dumped tree reads the same as the source it came from.
+impl fmt::Debug for AstNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl fmt::Display for AstNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeKind<'a> {
     BinaryExpr {
         left: &'a AstNode<'a>,
         op: String,
@@ -20,6 +141,9 @@ pub enum AstNode<'a> {
     FloatLiteral {
         value: f64,
     },
+    BoolLiteral {
+        value: bool,
+    },
     Identifier {
         name: String,
     },
@@ -66,6 +190,40 @@ pub enum AstNode<'a> {
         name: String,
         value: &'a AstNode<'a>,
     },
+    If {
+        cond: &'a AstNode<'a>,
+        then_body: Vec<&'a AstNode<'a>>,
+        else_body: Vec<&'a AstNode<'a>>,
+    },
+    While {
+        cond: &'a AstNode<'a>,
+        body: Vec<&'a AstNode<'a>>,
+    },
+    /// `try { .. }` with its `catch` handlers, tried in order, and an optional `finally` body.
+    Try {
+        body: Vec<&'a AstNode<'a>>,
+        handlers: Vec<&'a AstNode<'a>>,
+        finally: Vec<&'a AstNode<'a>>,
+    },
+    /// `catch name: Kind { .. }`. An empty `kind` catches every error and an empty `name`
+    /// binds nothing.
+    Catch {
+        kind: String,
+        name: String,
+        body: Vec<&'a AstNode<'a>>,
+    },
+    /// `spill Kind(message)`, or `spill value` with an empty `kind` to spill an error value
+    /// again or a string as a plain `Error`.
+    Spill {
+        kind: String,
+        value: &'a AstNode<'a>,
+    },
+    /// `require`/`lock`/`import` of another bottle. `path` is empty when not given.
+    Dependency {
+        kind: String,
+        name: String,
+        path: String,
+    },
     Variable {
         name: String,
     },
@@ -87,28 +245,31 @@ impl<'a> std::fmt::Display for AST<'a> {
     }
 }
 
-impl<'a> std::fmt::Display for AstNode<'a> {
+impl<'a> std::fmt::Display for NodeKind<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            AstNode::BinaryExpr { left, op, right } => {
+            NodeKind::BinaryExpr { left, op, right } => {
                 write!(f, "({} {} {})", left, op, right)
             }
-            AstNode::UnaryExpr { op, expr } => {
+            NodeKind::UnaryExpr { op, expr } => {
                 write!(f, "({}{})", op, expr)
             }
-            AstNode::StrLiteral { value } => {
+            NodeKind::StrLiteral { value } => {
                 write!(f, "\"{}\"", value)
             }
-            AstNode::IntLiteral { value } => {
+            NodeKind::IntLiteral { value } => {
                 write!(f, "{}", value)
             }
-            AstNode::FloatLiteral { value } => {
+            NodeKind::FloatLiteral { value } => {
                 write!(f, "{}", value)
             }
-            AstNode::Identifier { name } => {
+            NodeKind::BoolLiteral { value } => {
+                write!(f, "{}", value)
+            }
+            NodeKind::Identifier { name } | NodeKind::Variable { name } => {
                 write!(f, "{}", name)
             }
-            AstNode::Root { children } => {
+            NodeKind::Root { children } => {
                 for child in children {
                     writeln!(f, "{}", child)?;
                 }
@@ -126,19 +287,14 @@ impl std::fmt::Debug for AST<'_> {
 
 impl<'a> AST<'a> {
     pub fn new(arena: &'a Arena<AstNode<'a>>) -> AST<'a> {
-        let head = arena.alloc(AstNode::Root { children: vec![] });
+        let root = NodeKind::Root { children: vec![] };
+        let head = arena.alloc(AstNode::new(NodeId::default(), Span::default(), root));
         AST { head, arena }
     }
     pub fn is_empty(&self) -> bool {
-        match self.head {
-            AstNode::Root { children } => children.is_empty(),
+        match &self.head.kind {
+            NodeKind::Root { children } => children.is_empty(),
             _ => panic!("Root node was expected! The AST initialization is improper!"),
         }
     }
 }
-
-impl<'a> AstNode<'a> {
-    pub fn new(node: AstNode) -> AstNode {
-        node
-    }
-}
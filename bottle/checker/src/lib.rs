@@ -1 +1,105 @@
+//! Checks over a parsed bottle that need no code to run: what every name refers to, and what
+//! is wrong with how names are declared and used.
+pub mod resolve;
+pub mod types;
 
+pub use resolve::{resolve, resolve_in, Resolution, Symbol, SymbolKind};
+pub use types::{Bang, Type};
+
+/// A name that nothing in scope declares.
+pub const UNDEFINED_NAME: i32 = 5001;
+/// A function declared twice in one bottle.
+pub const DUPLICATE_FUNCTION: i32 = 5002;
+/// A declaration that hides another of the same name.
+pub const SHADOWED_NAME: i32 = 5003;
+/// A parameter, local or caught error that is never read.
+pub const UNUSED_BINDING: i32 = 5004;
+/// A value whose type doesn't fit where it is used.
+pub const TYPE_MISMATCH: i32 = 5005;
+/// A call with more or fewer arguments than the function has parameters.
+pub const WRONG_ARGUMENT_COUNT: i32 = 5006;
+/// A function with a return type whose end can be reached without a `return`.
+pub const MISSING_RETURN: i32 = 5007;
+/// An annotation naming a type that doesn't exist.
+pub const UNKNOWN_TYPE: i32 = 5008;
+/// A bang call, `name!(..)`, to an intrinsic that isn't registered.
+pub const UNKNOWN_INTRINSIC: i32 = 5009;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The bottle must not run.
+    Error,
+    Warning,
+}
+
+/// Something the checker found, at the node it is about.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub code: i32,
+    pub severity: Severity,
+    pub message: String,
+    pub span: ast::Span,
+    /// Another place worth pointing at, such as an earlier declaration, and what it is.
+    pub related: Option<(ast::Span, String)>,
+    /// What to write instead of the source at `span` to fix it, when that is clear.
+    pub fix: Option<String>,
+}
+
+/// The name in `candidates` closest to `name`, if one is close enough to be a likely typo: at
+/// most one edit in three away, and never all of it.
+pub fn nearest<'n>(name: &str, candidates: impl IntoIterator<Item = &'n str>) -> Option<&'n str> {
+    let most = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(d, candidate)| *d <= most && *d < candidate.chars().count())
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// How many characters must be inserted, removed, replaced or swapped with the next to turn
+/// `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // `rows[i][j]` is the distance between the first `i` of `a` and the first `j` of `b`.
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let replace = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut best = replace.min(rows[i - 1][j] + 1).min(rows[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_finds_likely_typos() {
+        let names = ["count", "fib", "total", "shatter"];
+        assert_eq!(nearest("coutn", names), Some("count"));
+        assert_eq!(nearest("fbi", names), Some("fib"));
+        assert_eq!(nearest("totl", names), Some("total"));
+        assert_eq!(nearest("shattr", names), Some("shatter"));
+    }
+
+    #[test]
+    fn nearest_ignores_distant_and_identical_names() {
+        assert_eq!(nearest("x", ["y"]), None);
+        assert_eq!(nearest("main", ["count", "fib"]), None);
+        assert_eq!(nearest("fib", ["fib"]), None);
+    }
+}
@@ -0,0 +1,544 @@
+//! Name resolution: which declaration each name in a bottle refers to.
+//!
+//! Scopes follow the interpreter. Functions and dependencies are visible everywhere in the
+//! bottle that declares them, and so is every global from inside a function, while top-level
+//! code only sees a global once it has been declared. In a function, parameters and `let`s are
+//! visible to the end of the block they are in; a `catch` binds its name for its body alone.
+//! Functions and values are looked up apart, as calls and variables are when running. A dotted
+//! name such as `math.add` resolves its first segment only; the rest is up to what that names.
+use crate::{Finding, Severity, DUPLICATE_FUNCTION, SHADOWED_NAME, UNDEFINED_NAME, UNUSED_BINDING};
+use ast::{AstNode, NodeId, NodeKind, Span, AST};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Dependency,
+    /// Declared by a top-level `let`.
+    Global,
+    Param,
+    /// Declared by a `let` in a function, or in a `catch` at the top level.
+    Local,
+    /// The name a `catch` gives the error it caught.
+    Caught,
+}
+
+impl SymbolKind {
+    fn describe(self) -> &'static str {
+        match self {
+            SymbolKind::Function => "function",
+            SymbolKind::Dependency => "dependency",
+            SymbolKind::Global => "global",
+            SymbolKind::Param => "parameter",
+            SymbolKind::Local => "variable",
+            SymbolKind::Caught => "caught error",
+        }
+    }
+}
+
+/// Something a bottle declares.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the name is written in the declaration.
+    pub span: Span,
+    /// The type a `let` or parameter is annotated with, empty when it has none.
+    pub annotation: String,
+}
+
+/// What [`resolve`] found out about one tree.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Every declaration, by the id of the `Function`, `Dependency`, `Declaration` or `Catch`
+    /// node that makes it.
+    pub symbols: HashMap<NodeId, Symbol>,
+    /// The declaration each `Call`, `Variable` or assigned `Identifier` refers to, by id. A
+    /// dotted name is bound to the declaration of its first segment.
+    pub bindings: HashMap<NodeId, NodeId>,
+    /// The frame slot of each parameter, local and caught error, by the id of its declaration.
+    /// Slots are numbered from 0 in each function, parameters first, and at the top level for
+    /// what a `catch` there binds. No two declarations in one function share a slot.
+    pub slots: HashMap<NodeId, usize>,
+    /// Problems found, in source order.
+    pub findings: Vec<Finding>,
+}
+
+impl Resolution {
+    /// The declaration the name used by node `id` refers to.
+    pub fn definition(&self, id: NodeId) -> Option<(NodeId, &Symbol)> {
+        let declaration = *self.bindings.get(&id)?;
+        Some((declaration, &self.symbols[&declaration]))
+    }
+
+    /// The nodes that refer to declaration `declaration`.
+    pub fn uses(&self, declaration: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(_, d)| **d == declaration)
+            .map(|(id, _)| *id)
+    }
+
+    /// Whether anything found means the bottle must not run.
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|f| f.severity == Severity::Error)
+    }
+}
+
+/// Binds every name in `ast`, parsed from `source`, to its declaration.
+pub fn resolve(ast: &AST, source: &str) -> Resolution {
+    resolve_in(ast, source, &[])
+}
+
+/// [`resolve`] for source that carries on from earlier code, as an entry of an interactive
+/// session does. Names in `known` were declared there: using them is not an error, but they
+/// are bound to nothing.
+pub fn resolve_in(ast: &AST, source: &str, known: &[&str]) -> Resolution {
+    let mut resolver = Resolver {
+        source,
+        known: known.iter().map(|name| name.to_string()).collect(),
+        out: Resolution::default(),
+        functions: HashMap::new(),
+        dependencies: HashMap::new(),
+        globals: HashMap::new(),
+        declared: HashSet::new(),
+        scopes: Vec::new(),
+        in_function: false,
+        slots: 0,
+        used: HashSet::new(),
+    };
+    let NodeKind::Root { children } = &ast.head.kind else {
+        return resolver.out;
+    };
+    for child in children {
+        resolver.declare_top_level(child);
+    }
+    for child in children {
+        match &child.kind {
+            NodeKind::Function { .. } | NodeKind::Dependency { .. } => {}
+            _ => resolver.stmt(child),
+        }
+    }
+    for child in children {
+        if let NodeKind::Function { .. } = &child.kind {
+            resolver.function(child);
+        }
+    }
+    resolver.out.findings.sort_by_key(|f| f.span.start);
+    resolver.out
+}
+
+struct Resolver<'s> {
+    source: &'s str,
+    known: HashSet<String>,
+    out: Resolution,
+    functions: HashMap<String, NodeId>,
+    dependencies: HashMap<String, NodeId>,
+    globals: HashMap<String, NodeId>,
+    /// Globals top-level code has declared so far.
+    declared: HashSet<String>,
+    /// Innermost last. Empty at the top level outside of a `catch`.
+    scopes: Vec<HashMap<String, NodeId>>,
+    /// Resolving a function body, where every global is visible and blocks have scopes.
+    in_function: bool,
+    /// Slots given out so far in the function being resolved, or at the top level.
+    slots: usize,
+    /// Declarations something reads.
+    used: HashSet<NodeId>,
+}
+
+impl Resolver<'_> {
+    /// Records what a top-level statement declares for the whole bottle: a function, a
+    /// dependency or globals.
+    fn declare_top_level(&mut self, node: &AstNode) {
+        match &node.kind {
+            NodeKind::Function { name, .. } => {
+                let span = self.symbol(node, name, SymbolKind::Function);
+                match self.functions.get(name) {
+                    Some(first) => {
+                        let first = self.out.symbols[first].span;
+                        self.report(
+                            DUPLICATE_FUNCTION,
+                            Severity::Error,
+                            format!("function `{}` is declared more than once", name),
+                            span,
+                            Some((first, "first declared here")),
+                        );
+                    }
+                    None => {
+                        self.functions.insert(name.clone(), node.id);
+                    }
+                }
+            }
+            NodeKind::Dependency { name, .. } => {
+                self.symbol(node, name, SymbolKind::Dependency);
+                self.dependencies.entry(name.clone()).or_insert(node.id);
+            }
+            NodeKind::Declaration { name, .. } => {
+                self.symbol(node, name, SymbolKind::Global);
+                self.globals.entry(name.clone()).or_insert(node.id);
+            }
+            // Blocks at the top level don't have scopes of their own, except for `catch`.
+            NodeKind::If {
+                then_body: body,
+                else_body: more,
+                ..
+            }
+            | NodeKind::Try {
+                body,
+                finally: more,
+                ..
+            } => {
+                for node in body.iter().chain(more) {
+                    self.declare_top_level(node);
+                }
+            }
+            NodeKind::While { body, .. } => {
+                for node in body {
+                    self.declare_top_level(node);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn function(&mut self, node: &AstNode) {
+        let (NodeKind::Function { params, body, .. } | NodeKind::BottleCall { params, body, .. }) =
+            &node.kind
+        else {
+            return;
+        };
+        let outer = std::mem::replace(&mut self.in_function, true);
+        let slots = std::mem::take(&mut self.slots);
+        let scopes = std::mem::take(&mut self.scopes);
+        self.scopes.push(HashMap::new());
+        for param in params {
+            if let NodeKind::Declaration { name, .. } = &param.kind {
+                self.declare(param, name, SymbolKind::Param);
+            }
+        }
+        self.stmts(body);
+        self.pop();
+        self.scopes = scopes;
+        self.slots = slots;
+        self.in_function = outer;
+    }
+
+    fn stmts(&mut self, body: &[&AstNode]) {
+        for stmt in body {
+            self.stmt(stmt);
+        }
+    }
+
+    fn block(&mut self, body: &[&AstNode]) {
+        if !self.in_function {
+            return self.stmts(body);
+        }
+        self.scopes.push(HashMap::new());
+        self.stmts(body);
+        self.pop();
+    }
+
+    fn stmt(&mut self, node: &AstNode) {
+        match &node.kind {
+            NodeKind::Declaration { name, value, .. } => {
+                self.expr(value);
+                if self.scopes.is_empty() {
+                    self.globals.insert(name.clone(), node.id);
+                    self.declared.insert(name.clone());
+                } else {
+                    self.declare(node, name, SymbolKind::Local);
+                }
+            }
+            NodeKind::Assignment { identifier, value } => {
+                self.expr(value);
+                if let NodeKind::Identifier { name } = &identifier.kind {
+                    match self.lookup(name) {
+                        Some(declaration) => {
+                            self.out.bindings.insert(identifier.id, declaration);
+                        }
+                        None if self.known.contains(name) => {}
+                        None => {
+                            let candidates = self.variables();
+                            self.undefined(
+                                identifier,
+                                name,
+                                format!("assignment to undeclared variable `{}`", name),
+                                candidates.iter().map(String::as_str),
+                            )
+                        }
+                    }
+                }
+            }
+            NodeKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                self.expr(cond);
+                self.block(then_body);
+                self.block(else_body);
+            }
+            NodeKind::While { cond, body } => {
+                self.expr(cond);
+                self.block(body);
+            }
+            NodeKind::Try {
+                body,
+                handlers,
+                finally,
+            } => {
+                self.block(body);
+                for handler in handlers {
+                    self.catch(handler);
+                }
+                self.block(finally);
+            }
+            NodeKind::Function { .. } | NodeKind::BottleCall { .. } => self.function(node),
+            NodeKind::Dependency { .. } | NodeKind::Sharp { .. } | NodeKind::At { .. } => {}
+            _ => self.expr(node),
+        }
+    }
+
+    fn catch(&mut self, node: &AstNode) {
+        let NodeKind::Catch { name, body, .. } = &node.kind else {
+            return;
+        };
+        self.scopes.push(HashMap::new());
+        if !name.is_empty() {
+            self.declare(node, name, SymbolKind::Caught);
+        }
+        self.stmts(body);
+        self.pop();
+    }
+
+    fn expr(&mut self, node: &AstNode) {
+        match &node.kind {
+            NodeKind::Variable { name } | NodeKind::Identifier { name } => {
+                let first = name.split('.').next().unwrap_or_default();
+                let declaration = self.lookup(first).or_else(|| {
+                    name.contains('.')
+                        .then(|| self.dependencies.get(first).copied())
+                        .flatten()
+                });
+                match declaration {
+                    Some(declaration) => self.bind(node, declaration),
+                    None if self.known.contains(first) => {}
+                    None => {
+                        let candidates = self.variables();
+                        let message = format!("undefined variable `{}`", first);
+                        let candidates = candidates.iter().map(String::as_str);
+                        self.undefined(node, first, message, candidates)
+                    }
+                }
+            }
+            NodeKind::Call { name, args } => {
+                for arg in args {
+                    self.expr(arg);
+                }
+                let declaration = match name.split_once('.') {
+                    Some((first, _)) => self.dependencies.get(first).map(|d| (first, *d)),
+                    None => self.functions.get(name).map(|f| (name.as_str(), *f)),
+                };
+                match declaration {
+                    Some((_, declaration)) => self.bind(node, declaration),
+                    None => {
+                        let (first, what, names) = match name.split_once('.') {
+                            Some((first, _)) => (first, "dependency", &self.dependencies),
+                            None => (name.as_str(), "function", &self.functions),
+                        };
+                        if !self.known.contains(first) {
+                            let candidates: Vec<String> = names.keys().cloned().collect();
+                            let message = format!("undefined {} `{}`", what, first);
+                            let candidates = candidates.iter().map(String::as_str);
+                            self.undefined(node, first, message, candidates);
+                        }
+                    }
+                }
+            }
+            _ => {
+                for child in node.children() {
+                    self.expr(child);
+                }
+            }
+        }
+    }
+
+    /// The value `name` refers to here: the innermost local, else a global.
+    fn lookup(&self, name: &str) -> Option<NodeId> {
+        let local = self.scopes.iter().rev().find_map(|s| s.get(name)).copied();
+        local.or_else(|| self.global(name))
+    }
+
+    fn global(&self, name: &str) -> Option<NodeId> {
+        let visible = self.in_function || self.declared.contains(name);
+        visible.then(|| self.globals.get(name).copied()).flatten()
+    }
+
+    fn bind(&mut self, node: &AstNode, declaration: NodeId) {
+        self.out.bindings.insert(node.id, declaration);
+        self.used.insert(declaration);
+    }
+
+    /// Declares `name` in the innermost scope in a slot of its own, warning if it hides another declaration.
+    fn declare(&mut self, node: &AstNode, name: &str, kind: SymbolKind) {
+        let span = self.symbol(node, name, kind);
+        self.out.slots.insert(node.id, self.slots);
+        self.slots += 1;
+        let scope = self.scopes.last().expect("locals are declared in a scope");
+        let hidden = match scope.get(name) {
+            Some(earlier) => Some((*earlier, "declared again in the same scope")),
+            None => self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|s| s.get(name))
+                .copied()
+                .or_else(|| self.global(name))
+                .or_else(|| self.dependencies.get(name).copied())
+                .map(|outer| (outer, "shadows an outer declaration")),
+        };
+        if let Some((earlier, how)) = hidden {
+            let earlier_symbol = &self.out.symbols[&earlier];
+            let related = (earlier_symbol.span, "the earlier declaration");
+            let message = format!("{} `{}` {}", kind.describe(), name, how);
+            self.report(
+                SHADOWED_NAME,
+                Severity::Warning,
+                message,
+                span,
+                Some(related),
+            );
+        }
+        let replaced = self
+            .scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_owned(), node.id);
+        if let Some(replaced) = replaced {
+            self.unused(replaced);
+        }
+    }
+
+    /// Leaves the innermost scope, warning about what it declared and nothing read.
+    fn pop(&mut self) {
+        let scope = self.scopes.pop().expect("scopes are balanced");
+        for declaration in scope.into_values() {
+            self.unused(declaration);
+        }
+    }
+
+    fn unused(&mut self, declaration: NodeId) {
+        let symbol = &self.out.symbols[&declaration];
+        if self.used.contains(&declaration) || symbol.name.starts_with('_') {
+            return;
+        }
+        let message = format!("{} `{}` is never read", symbol.kind.describe(), symbol.name);
+        let span = symbol.span;
+        self.report(UNUSED_BINDING, Severity::Warning, message, span, None);
+    }
+
+    /// Reports `name`, the start of `node`, as undefined, suggesting the nearest of
+    /// `candidates` in its place.
+    fn undefined<'c>(
+        &mut self,
+        node: &AstNode,
+        name: &str,
+        message: String,
+        candidates: impl IntoIterator<Item = &'c str>,
+    ) {
+        let fix = crate::nearest(name, candidates).map(str::to_owned);
+        let span = self.slice(node.span, node.span.start, node.span.start + name.len());
+        self.report(UNDEFINED_NAME, Severity::Error, message, span, None);
+        self.out.findings.last_mut().expect("just reported").fix = fix;
+    }
+
+    /// Names a variable could be read or assigned by here.
+    fn variables(&self) -> Vec<String> {
+        let locals = self.scopes.iter().flat_map(|scope| scope.keys());
+        let globals = self
+            .globals
+            .keys()
+            .filter(|name| self.global(name).is_some());
+        locals.chain(globals).chain(&self.known).cloned().collect()
+    }
+
+    /// Records the symbol `node` declares and returns where its name is.
+    fn symbol(&mut self, node: &AstNode, name: &str, kind: SymbolKind) -> Span {
+        let span = self.name_span(node, name);
+        let annotation = match &node.kind {
+            NodeKind::Declaration { struct_type, .. } => struct_type.clone(),
+            _ => String::new(),
+        };
+        let symbol = Symbol {
+            name: name.to_owned(),
+            kind,
+            span,
+            annotation,
+        };
+        self.out.symbols.insert(node.id, symbol);
+        span
+    }
+
+    fn report(
+        &mut self,
+        code: i32,
+        severity: Severity,
+        message: String,
+        span: Span,
+        related: Option<(Span, &str)>,
+    ) {
+        self.out.findings.push(Finding {
+            code,
+            severity,
+            message,
+            span,
+            related: related.map(|(span, what)| (span, what.to_owned())),
+            fix: None,
+        });
+    }
+
+    /// Where `name` is first written as a whole word in `node`, or all of `node` if it isn't.
+    fn name_span(&self, node: &AstNode, name: &str) -> Span {
+        let text = node.span.text(self.source);
+        let word = |c: char| c.is_alphanumeric() || c == '_';
+        let found = text.match_indices(name).find(|(i, _)| {
+            let before = text[..*i].chars().next_back();
+            let after = text[i + name.len()..].chars().next();
+            !before.is_some_and(word) && !after.is_some_and(word)
+        });
+        match found {
+            Some((i, _)) => {
+                let start = node.span.start + i;
+                self.slice(node.span, start, start + name.len())
+            }
+            None => node.span,
+        }
+    }
+
+    /// The part of `span` from byte `start` up to `end`.
+    fn slice(&self, span: Span, start: usize, end: usize) -> Span {
+        if self.source.get(span.start..end).is_none() || end > span.end {
+            return span;
+        }
+        let at = |offset: usize| {
+            let before = &self.source[span.start..offset];
+            match before.rfind('\n') {
+                Some(newline) => (
+                    span.line + before.matches('\n').count(),
+                    before[newline + 1..].chars().count(),
+                ),
+                None => (span.line, span.column + before.chars().count()),
+            }
+        };
+        let ((line, column), (end_line, end_column)) = (at(start), at(end));
+        Span {
+            start,
+            end,
+            line,
+            column,
+            end_line,
+            end_column,
+        }
+    }
+}
@@ -0,0 +1,672 @@
+//! Gradual type checking against the types declarations are annotated with.
+//!
+//! Every expression gets a [`Type`] inferred from its operands the way the runtime would
+//! compute its value. Where nothing says what a value is, such as an `auto` parameter or a
+//! call into a dependency, it is [`Type::Unknown`] and fits anywhere, so unannotated code is
+//! never rejected. A `let` without a type takes the type of its value, unless something
+//! assigns to it later, when it could hold anything. An `i64` is accepted where an `f64` is
+//! declared, as a `let`, parameter or return value or when assigned to a binding annotated
+//! `f64`, and becomes one when it gets there. Bang calls are checked against the [`Bang`]
+//! signatures of the intrinsics the runtime has.
+use crate::resolve::{Resolution, SymbolKind};
+use crate::{
+    Finding, Severity, MISSING_RETURN, TYPE_MISMATCH, UNKNOWN_INTRINSIC, UNKNOWN_TYPE,
+    WRONG_ARGUMENT_COUNT,
+};
+use ast::{AstNode, NodeId, NodeKind, Span, AST};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    Str,
+    /// A caught error, with its `kind`, `message` and `code`.
+    Error,
+    /// No value, as a function without a return type gives.
+    Void,
+    /// Not known until the code runs.
+    Unknown,
+}
+
+impl Type {
+    /// The type a name in an annotation stands for. `auto` is [`Type::Unknown`].
+    pub fn parse(name: &str) -> Option<Type> {
+        match name {
+            "i64" => Some(Type::Int),
+            "f64" => Some(Type::Float),
+            "bool" => Some(Type::Bool),
+            "str" => Some(Type::Str),
+            "error" => Some(Type::Error),
+            "void" => Some(Type::Void),
+            "auto" => Some(Type::Unknown),
+            _ => None,
+        }
+    }
+
+    /// Whether a value of type `found` can go where `self` is expected.
+    pub fn accepts(self, found: Type) -> bool {
+        self == found
+            || self == Type::Unknown
+            || found == Type::Unknown
+            || (self == Type::Float && found == Type::Int)
+    }
+
+    fn numeric(self) -> bool {
+        matches!(self, Type::Int | Type::Float)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Int => "i64",
+            Type::Float => "f64",
+            Type::Bool => "bool",
+            Type::Str => "str",
+            Type::Error => "error",
+            Type::Void => "void",
+            Type::Unknown => "auto",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What `op` gives when applied to `l` and `r`, or `None` if the runtime would refuse.
+pub fn binary(op: &str, l: Type, r: Type) -> Option<Type> {
+    use Type::*;
+    let comparison = matches!(op, "==" | "!=" | "<" | "<=" | ">" | ">=" | "&&" | "||");
+    if l == Unknown || r == Unknown {
+        return Some(if comparison { Bool } else { Unknown });
+    }
+    let numbers = l.numeric() && r.numeric();
+    match op {
+        "&&" | "||" => (l == Bool && r == Bool).then_some(Bool),
+        "==" | "!=" => (numbers || (l == r && matches!(l, Bool | Str))).then_some(Bool),
+        "<" | "<=" | ">" | ">=" => (numbers || (l == Str && r == Str)).then_some(Bool),
+        "+" if l == Str && r == Str => Some(Str),
+        "+" | "-" | "*" | "/" | "%" | "**" => match (l, r) {
+            (Int, Int) => Some(Int),
+            _ if numbers => Some(Float),
+            _ => None,
+        },
+        "<<" | ">>" => (l == Int && r == Int).then_some(Int),
+        "&" | "|" | "^" => match (l, r) {
+            (Int, Int) => Some(Int),
+            (Bool, Bool) => Some(Bool),
+            _ => None,
+        },
+        _ => Some(Unknown),
+    }
+}
+
+/// What prefix `op` gives when applied to `t`, or `None` if the runtime would refuse.
+pub fn unary(op: &str, t: Type) -> Option<Type> {
+    match (op, t) {
+        (_, Type::Unknown) => Some(Type::Unknown),
+        ("-", Type::Int | Type::Float) | ("~", Type::Int) | ("!", Type::Bool) => Some(t),
+        _ => None,
+    }
+}
+
+/// What bang intrinsic `name!` takes and gives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bang {
+    pub name: String,
+    pub params: Vec<Type>,
+    pub returns: Type,
+}
+
+/// Checks the types in `ast`, whose names `names` resolved, knowing no bang intrinsics.
+/// Returns what doesn't fit.
+pub fn check(ast: &AST, names: &Resolution) -> Vec<Finding> {
+    check_in(ast, names, &[])
+}
+
+/// [`check`], with bang calls going to the intrinsics in `bangs`.
+pub fn check_in(ast: &AST, names: &Resolution, bangs: &[Bang]) -> Vec<Finding> {
+    let mut checker = Checker {
+        names,
+        bangs,
+        signatures: HashMap::new(),
+        types: HashMap::new(),
+        reassigned: HashSet::new(),
+        returns: None,
+        findings: Vec::new(),
+    };
+    let NodeKind::Root { children } = &ast.head.kind else {
+        return checker.findings;
+    };
+    checker.assignments(ast.head);
+    for child in children {
+        if let NodeKind::Function { .. } = &child.kind {
+            checker.signature(child);
+        }
+    }
+    for child in children {
+        match &child.kind {
+            NodeKind::Function { .. } | NodeKind::Dependency { .. } => {}
+            _ => checker.stmt(child),
+        }
+    }
+    for child in children {
+        if let NodeKind::Function { .. } = &child.kind {
+            checker.function(child);
+        }
+    }
+    checker.findings.sort_by_key(|f| f.span.start);
+    checker.findings
+}
+
+/// What a function takes and gives.
+struct Signature {
+    name: String,
+    params: Vec<(String, Type)>,
+    ret: Type,
+}
+
+struct Checker<'r> {
+    names: &'r Resolution,
+    bangs: &'r [Bang],
+    signatures: HashMap<NodeId, Signature>,
+    /// The type of each declaration checked so far.
+    types: HashMap<NodeId, Type>,
+    /// Declarations something assigns to.
+    reassigned: HashSet<NodeId>,
+    /// The name and return type of the function being checked.
+    returns: Option<(String, Type)>,
+    findings: Vec<Finding>,
+}
+
+impl Checker<'_> {
+    /// Finds the declarations assigned to anywhere in `node`.
+    fn assignments(&mut self, node: &AstNode) {
+        if let NodeKind::Assignment { identifier, .. } = &node.kind {
+            if let Some((declaration, _)) = self.names.definition(identifier.id) {
+                self.reassigned.insert(declaration);
+            }
+        }
+        for child in node.children() {
+            self.assignments(child);
+        }
+    }
+
+    fn signature(&mut self, node: &AstNode) {
+        let NodeKind::Function {
+            name,
+            params,
+            return_type,
+            ..
+        } = &node.kind
+        else {
+            return;
+        };
+        let params = params
+            .iter()
+            .filter_map(|param| match &param.kind {
+                NodeKind::Declaration {
+                    name, struct_type, ..
+                } => Some((name.clone(), self.annotation(param, struct_type))),
+                _ => None,
+            })
+            .collect();
+        let ret = self.annotation(node, return_type);
+        let signature = Signature {
+            name: name.clone(),
+            params,
+            ret,
+        };
+        self.signatures.insert(node.id, signature);
+    }
+
+    /// The type `name` stands for in the annotation of declaration `node`.
+    fn annotation(&mut self, node: &AstNode, name: &str) -> Type {
+        Type::parse(name).unwrap_or_else(|| {
+            let span = self.name_span(node.id).unwrap_or(node.span);
+            self.report(UNKNOWN_TYPE, format!("unknown type `{}`", name), span);
+            Type::Unknown
+        })
+    }
+
+    fn function(&mut self, node: &AstNode) {
+        let (NodeKind::Function { params, body, .. }, Some(signature)) =
+            (&node.kind, self.signatures.get(&node.id))
+        else {
+            return;
+        };
+        let (name, ret) = (signature.name.clone(), signature.ret);
+        for (param, (_, ty)) in params.iter().zip(&signature.params) {
+            self.types.insert(param.id, *ty);
+        }
+        let outer = self.returns.replace((name.clone(), ret));
+        self.stmts(body);
+        self.returns = outer;
+        if !matches!(ret, Type::Void | Type::Unknown) && !returns(body) {
+            let span = self.name_span(node.id).unwrap_or(node.span);
+            let message = format!(
+                "`{}` returns `{}` but can reach its end without returning",
+                name, ret
+            );
+            self.report(MISSING_RETURN, message, span);
+        }
+    }
+
+    fn stmts(&mut self, body: &[&AstNode]) {
+        for stmt in body {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, node: &AstNode) {
+        match &node.kind {
+            NodeKind::Declaration {
+                name,
+                struct_type,
+                value,
+            } => {
+                let declared = self.annotation(node, struct_type);
+                let found = self.value(value);
+                if !declared.accepts(found) {
+                    let message = format!(
+                        "`{}` is declared `{}` but its value is `{}`",
+                        name, declared, found
+                    );
+                    self.report(TYPE_MISMATCH, message, value.span);
+                }
+                let ty = match declared {
+                    Type::Unknown if self.reassigned.contains(&node.id) => Type::Unknown,
+                    Type::Unknown => found,
+                    declared => declared,
+                };
+                self.types.insert(node.id, ty);
+            }
+            NodeKind::Assignment { identifier, value } => {
+                let found = self.value(value);
+                let declared = self.expr(identifier);
+                if !declared.accepts(found) {
+                    let message = format!(
+                        "`{}` is `{}` but the value assigned is `{}`",
+                        identifier, declared, found
+                    );
+                    self.report(TYPE_MISMATCH, message, value.span);
+                }
+            }
+            NodeKind::Return { value } => {
+                let found = match &value.kind {
+                    NodeKind::None => Type::Void,
+                    _ => self.value(value),
+                };
+                let Some((name, ret)) = self.returns.clone() else {
+                    return;
+                };
+                let message = match ret {
+                    Type::Void if found != Type::Void => {
+                        format!("`{}` returns nothing but this returns `{}`", name, found)
+                    }
+                    _ if found == Type::Void && ret != Type::Void && ret != Type::Unknown => {
+                        format!("`{}` returns `{}` but this returns nothing", name, ret)
+                    }
+                    _ if !ret.accepts(found) => {
+                        format!("`{}` returns `{}` but this is `{}`", name, ret, found)
+                    }
+                    _ => return,
+                };
+                self.report(TYPE_MISMATCH, message, node.span);
+            }
+            NodeKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                self.condition(cond);
+                self.stmts(then_body);
+                self.stmts(else_body);
+            }
+            NodeKind::While { cond, body } => {
+                self.condition(cond);
+                self.stmts(body);
+            }
+            NodeKind::Try {
+                body,
+                handlers,
+                finally,
+            } => {
+                self.stmts(body);
+                for handler in handlers {
+                    if let NodeKind::Catch { body, .. } = &handler.kind {
+                        self.types.insert(handler.id, Type::Error);
+                        self.stmts(body);
+                    }
+                }
+                self.stmts(finally);
+            }
+            NodeKind::Spill { kind, value } => {
+                let found = self.value(value);
+                let fits = match kind.is_empty() {
+                    true => matches!(found, Type::Str | Type::Error | Type::Unknown),
+                    false => Type::Str.accepts(found),
+                };
+                if !fits {
+                    let message = match kind.is_empty() {
+                        true => format!("cannot spill `{}`", found),
+                        false => format!("`{}` takes a `str` message, found `{}`", kind, found),
+                    };
+                    self.report(TYPE_MISMATCH, message, value.span);
+                }
+            }
+            NodeKind::Function { .. }
+            | NodeKind::BottleCall { .. }
+            | NodeKind::Dependency { .. }
+            | NodeKind::Sharp { .. }
+            | NodeKind::At { .. }
+            | NodeKind::Skip
+            | NodeKind::None
+            | NodeKind::Eof => {}
+            _ => {
+                self.expr(node);
+            }
+        }
+    }
+
+    fn condition(&mut self, cond: &AstNode) {
+        let found = self.value(cond);
+        if !Type::Bool.accepts(found) {
+            let message = format!("a condition must be `bool`, found `{}`", found);
+            self.report(TYPE_MISMATCH, message, cond.span);
+        }
+    }
+
+    /// [`Checker::expr`] for an expression whose value is used, which must have one.
+    fn value(&mut self, node: &AstNode) -> Type {
+        match self.expr(node) {
+            Type::Void => {
+                let message = match &node.kind {
+                    NodeKind::Call { name, .. } => format!("`{}` returns nothing to use", name),
+                    NodeKind::BangCall { name, .. } => {
+                        format!("`{}!` returns nothing to use", name)
+                    }
+                    _ => "this has no value to use".to_owned(),
+                };
+                self.report(TYPE_MISMATCH, message, node.span);
+                Type::Unknown
+            }
+            ty => ty,
+        }
+    }
+
+    fn expr(&mut self, node: &AstNode) -> Type {
+        match &node.kind {
+            NodeKind::IntLiteral { .. } => Type::Int,
+            NodeKind::FloatLiteral { .. } => Type::Float,
+            NodeKind::StrLiteral { .. } => Type::Str,
+            NodeKind::BoolLiteral { .. } => Type::Bool,
+            NodeKind::Variable { name } | NodeKind::Identifier { name } => {
+                let Some((declaration, symbol)) = self.names.definition(node.id) else {
+                    return Type::Unknown;
+                };
+                if symbol.kind == SymbolKind::Dependency {
+                    return Type::Unknown;
+                }
+                let mut ty = self
+                    .types
+                    .get(&declaration)
+                    .copied()
+                    .unwrap_or(Type::Unknown);
+                for field in name.split('.').skip(1) {
+                    ty = match (ty, field) {
+                        (Type::Unknown, _) => Type::Unknown,
+                        (Type::Error, "kind" | "message") => Type::Str,
+                        (Type::Error, "code") => Type::Int,
+                        (ty, field) => {
+                            let message = format!("`{}` has no field `{}`", ty, field);
+                            self.report(TYPE_MISMATCH, message, node.span);
+                            Type::Unknown
+                        }
+                    };
+                }
+                ty
+            }
+            NodeKind::UnaryExpr { op, expr } => {
+                let ty = self.value(expr);
+                unary(op, ty).unwrap_or_else(|| {
+                    let message = format!("cannot apply `{}` to `{}`", op, ty);
+                    self.report(TYPE_MISMATCH, message, node.span);
+                    Type::Unknown
+                })
+            }
+            NodeKind::BinaryExpr { left, op, right } => {
+                let (l, r) = (self.value(left), self.value(right));
+                binary(op, l, r).unwrap_or_else(|| {
+                    let message = format!("cannot apply `{}` to `{}` and `{}`", op, l, r);
+                    self.report(TYPE_MISMATCH, message, node.span);
+                    Type::Unknown
+                })
+            }
+            NodeKind::Call { args, .. } => self.call(node, args),
+            NodeKind::BangCall { name, args } => self.bang(node, name, args),
+            _ => {
+                for child in node.children() {
+                    self.value(child);
+                }
+                Type::Unknown
+            }
+        }
+    }
+
+    /// Checks the arguments of bang call `node` against the intrinsic it calls, returning what
+    /// it gives.
+    fn bang(&mut self, node: &AstNode, name: &str, args: &[&AstNode]) -> Type {
+        let found: Vec<Type> = args.iter().map(|arg| self.value(arg)).collect();
+        let Some(bang) = self.bangs.iter().find(|b| b.name == name) else {
+            let message = format!("unknown intrinsic `{}!`", name);
+            // Bang names are ASCII words, so `name!` is as many columns as bytes.
+            let len = name.len() + 1;
+            let span = Span {
+                end: node.span.start + len,
+                end_line: node.span.line,
+                end_column: node.span.column + len,
+                ..node.span
+            };
+            self.report(UNKNOWN_INTRINSIC, message, span);
+            let fix = crate::nearest(name, self.bangs.iter().map(|b| b.name.as_str()))
+                .map(|fix| format!("{}!", fix));
+            self.suggest(fix.as_deref());
+            return Type::Unknown;
+        };
+        let returns = bang.returns;
+        if bang.params.len() != args.len() {
+            let message = format!(
+                "`{}!` takes {} argument(s) but {} were given",
+                name,
+                bang.params.len(),
+                args.len()
+            );
+            self.report(WRONG_ARGUMENT_COUNT, message, node.span);
+            return returns;
+        }
+        let mismatches: Vec<(String, Span)> = args
+            .iter()
+            .zip(found)
+            .zip(&bang.params)
+            .enumerate()
+            .filter(|(_, ((_, found), declared))| !declared.accepts(*found))
+            .map(|(i, ((arg, found), declared))| {
+                let message = format!(
+                    "argument {} of `{}!` is `{}` but `{}` was given",
+                    i + 1,
+                    name,
+                    declared,
+                    found
+                );
+                (message, arg.span)
+            })
+            .collect();
+        for (message, span) in mismatches {
+            self.report(TYPE_MISMATCH, message, span);
+        }
+        returns
+    }
+
+    /// Checks the arguments of call `node` against what it calls, returning what it gives.
+    fn call(&mut self, node: &AstNode, args: &[&AstNode]) -> Type {
+        let found: Vec<Type> = args.iter().map(|arg| self.value(arg)).collect();
+        let signature = self
+            .names
+            .definition(node.id)
+            .and_then(|(declaration, _)| self.signatures.get(&declaration));
+        let Some(signature) = signature else {
+            return Type::Unknown;
+        };
+        if signature.params.len() != args.len() {
+            let message = format!(
+                "`{}` takes {} argument(s) but {} were given",
+                signature.name,
+                signature.params.len(),
+                args.len()
+            );
+            let ret = signature.ret;
+            self.report(WRONG_ARGUMENT_COUNT, message, node.span);
+            return ret;
+        }
+        let mut mismatches = Vec::new();
+        for ((arg, found), (param, declared)) in args.iter().zip(found).zip(&signature.params) {
+            if !declared.accepts(found) {
+                let message = format!(
+                    "`{}` of `{}` is `{}` but `{}` was given",
+                    param, signature.name, declared, found
+                );
+                mismatches.push((message, arg.span));
+            }
+        }
+        let ret = signature.ret;
+        for (message, span) in mismatches {
+            self.report(TYPE_MISMATCH, message, span);
+        }
+        ret
+    }
+
+    /// Where the name of declaration `id` is written.
+    fn name_span(&self, id: NodeId) -> Option<Span> {
+        self.names.symbols.get(&id).map(|symbol| symbol.span)
+    }
+
+    fn report(&mut self, code: i32, message: String, span: Span) {
+        self.findings.push(Finding {
+            code,
+            severity: Severity::Error,
+            message,
+            span,
+            related: None,
+            fix: None,
+        });
+    }
+
+    /// Suggests `fix` for the last finding reported.
+    fn suggest(&mut self, fix: Option<&str>) {
+        if let Some(finding) = self.findings.last_mut() {
+            finding.fix = fix.map(str::to_owned);
+        }
+    }
+}
+
+/// Whether running `body` always ends in a `return` or a `spill`.
+fn returns(body: &[&AstNode]) -> bool {
+    body.iter().any(|stmt| match &stmt.kind {
+        NodeKind::Return { .. } | NodeKind::Spill { .. } => true,
+        NodeKind::If {
+            then_body,
+            else_body,
+            ..
+        } => returns(then_body) && returns(else_body),
+        NodeKind::Try {
+            body,
+            handlers,
+            finally,
+        } => {
+            let handled = handlers.iter().all(|handler| match &handler.kind {
+                NodeKind::Catch { body, .. } => returns(body),
+                _ => false,
+            });
+            (returns(body) && handled) || returns(finally)
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::{Parser, Span};
+    use typed_arena::Arena;
+
+    /// What the checker finds wrong with the types in `source`.
+    fn mismatches(source: &str) -> Vec<String> {
+        let arena = Arena::new();
+        let (_, ast) = Parser::new(Span::new(source), &arena).parse().unwrap();
+        let names = crate::resolve(&ast, source);
+        check(&ast, &names)
+            .into_iter()
+            .filter(|f| f.code == TYPE_MISMATCH)
+            .map(|f| f.message)
+            .collect()
+    }
+
+    #[test]
+    fn i64_widens_wherever_f64_is_declared() {
+        let source = "\
+fn half(x: f64) -> f64 { return 1; }
+fn main() -> i64 {
+    let a: f64 = 1;
+    a = 2;
+    half(3);
+    return 0;
+}
+";
+        assert_eq!(mismatches(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn other_values_do_not_fit_f64() {
+        let source = "\
+fn half(x: f64) -> f64 { return true; }
+fn main() -> i64 {
+    let a: f64 = \"one\";
+    let b: f64 = 1.0;
+    b = false;
+    half(\"three\");
+    return 0;
+}
+";
+        assert_eq!(mismatches(source).len(), 4, "{:?}", mismatches(source));
+    }
+
+    #[test]
+    fn f64_does_not_narrow_to_i64() {
+        assert_eq!(mismatches("let a: i64 = 1.5;").len(), 1);
+        assert_eq!(mismatches("let a: i64 = 1;\na = 1.5;").len(), 1);
+    }
+
+    #[test]
+    fn unannotated_lets_take_their_value_type_until_reassigned() {
+        // `a` stays an `i64`, so it can't be used as a `bool`.
+        assert_eq!(mismatches("let a = 1;\nlet b: bool = a;").len(), 1);
+        // Once reassigned, `a` could hold anything and fits anywhere.
+        let reassigned = "let a = 1;\na = \"one\";\nlet b: bool = a;";
+        assert_eq!(mismatches(reassigned), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_values_fit_anywhere() {
+        let source = "\
+fn id(x: auto) -> auto { return x; }
+fn main() -> i64 {
+    let a: bool = id(1);
+    let b: str = id(true);
+    return id(\"zero\");
+}
+";
+        assert_eq!(mismatches(source), Vec::<String>::new());
+    }
+}
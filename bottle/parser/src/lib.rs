@@ -1,97 +1,332 @@
-use ast::AstNode::{self, *};
-use ast::AST;
-use core::error;
-use log::{debug, error, info, log, trace, warn, Level};
+use ast::NodeKind::{self, *};
+use ast::{AstNode, NodeId, AST};
+use log::trace;
 use nom::{
     branch::alt,
-    bytes::complete::tag,
+    bytes::complete::{escaped_transform, is_a, is_not, tag},
     character::complete::*,
-    combinator::{map, opt, recognize},
-    multi::many0,
-    sequence::{delimited, pair, preceded},
-    Err, IResult,
+    combinator::{not, opt, recognize, value},
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, terminated},
+    IResult,
 };
 use nom_locate::LocatedSpan;
 use typed_arena::Arena;
 
 pub type Span<'a> = LocatedSpan<&'a str>;
 
+/// Words that can never be used as identifiers.
+pub const KEYWORDS: &[&str] = &[
+    "fn", "return", "true", "false", "let", "if", "else", "while", "require", "lock", "import",
+    "from", "try", "catch", "finally", "spill",
+];
+
+/// Dependency kinds a bottle can declare, see the README.
+pub const DEPENDENCY_KINDS: &[&str] = &["require", "lock", "import"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+/// Binary operators as `(token, precedence, associativity)`.
+/// A higher precedence binds tighter. Tokens sharing a prefix are listed longest first,
+/// so the scanner never reads `<<` as `<` or `&&` as `&`.
+pub const BINARY_OPS: &[(&str, u8, Assoc)] = &[
+    ("**", 11, Assoc::Right),
+    ("||", 1, Assoc::Left),
+    ("&&", 2, Assoc::Left),
+    ("==", 6, Assoc::Left),
+    ("!=", 6, Assoc::Left),
+    ("<=", 7, Assoc::Left),
+    (">=", 7, Assoc::Left),
+    ("<<", 8, Assoc::Left),
+    (">>", 8, Assoc::Left),
+    ("|", 3, Assoc::Left),
+    ("^", 4, Assoc::Left),
+    ("&", 5, Assoc::Left),
+    ("<", 7, Assoc::Left),
+    (">", 7, Assoc::Left),
+    ("+", 9, Assoc::Left),
+    ("-", 9, Assoc::Left),
+    ("*", 10, Assoc::Left),
+    ("/", 10, Assoc::Left),
+    ("%", 10, Assoc::Left),
+];
+
+/// Prefix operators. These bind tighter than every binary operator, so `-2 ** 2` is `(-2) ** 2`.
+pub const UNARY_OPS: &[&str] = &["-", "!", "~"];
+
+/// Skips whitespace and `//` line comments.
+fn ws(input: Span) -> IResult<Span, Span> {
+    recognize(many0(alt((
+        multispace1,
+        recognize(pair(tag("//"), opt(is_not("\n")))),
+    ))))(input)
+}
+
+/// Matches `kw` only as a whole word, so `fnord` is not read as `fn ord`.
+fn keyword<'a>(kw: &'static str) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Span<'a>> {
+    terminated(tag(kw), not(alt((alphanumeric1, tag("_")))))
+}
+
+/// Turns a recoverable error into a [`nom::Err::Failure`], for input that what has been read
+/// already rules out parsing any other way. The error then stays where it happened instead of
+/// being retried as something else and reported at the start of the item around it.
+fn commit<'a, T>(result: IResult<Span<'a>, T>) -> IResult<Span<'a>, T> {
+    result.map_err(|e| match e {
+        nom::Err::Error(e) => nom::Err::Failure(e),
+        e => e,
+    })
+}
+
+/// Tries `next` only when `result` failed without committing to anything.
+trait Or<'a, T> {
+    fn or_try(self, next: impl FnOnce() -> IResult<Span<'a>, T>) -> IResult<Span<'a>, T>;
+}
+
+impl<'a, T> Or<'a, T> for IResult<Span<'a>, T> {
+    fn or_try(self, next: impl FnOnce() -> IResult<Span<'a>, T>) -> IResult<Span<'a>, T> {
+        match self {
+            Err(nom::Err::Error(_)) => next(),
+            result => result,
+        }
+    }
+}
+
+/// Where a source stops partway through something, as found by [`unfinished`]. Offsets are
+/// in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unfinished {
+    /// An opening bracket that is never closed.
+    Delimiter(usize, char),
+    /// A string literal with no closing quote.
+    Str(usize),
+    /// An operator at the very end, still waiting for what follows it.
+    Operator(usize, &'static str),
+}
+
+impl Unfinished {
+    pub fn offset(&self) -> usize {
+        match self {
+            Unfinished::Delimiter(at, _) | Unfinished::Str(at) | Unfinished::Operator(at, _) => *at,
+        }
+    }
+
+    /// How many bytes the unfinished token takes up.
+    pub fn width(&self) -> usize {
+        match self {
+            Unfinished::Delimiter(..) | Unfinished::Str(_) => 1,
+            Unfinished::Operator(_, op) => op.len(),
+        }
+    }
+}
+
+impl std::fmt::Display for Unfinished {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unfinished::Delimiter(_, c) => write!(f, "`{}` is never closed", c),
+            Unfinished::Str(_) => write!(f, "string is never closed"),
+            Unfinished::Operator(_, op) => write!(f, "`{}` is missing what follows it", op),
+        }
+    }
+}
+
+/// Whether `source` ends partway through a construct that more input could finish: an open
+/// bracket or string, or a trailing operator. A closing bracket that matches nothing is a plain
+/// syntax error, not unfinished input.
+pub fn unfinished(source: &str) -> Option<Unfinished> {
+    let mut open = Vec::new();
+    let mut last = Option::None;
+    let mut chars = source.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '/' if source[at..].starts_with("//") => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            '#' | '@' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            '"' => loop {
+                match chars.next() {
+                    Some((_, '\\')) => {
+                        chars.next();
+                    }
+                    Some((_, '"')) => break,
+                    Some(_) => {}
+                    Option::None => return Some(Unfinished::Str(at)),
+                }
+            },
+            '(' | '[' | '{' => open.push((at, c)),
+            ')' | ']' | '}' => match open.pop() {
+                Some((_, o)) if matches!((o, c), ('(', ')') | ('[', ']') | ('{', '}')) => {}
+                _ => return Option::None,
+            },
+            c if c.is_whitespace() => continue,
+            _ => {}
+        }
+        last = Some(at + c.len_utf8());
+    }
+    if let Some((at, c)) = open.pop() {
+        return Some(Unfinished::Delimiter(at, c));
+    }
+    let text = &source[..last?];
+    ["->"]
+        .into_iter()
+        .chain(BINARY_OPS.iter().map(|(op, _, _)| *op))
+        .chain(UNARY_OPS.iter().copied())
+        .chain(["=", ","])
+        .find(|op| text.ends_with(op))
+        .map(|op| Unfinished::Operator(text.len() - op.len(), op))
+}
+
+/// An empty span where `at` starts.
+fn span_at(at: Span) -> ast::Span {
+    let (line, column) = (at.location_line() as usize - 1, at.get_utf8_column() - 1);
+    ast::Span {
+        start: at.location_offset(),
+        end: at.location_offset(),
+        line,
+        column,
+        end_line: line,
+        end_column: column,
+    }
+}
+
 pub struct Parser<'a> {
-    current_node: &'a AstNode<'a>,
     input: Span<'a>,
     arena: &'a Arena<AstNode<'a>>,
-    level: u32,
+    next_id: u32,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: Span<'a>, arena: &'a Arena<AstNode<'a>>) -> Self {
         Self {
-            current_node: arena.alloc(Root { children: vec![] }),
             input,
             arena,
-            level: 0,
+            next_id: 0,
         }
     }
 
     pub fn parse(&mut self) -> IResult<Span<'a>, AST<'a>> {
-        let input = self.input;
-        let (input, root) = many0(|i| self.parse_stmt(i))(input)?;
+        let start = self.input;
+        let (input, root) = many0(|i| self.directive(i).or_try(|| self.parse_stmt(i)))(start)?;
+        let (input, _) = ws(input)?;
         self.input = input;
         Ok((
             self.input,
             AST {
-                head: self.arena.alloc(AstNode::Root { children: root }),
+                head: self.node(start, input, Root { children: root }),
                 arena: self.arena,
             },
         ))
     }
 
+    /// Allocates a node for the source from `from` up to `to`.
+    fn node(&mut self, from: Span<'a>, to: Span<'a>, kind: NodeKind<'a>) -> &'a AstNode<'a> {
+        self.node_from(span_at(from), to, kind)
+    }
+
+    /// Allocates a node for the source from where `first` starts up to `to`.
+    fn node_from(&mut self, first: ast::Span, to: Span<'a>, kind: NodeKind<'a>) -> &'a AstNode<'a> {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        let end = span_at(to);
+        let span = ast::Span {
+            end: end.start,
+            end_line: end.line,
+            end_column: end.column,
+            ..first
+        };
+        self.arena.alloc(AstNode::new(id, span, kind))
+    }
+
     fn parse_identifier(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, _) = multispace0(input)?;
-        map(
-            recognize(pair(
-                alt((alpha1, tag("_"))),
-                many0(alt((alphanumeric1, tag("_")))),
-            )),
-            |s: Span<'a>| {
-                &*self.arena.alloc(Identifier {
-                    name: s.fragment().to_string(),
-                })
-            },
-        )(input)
+        let (input, _) = ws(input)?;
+        let (rest, s) = recognize(pair(
+            alt((alpha1, tag("_"))),
+            many0(alt((alphanumeric1, tag("_")))),
+        ))(input)?;
+        if KEYWORDS.contains(s.fragment()) {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Tag,
+            )));
+        }
+        let name = s.fragment().to_string();
+        Ok((rest, self.node(input, rest, Identifier { name })))
     }
 
     fn parse_ret_type(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, _) = delimited(multispace0, tag("->"), multispace0)(input)?;
-        self.parse_identifier(input)
+        let (input, _) = delimited(ws, tag("->"), ws)(input)?;
+        commit(self.parse_identifier(input))
     }
 
-    fn parse_literalnum(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        map(
+    fn parse_literalnum(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, (prefix, s)) = alt((
+            pair(tag("0x"), hex_digit1),
+            pair(tag("0b"), is_a("01")),
             pair(
-                opt(alt((tag("0x"), tag("0b")))),
-                recognize(pair(digit1, opt(pair(tag("."), digit1)))),
+                tag(""),
+                recognize(pair(digit1, opt(pair(char('.'), digit1)))),
             ),
-            |(prefix, s): (Option<Span<'a>>, Span<'a>)| {
-                &*self.arena.alloc(IntLiteral {
-                    value: i64::from_str_radix(
-                        s.fragment(),
-                        match prefix.map(|p| *p.fragment()) {
-                            Some("0x") => 16,
-                            Some("0b") => 2,
-                            _ => 10,
-                        },
-                    )
-                    .unwrap(),
-                })
-            },
-        )(input)
+        ))(start)?;
+        let radix = match *prefix.fragment() {
+            "0x" => 16,
+            "0b" => 2,
+            _ => 10,
+        };
+        let node = if s.fragment().contains('.') {
+            FloatLiteral {
+                value: s.fragment().parse().unwrap(),
+            }
+        } else {
+            IntLiteral {
+                value: i64::from_str_radix(s.fragment(), radix).map_err(|_| {
+                    nom::Err::Failure(nom::error::Error::new(s, nom::error::ErrorKind::Digit))
+                })?,
+            }
+        };
+        Ok((input, self.node(start, input, node)))
+    }
+
+    fn parse_literalstr(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, s) = delimited(
+            char('"'),
+            opt(escaped_transform(
+                is_not("\\\""),
+                '\\',
+                alt((
+                    value("\\", tag("\\")),
+                    value("\"", tag("\"")),
+                    value("\n", tag("n")),
+                    value("\t", tag("t")),
+                    value("\r", tag("r")),
+                    value("\0", tag("0")),
+                )),
+            )),
+            char('"'),
+        )(start)?;
+        let value = s.unwrap_or_default();
+        Ok((input, self.node(start, input, StrLiteral { value })))
+    }
+
+    fn parse_literalbool(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, value) = terminated(
+            alt((value(true, tag("true")), value(false, tag("false")))),
+            not(alt((alphanumeric1, tag("_")))),
+        )(start)?;
+        Ok((input, self.node(start, input, BoolLiteral { value })))
     }
 
     fn parse_fn(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, _) = delimited(multispace0, tag("fn"), multispace0)(input)?;
-        let (input, name_node) = self.parse_identifier(input)?;
-        let name = if let Identifier { ref name } = *name_node {
+        let (start, _) = ws(input)?;
+        let (input, _) = terminated(keyword("fn"), ws)(start)?;
+        let (input, name_node) = commit(self.parse_identifier(input))?;
+        let name = if let Identifier { name } = &name_node.kind {
             name.to_owned()
         } else {
             return Err(nom::Err::Error(nom::error::Error::new(
@@ -99,108 +334,481 @@ impl<'a> Parser<'a> {
                 nom::error::ErrorKind::Tag,
             )));
         };
-        let (input, _) = delimited(char('('), many0(|i| self.parse_arg(i)), char(')'))(input)?;
+        let (input, params) = commit(delimited(
+            char('('),
+            separated_list0(delimited(ws, char(','), ws), |i| self.parse_arg(i)),
+            preceded(ws, char(')')),
+        )(input))?;
         let (input, return_type_node) = match self.parse_ret_type(input) {
             Ok((input, return_type_node)) => (input, return_type_node),
-            Err(_) => {
-                let void_node = &*self.arena.alloc(Identifier {
-                    name: "void".to_string(),
-                });
-                (input, void_node)
+            Err(nom::Err::Error(_)) => {
+                let name = "void".to_string();
+                (input, self.node(input, input, Identifier { name }))
             }
+            Err(e) => return Err(e),
         };
-        let return_type = if let Identifier { ref name } = *return_type_node {
+        let return_type = if let Identifier { name } = &return_type_node.kind {
             name.clone()
         } else {
             "void".to_owned()
         };
-        let (input, body) = self.parse_body(input)?;
-        Ok((
-            input,
-            &*self.arena.alloc(Function {
-                name,
-                params: vec![],
-                return_type,
-                body,
-            }),
-        ))
+        let (input, body) = commit(self.parse_body(input))?;
+        let function = Function {
+            name,
+            params,
+            return_type,
+            body,
+        };
+        Ok((input, self.node(start, input, function)))
     }
 
+    /// Parses `name: type`. Parameters are kept as value-less [`Declaration`]s.
     fn parse_arg(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, name) = self.parse_identifier(input)?;
-        let (input, _) = char(':')(input)?;
-        let (input, _ptype) = self.parse_expr(input)?;
-        Ok((input, name))
+        let (start, _) = ws(input)?;
+        let (input, name) = self.parse_name(start)?;
+        let (input, _) = preceded(ws, char(':'))(input)?;
+        let (input, struct_type) = self.parse_name(input)?;
+        let declaration = Declaration {
+            struct_type,
+            name,
+            value: self.node(input, input, NodeKind::None),
+        };
+        Ok((input, self.node(start, input, declaration)))
+    }
+
+    fn parse_name(&mut self, input: Span<'a>) -> IResult<Span<'a>, String> {
+        let (input, ident) = self.parse_identifier(input)?;
+        match &ident.kind {
+            Identifier { name } => Ok((input, name.clone())),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Parses a dotted name such as `math.add`, naming something inside a dependency.
+    fn parse_path(&mut self, input: Span<'a>) -> IResult<Span<'a>, String> {
+        let (mut input, mut path) = self.parse_name(input)?;
+        while let Ok((rest, _)) = char::<Span<'a>, nom::error::Error<Span<'a>>>('.')(input) {
+            let (rest, segment) = self.parse_name(rest)?;
+            path.push('.');
+            path.push_str(&segment);
+            input = rest;
+        }
+        Ok((input, path))
     }
 
-    fn parse_expr(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+    /// Parses a full expression. See [`BINARY_OPS`] and [`UNARY_OPS`] for the operator table.
+    pub fn parse_expr(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        self.parse_binary(input, 0)
+    }
+
+    /// Precedence climbing over [`BINARY_OPS`]. Only operators binding at least as tight as
+    /// `min_prec` are consumed; the rest are left for the caller.
+    fn parse_binary(
+        &mut self,
+        input: Span<'a>,
+        min_prec: u8,
+    ) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (mut input, mut left) = self.parse_unary(input)?;
+        loop {
+            let (rest, _) = ws(input)?;
+            let Some((op, prec, assoc)) = BINARY_OPS
+                .iter()
+                .copied()
+                .find(|(op, _, _)| rest.fragment().starts_with(op))
+            else {
+                break;
+            };
+            if prec < min_prec {
+                break;
+            }
+            let (rest, _) = tag(op)(rest)?;
+            let next_prec = match assoc {
+                Assoc::Left => prec + 1,
+                Assoc::Right => prec,
+            };
+            let (rest, right) = commit(self.parse_binary(rest, next_prec))?;
+            let binary = BinaryExpr {
+                left,
+                op: op.to_owned(),
+                right,
+            };
+            left = self.node_from(left.span, rest, binary);
+            input = rest;
+        }
+        Ok((input, left))
+    }
+
+    fn parse_unary(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = ws(input)?;
+        if let Some(op) = UNARY_OPS
+            .iter()
+            .find(|op| input.fragment().starts_with(*op))
+        {
+            let (rest, _) = tag(*op)(input)?;
+            let (rest, expr) = commit(self.parse_unary(rest))?;
+            let op = op.to_string();
+            return Ok((rest, self.node(input, rest, UnaryExpr { op, expr })));
+        }
+        self.parse_primary(input)
+    }
+
+    fn parse_primary(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = ws(input)?;
+        if let Ok((inner, _)) = char::<Span<'a>, nom::error::Error<Span<'a>>>('(')(input) {
+            let (inner, expr) = commit(self.parse_expr(inner))?;
+            let (rest, _) = commit(preceded(ws, char(')'))(inner))?;
+            // Spanning the parentheses too, so the expression's source reads back as one.
+            return Ok((rest, self.node(input, rest, expr.kind.clone())));
+        }
         self.parse_literalnum(input)
-            .or(self.parse_identifier(input))
+            .or_try(|| self.parse_literalstr(input))
+            .or_try(|| self.parse_literalbool(input))
+            .or_try(|| self.call(input))
+            .or_try(|| self.parse_variable(input))
+    }
+
+    fn parse_variable(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, name) = self.parse_path(start)?;
+        Ok((input, self.node(start, input, Variable { name })))
     }
 
     fn parse_body(&mut self, input: Span<'a>) -> IResult<Span<'a>, Vec<&'a AstNode<'a>>> {
-        println!("Parsing body");
-        let (input, _) = preceded(multispace0, char('{'))(input)?;
+        trace!("Parsing body");
+        let (input, _) = preceded(ws, char('{'))(input)?;
         let (input, body) = many0(|i| self.parse_stmt(i))(input)?;
-        let (input, _) = preceded(multispace0, char('}'))(input)?;
-        println!("Parsed body: {:#?}", body);
+        let (input, _) = commit(preceded(ws, char('}'))(input))?;
+        trace!("Parsed body: {:#?}", body);
         Ok((input, body))
     }
 
     fn parse_stmt(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, _) = multispace0(input)?;
+        let (input, _) = ws(input)?;
         let (input, ret) = self
-            .call(input)
-            .or_else(|_| self.parse_kwd(input))
-            .or_else(|_| self.parse_fn(input))
-            .or_else(|_| self.parse_expr(input))?;
-        let (input, _) = multispace0(input)?;
+            .parse_kwd(input)
+            .or_try(|| self.parse_fn(input))
+            .or_try(|| self.assignment(input))
+            .or_try(|| self.parse_expr(input))?;
+        let (input, _) = ws(input)?;
         let (input, _) = opt(char(';'))(input)?;
         Ok((input, ret))
     }
 
     fn parse_kwd(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
         self.return_stmt(input)
-        //alt((|i| self.return_stmt(i)))(input)
+            .or_try(|| self.let_stmt(input))
+            .or_try(|| self.if_stmt(input))
+            .or_try(|| self.while_stmt(input))
+            .or_try(|| self.try_stmt(input))
+            .or_try(|| self.spill_stmt(input))
+            .or_try(|| self.dependency(input))
     }
 
-    fn call(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, name_node) = self.parse_identifier(input)?;
-        let name = if let Identifier { ref name } = *name_node {
-            name.clone()
-        } else {
+    /// `name(args)`, or `name!(args)` for an intrinsic. Intrinsics may share a keyword's name,
+    /// as `spill!` does.
+    fn call(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, (name, bang)) = match self.parse_path(start) {
+            Ok((input, name)) => {
+                let (input, bang) = opt(char('!'))(input)?;
+                (input, (name, bang))
+            }
+            Err(e) => {
+                let (input, _) = ws(start)?;
+                let (rest, kw) = alpha1(input)?;
+                if !KEYWORDS.contains(kw.fragment()) {
+                    return Err(e);
+                }
+                let (rest, bang) = char('!')(rest)?;
+                (rest, (kw.fragment().to_string(), Some(bang)))
+            }
+        };
+        let (input, _) = char('(')(input)?;
+        let (input, args) = commit(terminated(
+            separated_list0(delimited(ws, char(','), ws), |i| self.parse_expr(i)),
+            preceded(ws, char(')')),
+        )(input))?;
+        let call = match bang {
+            Some(_) => BangCall { name, args },
+            Option::None => Call { name, args },
+        };
+        Ok((input, self.node(start, input, call)))
+    }
+
+    fn return_stmt(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = keyword("return")(start)?;
+        let (input, value) = match self.parse_expr(input) {
+            Ok(res) => res,
+            Err(nom::Err::Error(_)) => (input, self.node(input, input, NodeKind::None)),
+            Err(e) => return Err(e),
+        };
+        Ok((input, self.node(start, input, Return { value })))
+    }
+
+    /// `let name = value` or `let name: type = value`. Untyped bindings get the type `auto`.
+    fn let_stmt(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = keyword("let")(start)?;
+        let (input, name) = commit(self.parse_name(input))?;
+        let (input, struct_type) = match preceded(ws, char(':'))(input) {
+            Ok((input, _)) => commit(self.parse_name(input))?,
+            Err(_) => (input, "auto".to_owned()),
+        };
+        let (input, _) = commit(delimited(ws, char('='), ws)(input))?;
+        let (input, value) = commit(self.parse_expr(input))?;
+        let declaration = Declaration {
+            struct_type,
+            name,
+            value,
+        };
+        Ok((input, self.node(start, input, declaration)))
+    }
+
+    fn assignment(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, identifier) = self.parse_identifier(start)?;
+        let (input, _) = delimited(ws, terminated(char('='), not(char('='))), ws)(input)?;
+        let (input, value) = commit(self.parse_expr(input))?;
+        Ok((
+            input,
+            self.node(start, input, Assignment { identifier, value }),
+        ))
+    }
+
+    fn if_stmt(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = keyword("if")(start)?;
+        let (input, cond) = commit(self.parse_expr(input))?;
+        let (input, then_body) = commit(self.parse_body(input))?;
+        let (input, else_body) = match preceded(ws, keyword("else"))(input) {
+            Ok((input, _)) => {
+                let (after_ws, _) = ws(input)?;
+                match self.if_stmt(after_ws) {
+                    Ok((input, elif)) => (input, vec![elif]),
+                    Err(nom::Err::Error(_)) => commit(self.parse_body(input))?,
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(_) => (input, vec![]),
+        };
+        let node = If {
+            cond,
+            then_body,
+            else_body,
+        };
+        Ok((input, self.node(start, input, node)))
+    }
+
+    /// `try { .. }` followed by `catch` handlers and an optional `finally { .. }`. A handler is
+    /// `catch { .. }`, `catch name { .. }` or `catch name: Kind { .. }`. At least one handler or
+    /// the `finally` body is required.
+    fn try_stmt(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = keyword("try")(start)?;
+        let (mut input, body) = commit(self.parse_body(input))?;
+        let mut handlers = Vec::new();
+        loop {
+            let (at, _) = ws(input)?;
+            let Ok((rest, _)) = keyword("catch")(at) else {
+                break;
+            };
+            let (rest, name) = match self.parse_name(rest) {
+                Ok((rest, name)) => (rest, name),
+                Err(_) => (rest, String::new()),
+            };
+            let (rest, kind) = match preceded(ws, char(':'))(rest) {
+                Ok((rest, _)) if !name.is_empty() => commit(self.parse_name(rest))?,
+                _ => (rest, String::new()),
+            };
+            let (rest, body) = commit(self.parse_body(rest))?;
+            handlers.push(self.node(at, rest, Catch { kind, name, body }));
+            input = rest;
+        }
+        let (input, finally) = match preceded(ws, keyword("finally"))(input) {
+            Ok((input, _)) => commit(self.parse_body(input))?,
+            Err(_) if handlers.is_empty() => {
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Tag,
+                )))
+            }
+            Err(_) => (input, Vec::new()),
+        };
+        let node = Try {
+            body,
+            handlers,
+            finally,
+        };
+        Ok((input, self.node(start, input, node)))
+    }
+
+    /// `spill Kind(message)` or `spill value`. A capitalised name directly followed by `(`
+    /// names the kind of error to spill. `spill!` directly after is the intrinsic instead.
+    fn spill_stmt(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = terminated(keyword("spill"), not(char('!')))(start)?;
+        let (input, (kind, value)) = match self.error_kind(input) {
+            Ok(res) => res,
+            Err(nom::Err::Error(_)) => {
+                let (input, value) = commit(self.parse_expr(input))?;
+                (input, (String::new(), value))
+            }
+            Err(e) => return Err(e),
+        };
+        Ok((input, self.node(start, input, Spill { kind, value })))
+    }
+
+    /// `Kind(message)` after `spill`.
+    fn error_kind(&mut self, input: Span<'a>) -> IResult<Span<'a>, (String, &'a AstNode<'a>)> {
+        let (rest, kind) = self.parse_name(input)?;
+        if !kind.starts_with(|c: char| c.is_ascii_uppercase()) {
             return Err(nom::Err::Error(nom::error::Error::new(
                 input,
                 nom::error::ErrorKind::Tag,
             )));
+        }
+        let (rest, _) = char('(')(rest)?;
+        let (rest, value) = commit(self.parse_expr(rest))?;
+        let (rest, _) = commit(preceded(ws, char(')'))(rest))?;
+        Ok((rest, (kind, value)))
+    }
+
+    /// `require name`, `lock name` or `import name`, optionally followed by `from "path"`.
+    /// Without a path the dependency is looked up next to the declaring bottle.
+    fn dependency(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, kind) = alt((keyword("require"), keyword("lock"), keyword("import")))(start)?;
+        let (input, name) = commit(self.parse_name(input))?;
+        let (input, path) = match preceded(ws, keyword("from"))(input) {
+            Ok((input, _)) => {
+                let (input, _) = ws(input)?;
+                match commit(self.parse_literalstr(input))? {
+                    (
+                        input,
+                        AstNode {
+                            kind: StrLiteral { value },
+                            ..
+                        },
+                    ) => (input, value.clone()),
+                    _ => unreachable!(),
+                }
+            }
+            Err(_) => (input, String::new()),
         };
-        let (input, bang) = opt(char('!'))(input)?;
-        let (input, args) = delimited(char('('), many0(|i| self.parse_expr(i)), char(')'))(input)?;
-        let node = if bang.is_some() {
-            self.arena.alloc(BangCall { name, args })
-        } else {
-            self.arena.alloc(Call { name, args })
+        let dependency = Dependency {
+            kind: kind.fragment().to_string(),
+            name,
+            path,
         };
-        Ok((input, node))
+        Ok((input, self.node(start, input, dependency)))
     }
 
-    fn return_stmt(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        let (input, _) = tag("return")(input)?;
-        let (input, _) = multispace1(input)?;
-        let (input, expr) = self.parse_expr(input)?;
-        Ok((input, self.arena.alloc(Return { value: expr })))
+    /// `#name args..` or `@name args..`: words up to the end of the line or a `//` comment. Only
+    /// allowed at the top level, and left for the bottle to interpret.
+    fn directive(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (start, _) = ws(input)?;
+        let (input, sigil) = one_of("#@")(start)?;
+        let line = input.fragment().split('\n').next().unwrap_or_default();
+        let line = &line[..line.find("//").unwrap_or(line.len())];
+        let args: Vec<String> = line.split_whitespace().map(str::to_owned).collect();
+        if args.is_empty() || line.starts_with(char::is_whitespace) {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                start,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let (input, _) = nom::bytes::complete::take(line.trim_end().len())(input)?;
+        let directive = match sigil {
+            '#' => Sharp { args },
+            _ => At { args },
+        };
+        Ok((input, self.node(start, input, directive)))
     }
 
-    fn unknown_stmt(&mut self, input: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
-        println!("Unknown statement");
-        let (input, _) = multispace0(input)?;
-        let (input, unknown) = recognize(nom::bytes::streaming::take_until(";"))(input)?;
-        let (input, _) = opt(char(';'))(input)?;
-        Ok((
-            input,
-            self.arena.alloc(Unknown {
-                stmt: unknown.fragment().to_string(),
-            }),
-        ))
+    fn while_stmt(&mut self, start: Span<'a>) -> IResult<Span<'a>, &'a AstNode<'a>> {
+        let (input, _) = keyword("while")(start)?;
+        let (input, cond) = commit(self.parse_expr(input))?;
+        let (input, body) = commit(self.parse_body(input))?;
+        Ok((input, self.node(start, input, While { cond, body })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<'a>(source: &'a str, arena: &'a Arena<AstNode<'a>>) -> AST<'a> {
+        let (rest, ast) = Parser::new(Span::new(source), arena).parse().unwrap();
+        assert!(
+            rest.fragment().is_empty(),
+            "left over: {:?}",
+            rest.fragment()
+        );
+        ast
+    }
+
+    fn first<'a>(ast: &AST<'a>) -> &'a AstNode<'a> {
+        let Root { children } = &ast.head.kind else {
+            unreachable!("the parser always returns a root");
+        };
+        children[0]
+    }
+
+    #[test]
+    fn parenthesised_operands_span_their_parentheses() {
+        let arena = Arena::new();
+        let source = "(a + b) * c";
+        let product = first(&parse(source, &arena));
+        assert_eq!(product.span.text(source), "(a + b) * c");
+        let BinaryExpr { left, right, .. } = &product.kind else {
+            panic!("expected a product, found {}", product);
+        };
+        assert_eq!(left.span.text(source), "(a + b)");
+        assert_eq!((left.span.line, left.span.column), (0, 0));
+        assert!(matches!(&left.kind, BinaryExpr { op, .. } if op == "+"));
+        assert_eq!(right.span.text(source), "c");
+    }
+
+    #[test]
+    fn operators_group_by_precedence_and_associativity() {
+        let table = [
+            ("1 - 2 - 3", "((1 - 2) - 3)"),
+            ("2 * 3 + 4", "((2 * 3) + 4)"),
+            ("2 + 3 * 4", "(2 + (3 * 4))"),
+            ("8 / 4 / 2", "((8 / 4) / 2)"),
+            ("2 ** 3 ** 2", "(2 ** (3 ** 2))"),
+            ("-2 ** 2", "((-2) ** 2)"),
+            ("-a * b", "((-a) * b)"),
+            ("- -1", "(-(-1))"),
+            ("!a && b", "((!a) && b)"),
+            ("a < b == c < d", "((a < b) == (c < d))"),
+            ("a == b != c", "((a == b) != c)"),
+            ("a < b < c", "((a < b) < c)"),
+            ("a || b && c", "(a || (b && c))"),
+            ("a | b ^ c & d", "(a | (b ^ (c & d)))"),
+            ("1 << 2 + 3", "(1 << (2 + 3))"),
+            ("(1 - 2) * 3", "((1 - 2) * 3)"),
+        ];
+        for (source, grouped) in table {
+            let arena = Arena::new();
+            assert_eq!(
+                first(&parse(source, &arena)).to_string(),
+                grouped,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn parse_failures_are_reported_where_they_happen() {
+        let table = [
+            ("let x = 1 +;", (1, 12)),
+            ("fn main() {\n    let x = (1 + 2;\n}", (2, 19)),
+            ("fn main() {\n    return 1 * ;\n}", (2, 16)),
+            ("fn f(a: i64 {}", (1, 13)),
+        ];
+        for (source, at) in table {
+            let arena = Arena::new();
+            let stopped = match Parser::new(Span::new(source), &arena).parse() {
+                Ok((rest, _)) => rest,
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => e.input,
+                Err(nom::Err::Incomplete(_)) => unreachable!("the parser reads complete input"),
+            };
+            let found = (stopped.location_line(), stopped.get_utf8_column());
+            assert_eq!(found, at, "{:?}", source);
+        }
     }
 }
@@ -0,0 +1,285 @@
+//! Winecellar: what a bottle is checked against before it may run where it is attached.
+//!
+//! A bottle attaches to a [`Site`], which grants capabilities and accepts some set of
+//! directives. Once the bottle and everything it depends on has loaded, [`inspect`] makes sure
+//! its entry point is `fn main()` returning `i64` or nothing, that every `#`/`@` directive in it
+//! is one the site accepts and reads, and that the site grants every capability the bottle asks
+//! for, in its `bottle.toml` or with `@require`. A bottle that fails any of these shatters as
+//! [`Shatter::Empty`](crate::Shatter::Empty) without running.
+use crate::deps::DependencyGraph;
+use crate::directive::{self, Settings};
+use crate::err::{Diagnostic, Error, Warning};
+use ast::{AstNode, NodeKind};
+use std::collections::BTreeSet;
+
+/// The root bottle has no `main`.
+pub const MISSING_ENTRY: i32 = 2001;
+/// `main` takes parameters or returns something other than `i64` or nothing.
+pub const ENTRY_SIGNATURE: i32 = 2002;
+/// A `#` or `@` directive the site does not accept.
+pub const DIRECTIVE_NOT_ACCEPTED: i32 = 2003;
+/// A capability the bottle asks for that the site does not grant.
+pub const CAPABILITY_NOT_GRANTED: i32 = 2004;
+/// A `#` or `@` directive that isn't in [`directive::DIRECTIVES`]. Only a warning.
+pub const UNKNOWN_DIRECTIVE: i32 = 2005;
+/// A directive whose arguments don't read.
+pub const DIRECTIVE_ARGUMENTS: i32 = 2006;
+
+/// Where a bottle runs, and what it may do there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    granted: BTreeSet<String>,
+    /// Directives by name, sigil included: `#loglevel`, `@require`.
+    directives: BTreeSet<String>,
+}
+
+/// A site granting nothing that accepts every directive there is.
+impl Default for Site {
+    fn default() -> Site {
+        Site {
+            granted: BTreeSet::new(),
+            directives: directive::DIRECTIVES
+                .iter()
+                .map(|d| format!("{}{}", d.sigil, d.name))
+                .collect(),
+        }
+    }
+}
+
+impl Site {
+    /// A site that grants every capability in `capabilities`, separated by commas.
+    pub fn granting(capabilities: &str) -> Site {
+        let mut site = Site::default();
+        for capability in capabilities.split(',').map(str::trim) {
+            if !capability.is_empty() {
+                site.grant(capability);
+            }
+        }
+        site
+    }
+
+    pub fn grant(&mut self, capability: &str) {
+        self.granted.insert(capability.to_owned());
+    }
+
+    pub fn is_granted(&self, capability: &str) -> bool {
+        self.granted.contains(capability)
+    }
+
+    /// Lets bottles here use `directive`, written with its sigil.
+    pub fn accept(&mut self, directive: &str) {
+        self.directives.insert(directive.to_owned());
+    }
+
+    /// Stops bottles here from using `what`: a directive written with its sigil, or a
+    /// capability, which is no longer granted.
+    pub fn refuse(&mut self, what: &str) {
+        match what.starts_with(['#', '@']) {
+            true => self.directives.remove(what),
+            false => self.granted.remove(what),
+        };
+    }
+
+    /// [`Site::refuse`]s everything in `list`, separated by commas.
+    pub fn refusing(mut self, list: &str) -> Site {
+        for what in list.split(',').map(str::trim) {
+            if !what.is_empty() {
+                self.refuse(what);
+            }
+        }
+        self
+    }
+
+    pub fn accepts(&self, sigil: char, name: &str) -> bool {
+        self.directives.contains(&format!("{}{}", sigil, name))
+    }
+}
+
+/// Everything stopping `graph` from running at `site` when its `bottle.toml`, `manifest`,
+/// asks for `requested`, along with what the root's directives set.
+pub fn inspect(
+    graph: &DependencyGraph,
+    site: &Site,
+    requested: &[String],
+    manifest: &str,
+) -> (Settings, Vec<Diagnostic>) {
+    let mut found = Vec::new();
+    entry(graph, &mut found);
+    let mut root = Settings::default();
+    for capability in requested {
+        if !site.is_granted(capability) {
+            found.push(not_granted(manifest, capability));
+        }
+    }
+    for (i, unit) in graph.units.iter().enumerate() {
+        let file = unit.file();
+        let mut settings = Settings::default();
+        directives(unit.ast.head, site, &file, &mut settings, &mut found);
+        // Code from every file runs here, so every file's capabilities are checked, but only
+        // the root's directives configure the bottle.
+        if i == 0 {
+            root = settings;
+        }
+    }
+    (root, found)
+}
+
+fn not_granted(file: &str, capability: &str) -> Diagnostic {
+    error(
+        CAPABILITY_NOT_GRANTED,
+        file,
+        format!("capability `{}` is not granted here", capability),
+    )
+    .with_help(format!(
+        "run with `--grant {}`, or add it to `grant` in wineglass.toml",
+        capability
+    ))
+}
+
+fn entry(graph: &DependencyGraph, found: &mut Vec<Diagnostic>) {
+    let root = &graph.units[0];
+    let NodeKind::Root { children } = &root.ast.head.kind else {
+        return;
+    };
+    let main = children
+        .iter()
+        .find(|n| matches!(&n.kind, NodeKind::Function { name, .. } if name == "main"));
+    let Some(main) = main else {
+        found.push(
+            error(
+                MISSING_ENTRY,
+                &root.file(),
+                "no `main` function defined".to_owned(),
+            )
+            .with_help("a bottle starts at `fn main() -> i64 { .. }`"),
+        );
+        return;
+    };
+    let NodeKind::Function {
+        params,
+        return_type,
+        ..
+    } = &main.kind
+    else {
+        return;
+    };
+    let at = root
+        .names
+        .symbols
+        .get(&main.id)
+        .map_or(main.span, |s| s.span);
+    if !matches!(return_type.as_str(), "i64" | "void" | "auto") {
+        found.push(
+            error(
+                ENTRY_SIGNATURE,
+                &root.file(),
+                format!("`main` must return i64 or nothing, not {}", return_type),
+            )
+            .at(at),
+        );
+    }
+    if let Some(first) = params.first() {
+        let last = params.last().unwrap_or(first);
+        let span = ast::Span {
+            end: last.span.end,
+            end_line: last.span.end_line,
+            end_column: last.span.end_column,
+            ..first.span
+        };
+        found.push(
+            error(
+                ENTRY_SIGNATURE,
+                &root.file(),
+                "`main` must not take parameters".to_owned(),
+            )
+            .at(span),
+        );
+    }
+}
+
+fn directives(
+    node: &AstNode,
+    site: &Site,
+    file: &str,
+    settings: &mut Settings,
+    found: &mut Vec<Diagnostic>,
+) {
+    let (sigil, args) = match &node.kind {
+        NodeKind::Sharp { args } => ('#', args),
+        NodeKind::At { args } => ('@', args),
+        _ => {
+            for child in node.children() {
+                directives(child, site, file, settings, found);
+            }
+            return;
+        }
+    };
+    let Some((name, args)) = args.split_first() else {
+        return;
+    };
+    let Some(directive) = directive::find(sigil, name) else {
+        let warning = Diagnostic::Warning(Warning {
+            code: UNKNOWN_DIRECTIVE,
+            file: file.to_owned(),
+            message: format!("unknown directive `{}{}`, ignored", sigil, name),
+            ..Default::default()
+        });
+        found.push(warning.at(node.span));
+        return;
+    };
+    if !site.accepts(sigil, name) {
+        found.push(
+            error(
+                DIRECTIVE_NOT_ACCEPTED,
+                file,
+                format!("`{}{}` is not accepted here", sigil, name),
+            )
+            .at(node.span),
+        );
+        return;
+    }
+    let asked = settings.requires.len();
+    if let Err(why) = directive.apply(settings, args) {
+        found.push(
+            error(
+                DIRECTIVE_ARGUMENTS,
+                file,
+                format!("`{}{}`: {}", sigil, name, why),
+            )
+            .at(node.span)
+            .with_help(format!(
+                "write it as `{}{} {}`",
+                sigil, name, directive.usage
+            )),
+        );
+    }
+    for capability in &settings.requires[asked..] {
+        if !site.is_granted(capability) {
+            found.push(not_granted(file, capability).at(node.span));
+        }
+    }
+}
+
+fn error(code: i32, file: &str, message: String) -> Diagnostic {
+    Diagnostic::Error(Error {
+        code,
+        file: file.to_owned(),
+        message,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sites_refuse_directives_and_capabilities() {
+        let site = Site::granting("net, fs").refusing("#loglevel, fs,");
+        assert!(!site.accepts('#', "loglevel"));
+        assert!(site.accepts('#', "max-threads"));
+        assert!(site.accepts('@', "require"));
+        assert!(site.is_granted("net"));
+        assert!(!site.is_granted("fs"));
+    }
+}
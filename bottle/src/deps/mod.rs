@@ -0,0 +1,429 @@
+//! Require / Lock / Import dependencies between bottles.
+//!
+//! [`DependencyGraph::resolve`] parses a bottle and everything it declares, so problems are
+//! reported before any code runs. Each kind is honoured at runtime as the README describes:
+//! - `require`: loaded and initialised before the dependent; failing to load spills it.
+//! - `lock`: the target runs as its own bottle and the dependent waits until it is running.
+//! - `import`: linked up front but only initialised on the first call into it.
+use crate::err::{self, Diagnostic, Label, Source};
+use ast::{AstNode, NodeKind, AST};
+use log::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use typed_arena::Arena;
+
+/// A `require` or `lock` target could not be found.
+pub const DEPENDENCY_NOT_FOUND: i32 = 4001;
+/// A chain of `require`/`lock` dependencies leads back to where it started.
+pub const DEPENDENCY_CYCLE: i32 = 4002;
+/// An `import` target could not be found. Only fatal once something calls into it.
+pub const IMPORT_NOT_FOUND: i32 = 4003;
+/// A cycle that goes through at least one `import`, which is allowed.
+pub const IMPORT_CYCLE: i32 = 4004;
+/// Two dependencies of one bottle share a name.
+pub const DUPLICATE_DEPENDENCY: i32 = 4005;
+/// A `lock` target failed before it ever started running.
+pub const LOCK_FAILED: i32 = 4006;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DepKind {
+    Require,
+    Lock,
+    Import,
+}
+
+impl DepKind {
+    pub fn parse(kind: &str) -> Option<DepKind> {
+        match kind {
+            "require" => Some(DepKind::Require),
+            "lock" => Some(DepKind::Lock),
+            "import" => Some(DepKind::Import),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DepKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            DepKind::Require => "require",
+            DepKind::Lock => "lock",
+            DepKind::Import => "import",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// One declared dependency. `target` is the index of the resolved [`Unit`], if any.
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub kind: DepKind,
+    pub name: String,
+    pub path: PathBuf,
+    pub target: Option<usize>,
+    /// Where the declaration is in the dependent's source.
+    pub span: ast::Span,
+}
+
+/// A parsed bottle in the graph.
+pub struct Unit<'a> {
+    pub path: PathBuf,
+    pub source: &'a str,
+    pub ast: AST<'a>,
+    pub deps: Vec<Edge>,
+    /// What each name in `ast` refers to.
+    pub names: checker::Resolution,
+}
+
+impl Unit<'_> {
+    pub fn file(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// Every bottle reachable from a root. `units[0]` is always the root.
+pub struct DependencyGraph<'a> {
+    pub units: Vec<Unit<'a>>,
+}
+
+impl<'a> DependencyGraph<'a> {
+    /// Parses `root` and, transitively, every bottle it depends on. All diagnostics found on the
+    /// way are returned alongside the graph; any [`Diagnostic::Error`] means it must not run.
+    /// `text` is the root's source when it does not come from disk.
+    pub fn resolve(
+        root: &Path,
+        text: Option<String>,
+        sources: &'a Arena<Source>,
+        nodes: &'a Arena<AstNode<'a>>,
+    ) -> Result<(DependencyGraph<'a>, Vec<Diagnostic>), Diagnostic> {
+        Self::resolve_in(root, text, &[], sources, nodes)
+    }
+
+    /// [`DependencyGraph::resolve`] for a root that carries on from earlier code, which
+    /// declared `known`. See [`checker::resolve_in`].
+    pub fn resolve_in(
+        root: &Path,
+        text: Option<String>,
+        known: &[&str],
+        sources: &'a Arena<Source>,
+        nodes: &'a Arena<AstNode<'a>>,
+    ) -> Result<(DependencyGraph<'a>, Vec<Diagnostic>), Diagnostic> {
+        let mut graph = DependencyGraph { units: Vec::new() };
+        let mut index: HashMap<PathBuf, usize> = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let root = canonical(root);
+        let file = root.display().to_string();
+        let text = match text {
+            Some(text) => {
+                &sources
+                    .alloc(Source {
+                        name: file.clone(),
+                        text,
+                    })
+                    .text
+            }
+            None => load(&root, sources)?,
+        };
+        let ast = crate::parse(&file, text, nodes)?;
+        let names = check(&file, &ast, text, known, &mut diagnostics);
+        index.insert(root.clone(), 0);
+        graph.units.push(Unit {
+            path: root,
+            source: text,
+            ast,
+            deps: Vec::new(),
+            names,
+        });
+        let mut next = 0;
+        while next < graph.units.len() {
+            let unit = &graph.units[next];
+            let file = unit.file();
+            let dir = unit
+                .path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            let mut deps: Vec<Edge> = Vec::new();
+            for (kind, name, path, span) in declared(&unit.ast) {
+                if let Some(earlier) = deps.iter().find(|d| d.name == name) {
+                    let d = error(
+                        DUPLICATE_DEPENDENCY,
+                        &file,
+                        format!("dependency `{}` is declared more than once", name),
+                    );
+                    let first =
+                        Label::secondary(err::node_span(earlier.span), "first declared here");
+                    diagnostics.push(d.at(span).with_label(first));
+                    continue;
+                }
+                let path = locate(&dir, &name, &path);
+                let target = match path.as_ref().map(|p| canonical(p)) {
+                    Some(path) if index.contains_key(&path) => Some(index[&path]),
+                    Some(path) => {
+                        let text = load(&path, sources)?;
+                        let file = path.display().to_string();
+                        let ast = crate::parse(&file, text, nodes)?;
+                        debug!("Resolved {} {} to {}", kind, name, path.display());
+                        let names = check(&file, &ast, text, &[], &mut diagnostics);
+                        index.insert(path.clone(), graph.units.len());
+                        graph.units.push(Unit {
+                            path,
+                            source: text,
+                            ast,
+                            deps: Vec::new(),
+                            names,
+                        });
+                        Some(graph.units.len() - 1)
+                    }
+                    None if kind == DepKind::Import => {
+                        diagnostics.push(
+                            Diagnostic::Warning(err::Warning {
+                                code: IMPORT_NOT_FOUND,
+                                file: file.clone(),
+                                message: format!("import `{}` not found", name),
+                                ..Default::default()
+                            })
+                            .at(span),
+                        );
+                        None
+                    }
+                    None => {
+                        diagnostics.push(
+                            error(
+                                DEPENDENCY_NOT_FOUND,
+                                &file,
+                                format!("{} `{}` not found", kind, name),
+                            )
+                            .at(span),
+                        );
+                        None
+                    }
+                };
+                deps.push(Edge {
+                    kind,
+                    name,
+                    path: path.unwrap_or_default(),
+                    target,
+                    span,
+                });
+            }
+            graph.units[next].deps = deps;
+            next += 1;
+        }
+        diagnostics.extend(graph.cycle_diagnostics());
+        Ok((graph, diagnostics))
+    }
+
+    /// Every elementary cycle, as unit indices with the first repeated at the end.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut cycles = Vec::new();
+        let mut path = Vec::new();
+        for start in 0..self.units.len() {
+            self.find_cycles(start, start, &mut path, &mut cycles);
+        }
+        cycles
+    }
+
+    /// DFS from `at` that only records cycles whose smallest index is `start`, so each cycle
+    /// is reported exactly once.
+    fn find_cycles(
+        &self,
+        start: usize,
+        at: usize,
+        path: &mut Vec<usize>,
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        path.push(at);
+        for target in self.units[at].deps.iter().filter_map(|d| d.target) {
+            if target == start {
+                let mut cycle = path.clone();
+                cycle.push(start);
+                cycles.push(cycle);
+            } else if target > start && !path.contains(&target) {
+                self.find_cycles(start, target, path, cycles);
+            }
+        }
+        path.pop();
+    }
+
+    fn edge(&self, from: usize, to: usize) -> Option<&Edge> {
+        self.units[from].deps.iter().find(|d| d.target == Some(to))
+    }
+
+    fn cycle_diagnostics(&self) -> Vec<Diagnostic> {
+        self.cycles()
+            .into_iter()
+            .map(|cycle| {
+                let lazy = cycle
+                    .windows(2)
+                    .any(|w| self.edge(w[0], w[1]).unwrap().kind == DepKind::Import);
+                let chain = cycle
+                    .iter()
+                    .map(|u| self.units[*u].file())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                let file = self.units[cycle[0]].file();
+                // The declaration the cycle leaves its first bottle through.
+                let span = self.edge(cycle[0], cycle[1]).unwrap().span;
+                let d = if lazy {
+                    Diagnostic::Warning(err::Warning {
+                        code: IMPORT_CYCLE,
+                        file,
+                        message: format!("import cycle: {}", chain),
+                        ..Default::default()
+                    })
+                } else {
+                    error(
+                        DEPENDENCY_CYCLE,
+                        &file,
+                        format!("dependency cycle: {}", chain),
+                    )
+                };
+                d.at(span)
+            })
+            .collect()
+    }
+
+    /// Units reachable from `from` through `kind` edges only, dependencies before dependents.
+    /// `from` itself is not included.
+    pub fn order(&self, from: usize, kind: DepKind) -> Vec<usize> {
+        fn visit(
+            g: &DependencyGraph,
+            at: usize,
+            kind: DepKind,
+            seen: &mut [bool],
+            out: &mut Vec<usize>,
+        ) {
+            if std::mem::replace(&mut seen[at], true) {
+                return;
+            }
+            for dep in g.units[at].deps.iter().filter(|d| d.kind == kind) {
+                if let Some(target) = dep.target {
+                    visit(g, target, kind, seen, out);
+                }
+            }
+            out.push(at);
+        }
+        let mut seen = vec![false; self.units.len()];
+        let mut out = Vec::new();
+        visit(self, from, kind, &mut seen, &mut out);
+        out.pop();
+        out
+    }
+}
+
+/// The `(kind, name, path, span)` of every top-level dependency declaration in `ast`.
+pub fn declared(ast: &AST) -> Vec<(DepKind, String, String, ast::Span)> {
+    let NodeKind::Root { children } = &ast.head.kind else {
+        return Vec::new();
+    };
+    children
+        .iter()
+        .filter_map(|node| match &node.kind {
+            NodeKind::Dependency { kind, name, path } => {
+                DepKind::parse(kind).map(|kind| (kind, name.clone(), path.clone(), node.span))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Finds the file for dependency `name` declared in `dir`: the explicit path if one was given,
+/// otherwise `<name>.wg` or `<name>/main.wg`, and failing those a bottle installed as
+/// `bottles/<name>/` in `dir` or a directory above it.
+pub(crate) fn locate(dir: &Path, name: &str, path: &str) -> Option<PathBuf> {
+    if !path.is_empty() {
+        return Some(dir.join(path)).filter(|p| p.is_file());
+    }
+    let local = [
+        dir.join(format!("{}.wg", name)),
+        dir.join(name).join("main.wg"),
+    ];
+    if let Some(found) = local.into_iter().find(|p| p.is_file()) {
+        return Some(found);
+    }
+    dir.ancestors().find_map(|d| {
+        let installed = d.join(crate::pkg::BOTTLES_DIR).join(name);
+        let manifest =
+            crate::pack::Manifest::load(&installed.join(crate::pack::MANIFEST_FILE)).ok()?;
+        Some(installed.join(manifest.entry)).filter(|p| p.is_file())
+    })
+}
+
+pub(crate) fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn load<'a>(path: &Path, sources: &'a Arena<Source>) -> Result<&'a str, Diagnostic> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let name = path.display().to_string();
+            Ok(&sources.alloc(Source { name, text }).text)
+        }
+        Err(why) => Err(error(
+            crate::READ_ERROR,
+            &path.display().to_string(),
+            format!("couldn't read source: {}", why),
+        )),
+    }
+}
+
+/// Resolves the names in `ast`, parsed from `file`, and checks its types, adding what is
+/// wrong to `diagnostics`.
+fn check(
+    file: &str,
+    ast: &AST,
+    text: &str,
+    known: &[&str],
+    diagnostics: &mut Vec<Diagnostic>,
+) -> checker::Resolution {
+    let names = checker::resolve_in(ast, text, known);
+    let types = checker::types::check_in(ast, &names, &crate::intrinsic::signatures());
+    let found = names.findings.iter().chain(&types);
+    diagnostics.extend(found.map(|f| err::finding(file, f)));
+    names
+}
+
+fn error(code: i32, file: &str, message: String) -> Diagnostic {
+    Diagnostic::Error(err::Error {
+        code,
+        file: file.to_owned(),
+        message,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_and_missing_imports_point_at_their_declarations() {
+        let dir = std::env::temp_dir().join(format!("wineglass-deps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let root = dir.join("a.wg");
+        let source = "\
+// Needs `b`, which needs it back.
+require b from \"b.wg\"
+import missing
+
+fn main() -> i64 {
+    return 0;
+}
+";
+        fs::write(&root, source).unwrap();
+        fs::write(dir.join("b.wg"), "require a from \"a.wg\"\n").unwrap();
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let (graph, diagnostics) = DependencyGraph::resolve(&root, None, &sources, &nodes).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(graph.cycles(), vec![vec![0, 1, 0]]);
+        let at = |code: i32| {
+            let d = diagnostics.iter().find(|d| d.get_code() == code);
+            d.map(|d| d.get_idx())
+        };
+        assert_eq!(at(DEPENDENCY_CYCLE), Some((1, 0, 21)));
+        assert_eq!(at(IMPORT_NOT_FOUND), Some((2, 0, 14)));
+    }
+}
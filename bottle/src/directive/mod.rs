@@ -0,0 +1,205 @@
+//! Directives: `#` lines that configure how a bottle is run and `@` lines about the bottle
+//! itself, read before any of its code runs.
+//!
+//! ```text
+//! #max-threads 4     // how many bottles the scheduler runs at once
+//! #loglevel debug    // off, error, warn, info, debug or trace, or 0-5
+//! @require net       // a capability the site must grant
+//! ```
+//! Each is written on a line of its own at the top level of a file. [`DIRECTIVES`] lists the
+//! ones there are; the [`cellar`](crate::cellar) warns about any other and refuses a bottle
+//! whose directives don't read.
+use log::LevelFilter;
+use std::str::FromStr;
+
+/// A directive the bottle understands.
+pub struct Directive {
+    /// `#` or `@`.
+    pub sigil: char,
+    pub name: &'static str,
+    /// What follows the name, as shown in help.
+    pub usage: &'static str,
+    pub about: &'static str,
+    apply: fn(&mut Settings, &[String]) -> Result<(), String>,
+}
+
+impl Directive {
+    /// Records what the directive says in `settings`, given the words after its name.
+    pub fn apply(&self, settings: &mut Settings, args: &[String]) -> Result<(), String> {
+        (self.apply)(settings, args)
+    }
+}
+
+/// What a file's directives ask for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub max_threads: Option<usize>,
+    pub log_level: Option<LevelFilter>,
+    /// Capabilities asked for with `@require`, in order.
+    pub requires: Vec<String>,
+}
+
+pub static DIRECTIVES: &[Directive] = &[
+    Directive {
+        sigil: '#',
+        name: "max-threads",
+        usage: "<n>",
+        about: "How many bottles may execute at once",
+        apply: |settings, args| {
+            let [n] = args else {
+                return Err("expected one number".to_owned());
+            };
+            match n.parse::<usize>() {
+                Ok(n) if n > 0 => settings.max_threads = Some(n),
+                _ => return Err(format!("`{}` is not a number of threads", n)),
+            }
+            Ok(())
+        },
+    },
+    Directive {
+        sigil: '#',
+        name: "loglevel",
+        usage: "<level>",
+        about: "How much to log while the bottle runs",
+        apply: |settings, args| {
+            let [level] = args else {
+                return Err("expected one level".to_owned());
+            };
+            let parsed = match level.parse::<usize>() {
+                Ok(n) => LevelFilter::iter().nth(n),
+                Err(_) => LevelFilter::from_str(level).ok(),
+            };
+            match parsed {
+                Some(level) => settings.log_level = Some(level),
+                None => {
+                    return Err(format!(
+                        "`{}` is not one of off, error, warn, info, debug, trace or 0-5",
+                        level
+                    ))
+                }
+            }
+            Ok(())
+        },
+    },
+    Directive {
+        sigil: '@',
+        name: "require",
+        usage: "<capability>..",
+        about: "Capabilities the site must grant the bottle",
+        apply: |settings, args| {
+            if args.is_empty() {
+                return Err("expected at least one capability".to_owned());
+            }
+            settings.requires.extend(args.iter().cloned());
+            Ok(())
+        },
+    },
+];
+
+/// The directive written `<sigil><name>`, if there is one.
+pub fn find(sigil: char, name: &str) -> Option<&'static Directive> {
+    DIRECTIVES
+        .iter()
+        .find(|d| d.sigil == sigil && d.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cellar::{self, Site};
+    use crate::deps::DependencyGraph;
+    use std::path::Path;
+    use typed_arena::Arena;
+
+    /// The settings `#`/`@` `name` leaves after being given `args`.
+    fn apply(sigil: char, name: &str, args: &[&str]) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        let args: Vec<String> = args.iter().map(|a| (*a).to_owned()).collect();
+        find(sigil, name).unwrap().apply(&mut settings, &args)?;
+        Ok(settings)
+    }
+
+    #[test]
+    fn directives_are_found_by_sigil_and_name() {
+        assert!(find('#', "max-threads").is_some());
+        assert!(find('@', "require").is_some());
+        assert!(find('@', "max-threads").is_none());
+        assert!(find('#', "require").is_none());
+        assert!(find('#', "colour").is_none());
+    }
+
+    #[test]
+    fn max_threads_takes_a_positive_number() {
+        let set = apply('#', "max-threads", &["4"]).unwrap();
+        assert_eq!(set.max_threads, Some(4));
+        for args in [&["0"][..], &["four"], &["-1"], &[], &["4", "5"]] {
+            assert!(apply('#', "max-threads", args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn loglevel_takes_a_name_or_number() {
+        let level = |arg| apply('#', "loglevel", &[arg]).map(|s| s.log_level.unwrap());
+        assert_eq!(level("debug"), Ok(LevelFilter::Debug));
+        assert_eq!(level("WARN"), Ok(LevelFilter::Warn));
+        assert_eq!(level("0"), Ok(LevelFilter::Off));
+        assert_eq!(level("3"), Ok(LevelFilter::Info));
+        assert_eq!(level("5"), Ok(LevelFilter::Trace));
+        assert!(level("6").is_err());
+        assert!(level("loud").unwrap_err().contains("`loud`"));
+        assert!(apply('#', "loglevel", &[]).is_err());
+    }
+
+    #[test]
+    fn require_collects_capabilities_in_order() {
+        let mut settings = apply('@', "require", &["net", "fs"]).unwrap();
+        let more = ["clock".to_owned()];
+        find('@', "require")
+            .unwrap()
+            .apply(&mut settings, &more)
+            .unwrap();
+        assert_eq!(settings.requires, ["net", "fs", "clock"]);
+        assert!(apply('@', "require", &[]).is_err());
+    }
+
+    #[test]
+    fn directives_are_read_from_the_top_level_of_a_file() {
+        let source = "\
+#max-threads 3   // comments after a directive are ignored
+#loglevel 1
+@require net
+@require fs
+#colour red
+#max-threads lots
+
+fn main() {
+}
+";
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let path = Path::new("directives.wg");
+        let (graph, _) =
+            DependencyGraph::resolve(path, Some(source.to_owned()), &sources, &nodes).unwrap();
+        let site = Site::granting("net");
+        let (settings, found) = cellar::inspect(&graph, &site, &[], "bottle.toml");
+        assert_eq!(
+            settings,
+            Settings {
+                max_threads: Some(3),
+                log_level: Some(LevelFilter::Error),
+                requires: vec!["net".to_owned(), "fs".to_owned()],
+            }
+        );
+        let found: Vec<(i32, usize)> = found
+            .iter()
+            .map(|d| (d.get_code(), d.get_idx().0))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (cellar::CAPABILITY_NOT_GRANTED, 3),
+                (cellar::UNKNOWN_DIRECTIVE, 4),
+                (cellar::DIRECTIVE_ARGUMENTS, 5),
+            ]
+        );
+    }
+}
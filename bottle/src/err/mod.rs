@@ -1,35 +1,89 @@
-use std::fs;
+//! Diagnostics raised while a bottle is loaded and run. They are rendered by the `error`
+//! crate, whose span, label and note types are re-exported here.
+pub use error::{Emitter, ErrorLevel, Label, Note, Position, Renderer, Report, Source, Span};
 
+/// The source a node of the tree came from.
+pub fn node_span(span: ast::Span) -> Span {
+    Span::new(
+        Position::new(span.line, span.column),
+        Position::new(span.end_line, span.end_column),
+    )
+}
+
+/// What the checker found in `file`, as a diagnostic pointing at the node it is about.
+pub fn finding(file: &str, finding: &checker::Finding) -> Diagnostic {
+    let (code, file, message) = (finding.code, file.to_owned(), finding.message.clone());
+    let d = match finding.severity {
+        checker::Severity::Error => Diagnostic::Error(Error {
+            code,
+            file,
+            message,
+            ..Default::default()
+        }),
+        checker::Severity::Warning => Diagnostic::Warning(Warning {
+            code,
+            file,
+            message,
+            ..Default::default()
+        }),
+    };
+    let d = d.at(finding.span);
+    let d = match &finding.related {
+        Some((span, what)) => d.with_label(Label::secondary(node_span(*span), what.as_str())),
+        None => d,
+    };
+    match &finding.fix {
+        Some(fix) => d.with_fix("did you mean", node_span(finding.span), fix.as_str()),
+        None => d,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Warning {
     pub code: i32,
     pub file: String,
     pub message: String,
     pub loc: (usize, usize),
     pub len: usize,
+    /// Spans to point at besides `loc`.
+    pub labels: Box<[Label]>,
+    pub notes: Box<[Note]>,
 }
 
+#[derive(Debug, Clone, Default)]
 pub struct Info {
     pub code: i32,
     pub file: String,
     pub message: String,
     pub loc: (usize, usize),
     pub len: usize,
+    /// Spans to point at besides `loc`.
+    pub labels: Box<[Label]>,
+    pub notes: Box<[Note]>,
 }
 
+#[derive(Debug, Clone, Default)]
 pub struct Error {
     pub code: i32,
     pub file: String,
     pub message: String,
     pub loc: (usize, usize),
     pub len: usize,
+    /// Spans to point at besides `loc`.
+    pub labels: Box<[Label]>,
+    pub notes: Box<[Note]>,
 }
 
+#[derive(Debug, Clone, Default)]
 pub struct Fatal {
     pub code: i32,
     pub file: String,
     pub message: String,
     pub loc: (usize, usize),
     pub len: usize,
+    /// Spans to point at besides `loc`.
+    pub labels: Box<[Label]>,
+    pub notes: Box<[Note]>,
 }
 
 pub struct InternalWarning {
@@ -55,6 +109,7 @@ pub struct InternalFatal {
     pub file: String,
     pub message: String,
 }
+#[derive(Debug, Clone)]
 pub enum Diagnostic {
     ///container for Diagnostics of code
     Warning(Warning),
@@ -127,58 +182,122 @@ impl Diagnostic {
             Diagnostic::Fatal(f) => (f.loc.0, f.loc.1, f.len),
         }
     }
-    pub fn get_problem(&self) -> String {
-        match &self {
-            Diagnostic::Warning(w) => {
-                if w.code == 0 {
-                    return "-".to_owned();
-                }
-            }
-            Diagnostic::Info(i) => {
-                if i.code == 0 {
-                    return "-".to_owned();
-                }
-            }
-            Diagnostic::Error(e) => {
-                if e.code == 0 {
-                    return "-".to_owned();
-                }
-            }
-            Diagnostic::Fatal(f) => {
-                if f.code == 0 {
-                    return "-".to_owned();
-                }
-            }
-        }
-        let binding = fs::read_to_string(match &self {
-            Diagnostic::Warning(w) => &w.file,
-            Diagnostic::Info(i) => &i.file,
-            Diagnostic::Error(e) => &e.file,
-            Diagnostic::Fatal(f) => &f.file,
-        })
-        .expect("Invalid Diagnostic! Failed to read to string!");
-        let loc_idx = &self.get_idx();
-        let spliced_str = match binding.lines().nth(loc_idx.0) {
-            Some(line) => line,
-            None => panic!("Invalid Diagnostic! Failed to find line"),
+    /// Where `loc` and `len` point, unless they were left at zero.
+    pub fn get_span(&self) -> Option<Span> {
+        match self.get_idx() {
+            (0, 0, 0) => None,
+            (line, column, len) => Some(Span::at(line, column, len)),
+        }
+    }
+    pub fn get_labels(&self) -> &[Label] {
+        match self {
+            Diagnostic::Warning(w) => &w.labels,
+            Diagnostic::Info(i) => &i.labels,
+            Diagnostic::Error(e) => &e.labels,
+            Diagnostic::Fatal(f) => &f.labels,
+        }
+    }
+    pub fn get_notes(&self) -> &[Note] {
+        match self {
+            Diagnostic::Warning(w) => &w.notes,
+            Diagnostic::Info(i) => &i.notes,
+            Diagnostic::Error(e) => &e.notes,
+            Diagnostic::Fatal(f) => &f.notes,
+        }
+    }
+    fn parts(&mut self) -> (&mut Box<[Label]>, &mut Box<[Note]>) {
+        match self {
+            Diagnostic::Warning(w) => (&mut w.labels, &mut w.notes),
+            Diagnostic::Info(i) => (&mut i.labels, &mut i.notes),
+            Diagnostic::Error(e) => (&mut e.labels, &mut e.notes),
+            Diagnostic::Fatal(f) => (&mut f.labels, &mut f.notes),
+        }
+    }
+    /// Points at `span`, the source of a node, unless the diagnostic already points somewhere.
+    pub fn at(mut self, span: ast::Span) -> Self {
+        if self.get_span().is_some() || !self.get_labels().is_empty() {
+            return self;
+        }
+        // `loc` and `len` can only cover one line; longer nodes get a label instead.
+        if span.end_line != span.line {
+            return self.with_label(Label::primary(node_span(span), ""));
+        }
+        let (loc, len) = match &mut self {
+            Diagnostic::Warning(w) => (&mut w.loc, &mut w.len),
+            Diagnostic::Info(i) => (&mut i.loc, &mut i.len),
+            Diagnostic::Error(e) => (&mut e.loc, &mut e.len),
+            Diagnostic::Fatal(f) => (&mut f.loc, &mut f.len),
         };
-        spliced_str[loc_idx.1..loc_idx.2].to_owned()
+        *loc = (span.line, span.column);
+        *len = span.end_column - span.column;
+        self
     }
-    pub fn display(&self) -> String {
-        let idx = self.get_idx();
-        let problem = self.get_problem();
-        let message = self.get_message();
-        let code = self.get_code();
-        let mut display: String = code.to_string();
-        display.push('\n');
-        display.push_str(&format!("at ({}, {}),\n", idx.0, idx.1));
-        display.push_str(&format!("{}\n", problem));
-        display.push_str(&(" ".repeat(idx.1)));
-        display.push('^');
-        display.push_str(&("~".repeat(idx.2)));
-        display.push('\n');
-        display.push_str(&message);
-        display
+    pub fn with_label(mut self, label: Label) -> Self {
+        push(self.parts().0, label);
+        self
+    }
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        push(self.parts().1, Note::Note(note.into()));
+        self
+    }
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        push(self.parts().1, Note::Help(help.into()));
+        self
+    }
+    /// Suggests replacing `span` with `replacement`.
+    pub fn with_fix(
+        mut self,
+        message: impl Into<String>,
+        span: Span,
+        replacement: impl Into<String>,
+    ) -> Self {
+        let fix = Note::Fix {
+            message: message.into(),
+            span,
+            replacement: replacement.into(),
+        };
+        push(self.parts().1, fix);
+        self
+    }
+    /// Renders the diagnostic without colour, quoting `source` where it points.
+    pub fn display(&self, source: Option<&str>) -> String {
+        Renderer::plain().render(self, source)
+    }
+}
+
+/// Labels and notes are boxed slices to keep [`Diagnostic`] small enough to return by value.
+fn push<T>(items: &mut Box<[T]>, item: T) {
+    let mut grown = std::mem::take(items).into_vec();
+    grown.push(item);
+    *items = grown.into_boxed_slice();
+}
+
+impl Report for Diagnostic {
+    fn level(&self) -> ErrorLevel {
+        match self {
+            Diagnostic::Warning(_) => ErrorLevel::WARN,
+            Diagnostic::Info(_) => ErrorLevel::INFO,
+            Diagnostic::Error(_) => ErrorLevel::ERROR,
+            Diagnostic::Fatal(_) => ErrorLevel::FATAL,
+        }
+    }
+    fn code(&self) -> i64 {
+        self.get_code() as i64
+    }
+    fn message(&self) -> String {
+        self.get_message()
+    }
+    fn file(&self) -> String {
+        self.get_file()
+    }
+    fn span(&self) -> Option<Span> {
+        self.get_span()
+    }
+    fn labels(&self) -> &[Label] {
+        self.get_labels()
+    }
+    fn notes(&self) -> &[Note] {
+        self.get_notes()
     }
 }
 
@@ -218,12 +337,7 @@ impl InternalReport {
         .to_owned()
     }
     pub fn display(&self) -> String {
-        let message = self.get_message();
-        let code = self.get_code();
-        let t = self.get_type();
-        let mut display: String = format!("{:?}{:?} ", t, code);
-        display.push_str(&message);
-        display
+        Renderer::plain().render(self, None)
     }
     pub fn expect(&self, m: &str) {
         match self {
@@ -240,3 +354,23 @@ impl InternalReport {
         }
     }
 }
+
+impl Report for InternalReport {
+    fn level(&self) -> ErrorLevel {
+        match self {
+            Self::InternalWarning(_) => ErrorLevel::WARN,
+            Self::InternalInfo(_) => ErrorLevel::INFO,
+            Self::InternalError(_) => ErrorLevel::ERROR,
+            Self::InternalFatal(_) => ErrorLevel::FATAL,
+        }
+    }
+    fn code(&self) -> i64 {
+        self.get_code()
+    }
+    fn message(&self) -> String {
+        self.get_message()
+    }
+    fn file(&self) -> String {
+        self.get_file()
+    }
+}
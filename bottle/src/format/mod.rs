@@ -0,0 +1,398 @@
+//! The canonical layout of `.wg` source, as `wineglass fmt` writes it.
+//!
+//! Formatting works on the tokens of the source, comments included, and only ever changes the
+//! whitespace between them. Statements go one to a line, blocks are indented, at most one
+//! empty line is kept between statements, and calls that don't fit in the line width get an
+//! argument per line. The result is parsed again and must give the same tree, so formatting
+//! never changes what a program means.
+use crate::err::{Diagnostic, Fatal};
+use crate::lex::{self, Token, TokenKind};
+use parser::{KEYWORDS, UNARY_OPS};
+use std::mem;
+use typed_arena::Arena;
+
+/// The formatted source would not mean what the original did. The source is left alone.
+pub const FORMAT_CHANGED_MEANING: i32 = 1004;
+
+/// Where the `{` opening a block goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BraceStyle {
+    /// At the end of the line that introduces the block.
+    #[default]
+    SameLine,
+    /// On a line of its own, as deeply indented as the line before.
+    NextLine,
+}
+
+impl BraceStyle {
+    /// `same-line` or `next-line`, as written in the `brace-style` setting.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "same-line" => Some(Self::SameLine),
+            "next-line" => Some(Self::NextLine),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    /// Spaces per level of nesting.
+    pub indent_width: usize,
+    pub brace_style: BraceStyle,
+    /// Lines longer than this are broken where possible.
+    pub line_width: usize,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            indent_width: 4,
+            brace_style: BraceStyle::SameLine,
+            line_width: 100,
+        }
+    }
+}
+
+/// `source` laid out in `style`. Fails with the parse error when `source` doesn't parse.
+pub fn format(file: &str, source: &str, style: &Style) -> Result<String, Diagnostic> {
+    let arena = Arena::new();
+    let before = crate::parse(file, source, &arena)?;
+    let formatted = layout(source, style);
+    let texts = |source| -> Vec<String> {
+        lex::lex(source)
+            .iter()
+            .map(|t| t.text.trim_end().to_owned())
+            .collect()
+    };
+    let same = texts(source) == texts(&formatted)
+        && crate::parse(file, &formatted, &arena).is_ok_and(|after| after.head == before.head);
+    if !same {
+        return Err(Diagnostic::Fatal(Fatal {
+            code: FORMAT_CHANGED_MEANING,
+            file: file.to_owned(),
+            message: "formatting would change what this source means, so it was left alone"
+                .to_owned(),
+            ..Default::default()
+        }));
+    }
+    Ok(formatted)
+}
+
+/// `source` laid out in `style`, without checking that it parses.
+pub fn layout(source: &str, style: &Style) -> String {
+    let mut out = Vec::new();
+    for line in Layout::split(&lex::lex(source), style.brace_style) {
+        if line.blank {
+            out.push(String::new());
+        }
+        render(&line.tokens, line.depth, style, &mut out);
+    }
+    match out.is_empty() {
+        true => String::new(),
+        false => out.join("\n") + "\n",
+    }
+}
+
+/// A line of output before it is rendered.
+struct Line<'s> {
+    depth: usize,
+    tokens: Vec<Token<'s>>,
+    /// Whether an empty line comes before it.
+    blank: bool,
+}
+
+impl Line<'_> {
+    /// Whether the line opens a block, so the next one is inside it.
+    fn opens(&self) -> bool {
+        self.tokens
+            .iter()
+            .rev()
+            .find(|t| t.kind != TokenKind::Comment)
+            .is_some_and(|t| t.is("{"))
+    }
+}
+
+/// Breaks a token stream into lines and works out how deeply each is nested.
+struct Layout<'s> {
+    lines: Vec<Line<'s>>,
+    current: Vec<Token<'s>>,
+    depth: usize,
+    blank: bool,
+}
+
+impl<'s> Layout<'s> {
+    fn split(tokens: &[Token<'s>], brace_style: BraceStyle) -> Vec<Line<'s>> {
+        let mut layout = Layout {
+            lines: Vec::new(),
+            current: Vec::new(),
+            depth: 0,
+            blank: false,
+        };
+        let mut parens = 0usize;
+        for (i, &token) in tokens.iter().enumerate() {
+            let next = tokens.get(i + 1);
+            if token.kind == TokenKind::Comment {
+                match (parens, token.newlines, layout.lines.last_mut()) {
+                    // Inside brackets a comment stays with its neighbours; rendering breaks
+                    // the line around it.
+                    (1.., ..) => layout.push(token),
+                    (0, 0, _) if !layout.current.is_empty() => {
+                        layout.push(token);
+                        layout.end();
+                    }
+                    (0, 0, Some(last)) if layout.current.is_empty() => last.tokens.push(token),
+                    _ => {
+                        layout.end();
+                        layout.push(token);
+                        layout.end();
+                    }
+                }
+                continue;
+            }
+            if token.kind == TokenKind::Directive {
+                // Directives are words the bottle reads, not code; they keep a line of their own
+                // exactly as written.
+                layout.end();
+                layout.push(token);
+                layout.end();
+                continue;
+            }
+            let after_close = layout.current.last().is_some_and(|t| t.is("}"))
+                && matches!(token.text, "else" | "catch" | "finally");
+            let continued = layout.current.last().is_some_and(continues);
+            if token.newlines > 0 && parens == 0 && !continued && !after_close {
+                layout.end();
+            }
+            match token.text {
+                "{" if parens == 0 => {
+                    if brace_style == BraceStyle::NextLine {
+                        layout.end();
+                    }
+                    layout.push(token);
+                    if !next.is_some_and(|t| t.is("}")) {
+                        layout.end();
+                        layout.depth += 1;
+                    }
+                }
+                "}" if parens == 0 => {
+                    if !layout.current.last().is_some_and(|t| t.is("{")) {
+                        layout.end();
+                        layout.depth = layout.depth.saturating_sub(1);
+                    }
+                    layout.push(token);
+                    let keep = match next.map(|t| t.text) {
+                        Some("else" | "catch" | "finally") => brace_style == BraceStyle::SameLine,
+                        Some(";") => true,
+                        _ => false,
+                    };
+                    if !keep {
+                        layout.end();
+                    }
+                }
+                ";" if parens == 0 => {
+                    layout.push(token);
+                    layout.end();
+                }
+                "(" => {
+                    parens += 1;
+                    layout.push(token);
+                }
+                ")" => {
+                    parens = parens.saturating_sub(1);
+                    layout.push(token);
+                }
+                _ => layout.push(token),
+            }
+        }
+        layout.end();
+        layout.lines
+    }
+
+    fn push(&mut self, token: Token<'s>) {
+        if self.current.is_empty() {
+            self.blank = token.newlines > 1;
+        }
+        self.current.push(token);
+    }
+
+    /// Finishes the current line, if anything is on it.
+    fn end(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        let tokens = mem::take(&mut self.current);
+        let blank =
+            self.blank && !tokens[0].is("}") && self.lines.last().is_some_and(|line| !line.opens());
+        self.lines.push(Line {
+            depth: self.depth,
+            tokens,
+            blank,
+        });
+    }
+}
+
+/// Whether a statement can't end after `token`, so a line break after it is only layout.
+fn continues(token: &Token) -> bool {
+    token.kind == TokenKind::Punct && !matches!(token.text, ")" | "}" | ";")
+}
+
+/// Renders `tokens` at `depth`, breaking them over several lines when they don't fit or hold
+/// a comment that must end its line.
+fn render(tokens: &[Token], depth: usize, style: &Style, out: &mut Vec<String>) {
+    let indent = " ".repeat(depth * style.indent_width);
+    let flat = format!("{}{}", indent, join(tokens));
+    let (last, rest) = tokens.split_last().expect("lines are never empty");
+    let commented = rest.iter().any(|t| t.kind == TokenKind::Comment)
+        || (last.kind == TokenKind::Comment && last.newlines > 0 && !rest.is_empty());
+    if !commented && flat.chars().count() <= style.line_width {
+        out.push(flat);
+        return;
+    }
+    if let Some((open, close)) = group(tokens) {
+        // Anything right after `(` on the same line, such as a comment, stays on its line.
+        let mut head = open + 1;
+        while head < close && tokens[head].kind == TokenKind::Comment && tokens[head].newlines == 0
+        {
+            head += 1;
+        }
+        render(&tokens[..head], depth, style, out);
+        for argument in arguments(&tokens[head..close]) {
+            render(argument, depth + 1, style, out);
+        }
+        render(&tokens[close..], depth, style, out);
+        return;
+    }
+    if !commented {
+        out.push(flat);
+        return;
+    }
+    // No brackets to break at: end the line after each comment instead.
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let own_line = token.kind == TokenKind::Comment && token.newlines > 0;
+        if own_line && i > start {
+            out.push(format!("{}{}", indent, join(&tokens[start..i])));
+            start = i;
+        }
+        if token.kind == TokenKind::Comment {
+            out.push(format!("{}{}", indent, join(&tokens[start..=i])));
+            start = i + 1;
+        }
+    }
+    if start < tokens.len() {
+        out.push(format!("{}{}", indent, join(&tokens[start..])));
+    }
+}
+
+/// The first bracketed group at the top of `tokens` worth breaking: one holding a comma or a
+/// comment. Returns the offsets of its `(` and `)`.
+fn group(tokens: &[Token]) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    let mut open = 0;
+    let mut breakable = false;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" => {
+                if depth == 0 {
+                    open = i;
+                    breakable = false;
+                }
+                depth += 1;
+            }
+            ")" if depth > 0 => {
+                depth -= 1;
+                if depth == 0 && breakable {
+                    return Some((open, i));
+                }
+            }
+            "," if depth > 0 => breakable = true,
+            _ if depth > 0 && token.kind == TokenKind::Comment => breakable = true,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits the inside of a bracketed group at its top-level commas. A comma, and a comment on
+/// the same line after it, go with the argument before.
+fn arguments<'t, 's>(tokens: &'t [Token<'s>]) -> Vec<&'t [Token<'s>]> {
+    let mut arguments = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].text {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            "," if depth == 0 => {
+                while tokens
+                    .get(i + 1)
+                    .is_some_and(|t| t.kind == TokenKind::Comment && t.newlines == 0)
+                {
+                    i += 1;
+                }
+                arguments.push(&tokens[start..=i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if start < tokens.len() {
+        arguments.push(&tokens[start..]);
+    }
+    arguments
+}
+
+/// `tokens` on one line, spaced the canonical way.
+fn join(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && spaced(tokens, i) {
+            text.push(' ');
+        }
+        text.push_str(token.text.trim_end());
+    }
+    text
+}
+
+/// Whether a space goes before `tokens[i]`.
+fn spaced(tokens: &[Token], i: usize) -> bool {
+    let (before, token) = (&tokens[i - 1], &tokens[i]);
+    if token.kind == TokenKind::Comment {
+        return true;
+    }
+    if prefix(tokens, i - 1) {
+        return false;
+    }
+    // The `!` of a bang call such as `shatter!(..)` or `spill!(..)` is part of the name.
+    let bang = token.is("!")
+        && before.kind == TokenKind::Ident
+        && before.end() == token.start
+        && tokens.get(i + 1).is_some_and(|t| t.is("("));
+    match (before.text, token.text) {
+        (_, "," | ";" | ")" | "." | ":") => false,
+        ("(" | "." | "!", _) => false,
+        ("{", "}") => false,
+        (_, "(") => !(before.is_name() || before.is(")")),
+        _ => !bang,
+    }
+}
+
+/// Whether `tokens[i]` is a prefix operator, such as the `-` of `-x`, rather than a binary one.
+fn prefix(tokens: &[Token], i: usize) -> bool {
+    if !UNARY_OPS.contains(&tokens[i].text) {
+        return false;
+    }
+    match i.checked_sub(1).map(|i| &tokens[i]) {
+        None => true,
+        Some(before) => match before.kind {
+            TokenKind::Punct => !before.is(")"),
+            TokenKind::Ident => {
+                KEYWORDS.contains(&before.text) && !matches!(before.text, "true" | "false")
+            }
+            _ => false,
+        },
+    }
+}
@@ -0,0 +1,979 @@
+mod value;
+
+pub use value::{ErrorValue, Value};
+
+use crate::deps::{DepKind, DependencyGraph};
+use crate::err::{self, Diagnostic};
+use crate::intrinsic::{self, Runtime};
+use crate::sched::Interrupt;
+use ast::{AstNode, NodeId, NodeKind, AST};
+use checker::resolve::SymbolKind;
+use checker::Resolution;
+use log::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Runtime error codes, reported as [`Diagnostic::Error`].
+pub const UNDEFINED_VARIABLE: i32 = 3001;
+pub const UNDEFINED_FUNCTION: i32 = 3002;
+pub const ARITY_MISMATCH: i32 = 3003;
+pub const TYPE_ERROR: i32 = 3004;
+pub const STACK_OVERFLOW: i32 = 3005;
+pub const MISSING_MAIN: i32 = 3006;
+pub const UNSUPPORTED: i32 = 3007;
+/// The scheduler asked the bottle to stop through its [`Interrupt`].
+pub const INTERRUPTED: i32 = 3008;
+/// A `spill` statement raised an error that nothing caught.
+pub const SPILLED: i32 = 3009;
+/// A bang intrinsic failed. `try` catches it as an `IntrinsicError`.
+pub const INTRINSIC_FAILED: i32 = 3010;
+/// `spill!` spilled the bottle, which no `try` can stop.
+pub const ABORTED: i32 = 3011;
+
+/// The kind a caught runtime error is given, by diagnostic code. Other codes are a plain `Error`.
+pub const ERROR_KINDS: &[(i32, &str)] = &[
+    (UNDEFINED_VARIABLE, "UndefinedVariable"),
+    (UNDEFINED_FUNCTION, "UndefinedFunction"),
+    (ARITY_MISMATCH, "ArityMismatch"),
+    (TYPE_ERROR, "TypeError"),
+    (STACK_OVERFLOW, "StackOverflow"),
+    (crate::deps::IMPORT_NOT_FOUND, "ImportError"),
+    (INTRINSIC_FAILED, "IntrinsicError"),
+];
+
+/// Whether `try` may catch a diagnostic with this code. Interrupts, and `spill!`, always stop
+/// the bottle.
+pub fn catchable(code: i32) -> bool {
+    code != INTERRUPTED && code != ABORTED
+}
+
+/// The value a `catch` binds for `d`. `spilled` is the value of the `spill` statement that
+/// raised `d`, if it came from one.
+pub fn error_value(d: &Diagnostic, spilled: Option<ErrorValue>) -> ErrorValue {
+    let mut error = spilled.unwrap_or_else(|| ErrorValue {
+        kind: ERROR_KINDS
+            .iter()
+            .find(|(code, _)| *code == d.get_code())
+            .map_or("Error", |(_, kind)| kind)
+            .to_owned(),
+        message: d.get_message(),
+        code: d.get_code(),
+        origin: None,
+        trace: Vec::new(),
+    });
+    error.origin = Some(Box::new(d.clone()));
+    error
+}
+
+/// Builds the error a `spill` statement raises. `kind` is empty for `spill value`, which
+/// spills an error value as it is and a string as a plain `Error`.
+pub fn spill_value(kind: &str, value: Value) -> Result<ErrorValue, String> {
+    match (kind, value) {
+        ("", Value::Error(e)) => Ok(*e),
+        ("", Value::Str(message)) => Ok(ErrorValue {
+            kind: "Error".to_owned(),
+            message,
+            code: SPILLED,
+            origin: None,
+            trace: Vec::new(),
+        }),
+        ("", v) => Err(format!("cannot spill {}", v.type_name())),
+        (kind, Value::Str(message)) => Ok(ErrorValue {
+            kind: kind.to_owned(),
+            message,
+            code: SPILLED,
+            origin: None,
+            trace: Vec::new(),
+        }),
+        (kind, v) => Err(format!(
+            "`{}` takes a str message, found {}",
+            kind,
+            v.type_name()
+        )),
+    }
+}
+
+/// The frame [`Interpreter::extend`] runs an entry's statements in.
+pub const ENTRY_FRAME: &str = "<repl>";
+
+/// Deepest call nesting allowed before the interpreter reports [`STACK_OVERFLOW`].
+pub const MAX_CALL_DEPTH: usize = 512;
+
+/// Stack for a thread running bottles: enough for [`MAX_CALL_DEPTH`] calls of the tree walker
+/// in an unoptimised build, which takes about 25 KiB a call, with room for nested expressions.
+pub const STACK_SIZE: usize = 64 << 20;
+
+/// A single function activation.
+struct Frame<'a> {
+    function: String,
+    module: usize,
+    /// How the names in the code being run were resolved.
+    names: Rc<Names<'a>>,
+    /// Parameters, locals and caught errors, by the slot the resolver gave them.
+    locals: Vec<Value>,
+}
+
+/// What a variable the resolver bound refers to.
+#[derive(Clone, Copy)]
+enum Var {
+    Local(usize),
+    Global,
+    Dependency,
+}
+
+/// The resolved names of one parsed tree. Node ids are only unique within a parse, so every
+/// tree run has its own, shared by its top level and its functions.
+#[derive(Default)]
+struct Names<'a> {
+    /// The function each plain call was resolved to, by the id of the call.
+    calls: HashMap<NodeId, &'a AstNode<'a>>,
+    /// What each declaration declares and each variable refers to, by node id.
+    vars: HashMap<NodeId, Var>,
+    /// The annotation of the declaration each name refers to, where it has one, so what is
+    /// assigned to the name is widened as the declaration's value was.
+    annotations: HashMap<NodeId, String>,
+}
+
+impl<'a> Names<'a> {
+    /// The names `resolution` resolved in a tree whose functions are `functions`.
+    fn new(resolution: &Resolution, functions: impl Iterator<Item = &'a AstNode<'a>>) -> Self {
+        let functions: HashMap<NodeId, &'a AstNode<'a>> = functions.map(|f| (f.id, f)).collect();
+        let var = |declaration: &NodeId| match resolution.symbols[declaration].kind {
+            SymbolKind::Param | SymbolKind::Local | SymbolKind::Caught => {
+                Some(Var::Local(resolution.slots[declaration]))
+            }
+            SymbolKind::Global => Some(Var::Global),
+            SymbolKind::Dependency => Some(Var::Dependency),
+            SymbolKind::Function => None,
+        };
+        let declarations = resolution.symbols.keys().map(|d| (d, d));
+        let vars = declarations
+            .chain(&resolution.bindings)
+            .filter_map(|(node, declaration)| Some((*node, var(declaration)?)))
+            .collect();
+        let calls = resolution
+            .bindings
+            .iter()
+            .filter_map(|(call, target)| Some((*call, *functions.get(target)?)))
+            .collect();
+        let annotations = resolution
+            .bindings
+            .iter()
+            .map(|(node, declaration)| (*node, &resolution.symbols[declaration].annotation))
+            .filter(|(_, annotation)| !annotation.is_empty())
+            .map(|(node, annotation)| (node, annotation.clone()))
+            .collect();
+        Names {
+            calls,
+            vars,
+            annotations,
+        }
+    }
+}
+
+/// A function of a module and the names of the tree it was parsed in.
+struct Function<'a> {
+    node: &'a AstNode<'a>,
+    names: Rc<Names<'a>>,
+}
+
+/// How control leaves a statement.
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+#[derive(PartialEq)]
+enum Init {
+    Pending,
+    Running,
+    Done,
+}
+
+/// One linked bottle: its functions, globals and the names it gave its dependencies.
+struct Module<'a> {
+    file: String,
+    functions: HashMap<String, Function<'a>>,
+    /// How the names in its top-level statements were resolved.
+    names: Rc<Names<'a>>,
+    globals: HashMap<String, Value>,
+    deps: HashMap<String, (DepKind, Option<usize>)>,
+    body: Vec<&'a AstNode<'a>>,
+    init: Init,
+    /// What the top-level statements returned, the value of a final expression statement.
+    returned: Option<Value>,
+}
+
+impl<'a> Module<'a> {
+    /// The module for `ast`, whose names were resolved to `resolution`.
+    fn new(file: &str, ast: &AST<'a>, resolution: &Resolution) -> Self {
+        let mut module = Module::empty(file);
+        let mut functions = Vec::new();
+        if let NodeKind::Root { children } = &ast.head.kind {
+            for child in children {
+                match &child.kind {
+                    NodeKind::Function { name, .. } => functions.push((name, *child)),
+                    // Directives were read before the bottle started.
+                    NodeKind::Dependency { .. } | NodeKind::Sharp { .. } | NodeKind::At { .. } => {}
+                    _ => module.body.push(child),
+                }
+            }
+        }
+        let nodes = functions.iter().map(|(_, node)| *node);
+        module.names = Rc::new(Names::new(resolution, nodes));
+        for (name, node) in functions {
+            debug!("Registering function {}", name);
+            let names = module.names.clone();
+            module
+                .functions
+                .insert(name.clone(), Function { node, names });
+        }
+        module
+    }
+
+    fn empty(file: &str) -> Self {
+        Module {
+            file: file.to_owned(),
+            functions: HashMap::new(),
+            names: Rc::default(),
+            globals: HashMap::new(),
+            deps: HashMap::new(),
+            body: Vec::new(),
+            init: Init::Pending,
+            returned: None,
+        }
+    }
+}
+
+/// Whether `node` is a statement that only evaluates an expression.
+pub(crate) fn is_expr(node: &AstNode) -> bool {
+    matches!(
+        node.kind,
+        NodeKind::BinaryExpr { .. }
+            | NodeKind::UnaryExpr { .. }
+            | NodeKind::StrLiteral { .. }
+            | NodeKind::IntLiteral { .. }
+            | NodeKind::FloatLiteral { .. }
+            | NodeKind::BoolLiteral { .. }
+            | NodeKind::Identifier { .. }
+            | NodeKind::Variable { .. }
+            | NodeKind::Call { .. }
+            | NodeKind::BangCall { .. }
+    )
+}
+
+/// Walks an [`AST`] directly. Each bottle is linked as a module: its functions are registered
+/// up front, its other top-level statements initialise globals, and `main` of the root module
+/// is then called with no arguments. Other modules are reached through dotted names such as
+/// `math.add`, where `math` is the name a dependency was declared with. Names are read and
+/// written as the checker resolved them, each local in the slot of its frame it was given.
+pub struct Interpreter<'a> {
+    file: String,
+    modules: Vec<Module<'a>>,
+    /// The module whose `main` is run.
+    root: usize,
+    frames: Vec<Frame<'a>>,
+    interrupt: Option<Interrupt>,
+    /// What bang calls reach of the bottle.
+    runtime: Runtime,
+    /// Call trace captured where the error being propagated was raised.
+    error_trace: Vec<String>,
+    unwinding: bool,
+    /// The value of the `spill` being propagated, so `catch` gets it back intact.
+    spilled: Option<ErrorValue>,
+    /// Where the node being run is, for errors to point at.
+    at: Option<ast::Span>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(file: &str) -> Self {
+        Self {
+            file: file.to_owned(),
+            modules: Vec::new(),
+            root: 0,
+            frames: Vec::new(),
+            interrupt: None,
+            runtime: Runtime::default(),
+            error_trace: Vec::new(),
+            unwinding: false,
+            spilled: None,
+            at: None,
+        }
+    }
+
+    /// Registers every function in `ast` and runs its top-level statements.
+    pub fn load(&mut self, ast: &AST<'a>) -> Result<(), Diagnostic> {
+        if !matches!(ast.head.kind, NodeKind::Root { .. }) {
+            return Err(self.error(UNSUPPORTED, "expected a root node".to_owned()));
+        }
+        let names = checker::resolve(ast, "");
+        self.modules.push(Module::new(&self.file, ast, &names));
+        self.root = self.modules.len() - 1;
+        self.ensure_init(self.root)
+    }
+
+    /// Links every bottle in `graph` and initialises the root along with everything it
+    /// requires. Imports and locks are initialised the first time they are used.
+    pub fn link(&mut self, graph: &DependencyGraph<'a>) -> Result<(), Diagnostic> {
+        let base = self.modules.len();
+        for unit in &graph.units {
+            let mut module = Module::new(&unit.file(), &unit.ast, &unit.names);
+            for dep in &unit.deps {
+                module
+                    .deps
+                    .insert(dep.name.clone(), (dep.kind, dep.target.map(|t| t + base)));
+            }
+            self.modules.push(module);
+        }
+        self.root = base;
+        self.ensure_init(base)
+    }
+
+    /// Adds the root of `graph` to the root module as one entry of an interactive session. Its
+    /// functions are registered, replacing any of the same name, its dependencies are linked and
+    /// its statements run at the top level, so declarations become globals that later entries
+    /// see. Returns the value of a final expression statement, if the entry ends with one.
+    ///
+    /// An entry that fails keeps whatever its statements did before the error.
+    pub fn extend(&mut self, graph: &DependencyGraph<'a>) -> Result<Option<Value>, Diagnostic> {
+        if self.modules.is_empty() {
+            let mut module = Module::empty(&self.file);
+            module.init = Init::Done;
+            self.modules.push(module);
+            self.root = 0;
+        }
+        let (root, base) = (self.root, self.modules.len());
+        // The entry itself joins the root; the units it depends on are linked after it.
+        let index = |target: usize| match target {
+            0 => root,
+            target => base + target - 1,
+        };
+        for unit in &graph.units[1..] {
+            let mut module = Module::new(&unit.file(), &unit.ast, &unit.names);
+            for dep in &unit.deps {
+                module
+                    .deps
+                    .insert(dep.name.clone(), (dep.kind, dep.target.map(index)));
+            }
+            self.modules.push(module);
+        }
+        let entry = &graph.units[0];
+        let Module {
+            functions,
+            body,
+            names,
+            ..
+        } = Module::new(&entry.file(), &entry.ast, &entry.names);
+        self.modules[root].functions.extend(functions);
+        for dep in &entry.deps {
+            let target = dep.target.map(index);
+            self.modules[root]
+                .deps
+                .insert(dep.name.clone(), (dep.kind, target));
+            if let (DepKind::Require, Some(target)) = (dep.kind, target) {
+                self.ensure_init(target)?;
+            }
+        }
+        let frame = Frame {
+            function: ENTRY_FRAME.to_owned(),
+            module: root,
+            names,
+            locals: Vec::new(),
+        };
+        self.framed(frame, |this| this.top_level(&body))
+    }
+
+    /// The root module's globals, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, &Value)> {
+        let Some(module) = self.modules.get(self.root) else {
+            return Vec::new();
+        };
+        let mut globals: Vec<_> = module
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+        globals
+    }
+
+    /// The root module's functions, sorted by name.
+    pub fn functions(&self) -> Vec<&'a AstNode<'a>> {
+        let Some(module) = self.modules.get(self.root) else {
+            return Vec::new();
+        };
+        let mut functions: Vec<_> = module.functions.iter().collect();
+        functions.sort_by_key(|(name, _)| *name);
+        functions.into_iter().map(|(_, f)| f.node).collect()
+    }
+
+    /// The root module's dependencies, sorted by name, with the file each was found at.
+    pub fn dependencies(&self) -> Vec<(&str, DepKind, Option<&str>)> {
+        let Some(module) = self.modules.get(self.root) else {
+            return Vec::new();
+        };
+        let mut deps: Vec<_> = module
+            .deps
+            .iter()
+            .map(|(name, (kind, target))| {
+                let file = target.map(|t| self.modules[t].file.as_str());
+                (name.as_str(), *kind, file)
+            })
+            .collect();
+        deps.sort_by_key(|(name, _, _)| *name);
+        deps
+    }
+
+    /// Loads `ast` and calls `main`, returning its result as an exit code.
+    pub fn run(&mut self, ast: &AST<'a>) -> Result<i64, Diagnostic> {
+        self.load(ast)?;
+        self.run_main()
+    }
+
+    /// Links `graph` and calls `main` of its root, returning the result as an exit code.
+    pub fn run_graph(&mut self, graph: &DependencyGraph<'a>) -> Result<i64, Diagnostic> {
+        self.link(graph)?;
+        self.run_main()
+    }
+
+    /// Links `graph` and runs only its root's top-level statements, as a snippet without `main`
+    /// is run. Returns the value of a final expression statement, or void.
+    pub fn run_top_level(&mut self, graph: &DependencyGraph<'a>) -> Result<Value, Diagnostic> {
+        self.link(graph)?;
+        Ok(self.modules[self.root]
+            .returned
+            .take()
+            .unwrap_or(Value::Void))
+    }
+
+    fn run_main(&mut self) -> Result<i64, Diagnostic> {
+        let root = self.root;
+        if !self.modules[root].functions.contains_key("main") {
+            return Err(self.error(MISSING_MAIN, "no `main` function defined".to_owned()));
+        }
+        match self.call_in(root, "main", vec![])? {
+            Value::Int(code) => Ok(code),
+            Value::Void => Ok(0),
+            v => Err(self.error(
+                TYPE_ERROR,
+                format!("`main` must return i64 or void, found {}", v.type_name()),
+            )),
+        }
+    }
+
+    /// Calls a function by name, relative to the root module.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        self.call_in(self.root, name, args)
+    }
+
+    fn call_in(
+        &mut self,
+        module: usize,
+        name: &str,
+        args: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        let (module, fname) = self.resolve(module, name)?;
+        let Some(Function { node, names }) = self.modules[module].functions.get(fname) else {
+            return Err(self.error(UNDEFINED_FUNCTION, format!("undefined function `{}`", name)));
+        };
+        let (node, names) = (*node, names.clone());
+        self.invoke(module, name, node, names, args)
+    }
+
+    /// Calls `function`, a function of `module` resolved to `names`, as `name`.
+    fn invoke(
+        &mut self,
+        module: usize,
+        name: &str,
+        function: &'a AstNode<'a>,
+        names: Rc<Names<'a>>,
+        args: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        self.poll()?;
+        let NodeKind::Function {
+            params,
+            body,
+            return_type,
+            ..
+        } = &function.kind
+        else {
+            unreachable!("only functions are registered");
+        };
+        if params.len() != args.len() {
+            return Err(self.error(
+                ARITY_MISMATCH,
+                format!(
+                    "`{}` takes {} argument(s) but {} were given",
+                    name,
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(self.error(
+                STACK_OVERFLOW,
+                format!("call depth exceeded {} in `{}`", MAX_CALL_DEPTH, name),
+            ));
+        }
+        // Parameters take the first slots, in order.
+        let locals = params
+            .iter()
+            .zip(args)
+            .map(|(param, arg)| match &param.kind {
+                NodeKind::Declaration { struct_type, .. } => arg.widen(struct_type),
+                _ => arg,
+            })
+            .collect();
+        let value = self.enter(name, module, names, locals, body)?;
+        Ok(value.widen(return_type))
+    }
+
+    fn enter(
+        &mut self,
+        function: &str,
+        module: usize,
+        names: Rc<Names<'a>>,
+        locals: Vec<Value>,
+        body: &[&'a AstNode<'a>],
+    ) -> Result<Value, Diagnostic> {
+        let frame = Frame {
+            function: function.to_owned(),
+            module,
+            names,
+            locals,
+        };
+        match self.framed(frame, |this| this.exec_body(body))? {
+            Flow::Return(v) => Ok(v),
+            Flow::Normal => Ok(Value::Void),
+        }
+    }
+
+    /// Runs `run` with `frame` pushed, capturing the call trace if an error starts there.
+    fn framed<T>(
+        &mut self,
+        frame: Frame<'a>,
+        run: impl FnOnce(&mut Self) -> Result<T, Diagnostic>,
+    ) -> Result<T, Diagnostic> {
+        trace!("Entering {}", frame.function);
+        self.frames.push(frame);
+        let result = run(self);
+        if result.is_err() && !std::mem::replace(&mut self.unwinding, true) {
+            self.error_trace = self.call_trace();
+        }
+        // An error unwinds the frames above this one as it propagates.
+        let frame = self.frames.pop().unwrap();
+        if self.frames.is_empty() {
+            self.unwinding = false;
+            self.spilled = None;
+        }
+        trace!("Leaving {}", frame.function);
+        result
+    }
+
+    /// Runs a module's top-level statements once, after everything it requires.
+    fn ensure_init(&mut self, module: usize) -> Result<(), Diagnostic> {
+        if self.modules[module].init != Init::Pending {
+            return Ok(());
+        }
+        self.modules[module].init = Init::Running;
+        let required: Vec<usize> = self.modules[module]
+            .deps
+            .values()
+            .filter_map(|(kind, target)| (*kind == DepKind::Require).then_some(*target)?)
+            .collect();
+        for dep in required {
+            self.ensure_init(dep)?;
+        }
+        let body = std::mem::take(&mut self.modules[module].body);
+        let frame = Frame {
+            function: "<init>".to_owned(),
+            module,
+            names: self.modules[module].names.clone(),
+            locals: Vec::new(),
+        };
+        let result = self.framed(frame, |this| this.top_level(&body));
+        self.modules[module].body = body;
+        self.modules[module].returned = result?;
+        self.modules[module].init = Init::Done;
+        Ok(())
+    }
+
+    /// Runs top-level statements in the current frame, returning the value of the last one if
+    /// it only evaluates an expression.
+    fn top_level(&mut self, body: &[&'a AstNode<'a>]) -> Result<Option<Value>, Diagnostic> {
+        let (body, last) = match body.split_last() {
+            Some((last, rest)) if is_expr(last) => (rest, Some(*last)),
+            _ => (body, None),
+        };
+        match self.exec_body(body)? {
+            Flow::Return(_) => {
+                Err(self.error(UNSUPPORTED, "`return` outside of a function".to_owned()))
+            }
+            Flow::Normal => last.map(|expr| self.eval(expr)).transpose(),
+        }
+    }
+
+    /// Splits a dotted name into the module it lives in and its last segment, walking the
+    /// dependency names from `module` and initialising lazily loaded modules on the way.
+    fn resolve<'n>(
+        &mut self,
+        mut module: usize,
+        name: &'n str,
+    ) -> Result<(usize, &'n str), Diagnostic> {
+        let mut segments: Vec<&str> = name.split('.').collect();
+        let last = segments.pop().unwrap();
+        for segment in segments {
+            module = match self.modules[module].deps.get(segment) {
+                Some((_, Some(target))) => *target,
+                Some((kind, None)) => {
+                    return Err(self.error(
+                        crate::deps::IMPORT_NOT_FOUND,
+                        format!("{} `{}` could not be loaded", kind, segment),
+                    ))
+                }
+                None => {
+                    return Err(self.error(
+                        UNDEFINED_VARIABLE,
+                        format!("`{}` is not a dependency of this bottle", segment),
+                    ))
+                }
+            };
+            self.ensure_init(module)?;
+        }
+        Ok((module, last))
+    }
+
+    /// Names of the functions currently being executed, innermost last.
+    pub fn call_trace(&self) -> Vec<String> {
+        self.frames.iter().map(|f| f.function.clone()).collect()
+    }
+
+    /// The [`Interpreter::call_trace`] at the point the last error was raised.
+    pub fn error_trace(&self) -> &[String] {
+        &self.error_trace
+    }
+
+    /// Stops execution at the next call or loop iteration once `interrupt` is raised.
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Runs bang calls in `runtime`, and stops once its interrupt is raised.
+    pub fn set_runtime(&mut self, runtime: Runtime) {
+        self.interrupt = Some(runtime.interrupt());
+        self.runtime = runtime;
+    }
+
+    fn poll(&self) -> Result<(), Diagnostic> {
+        match self.interrupt.as_ref().and_then(Interrupt::check) {
+            Some(message) => Err(self.error(INTERRUPTED, message)),
+            None => Ok(()),
+        }
+    }
+
+    fn exec_body(&mut self, body: &[&'a AstNode<'a>]) -> Result<Flow, Diagnostic> {
+        for stmt in body {
+            if let Flow::Return(v) = self.exec(stmt)? {
+                return Ok(Flow::Return(v));
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs a `catch` body with the caught error bound to its name.
+    fn exec_catch(
+        &mut self,
+        handler: &'a AstNode<'a>,
+        error: ErrorValue,
+    ) -> Result<Flow, Diagnostic> {
+        let NodeKind::Catch { name, body, .. } = &handler.kind else {
+            unreachable!("the parser only puts catch nodes in handlers");
+        };
+        if let (false, Some(Var::Local(slot))) = (name.is_empty(), self.var(handler.id)) {
+            self.store(slot, Value::Error(Box::new(error)));
+        }
+        self.exec_body(body)
+    }
+
+    fn exec(&mut self, node: &'a AstNode<'a>) -> Result<Flow, Diagnostic> {
+        self.located(node, |this| this.exec_node(node))
+    }
+
+    fn exec_node(&mut self, node: &'a AstNode<'a>) -> Result<Flow, Diagnostic> {
+        match &node.kind {
+            NodeKind::Return { value } => Ok(Flow::Return(self.eval(value)?)),
+            NodeKind::Declaration {
+                name,
+                value,
+                struct_type,
+            } => {
+                let v = self.eval(value)?.widen(struct_type);
+                match self.var(node.id) {
+                    Some(Var::Local(slot)) => self.store(slot, v),
+                    _ => {
+                        let module = self.frames.last().unwrap().module;
+                        self.modules[module].globals.insert(name.clone(), v);
+                    }
+                }
+                Ok(Flow::Normal)
+            }
+            NodeKind::Assignment { identifier, value } => {
+                let NodeKind::Identifier { name } = &identifier.kind else {
+                    return Err(self.error(UNSUPPORTED, "invalid assignment target".to_owned()));
+                };
+                let mut v = self.eval(value)?;
+                if let Some(ty) = self
+                    .frames
+                    .last()
+                    .unwrap()
+                    .names
+                    .annotations
+                    .get(&identifier.id)
+                {
+                    v = v.widen(ty);
+                }
+                match self.lookup_mut(identifier.id, name) {
+                    Some(slot) => {
+                        *slot = v;
+                        Ok(Flow::Normal)
+                    }
+                    None => Err(self.error(
+                        UNDEFINED_VARIABLE,
+                        format!("assignment to undeclared variable `{}`", name),
+                    )),
+                }
+            }
+            NodeKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                if self.eval_bool(cond)? {
+                    self.exec_body(then_body)
+                } else {
+                    self.exec_body(else_body)
+                }
+            }
+            NodeKind::While { cond, body } => {
+                while self.eval_bool(cond)? {
+                    self.poll()?;
+                    if let Flow::Return(v) = self.exec_body(body)? {
+                        return Ok(Flow::Return(v));
+                    }
+                }
+                Ok(Flow::Normal)
+            }
+            NodeKind::Try {
+                body,
+                handlers,
+                finally,
+            } => {
+                let mut result = self.exec_body(body);
+                if let Err(d) = &result {
+                    if catchable(d.get_code()) {
+                        // The spilled value stays put unless a handler takes it, so one that
+                        // passes through keeps its kind for the handlers further out.
+                        let mut error = error_value(d, self.spilled.clone());
+                        error.trace = self.error_trace.clone();
+                        if let Some(handler) = handlers.iter().find(|h| matches!(&h.kind, NodeKind::Catch { kind, .. } if kind.is_empty() || *kind == error.kind)) {
+                            self.spilled = None;
+                            self.unwinding = false;
+                            result = self.exec_catch(handler, error);
+                        }
+                    }
+                }
+                if !finally.is_empty() {
+                    // Cleanup always runs, and a `return` or error inside it wins over whatever
+                    // it follows. Otherwise an error passing through is left as it was.
+                    let passing = result
+                        .is_err()
+                        .then(|| (std::mem::take(&mut self.error_trace), self.spilled.take()));
+                    self.unwinding = false;
+                    if let Flow::Return(v) = self.exec_body(finally)? {
+                        return Ok(Flow::Return(v));
+                    }
+                    if let Some((trace, spilled)) = passing {
+                        self.error_trace = trace;
+                        self.spilled = spilled;
+                        self.unwinding = true;
+                    }
+                }
+                result
+            }
+            NodeKind::Spill { kind, value } => {
+                let value = self.eval(value)?;
+                let mut error = spill_value(kind, value).map_err(|m| self.error(TYPE_ERROR, m))?;
+                let d = match error.origin.take() {
+                    Some(origin) => *origin,
+                    None => self.error(error.code, error.to_string()),
+                };
+                self.spilled = Some(error);
+                Err(d)
+            }
+            NodeKind::Function { name, .. } => Err(self.error(
+                UNSUPPORTED,
+                format!("nested function `{}` is not supported", name),
+            )),
+            NodeKind::Skip | NodeKind::None | NodeKind::Eof => Ok(Flow::Normal),
+            _ => {
+                self.eval_node(node)?;
+                Ok(Flow::Normal)
+            }
+        }
+    }
+
+    fn eval(&mut self, node: &'a AstNode<'a>) -> Result<Value, Diagnostic> {
+        self.located(node, |this| this.eval_node(node))
+    }
+
+    /// Runs `run` with errors pointing at `node`, then goes back to pointing where they did.
+    fn located<T>(&mut self, node: &AstNode, run: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = self.at.replace(node.span);
+        let result = run(self);
+        self.at = outer;
+        result
+    }
+
+    fn eval_node(&mut self, node: &'a AstNode<'a>) -> Result<Value, Diagnostic> {
+        match &node.kind {
+            NodeKind::IntLiteral { value } => Ok(Value::Int(*value)),
+            NodeKind::FloatLiteral { value } => Ok(Value::Float(*value)),
+            NodeKind::BoolLiteral { value } => Ok(Value::Bool(*value)),
+            NodeKind::StrLiteral { value } => Ok(Value::Str(value.clone())),
+            NodeKind::None => Ok(Value::Void),
+            NodeKind::Variable { name } | NodeKind::Identifier { name } => {
+                let mut fields = name.split('.');
+                let first = fields.next().unwrap();
+                let var = self.var(node.id);
+                if !matches!(var, Some(Var::Dependency)) {
+                    if let Some(value) = self.lookup(var, first) {
+                        return fields.try_fold(value.clone(), |v, field| {
+                            v.field(field).map_err(|m| self.error(TYPE_ERROR, m))
+                        });
+                    }
+                }
+                let module = self.frames.last().unwrap().module;
+                let (module, var) = self.resolve(module, name)?;
+                let value = match var.len() == name.len() {
+                    true => None,
+                    false => self.modules[module].globals.get(var),
+                };
+                match value {
+                    Some(v) => Ok(v.clone()),
+                    None => {
+                        Err(self
+                            .error(UNDEFINED_VARIABLE, format!("undefined variable `{}`", name)))
+                    }
+                }
+            }
+            NodeKind::UnaryExpr { op, expr } => {
+                let v = self.eval(expr)?;
+                Value::unary(op, v).map_err(|m| self.error(TYPE_ERROR, m))
+            }
+            NodeKind::BinaryExpr { left, op, right } if op == "&&" || op == "||" => {
+                let l = self.eval_bool(left)?;
+                if (op == "&&") != l {
+                    return Ok(Value::Bool(l));
+                }
+                Ok(Value::Bool(self.eval_bool(right)?))
+            }
+            NodeKind::BinaryExpr { left, op, right } => {
+                let l = self.eval(left)?;
+                let r = self.eval(right)?;
+                Value::binary(op, l, r).map_err(|m| self.error(TYPE_ERROR, m))
+            }
+            NodeKind::Call { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|a| self.eval(a))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let frame = self.frames.last().unwrap();
+                let (module, names) = (frame.module, frame.names.clone());
+                match names.calls.get(&node.id).copied() {
+                    Some(function) => self.invoke(module, name, function, names, args),
+                    None => self.call_in(module, name, args),
+                }
+            }
+            NodeKind::BangCall { name, args } => {
+                self.poll()?;
+                let args = args
+                    .iter()
+                    .map(|a| self.eval(a))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let file = match self.frames.last() {
+                    Some(frame) => &self.modules[frame.module].file,
+                    None => &self.file,
+                };
+                let at = format!("{}:{}:{}", file, node.span.line + 1, node.span.column + 1);
+                let context = intrinsic::Context::new(at, &self.runtime);
+                intrinsic::call(name, &context, args)
+                    .map_err(|(code, message)| self.error(code, message))
+            }
+            other => Err(self.error(
+                UNSUPPORTED,
+                format!("cannot evaluate `{}` as an expression", other),
+            )),
+        }
+    }
+
+    fn eval_bool(&mut self, node: &'a AstNode<'a>) -> Result<bool, Diagnostic> {
+        self.eval(node)?
+            .as_bool()
+            .map_err(|m| self.error(TYPE_ERROR, m))
+    }
+
+    /// What the resolver bound node `id` of the code being run to.
+    fn var(&self, id: NodeId) -> Option<Var> {
+        self.frames.last()?.names.vars.get(&id).copied()
+    }
+
+    /// The value of `var`, the variable `name`. A name bound to nothing, as one an earlier entry
+    /// of an interactive session declared, is looked up among the globals.
+    fn lookup(&self, var: Option<Var>, name: &str) -> Option<&Value> {
+        let frame = self.frames.last()?;
+        match var {
+            Some(Var::Local(slot)) => frame.locals.get(slot),
+            _ => self.modules[frame.module].globals.get(name),
+        }
+    }
+
+    /// [`Interpreter::lookup`] for assigning to the variable `name` that node `id` names.
+    fn lookup_mut(&mut self, id: NodeId, name: &str) -> Option<&mut Value> {
+        let var = self.var(id);
+        let frame = self.frames.last_mut()?;
+        match var {
+            Some(Var::Local(slot)) => frame.locals.get_mut(slot),
+            _ => self.modules[frame.module].globals.get_mut(name),
+        }
+    }
+
+    /// Sets local `slot` of the current frame.
+    fn store(&mut self, slot: usize, v: Value) {
+        let locals = &mut self.frames.last_mut().unwrap().locals;
+        if locals.len() <= slot {
+            locals.resize(slot + 1, Value::Void);
+        }
+        locals[slot] = v;
+    }
+
+    fn error(&self, code: i32, message: String) -> Diagnostic {
+        let (message, file) = match self.frames.last() {
+            Some(frame) => (
+                format!("{} (in `{}`)", message, frame.function),
+                self.modules[frame.module].file.clone(),
+            ),
+            None => (message, self.file.clone()),
+        };
+        let d = Diagnostic::Error(err::Error {
+            code,
+            file,
+            message,
+            ..Default::default()
+        });
+        match self.at {
+            Some(span) => d.at(span),
+            None => d,
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::err::Diagnostic;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A runtime value. Both execution backends share this representation and its operators.
+/// Errors only exist while running, so they are never written out with a compiled program.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    #[serde(skip)]
+    Error(Box<ErrorValue>),
+    Void,
+}
+
+/// What a `catch` binds: the kind of error, its message and the diagnostic code behind it.
+#[derive(Debug, Clone)]
+pub struct ErrorValue {
+    pub kind: String,
+    pub message: String,
+    pub code: i32,
+    /// The diagnostic it was caught from, so spilling it again reports the original.
+    pub origin: Option<Box<Diagnostic>>,
+    /// Functions that were executing where it was raised, outermost first.
+    pub trace: Vec<String>,
+}
+
+impl PartialEq for ErrorValue {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.kind, &self.message, self.code) == (&other.kind, &other.message, other.code)
+    }
+}
+
+impl Value {
+    /// The value as it is stored somewhere declared `ty`: an `i64` going where an `f64` is
+    /// declared becomes one, and anything else is left as it is.
+    pub fn widen(self, ty: &str) -> Value {
+        match (ty, self) {
+            ("f64", Value::Int(i)) => Value::Float(i as f64),
+            (_, v) => v,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "i64",
+            Value::Float(_) => "f64",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "str",
+            Value::Error(_) => "error",
+            Value::Void => "void",
+        }
+    }
+
+    /// Reads `.name` off a value. Only errors have fields: `kind`, `message` and `code`.
+    pub fn field(&self, name: &str) -> Result<Value, String> {
+        match (self, name) {
+            (Value::Error(e), "kind") => Ok(Value::Str(e.kind.clone())),
+            (Value::Error(e), "message") => Ok(Value::Str(e.message.clone())),
+            (Value::Error(e), "code") => Ok(Value::Int(e.code as i64)),
+            (v, name) => Err(format!("{} has no field `{}`", v.type_name(), name)),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            v => Err(format!("expected bool, found {}", v.type_name())),
+        }
+    }
+
+    /// Applies a prefix operator from `parser::UNARY_OPS`.
+    pub fn unary(op: &str, v: Value) -> Result<Value, String> {
+        match (op, v) {
+            ("-", Value::Int(i)) => i
+                .checked_neg()
+                .map(Value::Int)
+                .ok_or_else(|| "integer overflow".to_owned()),
+            ("-", Value::Float(f)) => Ok(Value::Float(-f)),
+            ("!", Value::Bool(b)) => Ok(Value::Bool(!b)),
+            ("~", Value::Int(i)) => Ok(Value::Int(!i)),
+            (op, v) => Err(format!("cannot apply `{}` to {}", op, v.type_name())),
+        }
+    }
+
+    /// Applies a binary operator from `parser::BINARY_OPS`. `&&` and `||` are evaluated here
+    /// without short-circuiting, so callers that want laziness must handle them first.
+    /// Mixing `i64` and `f64` widens the integer.
+    pub fn binary(op: &str, l: Value, r: Value) -> Result<Value, String> {
+        use Value::*;
+        let overflow = || "integer overflow".to_owned();
+        match (l, r) {
+            (Int(a), Int(b)) => match op {
+                "+" => a.checked_add(b).map(Int).ok_or_else(overflow),
+                "-" => a.checked_sub(b).map(Int).ok_or_else(overflow),
+                "*" => a.checked_mul(b).map(Int).ok_or_else(overflow),
+                "/" | "%" if b == 0 => Err("division by zero".to_owned()),
+                "/" => a.checked_div(b).map(Int).ok_or_else(overflow),
+                "%" => a.checked_rem(b).map(Int).ok_or_else(overflow),
+                "**" => u32::try_from(b)
+                    .map_err(|_| "negative exponent on i64".to_owned())
+                    .and_then(|b| a.checked_pow(b).ok_or_else(overflow))
+                    .map(Int),
+                "<<" | ">>" => {
+                    let b = u32::try_from(b)
+                        .ok()
+                        .filter(|b| *b < 64)
+                        .ok_or_else(|| format!("shift amount {} out of range", b))?;
+                    Ok(Int(if op == "<<" { a << b } else { a >> b }))
+                }
+                "&" => Ok(Int(a & b)),
+                "|" => Ok(Int(a | b)),
+                "^" => Ok(Int(a ^ b)),
+                _ => Self::compare(op, a.cmp(&b)),
+            },
+            (Int(a), Float(b)) => Self::binary(op, Float(a as f64), Float(b)),
+            (Float(a), Int(b)) => Self::binary(op, Float(a), Float(b as f64)),
+            (Float(a), Float(b)) => match op {
+                "+" => Ok(Float(a + b)),
+                "-" => Ok(Float(a - b)),
+                "*" => Ok(Float(a * b)),
+                "/" => Ok(Float(a / b)),
+                "%" => Ok(Float(a % b)),
+                "**" => Ok(Float(a.powf(b))),
+                _ => match a.partial_cmp(&b) {
+                    Some(ord) => Self::compare(op, ord),
+                    None => Ok(Bool(op == "!=")),
+                },
+            },
+            (Bool(a), Bool(b)) => match op {
+                "&&" | "&" => Ok(Bool(a && b)),
+                "||" | "|" => Ok(Bool(a || b)),
+                "^" => Ok(Bool(a ^ b)),
+                "==" | "!=" => Self::compare(op, a.cmp(&b)),
+                _ => Err(format!("cannot apply `{}` to bool", op)),
+            },
+            (Str(a), Str(b)) => match op {
+                "+" => Ok(Str(a + &b)),
+                _ => Self::compare(op, a.cmp(&b)),
+            },
+            (l, r) => Err(format!(
+                "cannot apply `{}` to {} and {}",
+                op,
+                l.type_name(),
+                r.type_name()
+            )),
+        }
+    }
+
+    fn compare(op: &str, ord: std::cmp::Ordering) -> Result<Value, String> {
+        Ok(Value::Bool(match op {
+            "==" => ord.is_eq(),
+            "!=" => ord.is_ne(),
+            "<" => ord.is_lt(),
+            "<=" => ord.is_le(),
+            ">" => ord.is_gt(),
+            ">=" => ord.is_ge(),
+            _ => return Err(format!("unsupported operator `{}`", op)),
+        }))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Error(e) => write!(f, "{}", e),
+            Value::Void => write!(f, "void"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
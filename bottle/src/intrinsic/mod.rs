@@ -0,0 +1,389 @@
+//! Bang calls, `name!(..)`: intrinsics that reach into the bottle runtime instead of running
+//! code the bottle declares.
+//!
+//! ```text
+//! shatter!(code)   // stop now, shattered with `code` as the detail (0xF1xxxxxx)
+//! spill!(message)  // spill now with `message`, past any `try`
+//! pause!()         // let a bottle waiting for a worker go first
+//! sleep!(ms)       // park as Sleeping for `ms` milliseconds
+//! state!()         // the bottle's state as its u32 code, see docs/states.md
+//! dbg!(value)      // print `value` and where it was printed from, then give it back
+//! ```
+//! Hosts add their own with [`register`] before any bottle runs. Every intrinsic declares the
+//! [`Type`] of each argument and of what it gives, so the checker rejects bad calls before a
+//! bottle starts and [`call`] checks them again when they run.
+use crate::interp::{self, Value};
+use crate::sched::{self, Interrupt};
+use crate::state::{Pause, Shatter, State};
+use checker::Bang;
+pub use checker::Type;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// What an intrinsic does with the arguments of a call, already checked against its
+/// signature.
+pub type Handler = dyn Fn(&Context, Vec<Value>) -> Result<Value, Stop> + Send + Sync;
+
+/// A registered intrinsic.
+#[derive(Clone)]
+pub struct Intrinsic {
+    pub signature: Bang,
+    run: Arc<Handler>,
+}
+
+/// Why an intrinsic did not give a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// The call failed. `try` catches it as an `IntrinsicError`.
+    Failed(String),
+    /// The bottle spills here, whatever `try` is around the call.
+    Spill(String),
+    /// The bottle stops in the state [`Context::shatter`] asked for.
+    Interrupted(String),
+}
+
+/// The bottle a bang call runs in, as far as intrinsics can reach it.
+#[derive(Clone, Default)]
+pub struct Runtime {
+    interrupt: Interrupt,
+    /// The scheduler running the bottle, if one is.
+    host: Option<sched::Handle>,
+}
+
+impl Runtime {
+    pub(crate) fn new(interrupt: Interrupt, host: Option<sched::Handle>) -> Runtime {
+        Runtime { interrupt, host }
+    }
+
+    pub(crate) fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+}
+
+/// What an intrinsic is given besides its arguments.
+pub struct Context<'r> {
+    /// Where the call is, as precisely as the backend running it knows.
+    pub at: String,
+    runtime: &'r Runtime,
+}
+
+impl<'r> Context<'r> {
+    pub(crate) fn new(at: String, runtime: &'r Runtime) -> Context<'r> {
+        Context { at, runtime }
+    }
+
+    /// The state the bottle is in. Always [`State::Executing`] unless a scheduler says
+    /// otherwise.
+    pub fn state(&self) -> State {
+        self.runtime
+            .host
+            .as_ref()
+            .map_or(State::Executing, |host| host.state())
+    }
+
+    /// Asks for the bottle to end shattered with `detail`. Return the [`Stop`] this gives to
+    /// stop it.
+    pub fn shatter(&self, detail: u32) -> Stop {
+        let state = State::Shattered(Shatter::Unhandled, detail);
+        self.runtime.interrupt.raise(state);
+        Stop::Interrupted(format!("shattered with {}", detail))
+    }
+
+    /// Parks the bottle as [`Pause::Sleeping`] for `duration`, letting other bottles have its
+    /// worker meanwhile.
+    pub fn sleep(&self, duration: Duration) {
+        match &self.runtime.host {
+            Some(host) => host.park(Pause::Sleeping, duration),
+            None => thread::sleep(duration),
+        }
+    }
+
+    /// Lets a bottle waiting for a worker run before this one carries on.
+    pub fn pause(&self) {
+        match &self.runtime.host {
+            Some(host) => host.park(Pause::Paused, Duration::ZERO),
+            None => thread::yield_now(),
+        }
+    }
+}
+
+fn registry() -> &'static RwLock<Vec<Intrinsic>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Intrinsic>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(builtins()))
+}
+
+/// Adds intrinsic `name!`, taking arguments of type `params` and giving a `returns`.
+/// [`Type::Unknown`] takes or gives anything. Fails if `name` is not a plain name or is
+/// taken.
+pub fn register(
+    name: &str,
+    params: &[Type],
+    returns: Type,
+    run: impl Fn(&Context, Vec<Value>) -> Result<Value, Stop> + Send + Sync + 'static,
+) -> Result<(), String> {
+    let plain = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !plain || parser::KEYWORDS.contains(&name) {
+        return Err(format!("`{}` can't be called as `{}!(..)`", name, name));
+    }
+    let mut intrinsics = registry().write().unwrap_or_else(|e| e.into_inner());
+    if intrinsics.iter().any(|i| i.signature.name == name) {
+        return Err(format!("intrinsic `{}!` is already registered", name));
+    }
+    intrinsics.push(intrinsic(name, params, returns, run));
+    Ok(())
+}
+
+/// The intrinsic called `name!`, if there is one.
+pub fn find(name: &str) -> Option<Intrinsic> {
+    let intrinsics = registry().read().unwrap_or_else(|e| e.into_inner());
+    intrinsics
+        .iter()
+        .find(|i| i.signature.name == name)
+        .cloned()
+}
+
+/// What every registered intrinsic takes and gives, for the checker.
+pub fn signatures() -> Vec<Bang> {
+    let intrinsics = registry().read().unwrap_or_else(|e| e.into_inner());
+    intrinsics.iter().map(|i| i.signature.clone()).collect()
+}
+
+/// Runs `name!(args)`. Fails with the runtime error code and message to report.
+pub(crate) fn call(
+    name: &str,
+    context: &Context,
+    args: Vec<Value>,
+) -> Result<Value, (i32, String)> {
+    let Some(intrinsic) = find(name) else {
+        return Err((
+            interp::UNDEFINED_FUNCTION,
+            format!("unknown intrinsic `{}!`", name),
+        ));
+    };
+    let params = &intrinsic.signature.params;
+    if params.len() != args.len() {
+        return Err((
+            interp::ARITY_MISMATCH,
+            format!(
+                "`{}!` takes {} argument(s) but {} were given",
+                name,
+                params.len(),
+                args.len()
+            ),
+        ));
+    }
+    let mut checked = Vec::with_capacity(args.len());
+    for (i, (arg, param)) in args.into_iter().zip(params).enumerate() {
+        let found = Type::parse(arg.type_name()).unwrap_or(Type::Unknown);
+        if !param.accepts(found) {
+            return Err((
+                interp::TYPE_ERROR,
+                format!(
+                    "argument {} of `{}!` is `{}` but `{}` was given",
+                    i + 1,
+                    name,
+                    param,
+                    found
+                ),
+            ));
+        }
+        checked.push(arg.widen(&param.to_string()));
+    }
+    match (intrinsic.run)(context, checked) {
+        Ok(value) => Ok(value),
+        Err(Stop::Failed(message)) => Err((interp::INTRINSIC_FAILED, message)),
+        Err(Stop::Spill(message)) => Err((interp::ABORTED, message)),
+        Err(Stop::Interrupted(message)) => Err((interp::INTERRUPTED, message)),
+    }
+}
+
+fn intrinsic(
+    name: &str,
+    params: &[Type],
+    returns: Type,
+    run: impl Fn(&Context, Vec<Value>) -> Result<Value, Stop> + Send + Sync + 'static,
+) -> Intrinsic {
+    Intrinsic {
+        signature: Bang {
+            name: name.to_owned(),
+            params: params.to_vec(),
+            returns,
+        },
+        run: Arc::new(run),
+    }
+}
+
+fn builtins() -> Vec<Intrinsic> {
+    vec![
+        intrinsic("shatter", &[Type::Int], Type::Void, |context, args| {
+            let [Value::Int(code)] = args[..] else {
+                unreachable!("checked against the signature")
+            };
+            match u32::try_from(code) {
+                Ok(detail) if detail <= 0xFF_FFFF => Err(context.shatter(detail)),
+                _ => Err(Stop::Failed(format!(
+                    "`{}` doesn't fit in a state's 24 bits of detail",
+                    code
+                ))),
+            }
+        }),
+        intrinsic(
+            "spill",
+            &[Type::Str],
+            Type::Void,
+            |_, mut args| match args.pop() {
+                Some(Value::Str(message)) => Err(Stop::Spill(message)),
+                _ => unreachable!("checked against the signature"),
+            },
+        ),
+        intrinsic("pause", &[], Type::Void, |context, _| {
+            context.pause();
+            Ok(Value::Void)
+        }),
+        intrinsic("sleep", &[Type::Int], Type::Void, |context, args| {
+            let [Value::Int(ms)] = args[..] else {
+                unreachable!("checked against the signature")
+            };
+            let ms = u64::try_from(ms)
+                .map_err(|_| Stop::Failed(format!("can't sleep for {} ms", ms)))?;
+            context.sleep(Duration::from_millis(ms));
+            Ok(Value::Void)
+        }),
+        intrinsic("state", &[], Type::Int, |context, _| {
+            Ok(Value::Int(context.state().encode() as i64))
+        }),
+        intrinsic(
+            "dbg",
+            &[Type::Unknown],
+            Type::Unknown,
+            |context, mut args| {
+                let value = args.pop().unwrap_or(Value::Void);
+                match &value {
+                    Value::Str(s) => eprintln!("[{}] {:?}", context.at, s),
+                    v => eprintln!("[{}] {}", context.at, v),
+                }
+                Ok(value)
+            },
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calls `name!(args)` outside any scheduler.
+    fn run(name: &str, args: Vec<Value>) -> Result<Value, (i32, String)> {
+        let runtime = Runtime::default();
+        call(
+            name,
+            &Context::new("test.wg:1:1".to_owned(), &runtime),
+            args,
+        )
+    }
+
+    fn code(result: Result<Value, (i32, String)>) -> i32 {
+        result.expect_err("the call fails").0
+    }
+
+    #[test]
+    fn only_plain_free_names_can_be_registered() {
+        let nothing = |_: &Context, _| Ok(Value::Void);
+        for name in [
+            "",
+            "9lives",
+            "two words",
+            "a-b",
+            "bang!",
+            "fn",
+            "while",
+            "return",
+        ] {
+            let why = register(name, &[], Type::Void, nothing).unwrap_err();
+            assert!(why.contains("can't be called"), "{:?}: {}", name, why);
+        }
+        let why = register("shatter", &[], Type::Void, nothing).unwrap_err();
+        assert_eq!(why, "intrinsic `shatter!` is already registered");
+        assert_eq!(register("_test_once", &[], Type::Void, nothing), Ok(()));
+        assert!(register("_test_once", &[], Type::Int, nothing).is_err());
+        assert!(signatures().iter().any(|b| b.name == "_test_once"));
+    }
+
+    #[test]
+    fn calls_are_checked_against_the_signature() {
+        register("test_half", &[Type::Float], Type::Float, |_, args| {
+            let [Value::Float(x)] = args[..] else {
+                unreachable!("checked against the signature")
+            };
+            Ok(Value::Float(x / 2.0))
+        })
+        .unwrap();
+        assert_eq!(
+            run("test_half", vec![Value::Float(3.0)]),
+            Ok(Value::Float(1.5))
+        );
+        // An `i64` is widened, as it would be passed to an `f64` parameter.
+        assert_eq!(run("test_half", vec![Value::Int(3)]), Ok(Value::Float(1.5)));
+        let result = run("test_half", vec![Value::Bool(true)]);
+        assert_eq!(
+            result,
+            Err((
+                interp::TYPE_ERROR,
+                "argument 1 of `test_half!` is `f64` but `bool` was given".to_owned()
+            ))
+        );
+        assert_eq!(code(run("test_half", vec![])), interp::ARITY_MISMATCH);
+        let two = vec![Value::Int(1), Value::Int(2)];
+        assert_eq!(code(run("test_half", two)), interp::ARITY_MISMATCH);
+        assert_eq!(
+            code(run("test_missing", vec![])),
+            interp::UNDEFINED_FUNCTION
+        );
+    }
+
+    #[test]
+    fn unknown_parameters_take_anything() {
+        let text = Value::Str("wine".to_owned());
+        assert_eq!(run("dbg", vec![text.clone()]), Ok(text));
+        assert_eq!(run("dbg", vec![Value::Int(7)]), Ok(Value::Int(7)));
+    }
+
+    #[test]
+    fn stops_become_runtime_errors() {
+        register("test_fail", &[], Type::Void, |_, _| {
+            Err(Stop::Failed("no".to_owned()))
+        })
+        .unwrap();
+        assert_eq!(
+            run("test_fail", vec![]),
+            Err((interp::INTRINSIC_FAILED, "no".to_owned()))
+        );
+        let message = vec![Value::Str("leaving".to_owned())];
+        assert_eq!(
+            run("spill", message),
+            Err((interp::ABORTED, "leaving".to_owned()))
+        );
+        assert_eq!(
+            code(run("shatter", vec![Value::Int(7)])),
+            interp::INTERRUPTED
+        );
+        assert_eq!(
+            code(run("shatter", vec![Value::Int(1 << 24)])),
+            interp::INTRINSIC_FAILED
+        );
+        assert_eq!(
+            code(run("sleep", vec![Value::Int(-1)])),
+            interp::INTRINSIC_FAILED
+        );
+    }
+
+    #[test]
+    fn builtins_run_outside_a_scheduler() {
+        assert_eq!(run("pause", vec![]), Ok(Value::Void));
+        assert_eq!(run("sleep", vec![Value::Int(0)]), Ok(Value::Void));
+        let executing = State::Executing.encode() as i64;
+        assert_eq!(run("state", vec![]), Ok(Value::Int(executing)));
+    }
+}
@@ -0,0 +1,131 @@
+//! Splitting source into tokens, for the tools that work on text rather than a tree: the
+//! language server's index and the formatter. The parser has its own scanner.
+use parser::{BINARY_OPS, KEYWORDS};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Ident,
+    Number,
+    Str,
+    Punct,
+    /// A `//` comment, up to but not including the end of its line.
+    Comment,
+    /// A `#` or `@` directive, up to a comment or the end of its line.
+    Directive,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Line breaks between the previous token and this one.
+    pub newlines: usize,
+}
+
+impl Token<'_> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    pub fn is(&self, text: &str) -> bool {
+        self.text == text
+    }
+
+    /// An identifier that isn't a keyword.
+    pub fn is_name(&self) -> bool {
+        self.kind == TokenKind::Ident && !KEYWORDS.contains(&self.text)
+    }
+}
+
+/// Splits `source` into tokens, skipping whitespace and comments. An unclosed string runs to
+/// the end.
+pub fn tokens(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = lex(source);
+    tokens.retain(|t| t.kind != TokenKind::Comment);
+    tokens
+}
+
+/// Splits `source` into tokens, keeping comments. Operators of more than one character, such
+/// as `->` and `<=`, are single tokens.
+pub fn lex(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    let mut newlines = 0;
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            '\n' => {
+                newlines += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '/' if source[start..].starts_with("//") => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                TokenKind::Comment
+            }
+            '#' | '@' => {
+                let line = source[start..].split('\n').next().unwrap_or_default();
+                let end = start
+                    + line[..line.find("//").unwrap_or(line.len())]
+                        .trim_end()
+                        .len();
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                TokenKind::Directive
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+                TokenKind::Str
+            }
+            c if c.is_ascii_digit() => {
+                while chars
+                    .next_if(|&(_, c)| c.is_alphanumeric() || c == '_')
+                    .is_some()
+                {}
+                let fraction = source[chars.peek().map_or(source.len(), |&(i, _)| i)..]
+                    .strip_prefix('.')
+                    .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+                if fraction {
+                    chars.next();
+                    while chars.next_if(|&(_, c)| c.is_ascii_digit()).is_some() {}
+                }
+                TokenKind::Number
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while chars
+                    .next_if(|&(_, c)| c.is_alphanumeric() || c == '_')
+                    .is_some()
+                {}
+                TokenKind::Ident
+            }
+            _ => {
+                let rest = &source[start..];
+                let long = BINARY_OPS
+                    .iter()
+                    .map(|(op, ..)| *op)
+                    .chain(["->"])
+                    .any(|op| op.len() > 1 && rest.starts_with(op));
+                if long {
+                    chars.next();
+                }
+                TokenKind::Punct
+            }
+        };
+        let end = chars.peek().map_or(source.len(), |&(i, _)| i);
+        tokens.push(Token {
+            kind,
+            text: &source[start..end],
+            start,
+            newlines: std::mem::take(&mut newlines),
+        });
+    }
+    tokens
+}
@@ -1,23 +1,90 @@
+pub mod cellar;
+pub mod deps;
+pub mod directive;
 pub mod err;
+pub mod format;
+pub mod interp;
+pub mod intrinsic;
+pub mod lex;
+pub mod lsp;
+pub mod pack;
+pub mod pkg;
+pub mod repl;
+pub mod sched;
+pub mod spill;
+pub mod state;
+pub mod vm;
 use configmgr::config;
+use err::{Diagnostic, InternalReport, Label, Source};
 use log::*;
+pub use state::{Shatter, State};
 use std::path::{Path, PathBuf};
-pub enum State {
-    Standard(i32),
-    Error(i32),
-    Critical(i32),
-    Paused(i32),
-    Defective(i32),
-    Fatal(i64),
-    Shattered(i64),
+use std::sync::OnceLock;
+use typed_arena::Arena;
+
+/// Diagnostic code for source the parser could not consume.
+pub const SYNTAX_ERROR: i32 = 1001;
+/// Diagnostic code for a bottle whose source could not be read.
+pub const READ_ERROR: i32 = 1002;
+/// Diagnostic code for source that ends before what it started is finished, such as an
+/// unclosed `{`. More input could still make it parse.
+pub const INCOMPLETE_INPUT: i32 = 1003;
+
+/// Which engine executes a bottle's code.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Backend {
+    /// Walk the parsed AST directly.
+    #[default]
+    TreeWalk,
+    /// Compile to bytecode first and run it on the stack VM.
+    Bytecode,
 }
+
 pub struct Bottle {
-    hash: u64,
-    pub state: State,
+    /// Content hash of the archive this bottle was unpacked from, in hex.
+    hash: Option<String>,
+    state: State,
     pub name: String,
     pub path: PathBuf,
     pub version: config::Version,
     pub description: String,
+    pub backend: Backend,
+    /// Where the bottle runs, checked by the [`cellar`] before it leaves the rack.
+    pub site: cellar::Site,
+    /// Source to run instead of reading `path`, for code that never lived in a file.
+    source: Option<String>,
+    /// Bytecode unpacked from an archive, run instead of compiling the sources again.
+    program: Option<vm::Program>,
+    /// Set while a [`sched::Scheduler`] runs this bottle.
+    host: Option<sched::Handle>,
+    /// The scheduled bottle a failure of this one started in.
+    cause: Option<sched::BottleId>,
+    spill: Option<spill::Spill>,
+    /// What the last load found that didn't stop it, or errors after the first.
+    notices: Vec<Notice>,
+    /// How to report what loading finds besides the error it fails with. Without one it is
+    /// logged.
+    pub emitter: Option<err::Emitter>,
+    /// What the root's directives set, once [`Bottle::rack`] has let the bottle through.
+    admitted: Option<directive::Settings>,
+    /// Code given inline, whose top level runs in place of a `main` it doesn't declare.
+    snippet: bool,
+    /// What the top level of a snippet without `main` last ran to.
+    returned: Option<interp::Value>,
+}
+
+/// A diagnostic found while loading a bottle, with the source it points into.
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub diagnostic: Diagnostic,
+    pub source: Option<Source>,
+}
+
+impl Notice {
+    pub fn emit(&self, emitter: err::Emitter) -> String {
+        let source = self.source.as_ref().map(|s| s.text.as_str());
+        emitter.emit(&self.diagnostic, source)
+    }
 }
 
 impl Bottle {
@@ -33,9 +100,9 @@ impl Bottle {
         }
         info!("Target found: {}", path.display());
         info!("Packing Bottle...");
-        let mut bottle = Bottle {
-            hash: 0,
-            state: State::Standard(0),
+        let bottle = Bottle {
+            hash: None,
+            state: State::Racked,
             name: match name {
                 Some(n) => n.to_string(),
                 None => path.file_name().unwrap().to_str().unwrap().to_string(),
@@ -49,26 +116,774 @@ impl Bottle {
                 Some(d) => d.to_string(),
                 None => String::new(),
             },
+            backend: Backend::default(),
+            site: cellar::Site::default(),
+            source: None,
+            program: None,
+            host: None,
+            cause: None,
+            spill: None,
+            notices: Vec::new(),
+            emitter: None,
+            admitted: None,
+            snippet: false,
+            returned: None,
+        };
+        Ok(bottle)
+    }
+    /// A bottle whose source is `text` rather than a file. Its dependencies are looked up
+    /// relative to the working directory.
+    pub fn from_source(name: &str, text: String) -> Bottle {
+        Bottle {
+            hash: None,
+            state: State::Racked,
+            name: name.to_owned(),
+            path: PathBuf::from(name),
+            version: config::Version::get_crate_ver(),
+            description: String::new(),
+            backend: Backend::default(),
+            site: cellar::Site::default(),
+            source: Some(text),
+            program: None,
+            host: None,
+            cause: None,
+            spill: None,
+            notices: Vec::new(),
+            emitter: None,
+            admitted: None,
+            snippet: false,
+            returned: None,
+        }
+    }
+
+    /// A bottle for code given inline, as `eval` takes it. Code that declares no `main` needn't:
+    /// its top-level statements are what runs, and the value of a final expression statement,
+    /// of whatever type, is kept as [`Bottle::returned`].
+    pub fn from_snippet(name: &str, text: String) -> Bottle {
+        Bottle {
+            snippet: true,
+            ..Bottle::from_source(name, text)
+        }
+    }
+
+    /// Has Winecellar inspect the bottle where it is to run: it and everything it depends on
+    /// must load, and the [`cellar`] must let it through. A bottle that fails shatters as
+    /// [`Shatter::Empty`] and is never started. A [`sched::Scheduler`] racks every bottle as it
+    /// is submitted; [`Bottle::start`] racks a bottle nobody has.
+    pub fn rack(&mut self) -> Result<(), Diagnostic> {
+        if self.state == State::Completed {
+            self.transition(State::Racked)?;
+        }
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let mut found = Vec::new();
+        let admitted = self.admit(&sources, &nodes, &mut found);
+        self.report(found);
+        match admitted {
+            Ok((_, settings)) => {
+                self.admitted = Some(settings);
+                Ok(())
+            }
+            Err(d) => {
+                let code = d.get_code();
+                self.spilled(&d, sources.into_vec(), Vec::new());
+                self.transition(State::Shattered(shatter_kind(code), code as u32))?;
+                Err(d)
+            }
+        }
+    }
+
+    /// Parses and runs the bottle's source, returning the `i64` result of `main`. The bottle is
+    /// [racked](Bottle::rack) first unless it already has been since it last ran. A snippet
+    /// without `main` returns what its top level ran to when that is an `i64`, or else 0.
+    pub fn start(&mut self) -> Result<i64, Diagnostic> {
+        info!("Starting bottle: {}", self.name);
+        info!("Version: {}", self.version);
+        info!("Description: {}", self.description);
+        info!("Path: {}", self.path.display());
+        if self.admitted.is_none() {
+            self.rack()?;
+        }
+        let settings = self.admitted.take().unwrap_or_default();
+        let backend = self.backend;
+        let prebuilt = self.program.clone();
+        let interrupt = self
+            .host
+            .as_ref()
+            .map(|h| h.interrupt())
+            .unwrap_or_default();
+        let runtime = intrinsic::Runtime::new(interrupt.clone(), self.host.clone());
+        let mut trace = Vec::new();
+        self.cause = None;
+        self.returned = None;
+        let snippet = self.snippet;
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        // What loading finds was reported when the bottle was racked.
+        let loaded = self.load(&sources, &nodes, &mut Vec::new());
+        if loaded.is_ok() {
+            self.configure(&settings);
+            self.transition(State::Executing)?;
+        }
+        let result = loaded.and_then(|graph| {
+            self.check_requires(&graph)?;
+            self.start_locks(&graph)?;
+            let file = graph.units[0].file();
+            let top_level = snippet && !declares_main(&graph.units[0].ast);
+            let result = match backend {
+                Backend::TreeWalk => {
+                    let mut interp = interp::Interpreter::new(&file);
+                    interp.set_runtime(runtime);
+                    let result = match top_level {
+                        true => interp.run_top_level(&graph).map(returned),
+                        false => interp.run_graph(&graph).map(|code| (code, None)),
+                    };
+                    trace = interp.error_trace().to_vec();
+                    result
+                }
+                Backend::Bytecode => {
+                    let program = match prebuilt {
+                        Some(program) => program,
+                        None => vm::Compiler::new(&file).compile_graph(&graph)?,
+                    };
+                    let mut vm = vm::Vm::new(&program);
+                    vm.set_runtime(runtime);
+                    let result = match top_level {
+                        true => vm.run_top_level().map(returned),
+                        false => vm.run().map(|code| (code, None)),
+                    };
+                    trace = vm.error_trace().to_vec();
+                    result
+                }
+            };
+            result
+        });
+        let result = result.map(|(code, returned)| {
+            self.returned = returned;
+            code
+        });
+        match &result {
+            Ok(code) => {
+                info!("Bottle {} finished with {}", self.name, code);
+                self.transition(State::Completed)?;
+            }
+            Err(d) => {
+                info!("Bottle {} spilled: {}", self.name, d.get_message());
+                let kind = shatter_kind(d.get_code());
+                let raised = interrupt.raised();
+                let to = match (self.cause, raised) {
+                    (Some(origin), _) => State::Cascaded(origin as u32),
+                    (None, Some(state)) if d.get_code() == interp::INTERRUPTED => state,
+                    _ => State::Shattered(kind, d.get_code() as u32),
+                };
+                if let State::Cascaded(origin) = to {
+                    self.cause = Some(origin as sched::BottleId);
+                }
+                self.spilled(d, sources.into_vec(), trace);
+                self.transition(to)?;
+            }
+        }
+        result
+    }
+
+    /// What the top level of a snippet without `main` ran to when it last completed: the value
+    /// of its final expression statement, or void.
+    pub fn returned(&self) -> Option<&interp::Value> {
+        self.returned.as_ref()
+    }
+
+    /// The SHA-256 content hash, in hex, of the archive the bottle was unpacked from.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// What [`Bottle::check`] found besides the error it failed with, if any.
+    pub fn notices(&self) -> &[Notice] {
+        &self.notices
+    }
+
+    /// Writes what loading found besides the error it failed with to stderr through the
+    /// bottle's [`emitter`](Bottle::emitter), or logs it when there is none.
+    fn report(&self, found: Vec<Notice>) {
+        for notice in found {
+            let d = &notice.diagnostic;
+            match (self.emitter, d) {
+                (Some(emitter), _) => eprintln!("{}", notice.emit(emitter)),
+                (None, Diagnostic::Warning(_) | Diagnostic::Info(_)) => {
+                    warn!("{}: {}", d.get_file(), d.get_message())
+                }
+                (None, _) => error!("{}: {}", d.get_file(), d.get_message()),
+            }
+        }
+    }
+
+    /// How the bottle last spilled, if it did.
+    pub fn spill(&self) -> Option<&spill::Spill> {
+        self.spill.as_ref()
+    }
+
+    /// Moves the bottle to `to` if [`State::can_transition`] allows it.
+    pub fn set_state(&mut self, to: State) -> Result<(), InternalReport> {
+        self.state.transition(to)?;
+        debug!("Bottle {} is now {}", self.name, to);
+        if let Some(host) = &self.host {
+            host.publish(to);
+        }
+        Ok(())
+    }
+
+    /// [`Bottle::set_state`], reporting an illegal transition as a diagnostic.
+    pub(crate) fn transition(&mut self, to: State) -> Result<(), Diagnostic> {
+        self.set_state(to).map_err(|r| self.report_to_diagnostic(r))
+    }
+
+    fn report_to_diagnostic(&self, report: InternalReport) -> Diagnostic {
+        Diagnostic::Fatal(err::Fatal {
+            code: report.get_code() as i32,
+            file: self.path.display().to_string(),
+            message: report.get_message(),
+            ..Default::default()
+        })
+    }
+
+    /// Compiles the bottle and its dependencies to bytecode without running anything.
+    pub fn compile(&self) -> Result<vm::Program, Diagnostic> {
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let graph = self.resolve(&sources, &nodes)?;
+        vm::Compiler::new(&graph.units[0].file()).compile_graph(&graph)
+    }
+
+    /// Loads, inspects and compiles the bottle and its dependencies without running anything. A
+    /// bottle that would not start shatters as it would have in [`Bottle::start`].
+    /// Warnings, and errors after the first, are kept as [`Bottle::notices`].
+    pub fn check(&mut self) -> Result<(), Diagnostic> {
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let mut found = Vec::new();
+        let result = self
+            .admit(&sources, &nodes, &mut found)
+            .and_then(|(graph, _)| {
+                vm::Compiler::new(&graph.units[0].file()).compile_graph(&graph)?;
+                Ok(())
+            });
+        let sources = sources.into_vec();
+        self.notices = found;
+        if let Err(d) = &result {
+            let code = d.get_code();
+            self.transition(State::Shattered(shatter_kind(code), code as u32))?;
+            self.spilled(d, sources, Vec::new());
+        }
+        result
+    }
+
+    /// Records how the bottle spilled, keeping the source `d` points into.
+    fn spilled(&mut self, d: &Diagnostic, sources: Vec<Source>, trace: Vec<String>) {
+        let file = d.get_file();
+        self.spill = Some(spill::Spill {
+            bottle: self.name.clone(),
+            diagnostic: d.clone(),
+            source: sources.into_iter().find(|s| s.name == file),
+            trace,
+            cause: self.cause,
+        });
+    }
+
+    /// Parses the bottle and everything it depends on. Warnings are [reported](Bottle::report);
+    /// the first error found is returned.
+    pub(crate) fn resolve<'a>(
+        &self,
+        sources: &'a Arena<Source>,
+        nodes: &'a Arena<ast::AstNode<'a>>,
+    ) -> Result<deps::DependencyGraph<'a>, Diagnostic> {
+        let mut found = Vec::new();
+        let result = self.load(sources, nodes, &mut found);
+        self.report(found);
+        result
+    }
+
+    /// [`Bottle::load`], then the [`cellar`] checks against the bottle's site and the
+    /// capabilities its `bottle.toml` asks for. Also returns what the root's directives set.
+    fn admit<'a>(
+        &self,
+        sources: &'a Arena<Source>,
+        nodes: &'a Arena<ast::AstNode<'a>>,
+        found: &mut Vec<Notice>,
+    ) -> Result<(deps::DependencyGraph<'a>, directive::Settings), Diagnostic> {
+        let graph = self.load(sources, nodes, found)?;
+        let manifest = match self.source {
+            Some(_) => None,
+            None => pack::Manifest::find(&self.path)?,
+        };
+        let requested = manifest.map(|m| m.capabilities).unwrap_or_default();
+        let file = self.path.with_file_name(pack::MANIFEST_FILE);
+        let (settings, mut diagnostics) =
+            cellar::inspect(&graph, &self.site, &requested, &file.display().to_string());
+        // A snippet without `main` runs its top level instead.
+        if self.snippet {
+            diagnostics.retain(|d| d.get_code() != cellar::MISSING_ENTRY);
+        }
+        match first_error(diagnostics, &graph, found) {
+            Some(d) => Err(d),
+            None => Ok((graph, settings)),
+        }
+    }
+
+    /// Applies what the bottle's directives set: its log level, and under a scheduler how many
+    /// bottles run at once. The log level never goes past what logging started at, as the
+    /// command line or `RUST_LOG` chose it, and is left alone when diagnostics are JSON.
+    fn configure(&self, settings: &directive::Settings) {
+        static CEILING: OnceLock<LevelFilter> = OnceLock::new();
+        if let Some(level) = settings
+            .log_level
+            .filter(|_| self.emitter != Some(err::Emitter::Json))
+        {
+            log::set_max_level(level.min(*CEILING.get_or_init(log::max_level)));
+        }
+        if let (Some(n), Some(host)) = (settings.max_threads, &self.host) {
+            host.set_max_threads(n);
+        }
+    }
+
+    /// [`Bottle::resolve`], putting what it would log in `found` instead.
+    fn load<'a>(
+        &self,
+        sources: &'a Arena<Source>,
+        nodes: &'a Arena<ast::AstNode<'a>>,
+        found: &mut Vec<Notice>,
+    ) -> Result<deps::DependencyGraph<'a>, Diagnostic> {
+        if self.source.is_none() {
+            pkg::check_lock(&self.path)?;
+        }
+        let (graph, diagnostics) =
+            deps::DependencyGraph::resolve(&self.path, self.source.clone(), sources, nodes)?;
+        match first_error(diagnostics, &graph, found) {
+            Some(d) => Err(d),
+            None => Ok(graph),
+        }
+    }
+
+    /// Under a scheduler, records what this bottle requires and fails as cascaded if one of
+    /// those has already spilled.
+    fn check_requires(&mut self, graph: &deps::DependencyGraph) -> Result<(), Diagnostic> {
+        let Some(host) = &self.host else {
+            return Ok(());
         };
+        let paths = graph.units[0]
+            .deps
+            .iter()
+            .filter(|d| d.kind == deps::DepKind::Require)
+            .filter_map(|d| Some(graph.units[d.target?].path.clone()))
+            .collect();
+        let Some(origin) = host.requires(paths) else {
+            return Ok(());
+        };
+        self.cause = Some(origin);
+        Err(Diagnostic::Error(err::Error {
+            code: spill::CASCADED,
+            file: graph.units[0].file(),
+            message: format!("required bottle #{} has spilled", origin),
+            ..Default::default()
+        }))
+    }
+
+    /// A bottle for the file at `path`, run the way this one is: on the same backend, at the
+    /// same site and reporting through the same emitter.
+    pub(crate) fn sibling(&self, path: &Path, name: &str) -> Result<Bottle, String> {
+        let mut bottle = Bottle::new(path, Some(name), None, None)?;
+        bottle.backend = self.backend;
+        bottle.site = self.site.clone();
+        bottle.emitter = self.emitter;
         Ok(bottle)
     }
-    pub fn start(&mut self) {
-        println!("Starting bottle: {}", self.name);
-        println!("Version: {}", self.version);
-        println!("Description: {}", self.description);
-        println!("Path: {}", self.path.display());
-        println!("lexing...");
+
+    /// Makes sure every bottle this one locks is running before this one continues. Under a
+    /// scheduler each target runs as a bottle of its own and this one parks until it starts;
+    /// otherwise each target is run to completion first.
+    fn start_locks(&mut self, graph: &deps::DependencyGraph) -> Result<(), Diagnostic> {
+        for dep in &graph.units[0].deps {
+            let (deps::DepKind::Lock, Some(target)) = (dep.kind, dep.target) else {
+                continue;
+            };
+            let path = &graph.units[target].path;
+            let not_found = |message| {
+                Diagnostic::Error(err::Error {
+                    code: deps::DEPENDENCY_NOT_FOUND,
+                    file: graph.units[0].file(),
+                    message,
+                    ..Default::default()
+                })
+            };
+            info!("Bottle {} waits on lock {}", self.name, dep.name);
+            let Some(host) = self.host.clone() else {
+                let mut locked = self.sibling(path, &dep.name).map_err(not_found)?;
+                locked.start()?;
+                continue;
+            };
+            let id = host
+                .find_or_submit(path, &dep.name, self)
+                .map_err(not_found)?;
+            let state = host.wait_running(self, id)?;
+            if state.is_failed() {
+                self.cause = Some(id);
+                return Err(Diagnostic::Error(err::Error {
+                    code: deps::LOCK_FAILED,
+                    file: graph.units[0].file(),
+                    message: format!("lock `{}` failed before it started: {}", dep.name, state),
+                    ..Default::default()
+                }));
+            }
+        }
+        Ok(())
     }
 }
 
+/// The first error in `diagnostics`, putting the rest in `found` with the source in `graph`
+/// each points into.
+fn first_error(
+    diagnostics: Vec<Diagnostic>,
+    graph: &deps::DependencyGraph,
+    found: &mut Vec<Notice>,
+) -> Option<Diagnostic> {
+    let mut first = None;
+    for d in diagnostics {
+        match d {
+            Diagnostic::Error(_) | Diagnostic::Fatal(_) if first.is_none() => first = Some(d),
+            diagnostic => {
+                let file = diagnostic.get_file();
+                found.push(Notice {
+                    source: graph
+                        .units
+                        .iter()
+                        .find(|u| u.file() == file)
+                        .map(|u| Source {
+                            name: file,
+                            text: u.source.to_owned(),
+                        }),
+                    diagnostic,
+                })
+            }
+        }
+    }
+    first
+}
+
+/// How a bottle that failed with diagnostic `code` shatters: [`Shatter::Empty`] when it never
+/// got as far as running, [`Shatter::Unhandled`] otherwise.
+pub fn shatter_kind(code: i32) -> Shatter {
+    match code {
+        SYNTAX_ERROR
+        | READ_ERROR
+        | INCOMPLETE_INPUT
+        | deps::DEPENDENCY_NOT_FOUND
+        | deps::DEPENDENCY_CYCLE
+        | deps::DUPLICATE_DEPENDENCY
+        | pkg::LOCK_MISMATCH
+        | pack::MANIFEST_INVALID
+        | cellar::MISSING_ENTRY
+        | cellar::ENTRY_SIGNATURE
+        | cellar::DIRECTIVE_NOT_ACCEPTED
+        | cellar::CAPABILITY_NOT_GRANTED
+        | cellar::DIRECTIVE_ARGUMENTS
+        | checker::UNDEFINED_NAME
+        | checker::DUPLICATE_FUNCTION
+        | checker::TYPE_MISMATCH
+        | checker::WRONG_ARGUMENT_COUNT
+        | checker::MISSING_RETURN
+        | checker::UNKNOWN_TYPE
+        | checker::UNKNOWN_INTRINSIC => Shatter::Empty,
+        _ => Shatter::Unhandled,
+    }
+}
+
+/// Whether the top level of `ast` declares a `main`.
+fn declares_main(ast: &ast::AST) -> bool {
+    match &ast.head.kind {
+        ast::NodeKind::Root { children } => children
+            .iter()
+            .any(|c| matches!(&c.kind, ast::NodeKind::Function { name, .. } if name == "main")),
+        _ => false,
+    }
+}
+
+/// The exit code and value of a snippet whose top level ran to `value`.
+fn returned(value: interp::Value) -> (i64, Option<interp::Value>) {
+    match value {
+        interp::Value::Int(code) => (code, Some(value)),
+        value => (0, Some(value)),
+    }
+}
+
+/// Parses `source`, reporting leftover input as a [`SYNTAX_ERROR`] at the point it stopped, or
+/// as [`INCOMPLETE_INPUT`] when the source ends partway through something.
+pub fn parse<'a>(
+    file: &str,
+    source: &'a str,
+    arena: &'a Arena<ast::AstNode<'a>>,
+) -> Result<ast::AST<'a>, Diagnostic> {
+    let mut parser = parser::Parser::new(nom_locate::LocatedSpan::new(source), arena);
+    let rest = match parser.parse() {
+        Ok((rest, ast)) if rest.fragment().is_empty() => {
+            debug!("Parsed: {:#?}", ast);
+            return Ok(ast);
+        }
+        Ok((rest, _)) => rest,
+        Err(nom::Err::Failure(e)) if e.code == nom::error::ErrorKind::Digit => {
+            let at = err::Position::of(&e.input);
+            let len = e.input.fragment().len();
+            return Err(Diagnostic::Error(err::Error {
+                code: SYNTAX_ERROR,
+                file: file.to_owned(),
+                message: format!("`{}` doesn't fit in an i64", e.input.fragment()),
+                loc: (at.line, at.column),
+                len,
+                labels: vec![Label::primary(
+                    err::Span::at(at.line, at.column, len),
+                    "this literal is too large",
+                )]
+                .into(),
+                ..Default::default()
+            }));
+        }
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => e.input,
+        Err(nom::Err::Incomplete(_)) => unreachable!("the parser only uses complete input"),
+    };
+    if let Some(unfinished) = parser::unfinished(source) {
+        let before = &source[..unfinished.offset()];
+        let line = before.matches('\n').count();
+        let column = before[before.rfind('\n').map_or(0, |i| i + 1)..]
+            .chars()
+            .count();
+        let len = unfinished.width();
+        let d = Diagnostic::Error(err::Error {
+            code: INCOMPLETE_INPUT,
+            file: file.to_owned(),
+            message: format!("unexpected end of input: {}", unfinished),
+            loc: (line, column),
+            len,
+            labels: vec![Label::primary(
+                err::Span::at(line, column, len),
+                "the input ends before this is finished",
+            )]
+            .into(),
+            ..Default::default()
+        });
+        let closer = match unfinished {
+            parser::Unfinished::Delimiter(_, '(') => ")",
+            parser::Unfinished::Delimiter(_, '[') => "]",
+            parser::Unfinished::Delimiter(_, _) => "}",
+            parser::Unfinished::Str(_) => "\"",
+            parser::Unfinished::Operator(..) => return Err(d),
+        };
+        let end = source.trim_end();
+        let end_line = end.matches('\n').count();
+        let end_column = end[end.rfind('\n').map_or(0, |i| i + 1)..].chars().count();
+        let at = err::Span::at(end_line, end_column, 0);
+        return Err(d.with_fix("close it", at, closer));
+    }
+    let line = rest.fragment().lines().next().unwrap_or_default();
+    let at = err::Position::of(&rest);
+    let len = line.chars().count();
+    Err(Diagnostic::Error(err::Error {
+        code: SYNTAX_ERROR,
+        file: file.to_owned(),
+        message: format!("unexpected input `{}`", line.trim()),
+        loc: (at.line, at.column),
+        len,
+        labels: vec![Label::primary(
+            err::Span::at(at.line, at.column, len),
+            "the parser stopped here",
+        )]
+        .into(),
+        ..Default::default()
+    }))
+}
+
 //for errors
 impl Bottle {
     pub fn error(&mut self, e: crate::err::Error) {
-        self.state = State::Error(e.code);
-        error!("Error in bottle {}: {}", self.name, e.message)
+        info!("Bottle {} errored: {}", self.name, e.message);
+        if let Err(r) = self.set_state(State::Errored(e.code as u8)) {
+            warn!("{}", r.display());
+        }
+        self.record(Diagnostic::Error(e));
     }
     pub fn shatter(&mut self, report: crate::err::InternalReport) {
-        self.state = State::Shattered(report.get_code());
-        error!("Bottle shattered {}: {}", self.name, report.get_message());
+        info!("Bottle {} shattered: {}", self.name, report.get_message());
+        let detail = report.get_code() as u32;
+        if let Err(r) = self.set_state(State::Shattered(Shatter::Unhandled, detail)) {
+            warn!("{}", r.display());
+        }
+        let d = self.report_to_diagnostic(report);
+        self.record(d);
+    }
+    fn record(&mut self, diagnostic: Diagnostic) {
+        self.spill = Some(spill::Spill {
+            bottle: self.name.clone(),
+            diagnostic,
+            source: None,
+            trace: Vec::new(),
+            cause: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets_without_main_keep_what_their_top_level_ran_to() {
+        use interp::Value;
+        let cases = [
+            ("true", 0, Value::Bool(true)),
+            ("\"wine\"", 0, Value::Str("wine".to_owned())),
+            ("let half = 0.5;\nhalf * 3.0", 0, Value::Float(1.5)),
+            (
+                "fn sq(x: i64) -> i64 { return x * x; }\nsq(6) + 6",
+                42,
+                Value::Int(42),
+            ),
+            ("pause!()", 0, Value::Void),
+            ("let a = 1;", 0, Value::Void),
+        ];
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            for (source, code, value) in &cases {
+                let mut bottle = Bottle::from_snippet("<eval>", (*source).to_owned());
+                bottle.backend = backend;
+                assert_eq!(bottle.start().ok(), Some(*code), "{:?} {}", backend, source);
+                assert_eq!(bottle.returned(), Some(value), "{:?} {}", backend, source);
+            }
+        }
+    }
+
+    #[test]
+    fn snippets_with_main_run_it() {
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let source = "let a = 3;\nfn main() -> i64 { return a + 4; }\na";
+            let mut bottle = Bottle::from_snippet("<eval>", source.to_owned());
+            bottle.backend = backend;
+            assert_eq!(bottle.start().ok(), Some(7), "{:?}", backend);
+            assert_eq!(bottle.returned(), None, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn assigning_to_f64_bindings_widens_in_both_backends() {
+        use interp::Value;
+        let cases = [
+            "let x: f64 = 1.0;\nx = 3;\nx / 2",
+            "fn half(x: f64) -> f64 { x = 3; return x / 2; }\nhalf(1.0)",
+            "fn half() -> f64 { let x: f64 = 1.0; x = 3; return x / 2; }\nhalf()",
+        ];
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            for source in cases {
+                let mut bottle = Bottle::from_snippet("<eval>", source.to_owned());
+                bottle.backend = backend;
+                assert!(bottle.start().is_ok(), "{:?} {}", backend, source);
+                let returned = bottle.returned();
+                assert_eq!(
+                    returned,
+                    Some(&Value::Float(1.5)),
+                    "{:?} {}",
+                    backend,
+                    source
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn snippet_errors_point_at_the_snippet() {
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let source = "let zero = 0;\n1 / zero";
+            let mut bottle = Bottle::from_snippet("<eval>", source.to_owned());
+            bottle.backend = backend;
+            let d = bottle.start().expect_err("dividing by zero fails");
+            assert_eq!(d.get_idx(), (1, 0, 8), "{:?}", backend);
+        }
+        let mut bottle = Bottle::from_snippet("<eval>", "let a: i64 = true;".to_owned());
+        let d = bottle.start().expect_err("the checker rejects it");
+        assert_eq!(d.get_idx().0, 0);
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_same_source_in_both_backends() {
+        let source = "fn main() -> i64 {\n    let zero = 0;\n    return 1 / zero;\n}\n";
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let mut bottle = Bottle::from_source("divide.wg", source.to_owned());
+            bottle.backend = backend;
+            let d = bottle.start().expect_err("dividing by zero fails");
+            assert_eq!(d.get_idx(), (2, 11, 8), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn locals_keep_to_the_declaration_they_were_resolved_to() {
+        let source = "\
+let caught = 0;
+try {
+    spill \"boom\";
+} catch e {
+    let kind = e.kind;
+    caught = 1;
+}
+
+fn depth(n: i64) -> i64 {
+    if n == 0 {
+        return 0;
+    }
+    let below = depth(n - 1);
+    return below + 1;
+}
+
+fn main() -> i64 {
+    let x = 1;
+    if x > 0 {
+        let x = x * 10;
+        x = x + 5;
+    }
+    let x = x + 1;
+    return x * 100 + depth(3) * 10 + caught;
+}
+";
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let mut bottle = Bottle::from_source("slots.wg", source.to_owned());
+            bottle.backend = backend;
+            assert_eq!(bottle.start().ok(), Some(231), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn spills_keep_their_kind_through_inner_trys() {
+        let source = "\
+fn main() -> i64 {
+    let got = 0;
+    try {
+        try {
+            spill Boom(\"inner\");
+        } finally {
+            got = got + 1;
+        }
+    } catch e: Boom {
+        got = got + 10;
+    }
+    try {
+        try {
+            spill Boom(\"again\");
+        } catch e: Other {
+            got = got + 1000;
+        }
+    } catch e: Boom {
+        got = got + 100;
+    }
+    return got;
+}
+";
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let mut bottle = Bottle::from_source("nested.wg", source.to_owned());
+            bottle.backend = backend;
+            assert_eq!(bottle.start().ok(), Some(111), "{:?}", backend);
+        }
     }
 }
@@ -0,0 +1,286 @@
+//! Where a source declares things, found from its tokens rather than its tree, so a file in
+//! the middle of being edited still has an index even when it doesn't parse.
+use crate::lex::{tokens, Token, TokenKind};
+use parser::DEPENDENCY_KINDS;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Parameter,
+    Variable,
+    Dependency,
+}
+
+/// Something a source declares.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the name is written.
+    pub name_range: Range<usize>,
+    /// The whole declaration. For a function that runs through its closing brace.
+    pub range: Range<usize>,
+    /// Where the name refers to this symbol.
+    pub scope: Range<usize>,
+    /// The declared type, a function's return type, or the path a dependency is taken from.
+    pub ty: Option<String>,
+    /// A function's parameters as `(name, type)`.
+    pub params: Vec<(String, String)>,
+    /// The dependency kind, for dependencies.
+    pub dependency: Option<&'static str>,
+    /// The function it is declared in, as an index into [`Index::symbols`].
+    pub parent: Option<usize>,
+}
+
+impl Symbol {
+    /// How the symbol was declared, as it would be written.
+    pub fn signature(&self) -> String {
+        match self.kind {
+            SymbolKind::Function => {
+                let params: Vec<String> = self
+                    .params
+                    .iter()
+                    .map(|(name, ty)| format!("{}: {}", name, ty))
+                    .collect();
+                let ret = self.ty.as_deref().unwrap_or("void");
+                format!("fn {}({}) -> {}", self.name, params.join(", "), ret)
+            }
+            SymbolKind::Parameter => {
+                format!("{}: {}", self.name, self.ty.as_deref().unwrap_or("auto"))
+            }
+            SymbolKind::Variable => match &self.ty {
+                Some(ty) => format!("let {}: {}", self.name, ty),
+                None => format!("let {}", self.name),
+            },
+            SymbolKind::Dependency => {
+                let kind = self.dependency.unwrap_or("require");
+                match &self.ty {
+                    Some(path) => format!("{} {} from {:?}", kind, self.name, path),
+                    None => format!("{} {}", kind, self.name),
+                }
+            }
+        }
+    }
+
+    /// Whether it belongs to the module rather than to a function or block.
+    pub fn is_top_level(&self) -> bool {
+        self.parent.is_none() && self.scope.start == 0
+    }
+}
+
+/// Every declaration in one source.
+#[derive(Debug, Default)]
+pub struct Index {
+    pub symbols: Vec<Symbol>,
+}
+
+impl Index {
+    pub fn new(source: &str) -> Index {
+        let tokens = tokens(source);
+        let end = source.len();
+        // Where the block each `{` opens ends: after its `}`, or at the end when unclosed.
+        let mut closes = vec![end; tokens.len()];
+        let mut open = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token.text {
+                "{" => open.push(i),
+                "}" => {
+                    if let Some(j) = open.pop() {
+                        closes[j] = token.end();
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut symbols: Vec<Symbol> = Vec::new();
+        let top = 0..end;
+        let mut blocks = vec![top];
+        // Functions being declared, innermost last, with where each one's body ends.
+        let mut functions: Vec<(usize, usize)> = Vec::new();
+        let name_at = |i: usize| tokens.get(i).filter(|t| t.is_name());
+        let is = |i: usize, text: &str| tokens.get(i).is_some_and(|t| t.is(text));
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = tokens[i];
+            while functions.last().is_some_and(|&(_, end)| end <= token.start) {
+                functions.pop();
+            }
+            let parent = functions.last().map(|&(f, _)| f);
+            let block = blocks.last().unwrap().clone();
+            let symbol = |name: &Token, kind, scope, ty: Option<String>| Symbol {
+                name: name.text.to_owned(),
+                kind,
+                name_range: name.start..name.end(),
+                range: name.start..name.end(),
+                scope,
+                ty,
+                params: Vec::new(),
+                dependency: None,
+                parent,
+            };
+            match token.text {
+                "{" => blocks.push(token.start..closes[i]),
+                "}" if blocks.len() > 1 => {
+                    blocks.pop();
+                }
+                "fn" if name_at(i + 1).is_some() => {
+                    let name = tokens[i + 1];
+                    let mut params = Vec::new();
+                    let mut j = i + 2;
+                    if is(j, "(") {
+                        while j < tokens.len() && !is(j, ")") && !is(j, "{") {
+                            match (name_at(j), is(j + 1, ":"), name_at(j + 2)) {
+                                (Some(param), true, Some(ty)) => {
+                                    params.push((*param, ty.text.to_owned()));
+                                    j += 3;
+                                }
+                                _ => j += 1,
+                            }
+                        }
+                        j += 1;
+                    }
+                    let mut ret = None;
+                    if let (true, Some(ty)) = (is(j, "->"), tokens.get(j + 1)) {
+                        ret = Some(ty.text.to_owned());
+                        j += 2;
+                    }
+                    let body = match is(j, "{") {
+                        true => tokens[j].start..closes[j],
+                        false => name.end()..name.end(),
+                    };
+                    let mut function = symbol(&name, SymbolKind::Function, block, ret);
+                    function.range = token.start..body.end.max(name.end());
+                    function.params = params
+                        .iter()
+                        .map(|(p, ty)| (p.text.to_owned(), ty.clone()))
+                        .collect();
+                    let index = symbols.len();
+                    symbols.push(function);
+                    for (param, ty) in params {
+                        let mut param = symbol(
+                            &param,
+                            SymbolKind::Parameter,
+                            body.clone(),
+                            Some(ty.clone()),
+                        );
+                        param.parent = Some(index);
+                        symbols.push(param);
+                    }
+                    functions.push((index, body.end));
+                    i = j;
+                    continue;
+                }
+                "let" if name_at(i + 1).is_some() => {
+                    let ty = match (is(i + 2, ":"), name_at(i + 3)) {
+                        (true, Some(ty)) => Some(ty.text.to_owned()),
+                        _ => None,
+                    };
+                    // Globals are set before any function runs, wherever they are declared.
+                    let scope = match blocks.len() {
+                        1 => block,
+                        _ => token.start..block.end,
+                    };
+                    symbols.push(symbol(&tokens[i + 1], SymbolKind::Variable, scope, ty));
+                }
+                "catch" if name_at(i + 1).is_some() => {
+                    let (ty, body) = match (is(i + 2, ":"), name_at(i + 3)) {
+                        (true, Some(ty)) => (ty.text.to_owned(), i + 4),
+                        _ => ("Error".to_owned(), i + 2),
+                    };
+                    if is(body, "{") {
+                        let scope = tokens[body].start..closes[body];
+                        symbols.push(symbol(
+                            &tokens[i + 1],
+                            SymbolKind::Variable,
+                            scope,
+                            Some(ty),
+                        ));
+                    }
+                }
+                kind if DEPENDENCY_KINDS.contains(&kind) && name_at(i + 1).is_some() => {
+                    let path = match (is(i + 2, "from"), tokens.get(i + 3)) {
+                        (true, Some(path)) if path.kind == TokenKind::Str => {
+                            Some(path.text.trim_matches('"').to_owned())
+                        }
+                        _ => None,
+                    };
+                    let mut dep = symbol(&tokens[i + 1], SymbolKind::Dependency, 0..end, path);
+                    dep.dependency = DEPENDENCY_KINDS.iter().copied().find(|k| *k == kind);
+                    symbols.push(dep);
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        Index { symbols }
+    }
+
+    /// What `name` refers to at byte `at`: the innermost variable or parameter in scope, else
+    /// a function, else a dependency.
+    pub fn definition(&self, name: &str, at: usize) -> Option<&Symbol> {
+        self.visible(at).into_iter().find(|s| s.name == name)
+    }
+
+    /// Every symbol that can be referred to at `at`, innermost first, each name once.
+    pub fn visible(&self, at: usize) -> Vec<&Symbol> {
+        let rank = |s: &Symbol| match s.kind {
+            SymbolKind::Variable | SymbolKind::Parameter => 0,
+            SymbolKind::Function => 1,
+            SymbolKind::Dependency => 2,
+        };
+        let mut visible: Vec<&Symbol> = self
+            .symbols
+            .iter()
+            .filter(|s| match s.kind {
+                SymbolKind::Variable => {
+                    covers(&s.scope, at) && (s.is_top_level() || s.name_range.start <= at)
+                }
+                SymbolKind::Parameter => covers(&s.scope, at) || covers(&s.name_range, at),
+                SymbolKind::Function | SymbolKind::Dependency => true,
+            })
+            .collect();
+        visible.sort_by_key(|s| (rank(s), std::cmp::Reverse(s.name_range.start)));
+        let mut seen = Vec::new();
+        visible.retain(|s| {
+            let new = !seen.contains(&&s.name);
+            seen.push(&s.name);
+            new
+        });
+        visible
+    }
+
+    /// What other bottles can reach through a dependency on this one: its functions and globals.
+    pub fn exports(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter().filter(|s| {
+            s.is_top_level() && matches!(s.kind, SymbolKind::Function | SymbolKind::Variable)
+        })
+    }
+
+    /// The symbols declared inside function `parent`, or at the top level for `None`.
+    pub fn children(&self, parent: Option<usize>) -> impl Iterator<Item = (usize, &Symbol)> {
+        self.symbols
+            .iter()
+            .enumerate()
+            .filter(move |(_, s)| s.parent == parent)
+    }
+}
+
+fn covers(range: &Range<usize>, at: usize) -> bool {
+    range.start <= at && at <= range.end
+}
+
+/// The identifier around byte `at`, along with the names before it in a dotted path such as
+/// `math.add`.
+pub fn path_at<'a>(tokens: &[Token<'a>], at: usize) -> Option<(Vec<String>, Token<'a>)> {
+    let i = tokens
+        .iter()
+        .position(|t| t.kind == TokenKind::Ident && t.start <= at && at <= t.end())?;
+    let mut qualifiers = Vec::new();
+    let mut j = i;
+    while j >= 2 && tokens[j - 1].is(".") && tokens[j - 2].kind == TokenKind::Ident {
+        qualifiers.insert(0, tokens[j - 2].text.to_owned());
+        j -= 2;
+    }
+    Some((qualifiers, tokens[i]))
+}
@@ -0,0 +1,664 @@
+//! A language server for `.wg` files, speaking JSON-RPC over a pair of streams.
+//!
+//! Diagnostics come from parsing, resolving dependencies and compiling, as `wineglass check`
+//! does, and are published whenever a document is opened, changed or saved. Definitions,
+//! hover, document symbols and completion come from an [`index::Index`] of what the document
+//! declares, which still works while it doesn't parse. While it does, names are looked up as
+//! the checker resolved them.
+pub mod index;
+
+use crate::cellar;
+use crate::deps::{self, DependencyGraph};
+use crate::err::{Diagnostic, Position as SourcePosition, Span};
+use crate::interp;
+use crate::lex;
+use index::{Index, Symbol, SymbolKind};
+use log::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use typed_arena::Arena;
+
+/// JSON-RPC error codes.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const SERVER_NOT_INITIALIZED: i64 = -32002;
+
+/// The longest message body read, in bytes. Longer ones are skipped rather than buffered.
+pub const MAX_MESSAGE: usize = 64 << 20;
+
+/// Type names offered when completing.
+pub const TYPES: &[&str] = &["i64", "f64", "bool", "str", "error", "void", "auto"];
+
+// Kinds from the protocol's `CompletionItemKind`, `SymbolKind` and `DiagnosticSeverity`.
+const COMPLETE_FUNCTION: u32 = 3;
+const COMPLETE_VARIABLE: u32 = 6;
+const COMPLETE_CLASS: u32 = 7;
+const COMPLETE_MODULE: u32 = 9;
+const COMPLETE_KEYWORD: u32 = 14;
+const COMPLETE_TYPE: u32 = 25;
+const SYMBOL_MODULE: u32 = 2;
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_VARIABLE: u32 = 13;
+const SEVERITY_ERROR: u32 = 1;
+const SEVERITY_WARNING: u32 = 2;
+const SEVERITY_INFO: u32 = 3;
+
+type Response = Result<Value, (i64, String)>;
+
+#[derive(Default)]
+pub struct Server {
+    /// Open documents by URI, as the client last sent them.
+    documents: HashMap<String, String>,
+    initialized: bool,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves messages from `input` until `exit` or the end of input. Returns the exit code the
+    /// protocol asks for: 0 if `shutdown` came first, 1 otherwise.
+    pub fn run(&mut self, mut input: impl BufRead, output: &mut impl Write) -> io::Result<i32> {
+        loop {
+            let body = match read_message(&mut input) {
+                Ok(Some(body)) => body,
+                Ok(None) => break,
+                Err(why) if why.kind() == io::ErrorKind::FileTooLarge => {
+                    reject(output, INVALID_REQUEST, why.to_string())?;
+                    continue;
+                }
+                Err(why) => return Err(why),
+            };
+            let message = match serde_json::from_str::<Value>(&body) {
+                Ok(message) => message,
+                Err(why) => {
+                    reject(output, PARSE_ERROR, why.to_string())?;
+                    continue;
+                }
+            };
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                return Ok(if self.shutdown { 0 } else { 1 });
+            }
+            let params = &message["params"];
+            let Some(id) = message.get("id") else {
+                for out in self.notify(method, params) {
+                    write_message(output, &out)?;
+                }
+                continue;
+            };
+            let response = match self.request(method, params) {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Err((code, message)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": code, "message": message},
+                }),
+            };
+            write_message(output, &response)?;
+        }
+        Ok(if self.shutdown { 0 } else { 1 })
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Response {
+        debug!("lsp request {}", method);
+        match method {
+            "initialize" => {
+                self.initialized = true;
+                return Ok(capabilities());
+            }
+            _ if !self.initialized => {
+                return Err((SERVER_NOT_INITIALIZED, "initialize first".to_owned()))
+            }
+            _ if self.shutdown => {
+                return Err((INVALID_REQUEST, "the server is shutting down".to_owned()))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                return Ok(Value::Null);
+            }
+            _ => {}
+        }
+        const DOCUMENT_METHODS: &[&str] = &[
+            "textDocument/definition",
+            "textDocument/hover",
+            "textDocument/documentSymbol",
+            "textDocument/completion",
+        ];
+        if !DOCUMENT_METHODS.contains(&method) {
+            return Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method)));
+        }
+        let (uri, at) = self.cursor(params)?;
+        let text = &self.documents[&uri];
+        match method {
+            "textDocument/definition" => Ok(self
+                .lookup(&uri, at)
+                .map_or(Value::Null, |(uri, text, symbol)| {
+                    json!({"uri": uri, "range": range(&text, &symbol.name_range)})
+                })),
+            "textDocument/hover" => {
+                let tokens = lex::tokens(text);
+                let hover = index::path_at(&tokens, at).zip(self.lookup(&uri, at));
+                Ok(hover.map_or(Value::Null, |((_, token), (_, _, symbol))| {
+                    json!({
+                        "contents": {
+                            "kind": "markdown",
+                            "value": format!("```wineglass\n{}\n```", symbol.signature()),
+                        },
+                        "range": range(text, &(token.start..token.end())),
+                    })
+                }))
+            }
+            "textDocument/documentSymbol" => {
+                let index = Index::new(text);
+                Ok(document_symbols(text, &index, None).into())
+            }
+            _ => Ok(self.complete(&uri, at).into()),
+        }
+    }
+
+    /// Handles a notification, returning the notifications to send back. Those that come
+    /// before `initialize` are dropped.
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        debug!("lsp notification {}", method);
+        if !self.initialized {
+            return Vec::new();
+        }
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // Changes are always whole documents, as `initialize` asked for.
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didSave" => self.documents.get(uri).map(String::as_str),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                let params = json!({"uri": uri, "diagnostics": []});
+                return vec![notification("textDocument/publishDiagnostics", params)];
+            }
+            _ => return Vec::new(),
+        };
+        let Some(text) = text.map(str::to_owned) else {
+            return Vec::new();
+        };
+        self.documents.insert(uri.to_owned(), text);
+        vec![self.publish(uri)]
+    }
+
+    /// The document and byte offset a `TextDocumentPositionParams` points at. Requests without
+    /// a position point at the start.
+    fn cursor(&self, params: &Value) -> Result<(String, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(text) = self.documents.get(uri) else {
+            return Err((INVALID_PARAMS, format!("`{}` is not open", uri)));
+        };
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        Ok((uri.to_owned(), offset(text, line, character)))
+    }
+
+    /// The text of `uri`: the open document if it is one, otherwise the file on disk.
+    fn text(&self, uri: &str) -> Option<String> {
+        match self.documents.get(uri) {
+            Some(text) => Some(text.clone()),
+            None => fs::read_to_string(uri_to_path(uri)?).ok(),
+        }
+    }
+
+    /// The declaration the name at `at` in `uri` refers to, with the URI and text of the
+    /// document it is in. A dotted name is followed through the dependencies it names.
+    fn lookup(&self, uri: &str, at: usize) -> Option<(String, String, Symbol)> {
+        let text = self.text(uri)?;
+        let tokens = lex::tokens(&text);
+        let (qualifiers, token) = index::path_at(&tokens, at)?;
+        let index = Index::new(&text);
+        let Some((first, rest)) = qualifiers.split_first() else {
+            let declared = resolved(&text, at)
+                .and_then(|start| index.symbols.iter().find(|s| s.name_range.start == start));
+            let symbol = declared
+                .or_else(|| index.definition(token.text, at))?
+                .clone();
+            return Some((uri.to_owned(), text, symbol));
+        };
+        let mut dep = index
+            .definition(first, at)
+            .filter(|s| s.kind == SymbolKind::Dependency)?
+            .clone();
+        let mut uri = self.dependency_uri(uri, &dep)?;
+        for qualifier in rest {
+            let text = self.text(&uri)?;
+            dep = Index::new(&text)
+                .symbols
+                .into_iter()
+                .find(|s| s.kind == SymbolKind::Dependency && s.name == *qualifier)?;
+            uri = self.dependency_uri(&uri, &dep)?;
+        }
+        let text = self.text(&uri)?;
+        let symbol = Index::new(&text)
+            .exports()
+            .find(|s| s.name == token.text)?
+            .clone();
+        Some((uri, text, symbol))
+    }
+
+    /// Where dependency `dep`, declared in `uri`, is found.
+    fn dependency_uri(&self, uri: &str, dep: &Symbol) -> Option<String> {
+        let path = uri_to_path(uri)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let found = deps::locate(dir, &dep.name, dep.ty.as_deref().unwrap_or_default())?;
+        Some(path_to_uri(&deps::canonical(&found)))
+    }
+
+    /// Completions at `at`: after `name.` what dependency `name` exports, otherwise what is in
+    /// scope along with keywords, type names, the registered intrinsics and the kinds of error
+    /// the runtime raises.
+    fn complete(&self, uri: &str, at: usize) -> Vec<Value> {
+        let Some(text) = self.documents.get(uri) else {
+            return Vec::new();
+        };
+        let index = Index::new(text);
+        let before = text[..at].trim_end_matches(|c: char| c.is_alphanumeric() || c == '_');
+        if let Some(qualified) = before.strip_suffix('.') {
+            let tokens = lex::tokens(qualified);
+            let Some(dep) = tokens
+                .last()
+                .and_then(|t| index.definition(t.text, at))
+                .filter(|s| s.kind == SymbolKind::Dependency)
+            else {
+                return Vec::new();
+            };
+            let Some(exports) = self
+                .dependency_uri(uri, dep)
+                .and_then(|uri| self.text(&uri))
+            else {
+                return Vec::new();
+            };
+            return Index::new(&exports).exports().map(completion).collect();
+        }
+        let mut items: Vec<Value> = index.visible(at).into_iter().map(completion).collect();
+        items.extend(
+            parser::KEYWORDS
+                .iter()
+                .map(|keyword| json!({"label": keyword, "kind": COMPLETE_KEYWORD})),
+        );
+        items.extend(
+            TYPES
+                .iter()
+                .map(|ty| json!({"label": ty, "kind": COMPLETE_TYPE})),
+        );
+        items.extend(crate::intrinsic::signatures().iter().map(|bang| {
+            let params: Vec<String> = bang.params.iter().map(|ty| ty.to_string()).collect();
+            json!({
+                "label": format!("{}!", bang.name),
+                "kind": COMPLETE_FUNCTION,
+                "detail": format!("fn {}!({}) -> {}", bang.name, params.join(", "), bang.returns),
+            })
+        }));
+        let kinds = interp::ERROR_KINDS.iter().map(|(_, kind)| *kind);
+        items.extend(
+            std::iter::once("Error")
+                .chain(kinds)
+                .map(|kind| json!({"label": kind, "kind": COMPLETE_CLASS, "detail": "error kind"})),
+        );
+        items
+    }
+
+    /// A `publishDiagnostics` notification for open document `uri`.
+    fn publish(&self, uri: &str) -> Value {
+        let text = &self.documents[uri];
+        let diagnostics: Vec<Value> = check(uri, text)
+            .iter()
+            .map(|d| diagnostic(uri, text, d))
+            .collect();
+        notification(
+            "textDocument/publishDiagnostics",
+            json!({"uri": uri, "diagnostics": diagnostics}),
+        )
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": {"openClose": true, "change": 1, "save": true},
+            "definitionProvider": true,
+            "hoverProvider": true,
+            "documentSymbolProvider": true,
+            "completionProvider": {"triggerCharacters": ["."]},
+        },
+        "serverInfo": {"name": "wineglass", "version": env!("CARGO_PKG_VERSION")},
+    })
+}
+
+/// Everything wrong with `text`, the contents of `uri`: what loading and compiling it as a
+/// bottle reports.
+fn check(uri: &str, text: &str) -> Vec<Diagnostic> {
+    let path = uri_to_path(uri).unwrap_or_else(|| PathBuf::from(uri));
+    let (sources, nodes) = (Arena::new(), Arena::new());
+    let mut found = Vec::new();
+    if let Err(d) = crate::pkg::check_lock(&path) {
+        found.push(d);
+    }
+    match DependencyGraph::resolve(&path, Some(text.to_owned()), &sources, &nodes) {
+        Err(d) => found.push(d),
+        Ok((graph, diagnostics)) => {
+            let failed = diagnostics
+                .iter()
+                .any(|d| matches!(d, Diagnostic::Error(_) | Diagnostic::Fatal(_)));
+            // Other files' warnings are theirs to show; only errors stop this one.
+            let file = graph.units[0].file();
+            found.extend(diagnostics.into_iter().filter(|d| {
+                matches!(d, Diagnostic::Error(_) | Diagnostic::Fatal(_)) || d.get_file() == file
+            }));
+            if !failed {
+                if let Err(d) = crate::vm::Compiler::new(&file).compile_graph(&graph) {
+                    found.push(d);
+                }
+            }
+            // Which site the file will run at isn't known here, so only directives that don't
+            // read are worth showing.
+            let (_, inspected) = cellar::inspect(&graph, &cellar::Site::default(), &[], "");
+            found.extend(inspected.into_iter().filter(|d| {
+                matches!(
+                    d.get_code(),
+                    cellar::UNKNOWN_DIRECTIVE | cellar::DIRECTIVE_ARGUMENTS
+                ) && d.get_file() == file
+            }));
+        }
+    }
+    found
+}
+
+/// Where the declaration of the name at `at` starts, as name resolution binds it. `None` when
+/// `text` doesn't parse or nothing there is bound.
+fn resolved(text: &str, at: usize) -> Option<usize> {
+    let nodes = Arena::new();
+    let ast = crate::parse("", text, &nodes).ok()?;
+    let names = checker::resolve(&ast, text);
+    let (_, symbol) = names.definition(ast.head.find(at)?.id)?;
+    Some(symbol.span.start)
+}
+
+/// `d` as a protocol diagnostic in document `uri`. One that points into another file is put
+/// at the start, naming that file.
+fn diagnostic(uri: &str, text: &str, d: &Diagnostic) -> Value {
+    let here = uri_to_path(uri).map(|p| deps::canonical(&p).display().to_string());
+    let file = d.get_file();
+    let mut message = d.get_message();
+    let span = if here.as_deref() == Some(file.as_str()) || file.is_empty() {
+        d.get_span()
+            .or_else(|| d.get_labels().first().map(|l| l.span))
+            .map(|span| span_range(text, span))
+            .or_else(|| mentioned(text, &message))
+    } else {
+        message = format!("{}: {}", file, message);
+        None
+    };
+    let severity = match d {
+        Diagnostic::Warning(_) => SEVERITY_WARNING,
+        Diagnostic::Info(_) => SEVERITY_INFO,
+        Diagnostic::Error(_) | Diagnostic::Fatal(_) => SEVERITY_ERROR,
+    };
+    json!({
+        "range": range(text, &span.unwrap_or(0..0)),
+        "severity": severity,
+        "code": d.get_code(),
+        "source": "wineglass",
+        "message": message,
+    })
+}
+
+/// Where the first name quoted in `message` appears in `text`, for diagnostics that don't say
+/// where they are.
+fn mentioned(text: &str, message: &str) -> Option<std::ops::Range<usize>> {
+    let name = message.split('`').nth(1)?;
+    let last = name.rsplit('.').next()?;
+    lex::tokens(text)
+        .into_iter()
+        .find(|t| t.text == last)
+        .map(|t| t.start..t.end())
+}
+
+/// A protocol `SymbolKind` for `kind`.
+fn symbol_kind(kind: SymbolKind) -> u32 {
+    match kind {
+        SymbolKind::Function => SYMBOL_FUNCTION,
+        SymbolKind::Parameter | SymbolKind::Variable => SYMBOL_VARIABLE,
+        SymbolKind::Dependency => SYMBOL_MODULE,
+    }
+}
+
+/// The `DocumentSymbol`s declared in function `parent`, or at the top level for `None`.
+fn document_symbols(text: &str, index: &Index, parent: Option<usize>) -> Vec<Value> {
+    index
+        .children(parent)
+        .map(|(i, symbol)| {
+            let mut item = json!({
+                "name": symbol.name,
+                "detail": symbol.signature(),
+                "kind": symbol_kind(symbol.kind),
+                "range": range(text, &symbol.range),
+                "selectionRange": range(text, &symbol.name_range),
+            });
+            if symbol.kind == SymbolKind::Function {
+                item["children"] = document_symbols(text, index, Some(i)).into();
+            }
+            item
+        })
+        .collect()
+}
+
+fn completion(symbol: &Symbol) -> Value {
+    let kind = match symbol.kind {
+        SymbolKind::Function => COMPLETE_FUNCTION,
+        SymbolKind::Parameter | SymbolKind::Variable => COMPLETE_VARIABLE,
+        SymbolKind::Dependency => COMPLETE_MODULE,
+    };
+    json!({"label": symbol.name, "kind": kind, "detail": symbol.signature()})
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "method": method, "params": params})
+}
+
+/// Answers a message whose id couldn't be read.
+fn reject(output: &mut impl Write, code: i64, message: String) -> io::Result<()> {
+    let error = json!({"code": code, "message": message});
+    write_message(
+        output,
+        &json!({"jsonrpc": "2.0", "id": null, "error": error}),
+    )
+}
+
+/// Reads one message body, or `None` at the end of input. A body over [`MAX_MESSAGE`] bytes
+/// is skipped, so the next message can still be read, and is an error of kind `FileTooLarge`.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without a Content-Length",
+        ));
+    };
+    if length > MAX_MESSAGE {
+        io::copy(
+            &mut io::Read::take(&mut *input, length as u64),
+            &mut io::sink(),
+        )?;
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!(
+                "a message of {} bytes is over the {} byte limit",
+                length, MAX_MESSAGE
+            ),
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// The byte offset of a protocol position, whose `character` counts UTF-16 code units.
+pub fn offset(text: &str, line: usize, character: usize) -> usize {
+    let start = match line {
+        0 => 0,
+        line => match text.match_indices('\n').nth(line - 1) {
+            Some((i, _)) => i + 1,
+            None => return text.len(),
+        },
+    };
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The protocol position of byte `offset`.
+pub fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset.min(text.len())];
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[start..].encode_utf16().count(),
+    })
+}
+
+fn range(text: &str, bytes: &std::ops::Range<usize>) -> Value {
+    json!({"start": position(text, bytes.start), "end": position(text, bytes.end)})
+}
+
+/// The bytes a diagnostic span covers. Its columns count characters, and any past the end of
+/// their line stop there.
+fn span_range(text: &str, span: Span) -> std::ops::Range<usize> {
+    let at = |p: SourcePosition| {
+        let start = match p.line {
+            0 => 0,
+            line => text
+                .match_indices('\n')
+                .nth(line - 1)
+                .map_or(text.len(), |(i, _)| i + 1),
+        };
+        let line = &text[start..];
+        let line = &line[..line.find('\n').unwrap_or(line.len())];
+        line.char_indices()
+            .nth(p.column)
+            .map_or(start + line.len(), |(i, _)| start + i)
+    };
+    at(span.start)..at(span.end)
+}
+
+/// The path of a `file://` URI.
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+/// The `file://` URI of `path`.
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.display().to_string().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            byte => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn oversized_messages_are_skipped_and_answered() {
+        let mut input = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE + 1).into_bytes();
+        input.resize(input.len() + MAX_MESSAGE + 1, b' ');
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}});
+        write_message(&mut input, &initialize).unwrap();
+        let mut output = Vec::new();
+        Server::new().run(Cursor::new(input), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        let rejected: Value =
+            serde_json::from_str(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(rejected["id"], Value::Null);
+        assert_eq!(rejected["error"]["code"], json!(INVALID_REQUEST));
+        let answered: Value =
+            serde_json::from_str(&read_message(&mut output).unwrap().unwrap()).unwrap();
+        assert_eq!(answered["id"], json!(1));
+        assert!(answered.get("result").is_some());
+    }
+
+    #[test]
+    fn span_columns_stop_at_the_end_of_their_line() {
+        let text = "let a = 1;\nlet é = 2;\n";
+        let span = |line, column, end_column| {
+            Span::new(
+                SourcePosition::new(line, column),
+                SourcePosition::new(line, end_column),
+            )
+        };
+        assert_eq!(span_range(text, span(0, 4, 5)), 4..5);
+        assert_eq!(span_range(text, span(0, 4, 40)), 4..10);
+        assert_eq!(span_range(text, span(1, 4, 5)), 15..17);
+        assert_eq!(span_range(text, span(1, 40, 50)), 22..22);
+        assert_eq!(span_range(text, span(9, 0, 1)), 23..23);
+    }
+}
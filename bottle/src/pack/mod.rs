@@ -0,0 +1,577 @@
+//! Bottle manifests and the archives bottles are distributed as.
+//!
+//! A manifest is a `bottle.toml` beside the bottle's entry file:
+//! ```toml
+//! [bottle]
+//! name = "math"
+//! version = "1.2.0"
+//! description = "Arithmetic helpers"
+//! entry = "main.wg"
+//! capabilities = ["net"]
+//! ```
+//! An archive is a single `.wgb` file: a `wineglass-bottle 2` line followed by JSON holding
+//! the manifest, every source the bottle reaches through its dependencies with the
+//! dependencies each declares, optionally the compiled bytecode, and a SHA-256 hash of all of it.
+use crate::err::{self, Diagnostic};
+use crate::{vm, Backend, Bottle};
+use configmgr::version::{Version, VersionReq};
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use typed_arena::Arena;
+
+/// Name of the manifest file beside a bottle's entry.
+pub const MANIFEST_FILE: &str = "bottle.toml";
+/// Extension archives are written with.
+pub const ARCHIVE_EXTENSION: &str = "wgb";
+const MAGIC: &str = "wineglass-bottle";
+/// Format 1 archives were hashed with FNV-1a, too weak to notice tampering.
+const FORMAT: u32 = 2;
+
+/// A manifest could not be read or is missing something.
+pub const MANIFEST_INVALID: i32 = 8001;
+/// An archive could not be read or written, or is not an archive at all.
+pub const ARCHIVE_READ: i32 = 8002;
+/// An archive's contents do not match its hash, or it is missing files it refers to.
+pub const ARCHIVE_CORRUPT: i32 = 8003;
+/// A file is outside the bottle's directory, or an archive names a path outside of where it
+/// is unpacked.
+pub const ARCHIVE_PATH: i32 = 8004;
+
+/// What `bottle.toml` says about a bottle.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    /// The file with `main`, relative to the manifest.
+    #[serde(default = "default_entry")]
+    pub entry: String,
+    /// What the bottle needs the site it runs at to grant it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    /// Bottles installed from a registry, by name, with the versions each may be.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+/// `bottle.toml` as it is laid out on disk: a `[bottle]` table, then `[dependencies]`.
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    bottle: BottleTable,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dependencies: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BottleTable {
+    name: String,
+    version: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default = "default_entry")]
+    entry: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    capabilities: Vec<String>,
+}
+
+fn default_entry() -> String {
+    "main.wg".to_owned()
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, Diagnostic> {
+        let file = path.display().to_string();
+        let text = fs::read_to_string(path)
+            .map_err(|why| error(MANIFEST_INVALID, &file, format!("couldn't read: {}", why)))?;
+        let ManifestFile {
+            bottle,
+            dependencies,
+        } = toml::from_str(&text)
+            .map_err(|why| error(MANIFEST_INVALID, &file, why.message().to_owned()))?;
+        if Version::parse(&bottle.version).is_err() {
+            return Err(error(
+                MANIFEST_INVALID,
+                &file,
+                format!("`{}` is not a version like `1.2.0`", bottle.version),
+            ));
+        }
+        for (name, req) in &dependencies {
+            if let Err(e) = VersionReq::parse(req) {
+                return Err(error(
+                    MANIFEST_INVALID,
+                    &file,
+                    format!("dependency `{}`: {}", name, e.message),
+                ));
+            }
+        }
+        Ok(Manifest {
+            name: bottle.name,
+            version: bottle.version,
+            description: bottle.description,
+            entry: bottle.entry,
+            capabilities: bottle.capabilities,
+            dependencies,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Diagnostic> {
+        fs::write(path, self.to_toml()).map_err(|why| {
+            error(
+                MANIFEST_INVALID,
+                &path.display().to_string(),
+                format!("couldn't write: {}", why),
+            )
+        })
+    }
+
+    /// The manifest in the same directory as `entry`, if there is one.
+    pub fn find(entry: &Path) -> Result<Option<Manifest>, Diagnostic> {
+        let path = entry.with_file_name(MANIFEST_FILE);
+        match path.is_file() {
+            true => Manifest::load(&path).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        let file = ManifestFile {
+            bottle: BottleTable {
+                name: self.name.clone(),
+                version: self.version.clone(),
+                description: self.description.clone(),
+                entry: self.entry.clone(),
+                capabilities: self.capabilities.clone(),
+            },
+            dependencies: self.dependencies.clone(),
+        };
+        toml::to_string(&file).expect("manifests only hold strings")
+    }
+}
+
+/// A dependency one packed file declares. `path` is the packed file it resolved to, if any.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackedDep {
+    pub kind: String,
+    pub name: String,
+    pub path: Option<String>,
+}
+
+/// A source file, by its path relative to the entry's directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackedFile {
+    pub path: String,
+    pub text: String,
+    pub deps: Vec<PackedDep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Archive {
+    pub manifest: Manifest,
+    /// The entry first, then its dependencies in the order they were found.
+    pub files: Vec<PackedFile>,
+    /// Version of the compiler that produced `bytecode`. Bytecode from any other version is
+    /// ignored and the sources compiled again.
+    pub compiler: String,
+    /// The compiled program, with file names relative to the entry's directory.
+    pub bytecode: Option<vm::Program>,
+    /// [`Archive::content_hash`] when the archive was packed, in hex.
+    pub hash: String,
+}
+
+/// What the hash covers: everything but the hash itself.
+#[derive(Serialize)]
+struct Contents<'a> {
+    manifest: &'a Manifest,
+    files: &'a [PackedFile],
+    compiler: &'a str,
+    bytecode: &'a Option<vm::Program>,
+}
+
+impl Archive {
+    /// Packs `bottle` with everything it depends on. With `bytecode`, the compiled program is
+    /// included so it need not be compiled again where it is unpacked.
+    pub fn pack(bottle: &Bottle, bytecode: bool) -> Result<Archive, Diagnostic> {
+        let file = bottle.path.display().to_string();
+        if bottle.source.is_some() {
+            return Err(error(
+                ARCHIVE_PATH,
+                &file,
+                "only bottles read from a file can be packed".to_owned(),
+            ));
+        }
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let graph = bottle.resolve(&sources, &nodes)?;
+        let dir = graph.units[0]
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let relative = |path: &Path| -> Result<String, Diagnostic> {
+            let inside = path.strip_prefix(&dir).map_err(|_| {
+                error(
+                    ARCHIVE_PATH,
+                    &file,
+                    format!(
+                        "{} is outside {}, so it can't be packed with it",
+                        path.display(),
+                        dir.display()
+                    ),
+                )
+            })?;
+            Ok(portable(inside))
+        };
+        let mut files = Vec::new();
+        for unit in &graph.units {
+            let mut deps = Vec::new();
+            for dep in &unit.deps {
+                deps.push(PackedDep {
+                    kind: dep.kind.to_string(),
+                    name: dep.name.clone(),
+                    path: match dep.target {
+                        Some(target) => Some(relative(&graph.units[target].path)?),
+                        None => None,
+                    },
+                });
+            }
+            files.push(PackedFile {
+                path: relative(&unit.path)?,
+                text: unit.source.to_owned(),
+                deps,
+            });
+        }
+        let mut manifest = match Manifest::find(&graph.units[0].path)? {
+            Some(manifest) => manifest,
+            None => Manifest {
+                name: bottle.name.clone(),
+                version: bottle.version.to_string(),
+                description: bottle.description.clone(),
+                entry: String::new(),
+                capabilities: Vec::new(),
+                dependencies: BTreeMap::new(),
+            },
+        };
+        manifest.entry = files[0].path.clone();
+        let bytecode = match bytecode {
+            true => {
+                let mut program =
+                    vm::Compiler::new(&graph.units[0].file()).compile_graph(&graph)?;
+                relocate(&mut program, |file| relative(Path::new(file)))?;
+                Some(program)
+            }
+            false => None,
+        };
+        let mut archive = Archive {
+            manifest,
+            files,
+            compiler: env!("CARGO_PKG_VERSION").to_owned(),
+            bytecode,
+            hash: String::new(),
+        };
+        archive.hash = archive.content_hash();
+        info!(
+            "Packed {} with {} files",
+            archive.manifest.name,
+            archive.files.len()
+        );
+        Ok(archive)
+    }
+
+    /// SHA-256 over everything in the archive but the hash, in hex.
+    pub fn content_hash(&self) -> String {
+        let contents = Contents {
+            manifest: &self.manifest,
+            files: &self.files,
+            compiler: &self.compiler,
+            bytecode: &self.bytecode,
+        };
+        let bytes = serde_json::to_vec(&contents).expect("archives only hold plain data");
+        Sha256::digest(&bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Diagnostic> {
+        let json = serde_json::to_string(self).expect("archives only hold plain data");
+        fs::write(path, format!("{} {}\n{}\n", MAGIC, FORMAT, json)).map_err(|why| {
+            error(
+                ARCHIVE_READ,
+                &path.display().to_string(),
+                format!("couldn't write archive: {}", why),
+            )
+        })
+    }
+
+    /// Reads the archive at `path` and [verifies](Archive::verify) it.
+    pub fn read(path: &Path) -> Result<Archive, Diagnostic> {
+        let file = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|why| {
+            error(
+                ARCHIVE_READ,
+                &file,
+                format!("couldn't read archive: {}", why),
+            )
+        })?;
+        let (header, json) = text.split_once('\n').unwrap_or((&text, ""));
+        match header.split_once(' ') {
+            Some((MAGIC, format)) if format == FORMAT.to_string() => {}
+            Some((MAGIC, format)) => {
+                return Err(error(
+                    ARCHIVE_READ,
+                    &file,
+                    format!("archive format {} is not supported", format),
+                ))
+            }
+            _ => {
+                return Err(error(
+                    ARCHIVE_READ,
+                    &file,
+                    "not a bottle archive".to_owned(),
+                ))
+            }
+        }
+        let archive: Archive = serde_json::from_str(json)
+            .map_err(|why| error(ARCHIVE_READ, &file, format!("malformed archive: {}", why)))?;
+        archive.verify().map_err(|d| retarget(d, &file))?;
+        Ok(archive)
+    }
+
+    /// Checks the hash, that every path stays inside the archive, that every file the archive
+    /// refers to is in it and that its bytecode, which anyone could have hashed, is safe to run.
+    pub fn verify(&self) -> Result<(), Diagnostic> {
+        let file = &self.manifest.name;
+        let hash = self.content_hash();
+        if hash != self.hash {
+            return Err(error(
+                ARCHIVE_CORRUPT,
+                file,
+                format!(
+                    "contents hash to {} but the archive says {}",
+                    hash, self.hash
+                ),
+            ));
+        }
+        for packed in &self.files {
+            if !contained(&packed.path) {
+                return Err(error(
+                    ARCHIVE_PATH,
+                    file,
+                    format!("`{}` would be unpacked outside the bottle", packed.path),
+                ));
+            }
+        }
+        let has = |path: &str| self.files.iter().any(|f| f.path == path);
+        if self.files.first().map(|f| &f.path) != Some(&self.manifest.entry) {
+            return Err(error(
+                ARCHIVE_CORRUPT,
+                file,
+                format!("entry `{}` is not the first file", self.manifest.entry),
+            ));
+        }
+        for packed in &self.files {
+            for path in packed.deps.iter().filter_map(|d| d.path.as_deref()) {
+                if !has(path) {
+                    return Err(error(
+                        ARCHIVE_CORRUPT,
+                        file,
+                        format!("`{}` depends on `{}`, which is missing", packed.path, path),
+                    ));
+                }
+            }
+        }
+        if let Some(program) = &self.bytecode {
+            vm::verify(program).map_err(|why| {
+                error(
+                    ARCHIVE_CORRUPT,
+                    file,
+                    format!("bytecode is unsafe to run: {}", why),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes the sources and manifest into `dir` and returns the bottle they make up. Bytecode
+    /// compiled by this version of wineglass is kept, so the bottle runs on the VM without being
+    /// compiled again.
+    pub fn unpack(&self, dir: &Path) -> Result<Bottle, Diagnostic> {
+        self.verify()?;
+        let write = |path: &Path, text: &str| {
+            let written = match path.parent() {
+                Some(parent) => fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|_| fs::write(path, text));
+            written.map_err(|why| {
+                error(
+                    ARCHIVE_READ,
+                    &path.display().to_string(),
+                    format!("couldn't unpack: {}", why),
+                )
+            })
+        };
+        for packed in &self.files {
+            write(&dir.join(&packed.path), &packed.text)?;
+        }
+        write(&dir.join(MANIFEST_FILE), &self.manifest.to_toml())?;
+        let entry = dir.join(&self.manifest.entry);
+        let mut bottle = Bottle::new(
+            &entry,
+            Some(&self.manifest.name),
+            Version::parse(&self.manifest.version).ok(),
+            Some(&self.manifest.description),
+        )
+        .map_err(|why| error(ARCHIVE_READ, &entry.display().to_string(), why))?;
+        bottle.hash = Some(self.hash.clone());
+        match &self.bytecode {
+            Some(program) if self.compiler == env!("CARGO_PKG_VERSION") => {
+                let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+                let mut program = program.clone();
+                relocate(&mut program, |file| {
+                    Ok::<_, Diagnostic>(dir.join(file).display().to_string())
+                })?;
+                bottle.program = Some(program);
+                bottle.backend = Backend::Bytecode;
+            }
+            Some(_) => warn!(
+                "{} was compiled by wineglass {}; compiling it again",
+                self.manifest.name, self.compiler
+            ),
+            None => {}
+        }
+        Ok(bottle)
+    }
+}
+
+/// The archive of bottle `name` in `dir` with the highest version `req` allows. Archives are
+/// found by the `<name>-<version>.wgb` names [`Archive::write`] is given by `wineglass pack`.
+pub fn find_archive(dir: &Path, name: &str, req: &VersionReq) -> Option<(Version, PathBuf)> {
+    let prefix = format!("{}-", name);
+    let candidates = fs::read_dir(dir).ok()?.filter_map(|entry| {
+        let path = entry.ok()?.path();
+        if path.extension()? != ARCHIVE_EXTENSION {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?;
+        let version = Version::parse(stem.strip_prefix(&prefix)?).ok()?;
+        Some((version, path))
+    });
+    req.best_match(candidates, |(version, _)| version)
+}
+
+/// Rewrites the file names in `program`.
+fn relocate<E>(program: &mut vm::Program, to: impl Fn(&str) -> Result<String, E>) -> Result<(), E> {
+    program.file = to(&program.file)?;
+    for module in &mut program.modules {
+        module.file = to(&module.file)?;
+    }
+    Ok(())
+}
+
+/// `path` with `/` between its parts, whatever the platform uses.
+fn portable(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether `path` names something beneath the directory it is relative to.
+fn contained(path: &str) -> bool {
+    let path = PathBuf::from(path);
+    !path.as_os_str().is_empty() && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Points `d` at the archive file rather than the bottle inside it.
+fn retarget(d: Diagnostic, file: &str) -> Diagnostic {
+    error(d.get_code(), file, d.get_message())
+}
+
+fn error(code: i32, file: &str, message: String) -> Diagnostic {
+    Diagnostic::Error(err::Error {
+        code,
+        file: file.to_owned(),
+        message,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs a bottle whose `main` returns 7, with its bytecode, into a fresh directory.
+    fn packed(test: &str) -> (PathBuf, Archive) {
+        let dir = std::env::temp_dir().join(format!("wineglass-{}-{}", test, std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("src/main.wg"),
+            "fn main() -> i64 {\n    return 7;\n}\n",
+        )
+        .unwrap();
+        let bottle = Bottle::new(dir.join("src/main.wg"), Some("seven"), None, None).unwrap();
+        let archive = Archive::pack(&bottle, true).expect("the bottle packs");
+        (dir, archive)
+    }
+
+    #[test]
+    fn archives_round_trip_with_their_bytecode_and_whole_hash() {
+        let (dir, archive) = packed("round-trip");
+        let file = dir.join("seven.wgb");
+        archive.write(&file).unwrap();
+        let read = Archive::read(&file).expect("the archive reads back");
+        let mut unpacked = read.unpack(&dir.join("out")).expect("the archive unpacks");
+        let code = unpacked.start();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read.hash, archive.hash);
+        assert_eq!(read.bytecode, archive.bytecode);
+        assert_eq!(archive.hash.len(), 64);
+        assert_eq!(unpacked.hash(), Some(archive.hash.as_str()));
+        assert_eq!(unpacked.backend, Backend::Bytecode);
+        assert_eq!(code.ok(), Some(7));
+    }
+
+    #[test]
+    fn tampered_archives_are_refused() {
+        let (dir, archive) = packed("tamper");
+        let file = dir.join("seven.wgb");
+        archive.write(&file).unwrap();
+        let text = fs::read_to_string(&file).unwrap();
+        fs::write(&file, text.replace("return 7;", "return 8;")).unwrap();
+        let edited = Archive::read(&file).map(|_| ());
+        fs::remove_dir_all(&dir).unwrap();
+        let d = edited.expect_err("the contents no longer match the hash");
+        assert_eq!(d.get_code(), ARCHIVE_CORRUPT);
+
+        // Anyone can hash what they wrote, so bytecode is checked on its own merits too.
+        let mut forged = archive.clone();
+        let program = forged.bytecode.as_mut().unwrap();
+        let main = program.main.unwrap();
+        program.chunks[main].code[0] = vm::Op::LoadGlobal(40);
+        forged.hash = forged.content_hash();
+        let d = forged
+            .verify()
+            .expect_err("the bytecode reads past the globals");
+        assert_eq!(d.get_code(), ARCHIVE_CORRUPT);
+        assert!(
+            d.get_message().contains("global #40"),
+            "{}",
+            d.get_message()
+        );
+
+        let mut forged = archive;
+        let program = forged.bytecode.as_mut().unwrap();
+        program.chunks[main].code.insert(0, vm::Op::Pop);
+        program.chunks[main].locs.insert(0, None);
+        forged.hash = forged.content_hash();
+        let d = forged
+            .verify()
+            .expect_err("the bytecode pops an empty stack");
+        assert_eq!(d.get_code(), ARCHIVE_CORRUPT);
+    }
+}
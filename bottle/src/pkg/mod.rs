@@ -0,0 +1,602 @@
+//! Installing bottles other projects publish.
+//!
+//! A project lists what it needs under `[dependencies]` in its `bottle.toml`, by name and
+//! version requirement. Versions are picked from a registry, a directory of archives named
+//! `<name>-<version>.wgb` as `wineglass pack` writes them. The chosen archives are copied into
+//! a cache and unpacked into `bottles/<name>/` beside the manifest, where `require <name>`
+//! finds them. What was chosen, and the hash of each archive, is written to `bottle.lock`,
+//! so installing again gets exactly the same bottles until one is added or updated.
+use crate::err::{self, Diagnostic};
+use crate::pack::{find_archive, Archive, Manifest, ARCHIVE_EXTENSION, MANIFEST_FILE};
+use configmgr::version::{Version, VersionReq};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The lockfile beside a project's manifest.
+pub const LOCK_FILE: &str = "bottle.lock";
+/// Where dependencies are unpacked, beside the manifest.
+pub const BOTTLES_DIR: &str = "bottles";
+const LOCK_FORMAT: u32 = 1;
+const LOCK_HEADER: &str =
+    "# Written by wineglass. Run `wineglass install` rather than editing it.\n";
+
+/// No version in the registry satisfies what is asked of a bottle.
+pub const PACKAGE_NOT_FOUND: i32 = 8005;
+/// `bottle.lock` does not match `bottle.toml` or what is installed.
+pub const LOCK_MISMATCH: i32 = 8006;
+/// No registry is configured, or it can't be read.
+pub const REGISTRY_MISSING: i32 = 8007;
+/// An archive does not have the hash the lockfile records for it.
+pub const HASH_MISMATCH: i32 = 8008;
+
+/// One installed bottle.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Locked {
+    pub name: String,
+    pub version: String,
+    /// Content hash of the archive it was installed from.
+    pub hash: String,
+    /// Names of the locked bottles it depends on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+}
+
+/// `bottle.lock`: every bottle a project installs, directly or not, sorted by name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: u32,
+    #[serde(default, rename = "bottle")]
+    pub bottles: Vec<Locked>,
+}
+
+impl Lockfile {
+    pub fn load(path: &Path) -> Result<Lockfile, Diagnostic> {
+        let file = path.display().to_string();
+        let text = fs::read_to_string(path)
+            .map_err(|why| error(LOCK_MISMATCH, &file, format!("couldn't read: {}", why)))?;
+        let lock: Lockfile = toml::from_str(&text)
+            .map_err(|why| error(LOCK_MISMATCH, &file, why.message().to_owned()))?;
+        if lock.version != LOCK_FORMAT {
+            return Err(error(
+                LOCK_MISMATCH,
+                &file,
+                format!("lockfile format {} is not supported", lock.version),
+            ));
+        }
+        Ok(lock)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Diagnostic> {
+        let text = toml::to_string(self).expect("lockfiles only hold strings");
+        fs::write(path, format!("{}{}", LOCK_HEADER, text)).map_err(|why| {
+            error(
+                LOCK_MISMATCH,
+                &path.display().to_string(),
+                format!("couldn't write: {}", why),
+            )
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Locked> {
+        self.bottles.iter().find(|b| b.name == name)
+    }
+}
+
+/// A project and where its bottles come from.
+pub struct PackageManager {
+    /// The directory holding `bottle.toml`.
+    pub project: PathBuf,
+    pub registry: Option<PathBuf>,
+    pub cache: PathBuf,
+}
+
+/// A bottle picked for a project while resolving.
+struct Chosen {
+    version: Version,
+    archive: Archive,
+    /// Where the archive was read from.
+    path: PathBuf,
+}
+
+impl PackageManager {
+    /// The project whose `bottle.toml` is in `dir` or the nearest directory above it.
+    pub fn open(dir: &Path, registry: Option<PathBuf>, cache: PathBuf) -> Result<Self, Diagnostic> {
+        let project = dir
+            .ancestors()
+            .find(|d| d.join(MANIFEST_FILE).is_file())
+            .ok_or_else(|| {
+                error(
+                    crate::pack::MANIFEST_INVALID,
+                    &dir.join(MANIFEST_FILE).display().to_string(),
+                    format!("no {} here or in any directory above", MANIFEST_FILE),
+                )
+                .with_help(
+                    "create one with a [bottle] table holding the project's name and version",
+                )
+            })?;
+        Ok(Self {
+            project: project.to_path_buf(),
+            registry,
+            cache,
+        })
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.project.join(MANIFEST_FILE)
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.project.join(LOCK_FILE)
+    }
+
+    /// Adds `name` to the manifest and installs it. Without `req` the newest version in the
+    /// registry is taken and required as `^version`.
+    pub fn add(&self, name: &str, req: Option<&str>) -> Result<Vec<Locked>, Diagnostic> {
+        let mut manifest = Manifest::load(&self.manifest_path())?;
+        let req = match req {
+            Some(req) => {
+                VersionReq::parse(req).map_err(|e| error(PACKAGE_NOT_FOUND, name, e.message))?;
+                req.to_owned()
+            }
+            None => {
+                let (version, _) = find_archive(self.registry()?, name, &VersionReq::any())
+                    .ok_or_else(|| self.not_found(name, &[]))?;
+                format!("^{}", version)
+            }
+        };
+        manifest.dependencies.insert(name.to_owned(), req);
+        self.install_with(&manifest, &BTreeSet::from([name.to_owned()]))?;
+        manifest.save(&self.manifest_path())?;
+        self.locked()
+    }
+
+    /// Takes `name` out of the manifest and uninstalls whatever only it needed.
+    pub fn remove(&self, name: &str) -> Result<Vec<Locked>, Diagnostic> {
+        let mut manifest = Manifest::load(&self.manifest_path())?;
+        if manifest.dependencies.remove(name).is_none() {
+            return Err(error(
+                PACKAGE_NOT_FOUND,
+                &self.manifest_path().display().to_string(),
+                format!("`{}` is not a dependency", name),
+            ));
+        }
+        self.install_with(&manifest, &BTreeSet::new())?;
+        manifest.save(&self.manifest_path())?;
+        self.locked()
+    }
+
+    /// Installs what the lockfile records, resolving only what it is missing.
+    pub fn install(&self) -> Result<Vec<Locked>, Diagnostic> {
+        let manifest = Manifest::load(&self.manifest_path())?;
+        self.install_with(&manifest, &BTreeSet::new())?;
+        self.locked()
+    }
+
+    /// Moves `names`, or every dependency when there are none, to the newest versions the
+    /// manifest allows.
+    pub fn update(&self, names: &[String]) -> Result<Vec<Locked>, Diagnostic> {
+        let manifest = Manifest::load(&self.manifest_path())?;
+        let unlock = match names.is_empty() {
+            true => Lockfile::load(&self.lock_path())
+                .unwrap_or_default()
+                .bottles
+                .into_iter()
+                .map(|b| b.name)
+                .chain(manifest.dependencies.keys().cloned())
+                .collect(),
+            false => names.iter().cloned().collect(),
+        };
+        self.install_with(&manifest, &unlock)?;
+        self.locked()
+    }
+
+    fn locked(&self) -> Result<Vec<Locked>, Diagnostic> {
+        Ok(Lockfile::load(&self.lock_path())?.bottles)
+    }
+
+    /// Resolves `manifest` against the lockfile, keeping locked versions except for the bottles
+    /// in `unlock`, then installs the result and writes the lockfile.
+    fn install_with(
+        &self,
+        manifest: &Manifest,
+        unlock: &BTreeSet<String>,
+    ) -> Result<(), Diagnostic> {
+        let lock = match self.lock_path().is_file() {
+            true => Lockfile::load(&self.lock_path())?,
+            false => Lockfile::default(),
+        };
+        let chosen = self.resolve(manifest, &lock, unlock)?;
+        let bottles = self.project.join(BOTTLES_DIR);
+        let mut next = Lockfile {
+            version: LOCK_FORMAT,
+            bottles: Vec::new(),
+        };
+        for (name, chosen) in &chosen {
+            let cached = self.cache(name, chosen)?;
+            let dir = bottles.join(name);
+            if dir.exists() {
+                fs::remove_dir_all(&dir).map_err(|why| io_error(&dir, why))?;
+            }
+            chosen
+                .archive
+                .unpack(&dir)
+                .map_err(|d| d.with_note(format!("while unpacking {}", cached.display())))?;
+            info!("Installed {} {}", name, chosen.version);
+            next.bottles.push(Locked {
+                name: name.clone(),
+                version: chosen.version.to_string(),
+                hash: chosen.archive.hash.clone(),
+                dependencies: chosen
+                    .archive
+                    .manifest
+                    .dependencies
+                    .keys()
+                    .cloned()
+                    .collect(),
+            });
+        }
+        // Only directories with a manifest are bottles this installed; leave anything else.
+        if let Ok(entries) = fs::read_dir(&bottles) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !chosen.contains_key(&name) && path.join(MANIFEST_FILE).is_file() {
+                    info!("Removing {}", name);
+                    fs::remove_dir_all(&path).map_err(|why| io_error(&path, why))?;
+                }
+            }
+        }
+        next.save(&self.lock_path())
+    }
+
+    /// Picks a version of every bottle `manifest` needs, directly or through another bottle.
+    /// A bottle several others need gets the newest version that satisfies all of them.
+    ///
+    /// Picks are made in passes. Each pass gathers what is wanted from the manifest and the
+    /// current pick of every bottle it still reaches, so a bottle picked again stops holding
+    /// others to what its old version asked for, and picks again any bottle whose pick no
+    /// longer fits. It is done once a pass changes nothing.
+    fn resolve(
+        &self,
+        manifest: &Manifest,
+        lock: &Lockfile,
+        unlock: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, Chosen>, Diagnostic> {
+        let mut chosen: BTreeMap<String, Chosen> = BTreeMap::new();
+        // Picks already given up, so passes that would only go back to one of them end.
+        let mut replaced: BTreeSet<(String, Version)> = BTreeSet::new();
+        loop {
+            let wanted = wanted(manifest, &chosen)?;
+            let mut changed = false;
+            for (name, reqs) in &wanted {
+                let all = VersionReq {
+                    comparators: reqs
+                        .iter()
+                        .flat_map(|(r, _)| r.comparators.clone())
+                        .collect(),
+                };
+                if chosen.get(name).is_some_and(|c| all.matches(&c.version)) {
+                    continue;
+                }
+                let locked = lock
+                    .get(name)
+                    .filter(|_| !unlock.contains(name))
+                    .and_then(|l| Some((l, Version::parse(&l.version).ok()?)))
+                    .filter(|(_, version)| all.matches(version));
+                let pick = match locked {
+                    Some((locked, version)) => self.locked_archive(name, locked, version)?,
+                    None => {
+                        let (version, path) = find_archive(self.registry()?, name, &all)
+                            .ok_or_else(|| self.not_found(name, reqs))?;
+                        let archive = Archive::read(&path)?;
+                        Chosen {
+                            version,
+                            archive,
+                            path,
+                        }
+                    }
+                };
+                if replaced.contains(&(name.clone(), pick.version.clone())) {
+                    return Err(self.not_found(name, reqs));
+                }
+                debug!("Resolved {} to {}", name, pick.version);
+                if let Some(old) = chosen.insert(name.clone(), pick) {
+                    replaced.insert((name.clone(), old.version));
+                }
+                changed = true;
+            }
+            if !changed {
+                // What only an old pick needed is no longer wanted.
+                chosen.retain(|name, _| wanted.contains_key(name));
+                return Ok(chosen);
+            }
+        }
+    }
+
+    /// The archive `locked` was installed from: the cached copy, else the registry's, so long
+    /// as it still has the locked hash.
+    fn locked_archive(
+        &self,
+        name: &str,
+        locked: &Locked,
+        version: Version,
+    ) -> Result<Chosen, Diagnostic> {
+        let file = format!("{}-{}.{}", name, version, ARCHIVE_EXTENSION);
+        let mut places = vec![self.cache.join(&file)];
+        if let Some(registry) = &self.registry {
+            places.push(registry.join(&file));
+        }
+        if let Some(path) = places.into_iter().find(|p| p.is_file()) {
+            let archive = Archive::read(&path)?;
+            if archive.hash != locked.hash {
+                return Err(error(
+                    HASH_MISMATCH,
+                    &path.display().to_string(),
+                    format!(
+                        "{} {} hashes to {} but {} locked {}",
+                        name, version, archive.hash, LOCK_FILE, locked.hash
+                    ),
+                )
+                .with_help(format!(
+                    "run `wineglass update {}` to accept the new contents",
+                    name
+                )));
+            }
+            return Ok(Chosen {
+                version,
+                archive,
+                path,
+            });
+        }
+        Err(error(
+            PACKAGE_NOT_FOUND,
+            &self.lock_path().display().to_string(),
+            format!(
+                "{} {} is locked but neither cached nor in the registry",
+                name, version
+            ),
+        ))
+    }
+
+    /// Copies the archive into the cache unless it is already there.
+    fn cache(&self, name: &str, chosen: &Chosen) -> Result<PathBuf, Diagnostic> {
+        let file = format!("{}-{}.{}", name, chosen.version, ARCHIVE_EXTENSION);
+        let path = self.cache.join(file);
+        if path != chosen.path {
+            fs::create_dir_all(&self.cache).map_err(|why| io_error(&self.cache, why))?;
+            chosen.archive.write(&path)?;
+        }
+        Ok(path)
+    }
+
+    fn registry(&self) -> Result<&Path, Diagnostic> {
+        match &self.registry {
+            Some(registry) if registry.is_dir() => Ok(registry),
+            Some(registry) => Err(error(
+                REGISTRY_MISSING,
+                &registry.display().to_string(),
+                "the registry is not a directory".to_owned(),
+            )),
+            None => Err(error(
+                REGISTRY_MISSING,
+                &self.manifest_path().display().to_string(),
+                "no registry to install bottles from".to_owned(),
+            )
+            .with_help("set `registry` in wineglass.toml, WINEGLASS_REGISTRY or --registry")),
+        }
+    }
+
+    fn not_found(&self, name: &str, reqs: &[(VersionReq, String)]) -> Diagnostic {
+        let wants: Vec<String> = reqs
+            .iter()
+            .map(|(req, from)| format!("{} wants {}", from, req))
+            .collect();
+        let d = error(
+            PACKAGE_NOT_FOUND,
+            &self.manifest_path().display().to_string(),
+            format!("no version of `{}` in the registry fits", name),
+        );
+        match wants.is_empty() {
+            true => d,
+            false => d.with_note(wants.join("\n")),
+        }
+    }
+}
+
+/// What each bottle is asked to be and by whom: by `manifest`, and by the pick in `chosen` of
+/// every bottle `manifest` reaches through them.
+fn wanted(
+    manifest: &Manifest,
+    chosen: &BTreeMap<String, Chosen>,
+) -> Result<BTreeMap<String, Vec<(VersionReq, String)>>, Diagnostic> {
+    let mut wanted: BTreeMap<String, Vec<(VersionReq, String)>> = BTreeMap::new();
+    let mut stack = vec![manifest];
+    let mut seen = BTreeSet::new();
+    while let Some(from) = stack.pop() {
+        if !seen.insert(&from.name) {
+            continue;
+        }
+        for (name, req) in &from.dependencies {
+            let req = VersionReq::parse(req).map_err(|e| {
+                error(
+                    PACKAGE_NOT_FOUND,
+                    &from.name,
+                    format!("dependency `{}`: {}", name, e.message),
+                )
+            })?;
+            wanted
+                .entry(name.clone())
+                .or_default()
+                .push((req, from.name.clone()));
+            if let Some(pick) = chosen.get(name) {
+                stack.push(&pick.archive.manifest);
+            }
+        }
+    }
+    Ok(wanted)
+}
+
+/// Fails when the lockfile beside `entry`'s manifest no longer matches it: a dependency is
+/// missing from it or locked to a version the manifest no longer allows, it holds bottles
+/// nothing needs, or what it locks is not what is installed.
+pub fn check_lock(entry: &Path) -> Result<(), Diagnostic> {
+    let Some(manifest) = Manifest::find(entry)? else {
+        return Ok(());
+    };
+    let dir = entry.parent().unwrap_or(Path::new(""));
+    let lock_path = dir.join(LOCK_FILE);
+    let file = lock_path.display().to_string();
+    let mismatch = |message: String| {
+        error(LOCK_MISMATCH, &file, message)
+            .with_help("run `wineglass install` to bring it up to date")
+    };
+    if !lock_path.is_file() {
+        return match manifest.dependencies.is_empty() {
+            true => Ok(()),
+            false => Err(mismatch(format!("{} is missing", LOCK_FILE))),
+        };
+    }
+    let lock = Lockfile::load(&lock_path)?;
+    for (name, req) in &manifest.dependencies {
+        let Some(locked) = lock.get(name) else {
+            return Err(mismatch(format!("`{}` is not locked", name)));
+        };
+        let req = VersionReq::parse(req).map_err(|e| error(LOCK_MISMATCH, &file, e.message))?;
+        let version =
+            Version::parse(&locked.version).map_err(|e| error(LOCK_MISMATCH, &file, e.message))?;
+        if !req.matches(&version) {
+            return Err(error(
+                LOCK_MISMATCH,
+                &file,
+                format!(
+                    "`{}` is locked to {} but {} wants {}",
+                    name, version, MANIFEST_FILE, req
+                ),
+            )
+            .with_help(format!("run `wineglass update {}`", name)));
+        }
+    }
+    let mut reachable = BTreeSet::new();
+    let mut stack: Vec<&String> = manifest.dependencies.keys().collect();
+    while let Some(name) = stack.pop() {
+        if let Some(locked) = lock.get(name).filter(|_| reachable.insert(name.clone())) {
+            stack.extend(&locked.dependencies);
+        }
+    }
+    for locked in &lock.bottles {
+        if !reachable.contains(&locked.name) {
+            return Err(mismatch(format!(
+                "`{}` is locked but nothing needs it",
+                locked.name
+            )));
+        }
+        let installed = dir.join(BOTTLES_DIR).join(&locked.name).join(MANIFEST_FILE);
+        match Manifest::load(&installed) {
+            Ok(m) if m.version == locked.version => {}
+            Ok(m) => {
+                return Err(mismatch(format!(
+                    "`{}` is locked to {} but {} is installed",
+                    locked.name, locked.version, m.version
+                )))
+            }
+            Err(_) => {
+                return Err(mismatch(format!(
+                    "`{}` {} is locked but not installed",
+                    locked.name, locked.version
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn io_error(path: &Path, why: std::io::Error) -> Diagnostic {
+    error(
+        crate::READ_ERROR,
+        &path.display().to_string(),
+        why.to_string(),
+    )
+}
+
+fn error(code: i32, file: &str, message: String) -> Diagnostic {
+    Diagnostic::Error(err::Error {
+        code,
+        file: file.to_owned(),
+        message,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::PackedFile;
+
+    /// Writes an archive of `name` at `version`, needing `deps`, into `registry`.
+    fn publish(registry: &Path, name: &str, version: &str, deps: &[(&str, &str)]) {
+        let mut archive = Archive {
+            manifest: Manifest {
+                name: name.to_owned(),
+                version: version.to_owned(),
+                description: String::new(),
+                entry: "main.wg".to_owned(),
+                capabilities: Vec::new(),
+                dependencies: deps
+                    .iter()
+                    .map(|(dep, req)| (dep.to_string(), req.to_string()))
+                    .collect(),
+            },
+            files: vec![PackedFile {
+                path: "main.wg".to_owned(),
+                text: "fn main() -> i64 {\n    return 0;\n}\n".to_owned(),
+                deps: Vec::new(),
+            }],
+            compiler: env!("CARGO_PKG_VERSION").to_owned(),
+            bytecode: None,
+            hash: String::new(),
+        };
+        archive.hash = archive.content_hash();
+        let file = format!("{}-{}.{}", name, version, ARCHIVE_EXTENSION);
+        archive.write(&registry.join(file)).unwrap();
+    }
+
+    #[test]
+    fn picking_again_drops_what_the_old_pick_wanted() {
+        let dir = std::env::temp_dir().join(format!("wineglass-pkg-{}", std::process::id()));
+        let registry = dir.join("registry");
+        fs::create_dir_all(&registry).unwrap();
+        publish(&registry, "c", "1.0.0", &[]);
+        publish(&registry, "c", "2.0.0", &[]);
+        publish(&registry, "z", "1.0.0", &[("c", "^1")]);
+        publish(&registry, "z", "2.0.0", &[("c", "^2")]);
+        publish(&registry, "b", "1.0.0", &[("z", "^1")]);
+        let manifest = Manifest {
+            name: "app".to_owned(),
+            version: "0.1.0".to_owned(),
+            description: String::new(),
+            entry: "main.wg".to_owned(),
+            capabilities: Vec::new(),
+            dependencies: BTreeMap::from([
+                ("b".to_owned(), "*".to_owned()),
+                ("z".to_owned(), "*".to_owned()),
+            ]),
+        };
+        let manager = PackageManager {
+            project: dir.clone(),
+            registry: Some(registry),
+            cache: dir.join("cache"),
+        };
+        let chosen = manager
+            .resolve(&manifest, &Lockfile::default(), &BTreeSet::new())
+            .expect("the requirements can all be met");
+        let picks: Vec<String> = chosen
+            .iter()
+            .map(|(name, c)| format!("{} {}", name, c.version))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(picks, ["b 1.0.0", "c 1.0.0", "z 1.0.0"]);
+    }
+}
@@ -0,0 +1,575 @@
+//! An interactive session. Each entry is parsed as a small bottle of its own and added to one
+//! that lives as long as the session, so its functions, globals and dependencies stay around
+//! for the entries after it. An entry that spills is reported and the session carries on.
+use crate::deps::DependencyGraph;
+use crate::err::{Diagnostic, Emitter, Source};
+use crate::interp::{Interpreter, Value, ENTRY_FRAME};
+use crate::spill::Spill;
+use ast::{AstNode, NodeKind};
+use log::*;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use typed_arena::Arena;
+
+/// The name entries are parsed and reported under.
+pub const REPL_FILE: &str = "<repl>";
+/// Entries kept in the history file; older ones are dropped.
+pub const HISTORY_LIMIT: usize = 1000;
+const PROMPT: &str = ">>> ";
+const CONTINUE: &str = "... ";
+
+const HELP: &str = "\
+Enter statements, functions or an expression to see its value. Input that is not finished
+yet, such as an open `{`, continues on the next line; two empty lines give up on it.
+
+  :ast [code]   Print the tree for code, or for the last entry
+  :state        List the globals, functions and dependencies defined so far
+  :load <file>  Run a file's top level and keep what it defines
+  :history      List earlier entries
+  :reset        Forget everything defined so far
+  :help         Show this
+  :quit         Leave, as end of input does";
+
+/// What the session should do after a line.
+enum Next {
+    Read,
+    Quit,
+}
+
+pub struct Repl<'a> {
+    sources: &'a Arena<Source>,
+    nodes: &'a Arena<AstNode<'a>>,
+    interp: Interpreter<'a>,
+    emitter: Emitter,
+    /// Whether to show prompts, which only make sense when someone is typing.
+    pub prompt: bool,
+    /// Every entry, oldest first, including those from earlier sessions.
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+    /// The tree of the last entry that parsed, for `:ast`.
+    last: Option<&'a AstNode<'a>>,
+    /// How the last entry that failed spilled.
+    spill: Option<Spill>,
+    /// The source of the entry or file that defined each function, for errors raised inside it.
+    definitions: HashMap<String, &'a str>,
+}
+
+impl<'a> Repl<'a> {
+    /// A session that allocates the sources and trees of its entries in `sources` and `nodes`,
+    /// which must outlive it. Diagnostics are written by `emitter`.
+    pub fn new(
+        sources: &'a Arena<Source>,
+        nodes: &'a Arena<AstNode<'a>>,
+        emitter: Emitter,
+    ) -> Self {
+        Self {
+            sources,
+            nodes,
+            interp: Interpreter::new(REPL_FILE),
+            emitter,
+            prompt: false,
+            history: Vec::new(),
+            history_file: None,
+            last: None,
+            spill: None,
+            definitions: HashMap::new(),
+        }
+    }
+
+    /// Keeps the history in `path`, starting with what is already there.
+    pub fn with_history(mut self, path: PathBuf) -> Self {
+        self.history = read_history(&path);
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.drain(..self.history.len() - HISTORY_LIMIT);
+            if let Err(why) = write_history(&path, &self.history) {
+                warn!("couldn't trim history {}: {}", path.display(), why);
+            }
+        }
+        self.history_file = Some(path);
+        self
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Reads entries from `input` until it ends or `:quit`. Values and command output go to
+    /// `out`, prompts and diagnostics to `err`.
+    pub fn run(
+        &mut self,
+        input: impl BufRead,
+        out: &mut impl Write,
+        err: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut lines = input.lines();
+        let mut buffer = String::new();
+        let mut blank = false;
+        loop {
+            if self.prompt {
+                write!(err, "{}", if buffer.is_empty() { PROMPT } else { CONTINUE })?;
+                err.flush()?;
+            }
+            let Some(line) = lines.next().transpose()? else {
+                if !buffer.trim().is_empty() {
+                    self.submit(&buffer, true, out, err)?;
+                }
+                return Ok(());
+            };
+            if buffer.is_empty() && line.trim_start().starts_with(':') {
+                self.remember(line.trim());
+                match self.command(line.trim(), out, err)? {
+                    Next::Read => continue,
+                    Next::Quit => return Ok(()),
+                }
+            }
+            if buffer.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            // A second empty line in a row gives up waiting for the entry to be finished.
+            let give_up = std::mem::replace(&mut blank, line.trim().is_empty()) && blank;
+            buffer.push_str(&line);
+            buffer.push('\n');
+            if self.submit(&buffer, give_up, out, err)? {
+                buffer.clear();
+                blank = false;
+            }
+        }
+    }
+
+    /// Runs `text` as one entry. Returns false, doing nothing, when it is unfinished and more
+    /// input is wanted, unless `finished` says no more is coming.
+    fn submit(
+        &mut self,
+        text: &str,
+        finished: bool,
+        out: &mut impl Write,
+        err: &mut impl Write,
+    ) -> io::Result<bool> {
+        let entry = Some(text.trim_end().to_owned());
+        let graph = match self.resolve(Path::new(REPL_FILE), entry, err)? {
+            Err(d) if d.get_code() == crate::INCOMPLETE_INPUT && !finished => return Ok(false),
+            Err(d) => {
+                self.remember(text.trim_end());
+                self.report(d, Some(text), false, err)?;
+                return Ok(true);
+            }
+            Ok(graph) => graph,
+        };
+        self.remember(text.trim_end());
+        self.last = Some(graph.units[0].ast.head);
+        self.define(&graph);
+        match self.interp.extend(&graph) {
+            Ok(Some(Value::Void)) | Ok(None) => {}
+            Ok(Some(value)) => writeln!(out, "{}", show(&value))?,
+            Err(d) => self.report(d, Some(text), true, err)?,
+        }
+        Ok(true)
+    }
+
+    /// Runs a `:command` line.
+    fn command(
+        &mut self,
+        line: &str,
+        out: &mut impl Write,
+        err: &mut impl Write,
+    ) -> io::Result<Next> {
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match command {
+            ":quit" | ":q" => return Ok(Next::Quit),
+            ":help" | ":h" => writeln!(out, "{}", HELP)?,
+            ":ast" if arg.is_empty() => match self.last {
+                Some(head) => writeln!(out, "{:#?}", head)?,
+                None => writeln!(err, "nothing has been entered yet")?,
+            },
+            ":ast" => {
+                let text = &self
+                    .sources
+                    .alloc(Source {
+                        name: REPL_FILE.to_owned(),
+                        text: arg.to_owned(),
+                    })
+                    .text;
+                match crate::parse(REPL_FILE, text, self.nodes) {
+                    Ok(ast) => writeln!(out, "{:?}", ast)?,
+                    Err(d) => self.report(d, Some(text), false, err)?,
+                }
+            }
+            ":state" => self.state(out)?,
+            ":load" if arg.is_empty() => writeln!(err, "usage: :load <file>")?,
+            ":load" => self.load(Path::new(arg), out, err)?,
+            ":history" => {
+                for (n, entry) in self.history.iter().enumerate() {
+                    writeln!(out, "{:>4}  {}", n + 1, entry.replace('\n', "\n      "))?;
+                }
+            }
+            ":reset" => {
+                self.interp = Interpreter::new(REPL_FILE);
+                self.last = None;
+                self.spill = None;
+                self.definitions.clear();
+            }
+            _ => writeln!(err, "unknown command `{}`, try :help", command)?,
+        }
+        Ok(Next::Read)
+    }
+
+    /// Adds the top level of the bottle at `path` to the session, without calling its `main`.
+    pub fn load(
+        &mut self,
+        path: &Path,
+        out: &mut impl Write,
+        err: &mut impl Write,
+    ) -> io::Result<()> {
+        let graph = match crate::pkg::check_lock(path) {
+            Ok(()) => self.resolve(path, None, err)?,
+            Err(d) => Err(d),
+        };
+        let graph = match graph {
+            Ok(graph) => graph,
+            Err(d) => return self.report(d, None, false, err),
+        };
+        let before = self.interp.functions().len();
+        let source = graph.units[0].source;
+        self.define(&graph);
+        match self.interp.extend(&graph) {
+            Ok(_) => writeln!(
+                out,
+                "loaded {} ({} new functions)",
+                path.display(),
+                self.interp.functions().len() - before
+            ),
+            Err(d) => self.report(d, Some(source), true, err),
+        }
+    }
+
+    /// Parses `path` and what it depends on, failing on the first error. Warnings are written
+    /// to `err` as they are found.
+    fn resolve(
+        &self,
+        path: &Path,
+        text: Option<String>,
+        err: &mut impl Write,
+    ) -> io::Result<Result<DependencyGraph<'a>, Diagnostic>> {
+        // What earlier entries defined is in scope, though not in this entry's source.
+        let known: Vec<&str> = (self.interp.globals().into_iter().map(|(name, _)| name))
+            .chain(self.definitions.keys().map(String::as_str))
+            .chain(
+                self.interp
+                    .dependencies()
+                    .into_iter()
+                    .map(|(name, ..)| name),
+            )
+            .collect();
+        let (graph, diagnostics) =
+            match DependencyGraph::resolve_in(path, text, &known, self.sources, self.nodes) {
+                Ok(resolved) => resolved,
+                Err(d) => return Ok(Err(d)),
+            };
+        for d in diagnostics {
+            match d {
+                Diagnostic::Warning(_) | Diagnostic::Info(_) => {
+                    let file = d.get_file();
+                    let unit = graph.units.iter().find(|u| u.file() == file);
+                    writeln!(err, "{}", self.emitter.emit(&d, unit.map(|u| u.source)))?;
+                }
+                d => return Ok(Err(d)),
+            }
+        }
+        Ok(Ok(graph))
+    }
+
+    /// Writes `:state`: the globals, functions and dependencies defined so far, and the last
+    /// spill.
+    fn state(&self, out: &mut impl Write) -> io::Result<()> {
+        for (name, value) in self.interp.globals() {
+            writeln!(out, "let {}: {} = {}", name, value.type_name(), show(value))?;
+        }
+        for function in self.interp.functions() {
+            let NodeKind::Function {
+                name,
+                params,
+                return_type,
+                ..
+            } = &function.kind
+            else {
+                continue;
+            };
+            let params: Vec<String> = params
+                .iter()
+                .filter_map(|p| match &p.kind {
+                    NodeKind::Declaration {
+                        name, struct_type, ..
+                    } => Some(format!("{}: {}", name, struct_type)),
+                    _ => None,
+                })
+                .collect();
+            writeln!(out, "fn {}({}) -> {}", name, params.join(", "), return_type)?;
+        }
+        for (name, kind, file) in self.interp.dependencies() {
+            match file {
+                Some(file) => writeln!(out, "{} {} from {}", kind, name, file)?,
+                None => writeln!(out, "{} {} (not found)", kind, name)?,
+            }
+        }
+        if let Some(spill) = &self.spill {
+            writeln!(out, "last {}", spill)?;
+        }
+        Ok(())
+    }
+
+    /// Remembers where the functions the first unit of `graph` defines came from.
+    fn define(&mut self, graph: &DependencyGraph<'a>) {
+        let unit = &graph.units[0];
+        if let NodeKind::Root { children } = &unit.ast.head.kind {
+            for child in children {
+                if let NodeKind::Function { name, .. } = &child.kind {
+                    self.definitions.insert(name.clone(), unit.source);
+                }
+            }
+        }
+    }
+
+    /// Writes the diagnostic an entry failed with and keeps it as the session's last spill.
+    /// `text` is the entry's source; an error raised inside a function is shown in the source
+    /// that defined it, and one raised in a dependency without source.
+    fn report(
+        &mut self,
+        d: Diagnostic,
+        text: Option<&str>,
+        ran: bool,
+        err: &mut impl Write,
+    ) -> io::Result<()> {
+        let trace: Vec<String> = match ran {
+            true => self
+                .interp
+                .error_trace()
+                .iter()
+                .filter(|f| *f != ENTRY_FRAME)
+                .cloned()
+                .collect(),
+            false => Vec::new(),
+        };
+        let text = match trace.last().and_then(|f| self.definitions.get(f)) {
+            Some(definition) => Some(*definition),
+            None => text,
+        };
+        let source = text
+            .filter(|_| !ran || d.get_file() == REPL_FILE)
+            .map(|text| Source {
+                name: d.get_file(),
+                text: text.to_owned(),
+            });
+        let spill = Spill {
+            bottle: REPL_FILE.to_owned(),
+            diagnostic: d,
+            source,
+            trace,
+            cause: None,
+        };
+        writeln!(err, "{}", spill.emit(self.emitter))?;
+        self.spill = Some(spill);
+        Ok(())
+    }
+
+    /// Adds `entry` to the history, and to the history file if there is one.
+    fn remember(&mut self, entry: &str) {
+        if self.history.last().is_some_and(|last| last == entry) {
+            return;
+        }
+        self.history.push(entry.to_owned());
+        let Some(path) = &self.history_file else {
+            return;
+        };
+        let line = serde_json::to_string(entry).expect("strings always serialize");
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(why) = written {
+            warn!("couldn't save history to {}: {}", path.display(), why);
+        }
+    }
+}
+
+/// `value` as it would be written in source, so strings are quoted.
+fn show(value: &Value) -> String {
+    match value {
+        Value::Str(s) => format!("{:?}", s),
+        value => value.to_string(),
+    }
+}
+
+/// The entries in a history file, one JSON string per line. Lines that are not are skipped.
+fn read_history(path: &Path) -> Vec<String> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    text.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn write_history(path: &Path, entries: &[String]) -> io::Result<()> {
+    let mut text = String::new();
+    for entry in entries {
+        text.push_str(&serde_json::to_string(entry).expect("strings always serialize"));
+        text.push('\n');
+    }
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::err::Renderer;
+
+    /// Feeds `input` to `repl`, returning what it wrote to `out` and to `err`.
+    fn feed(repl: &mut Repl, input: &str) -> (String, String) {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        repl.run(input.as_bytes(), &mut out, &mut err).unwrap();
+        let text = |bytes| String::from_utf8(bytes).unwrap();
+        (text(out), text(err))
+    }
+
+    /// A fresh session fed `input`.
+    fn session(input: &str) -> (String, String) {
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let mut repl = Repl::new(&sources, &nodes, Emitter::Human(Renderer::plain()));
+        feed(&mut repl, input)
+    }
+
+    fn temp(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wineglass-repl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn entries_see_what_earlier_ones_defined() {
+        let input = "\
+let a = 2;
+fn sq(x: i64) -> i64 { return x * x; }
+sq(a) + 1
+a = 4;
+sq(a)
+\"wine\"
+";
+        let (out, err) = session(input);
+        assert_eq!(out, "5\n16\n\"wine\"\n");
+        assert_eq!(err, "");
+    }
+
+    #[test]
+    fn unfinished_entries_carry_on_until_two_empty_lines() {
+        let (out, err) = session("fn three() -> i64 {\n    return 3;\n}\nthree()\n");
+        assert_eq!((out.as_str(), err.as_str()), ("3\n", ""));
+        let (out, err) = session("let a = (\n\n\n1\n");
+        assert_eq!(out, "1\n");
+        assert!(err.starts_with("error[1003]"), "{}", err);
+    }
+
+    #[test]
+    fn spills_are_reported_and_the_session_carries_on() {
+        let (out, err) = session("let zero = 0;\n1 / zero\nzero + 2\n:state\n");
+        assert!(err.contains("division by zero"), "{}", err);
+        assert!(err.contains("1 / zero"), "{}", err);
+        assert_eq!(
+            out,
+            "2\nlet zero: i64 = 0\nlast bottle <repl> spilled: error[3004]: division by zero (in `<repl>`)\n"
+        );
+    }
+
+    #[test]
+    fn state_lists_and_reset_forgets_definitions() {
+        let input = "\
+let a = 1.5;
+fn half(x: f64) -> f64 { return x / 2; }
+:state
+:reset
+:state
+a
+";
+        let (out, err) = session(input);
+        assert_eq!(out, "let a: f64 = 1.5\nfn half(x: f64) -> f64\n");
+        assert!(err.contains("undefined variable `a`"), "{}", err);
+    }
+
+    #[test]
+    fn commands_are_answered_and_quit_stops_reading() {
+        let (out, err) = session(":help\n:bogus\n:load\n:ast\n:quit\n1\n");
+        assert_eq!(out, format!("{}\n", HELP));
+        assert_eq!(
+            err,
+            "unknown command `:bogus`, try :help\nusage: :load <file>\n\
+             nothing has been entered yet\n"
+        );
+        let (out, _) = session("1 + 2\n:ast\n:ast true\n");
+        assert!(out.starts_with("3\n"), "{}", out);
+        assert!(out.contains("Binary"), "{}", out);
+        assert!(out.contains("Bool"), "{}", out);
+    }
+
+    #[test]
+    fn load_keeps_what_a_file_defines() {
+        let file = temp("load.wg");
+        fs::write(
+            &file,
+            "let base = 40;\nfn answer() -> i64 { return base + 2; }\n",
+        )
+        .unwrap();
+        let (out, err) = session(&format!(":load {}\nanswer()\n", file.display()));
+        assert_eq!(
+            out,
+            format!("loaded {} (1 new functions)\n42\n", file.display())
+        );
+        assert_eq!(err, "");
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn history_is_kept_across_sessions() {
+        let file = temp("history");
+        let _ = fs::remove_file(&file);
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let emitter = Emitter::Human(Renderer::plain());
+        let mut repl = Repl::new(&sources, &nodes, emitter).with_history(file.clone());
+        feed(
+            &mut repl,
+            "1\n1\nfn f() -> i64 {\n    return 2;\n}\n:history\n",
+        );
+        assert_eq!(
+            repl.history(),
+            ["1", "fn f() -> i64 {\n    return 2;\n}", ":history"]
+        );
+        let repl = Repl::new(&sources, &nodes, emitter).with_history(file.clone());
+        assert_eq!(repl.history().len(), 3);
+        let (out, _) = feed(
+            &mut Repl::new(&sources, &nodes, emitter).with_history(file.clone()),
+            ":history\n",
+        );
+        assert_eq!(
+            out,
+            "   1  1\n   2  fn f() -> i64 {\n          return 2;\n      }\n   3  :history\n"
+        );
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn history_files_are_trimmed_to_the_limit() {
+        let file = temp("long-history");
+        let entries: Vec<String> = (0..HISTORY_LIMIT + 5).map(|n| n.to_string()).collect();
+        write_history(&file, &entries).unwrap();
+        fs::write(&file, fs::read_to_string(&file).unwrap() + "not json\n").unwrap();
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let repl = Repl::new(&sources, &nodes, Emitter::Json).with_history(file.clone());
+        assert_eq!(repl.history(), &entries[5..]);
+        assert_eq!(read_history(&file), &entries[5..]);
+        fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,625 @@
+//! Runs many bottles at once.
+//!
+//! Submitted bottles stay [`State::Racked`] in a queue until one of at most `max-threads`
+//! workers takes them, oldest first. Workers start as bottles are submitted and stop once the
+//! queue is empty. A bottle that `lock`s another gives its worker back while it waits, parked
+//! as [`Pause::Waiting`], and is [`Pause::Blocked`] while it waits for a worker to resume on.
+//! A parked bottle keeps its thread, so a spare worker may stand in for it meanwhile: there are
+//! never more than `max-threads` threads plus one for each parked bottle.
+use crate::deps;
+use crate::err::{self, Diagnostic};
+use crate::interp::{self, Value};
+use crate::spill::Spill;
+use crate::state::{Critical, Pause};
+use crate::{Bottle, State};
+use log::*;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Used when `max-threads` is not given. Matches the default `configmgr` reserves for it.
+pub const DEFAULT_MAX_THREADS: usize = 3;
+
+/// Index of a bottle in its [`Scheduler`].
+pub type BottleId = usize;
+
+/// Lets the scheduler stop a running bottle. The engines check it on every call and loop
+/// iteration and fail with [`crate::interp::INTERRUPTED`] once it has been raised.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicU32>);
+
+impl Interrupt {
+    /// Asks the bottle to stop and end up in `state`. Only the first request counts.
+    /// [`State::Completed`] encodes as zero and cannot be requested.
+    pub fn raise(&self, state: State) {
+        let _ = self
+            .0
+            .compare_exchange(0, state.encode(), Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// The state a stop was requested with, if any.
+    pub fn raised(&self) -> Option<State> {
+        match self.0.load(Ordering::SeqCst) {
+            0 => None,
+            raw => State::decode(raw).ok(),
+        }
+    }
+
+    /// Why the bottle has to stop, once a stop has been requested.
+    pub(crate) fn check(&self) -> Option<String> {
+        match self.raised()? {
+            State::Cascaded(origin) => Some(format!("required bottle #{} has spilled", origin)),
+            state => Some(format!("stopped from outside: {}", state)),
+        }
+    }
+}
+
+struct Slot {
+    name: String,
+    path: PathBuf,
+    state: State,
+    /// Has reached [`State::Executing`] at least once.
+    started: bool,
+    /// Its thread is done and `result` has been filled in.
+    done: bool,
+    result: Option<Result<i64, Diagnostic>>,
+    /// What a snippet's top level ran to, as [`Bottle::returned`].
+    returned: Option<Value>,
+    spill: Option<Spill>,
+    /// Bottles it requires directly. Indirect ones cascade through these.
+    requires: Vec<PathBuf>,
+    interrupt: Interrupt,
+}
+
+impl Slot {
+    fn finished(&self) -> bool {
+        self.state == State::Completed || self.state.is_failed()
+    }
+}
+
+struct Table {
+    slots: Vec<Slot>,
+    /// Racked bottles no worker has taken yet, oldest first.
+    ready: VecDeque<(BottleId, Bottle)>,
+    /// Bottles currently holding a worker.
+    running: usize,
+    /// Bottles that gave their worker back and still hold their thread.
+    parked: usize,
+    /// Parked bottles waiting for a worker to resume on. They go before anything in `ready`.
+    resuming: usize,
+    limit: usize,
+    /// Worker threads alive, including those held by parked bottles.
+    workers: usize,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Table {
+    /// Whether another worker is needed to take what is queued: none is idle, and fewer
+    /// than `limit` are free of a parked bottle.
+    fn short_of_workers(&self) -> bool {
+        let busy = self.running + self.parked;
+        !self.ready.is_empty() && self.workers <= busy && self.workers - self.parked < self.limit
+    }
+}
+
+struct Shared {
+    table: Mutex<Table>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn table(&self) -> MutexGuard<'_, Table> {
+        // A bottle that panicked was already recorded as critical; the table is still sound.
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait<'t>(&self, table: MutexGuard<'t, Table>) -> MutexGuard<'t, Table> {
+        self.changed.wait(table).unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn submit(self: &Arc<Self>, mut bottle: Bottle) -> BottleId {
+        let racked = bottle.rack();
+        self.rack(&mut self.table(), bottle, racked)
+    }
+
+    /// Gives `bottle` a slot and queues it, or when Winecellar turned it away, `racked`,
+    /// records how it failed without ever running it.
+    fn rack(
+        self: &Arc<Self>,
+        table: &mut Table,
+        mut bottle: Bottle,
+        racked: Result<(), Diagnostic>,
+    ) -> BottleId {
+        let id = table.slots.len();
+        table.slots.push(Slot {
+            name: bottle.name.clone(),
+            path: deps::canonical(&bottle.path),
+            state: bottle.state(),
+            started: false,
+            done: racked.is_err(),
+            result: None,
+            returned: None,
+            spill: bottle.spill().cloned(),
+            requires: Vec::new(),
+            interrupt: Interrupt::default(),
+        });
+        if let Err(d) = racked {
+            debug!("Bottle #{} {} was turned away", id, bottle.name);
+            table.slots[id].result = Some(Err(d));
+            self.changed.notify_all();
+            return id;
+        }
+        let handle = Handle {
+            shared: Arc::clone(self),
+            id,
+        };
+        bottle.host = Some(handle);
+        debug!("Racked bottle #{} {}", id, bottle.name);
+        table.ready.push_back((id, bottle));
+        self.hire(table);
+        self.changed.notify_all();
+        id
+    }
+
+    /// Starts workers until there are enough for what is queued.
+    fn hire(self: &Arc<Self>, table: &mut Table) {
+        while table.short_of_workers() {
+            let shared = Arc::clone(self);
+            let n = table.workers;
+            let thread = thread::Builder::new()
+                .name(format!("wineglass-worker-{}", n))
+                .stack_size(interp::STACK_SIZE)
+                .spawn(move || shared.work())
+                .expect("failed to spawn a worker thread");
+            table.workers += 1;
+            table.threads.push(thread);
+        }
+    }
+
+    /// A worker's loop: runs queued bottles one after another, and stops once the queue is
+    /// empty or the parked bottles it stood in for have resumed.
+    fn work(self: Arc<Self>) {
+        let mut table = self.table();
+        while !table.ready.is_empty() && table.workers - table.parked <= table.limit {
+            if table.running + table.resuming >= table.limit {
+                table = self.wait(table);
+                continue;
+            }
+            let (id, bottle) = table.ready.pop_front().expect("checked above");
+            table.running += 1;
+            drop(table);
+            let handle = Handle {
+                shared: Arc::clone(&self),
+                id,
+            };
+            handle.run(bottle);
+            table = self.table();
+            table.running -= 1;
+            self.changed.notify_all();
+        }
+        table.workers -= 1;
+        drop(table);
+        self.changed.notify_all();
+    }
+
+    /// Gives the worker of a bottle that is about to park back, standing a spare in for it if
+    /// something is queued.
+    fn release(self: &Arc<Self>) {
+        let mut table = self.table();
+        table.running -= 1;
+        table.parked += 1;
+        self.hire(&mut table);
+        drop(table);
+        self.changed.notify_all();
+    }
+
+    /// Blocks a parked bottle until a worker is free and takes it.
+    fn acquire(&self) {
+        let mut table = self.table();
+        table.resuming += 1;
+        while table.running >= table.limit {
+            table = self.wait(table);
+        }
+        table.resuming -= 1;
+        table.parked -= 1;
+        table.running += 1;
+    }
+}
+
+/// Owns a pool of workers and every bottle submitted to it.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+impl Scheduler {
+    /// A scheduler running at most `max_threads` bottles at once. Zero is treated as one.
+    pub fn new(max_threads: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                table: Mutex::new(Table {
+                    slots: Vec::new(),
+                    ready: VecDeque::new(),
+                    running: 0,
+                    parked: 0,
+                    resuming: 0,
+                    limit: max_threads.max(1),
+                    workers: 0,
+                    threads: Vec::new(),
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn max_threads(&self) -> usize {
+        self.shared.table().limit
+    }
+
+    /// Racks `bottle` and starts it as soon as a worker is free.
+    pub fn submit(&self, bottle: Bottle) -> BottleId {
+        self.shared.submit(bottle)
+    }
+
+    /// The current state of bottle `id`.
+    pub fn state(&self, id: BottleId) -> Option<State> {
+        self.shared.table().slots.get(id).map(|s| s.state)
+    }
+
+    /// The name and current state of every bottle, indexed by [`BottleId`].
+    pub fn states(&self) -> Vec<(String, State)> {
+        self.shared
+            .table()
+            .slots
+            .iter()
+            .map(|s| (s.name.clone(), s.state))
+            .collect()
+    }
+
+    /// Blocks until bottle `id` has completed or failed and returns what `main` produced.
+    /// The result can only be taken once.
+    pub fn wait(&self, id: BottleId) -> Option<Result<i64, Diagnostic>> {
+        let mut table = self.shared.table();
+        while !table.slots.get(id)?.done {
+            table = self.shared.wait(table);
+        }
+        table.slots[id].result.take()
+    }
+
+    /// What the top level of snippet `id` ran to, once it has completed.
+    pub fn returned(&self, id: BottleId) -> Option<Value> {
+        self.shared.table().slots.get(id)?.returned.clone()
+    }
+
+    /// How bottle `id` spilled, if it did.
+    pub fn spill(&self, id: BottleId) -> Option<Spill> {
+        self.shared.table().slots.get(id)?.spill.clone()
+    }
+
+    /// Follows [`State::Cascaded`] pointers from `id`. The first entry is `id` itself and the
+    /// last is the bottle the failure started in.
+    pub fn causes(&self, id: BottleId) -> Vec<BottleId> {
+        let table = self.shared.table();
+        let mut chain = Vec::new();
+        let mut at = Some(id);
+        while let Some(id) = at.filter(|id| *id < table.slots.len() && !chain.contains(id)) {
+            chain.push(id);
+            at = match table.slots[id].state {
+                State::Cascaded(origin) => Some(origin as BottleId),
+                _ => None,
+            };
+        }
+        chain
+    }
+
+    /// The spill the failure of `id` started with, following cascades back to it.
+    pub fn root_cause(&self, id: BottleId) -> Option<Spill> {
+        self.spill(*self.causes(id).last()?)
+    }
+
+    /// Worker threads currently alive.
+    pub fn workers(&self) -> usize {
+        self.shared.table().workers
+    }
+
+    /// Blocks until every bottle, including those started through `lock`, has finished.
+    pub fn join(&self) {
+        loop {
+            let threads = std::mem::take(&mut self.shared.table().threads);
+            if threads.is_empty() {
+                return;
+            }
+            for thread in threads {
+                // Panics are caught and recorded inside the thread.
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// A bottle's link back to the scheduler running it.
+#[derive(Clone)]
+pub(crate) struct Handle {
+    shared: Arc<Shared>,
+    id: BottleId,
+}
+
+impl Handle {
+    /// Runs `bottle` on the worker this is called from, which it holds already.
+    fn run(&self, mut bottle: Bottle) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| bottle.start()));
+        let result = result.unwrap_or_else(|_| {
+            let to = State::Critical(Critical::Unknown);
+            if let Err(r) = bottle.set_state(to) {
+                warn!("{}", r.display());
+            }
+            Err(Diagnostic::Fatal(err::Fatal {
+                code: 0,
+                file: bottle.path.display().to_string(),
+                message: format!("bottle {} panicked", bottle.name),
+                ..Default::default()
+            }))
+        });
+        let mut table = self.shared.table();
+        table.slots[self.id].result = Some(result);
+        table.slots[self.id].returned = bottle.returned().cloned();
+        table.slots[self.id].spill = bottle.spill().cloned();
+        table.slots[self.id].done = true;
+        drop(table);
+        self.shared.changed.notify_all();
+    }
+
+    /// Records a state change of the bottle this handle belongs to. A failure stops every
+    /// unfinished bottle that requires this one, as cascaded from it.
+    pub(crate) fn publish(&self, state: State) {
+        let mut table = self.shared.table();
+        let slot = &mut table.slots[self.id];
+        slot.state = state;
+        slot.started |= state == State::Executing;
+        if state.is_failed() {
+            let path = slot.path.clone();
+            for (id, slot) in table.slots.iter().enumerate() {
+                if id != self.id && !slot.finished() && slot.requires.contains(&path) {
+                    debug!("Cascading {} from bottle #{}", slot.name, self.id);
+                    slot.interrupt.raise(State::Cascaded(self.id as u32));
+                }
+            }
+        }
+        drop(table);
+        self.shared.changed.notify_all();
+    }
+
+    pub(crate) fn interrupt(&self) -> Interrupt {
+        self.shared.table().slots[self.id].interrupt.clone()
+    }
+
+    /// Records what the bottle requires. Returns a scheduled bottle among them that has already
+    /// failed, if any.
+    pub(crate) fn requires(&self, paths: Vec<PathBuf>) -> Option<BottleId> {
+        let mut table = self.shared.table();
+        let failed = table
+            .slots
+            .iter()
+            .position(|s| s.state.is_failed() && paths.contains(&s.path));
+        table.slots[self.id].requires = paths;
+        failed
+    }
+
+    /// The state the scheduler last saw this bottle in.
+    pub(crate) fn state(&self) -> State {
+        self.shared.table().slots[self.id].state
+    }
+
+    /// Parks the bottle as `pause`, giving its worker back, for `duration` or until it is
+    /// interrupted, then takes a worker again. With no `duration` it only lets a bottle
+    /// waiting for a worker go first.
+    pub(crate) fn park(&self, pause: Pause, duration: Duration) {
+        self.publish(State::Paused(pause, 0));
+        self.shared.release();
+        let deadline = Instant::now() + duration;
+        let interrupt = self.interrupt();
+        let mut table = self.shared.table();
+        while interrupt.raised().is_none() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            table = self
+                .shared
+                .changed
+                .wait_timeout(table, left)
+                .map_or_else(|e| e.into_inner().0, |(table, _)| table);
+        }
+        drop(table);
+        self.publish(State::Paused(Pause::Blocked, 0));
+        self.shared.acquire();
+        self.publish(State::Executing);
+    }
+
+    /// Lets `n` bottles hold a worker at once from now on.
+    pub(crate) fn set_max_threads(&self, n: usize) {
+        let mut table = self.shared.table();
+        table.limit = n.max(1);
+        self.shared.hire(&mut table);
+        drop(table);
+        self.shared.changed.notify_all();
+    }
+
+    /// The bottle already scheduled from `path`, or a new one racked for it, run the way `from`
+    /// is.
+    pub(crate) fn find_or_submit(
+        &self,
+        path: &Path,
+        name: &str,
+        from: &Bottle,
+    ) -> Result<BottleId, String> {
+        let path = deps::canonical(path);
+        let find = |table: &Table| table.slots.iter().position(|s| s.path == path);
+        if let Some(id) = find(&self.shared.table()) {
+            return Ok(id);
+        }
+        // Racking loads the bottle, which is too slow to do holding the table.
+        let mut bottle = from.sibling(&path, name)?;
+        let racked = bottle.rack();
+        let mut table = self.shared.table();
+        match find(&table) {
+            Some(id) => Ok(id),
+            None => Ok(self.shared.rack(&mut table, bottle, racked)),
+        }
+    }
+
+    /// Parks `bottle` as [`Pause::Waiting`] until `target` has started running, then takes a
+    /// worker again. Returns the state `target` was in when the wait ended.
+    pub(crate) fn wait_running(
+        &self,
+        bottle: &mut Bottle,
+        target: BottleId,
+    ) -> Result<State, Diagnostic> {
+        let ready = |slot: &Slot| slot.started || slot.finished();
+        let table = self.shared.table();
+        if ready(&table.slots[target]) {
+            return Ok(table.slots[target].state);
+        }
+        drop(table);
+        bottle.transition(State::Paused(Pause::Waiting, 0))?;
+        self.shared.release();
+        let mut table = self.shared.table();
+        while !ready(&table.slots[target]) {
+            table = self.shared.wait(table);
+        }
+        let state = table.slots[target].state;
+        drop(table);
+        let blocked = bottle.transition(State::Paused(Pause::Blocked, 0));
+        // Take the worker back even on error; the caller gives it up when the bottle stops.
+        self.shared.acquire();
+        blocked?;
+        bottle.transition(State::Executing)?;
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sleeper(ms: u32) -> Bottle {
+        let source = format!(
+            "fn main() -> i64 {{\n    sleep!({});\n    return 7;\n}}\n",
+            ms
+        );
+        Bottle::from_source("sleeper.wg", source)
+    }
+
+    #[test]
+    fn never_runs_more_workers_than_allowed() {
+        let scheduler = Scheduler::new(2);
+        let ids: Vec<BottleId> = (0..8).map(|_| scheduler.submit(sleeper(0))).collect();
+        let mut most = 0;
+        while ids
+            .iter()
+            .any(|id| !scheduler.shared.table().slots[*id].done)
+        {
+            most = most.max(scheduler.workers());
+            thread::sleep(Duration::from_millis(1));
+        }
+        scheduler.join();
+        assert!(most <= 2, "{} workers for a limit of 2", most);
+        assert_eq!(scheduler.workers(), 0);
+        for id in ids {
+            assert_eq!(scheduler.wait(id).map(|r| r.ok()), Some(Some(7)));
+        }
+    }
+
+    #[test]
+    fn parked_bottles_free_their_worker() {
+        // Both sleep at once only if the first gives its worker to the second meanwhile.
+        let scheduler = Scheduler::new(1);
+        let started = Instant::now();
+        let ids = [
+            scheduler.submit(sleeper(300)),
+            scheduler.submit(sleeper(300)),
+        ];
+        scheduler.join();
+        assert!(started.elapsed() < Duration::from_millis(550));
+        for id in ids {
+            assert_eq!(scheduler.wait(id).map(|r| r.ok()), Some(Some(7)));
+        }
+    }
+
+    #[test]
+    fn lock_with_a_single_worker_does_not_deadlock() {
+        let dir = std::env::temp_dir().join(format!("wineglass-sched-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("server.wg"),
+            "fn main() -> i64 {\n    return 0;\n}\n",
+        )
+        .unwrap();
+        let main = dir.join("main.wg");
+        let source = "lock server from \"server.wg\"\n\nfn main() -> i64 {\n    return 3;\n}\n";
+        fs::write(&main, source).unwrap();
+        let scheduler = Scheduler::new(1);
+        let id = scheduler.submit(Bottle::new(&main, None, None, None).unwrap());
+        scheduler.join();
+        assert_eq!(scheduler.wait(id).map(|r| r.ok()), Some(Some(3)));
+        assert_eq!(scheduler.states().len(), 2);
+        assert_eq!(scheduler.workers(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bottles_winecellar_turns_away_never_run() {
+        let scheduler = Scheduler::new(1);
+        let source = "fn helper() -> i64 {\n    return 1;\n}\n".to_owned();
+        let id = scheduler.submit(Bottle::from_source("nomain.wg", source));
+        let code = crate::cellar::MISSING_ENTRY;
+        assert_eq!(scheduler.workers(), 0);
+        let result = scheduler.wait(id).expect("the bottle was submitted");
+        assert_eq!(result.err().map(|d| d.get_code()), Some(code));
+        assert_eq!(
+            scheduler.state(id),
+            Some(State::Shattered(crate::Shatter::Empty, code as u32))
+        );
+        scheduler.join();
+    }
+
+    #[test]
+    fn deep_recursion_spills_at_the_depth_limit() {
+        let source = "\
+fn down(n: i64) -> i64 {
+    if n == 0 {
+        return 0;
+    }
+    return down(n - 1) + 1;
+}
+
+fn main() -> i64 {
+    return down(100000);
+}
+";
+        let scheduler = Scheduler::new(2);
+        let ids = [crate::Backend::TreeWalk, crate::Backend::Bytecode].map(|backend| {
+            let mut bottle = Bottle::from_source("deep.wg", source.to_owned());
+            bottle.backend = backend;
+            scheduler.submit(bottle)
+        });
+        scheduler.join();
+        for id in ids {
+            let result = scheduler.wait(id).expect("the bottle was submitted");
+            assert_eq!(
+                result.err().map(|d| d.get_code()),
+                Some(interp::STACK_OVERFLOW)
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! What a bottle leaves behind when it spills.
+//!
+//! A bottle spills when an error escapes it. The [`Spill`] keeps the diagnostic that caused it
+//! along with the functions that were running at the time. Bottles that `require` a spilled
+//! bottle stop as [`State::Cascaded`](crate::State::Cascaded) and their spill points back at it
+//! through [`Spill::cause`], so a host can follow the chain to the root cause.
+use crate::err::{Diagnostic, Emitter, Renderer, Source};
+use crate::sched::BottleId;
+use std::fmt;
+
+/// A bottle stopped because a bottle it requires spilled.
+pub const CASCADED: i32 = 6001;
+
+/// How many frames at each end of a trace are shown. Those between them are only counted, so
+/// deep recursion doesn't bury the rest of the report.
+const TRACE_ENDS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct Spill {
+    /// Name of the bottle that spilled.
+    pub bottle: String,
+    pub diagnostic: Diagnostic,
+    /// The source the diagnostic points into, as it was when the bottle loaded it.
+    pub source: Option<Source>,
+    /// Functions that were executing when the error was raised, outermost first.
+    pub trace: Vec<String>,
+    /// The bottle whose spill this one cascaded from.
+    pub cause: Option<BottleId>,
+}
+
+impl Spill {
+    /// The diagnostic with the trace and cause added as notes.
+    pub fn report(&self) -> Diagnostic {
+        let mut diagnostic = self.diagnostic.clone();
+        if !self.trace.is_empty() {
+            diagnostic = diagnostic.with_note(format!("in {}", self.path()));
+        }
+        if let Some(cause) = self.cause {
+            diagnostic = diagnostic.with_note(format!("caused by bottle #{}", cause));
+        }
+        diagnostic
+    }
+
+    /// The trace as one line, outermost first, with the middle of a deep one cut out.
+    fn path(&self) -> String {
+        let hidden = self.trace.len().saturating_sub(2 * TRACE_ENDS);
+        // Cutting out a single frame would take as much room as showing it.
+        if hidden <= 1 {
+            return self.trace.join(" > ");
+        }
+        let (first, last) = (
+            &self.trace[..TRACE_ENDS],
+            &self.trace[TRACE_ENDS + hidden..],
+        );
+        format!(
+            "{} > … {} more > {}",
+            first.join(" > "),
+            hidden,
+            last.join(" > ")
+        )
+    }
+
+    /// [`Spill::report`] rendered against the source it points into.
+    pub fn render(&self, renderer: Renderer) -> String {
+        self.emit(Emitter::Human(renderer))
+    }
+
+    /// [`Spill::report`] written by `emitter`.
+    pub fn emit(&self, emitter: Emitter) -> String {
+        let source = self.source.as_ref().map(|s| s.text.as_str());
+        emitter.emit(&self.report(), source)
+    }
+}
+
+impl fmt::Display for Spill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bottle {} spilled: error[{}]: {}",
+            self.bottle,
+            self.diagnostic.get_code(),
+            self.diagnostic.get_message()
+        )?;
+        if !self.trace.is_empty() {
+            write!(f, "\n  in {}", self.path())?;
+        }
+        if let Some(cause) = self.cause {
+            write!(f, "\n  caused by bottle #{}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::err::Error;
+
+    fn spill(trace: &[&str]) -> Spill {
+        Spill {
+            bottle: "deep.wg".to_owned(),
+            diagnostic: Diagnostic::Error(Error::default()),
+            source: None,
+            trace: trace.iter().map(|f| (*f).to_owned()).collect(),
+            cause: None,
+        }
+    }
+
+    #[test]
+    fn short_traces_are_shown_whole() {
+        assert_eq!(spill(&["main", "div"]).path(), "main > div");
+        let nine = ["main", "a", "b", "c", "d", "e", "f", "g", "h"];
+        assert_eq!(spill(&nine).path(), nine.join(" > "));
+    }
+
+    #[test]
+    fn deep_traces_keep_their_ends() {
+        let mut trace = vec!["main"];
+        trace.extend(["down"; 511]);
+        let spill = spill(&trace);
+        let path = "main > down > down > down > … 504 more > down > down > down > down";
+        assert_eq!(spill.path(), path);
+        assert!(spill.to_string().ends_with(&format!("\n  in {}", path)));
+    }
+}
@@ -0,0 +1,397 @@
+//! The bottle state machine described in `docs/states.md`.
+//! Every [`State`] round-trips through the documented `u32` encoding.
+use crate::err::{InternalError, InternalReport};
+use std::fmt;
+
+/// Reported when a state change is not allowed from the current state.
+pub const ILLEGAL_TRANSITION: i64 = 0x5001;
+/// Reported when a raw `u32` does not fall in any documented range.
+pub const INVALID_STATE_CODE: i64 = 0x5002;
+
+/// The kinds of paused states (`0x00001kxx`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pause {
+    Sleeping,
+    Waiting,
+    Blocked,
+    Paused,
+    HardPaused,
+}
+
+impl Pause {
+    fn nibble(self) -> u32 {
+        match self {
+            Pause::Sleeping => 0x0,
+            Pause::Waiting => 0x1,
+            Pause::Blocked => 0x2,
+            Pause::Paused => 0x3,
+            Pause::HardPaused => 0x5,
+        }
+    }
+}
+
+/// Defective states (`0x1xxxxxxx`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Defect {
+    /// `0x1000xxxx`
+    General(u16),
+    /// `0x110xxxxx`. Only the low 20 bits are kept.
+    Restart(u32),
+}
+
+/// Critical states (`0x8xxxxxxx`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Critical {
+    /// `0x80000000`
+    Unknown,
+    /// `0x800000xx`. `Killed(0)` encodes the same as [`Critical::Unknown`].
+    Killed(u8),
+    /// `0x800001xx`
+    Signal(u8),
+}
+
+/// Shattered states (`0xFkxxxxxx`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Shatter {
+    Disposed,
+    Unhandled,
+    Empty,
+    Debug,
+}
+
+impl Shatter {
+    fn byte(self) -> u32 {
+        match self {
+            Shatter::Disposed => 0xF0,
+            Shatter::Unhandled => 0xF1,
+            Shatter::Empty => 0xF2,
+            Shatter::Debug => 0xFF,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    /// `0x00000000`
+    Completed,
+    /// `0x00000001`
+    Racked,
+    /// `0x00000002`
+    Executing,
+    Paused(Pause, u8),
+    Defective(Defect),
+    /// `0x200000xx`
+    Errored(u8),
+    Critical(Critical),
+    /// `0x9xxxxxxx`. The low 28 bits identify the bottle the failure came from.
+    Cascaded(u32),
+    /// The low 24 bits carry detail, usually the code of the report that shattered it.
+    Shattered(Shatter, u32),
+}
+
+impl State {
+    pub fn encode(&self) -> u32 {
+        match *self {
+            State::Completed => 0x0000_0000,
+            State::Racked => 0x0000_0001,
+            State::Executing => 0x0000_0002,
+            State::Paused(kind, detail) => 0x0000_1000 | kind.nibble() << 8 | detail as u32,
+            State::Defective(Defect::General(detail)) => 0x1000_0000 | detail as u32,
+            State::Defective(Defect::Restart(detail)) => 0x1100_0000 | (detail & 0xF_FFFF),
+            State::Errored(detail) => 0x2000_0000 | detail as u32,
+            State::Critical(Critical::Unknown) => 0x8000_0000,
+            State::Critical(Critical::Killed(detail)) => 0x8000_0000 | detail as u32,
+            State::Critical(Critical::Signal(detail)) => 0x8000_0100 | detail as u32,
+            State::Cascaded(origin) => 0x9000_0000 | (origin & 0x0FFF_FFFF),
+            State::Shattered(kind, detail) => kind.byte() << 24 | (detail & 0xFF_FFFF),
+        }
+    }
+
+    pub fn decode(raw: u32) -> Result<State, InternalReport> {
+        let state = match raw {
+            0x0000_0000 => State::Completed,
+            0x0000_0001 => State::Racked,
+            0x0000_0002 => State::Executing,
+            0x0000_1000..=0x0000_15FF => {
+                let kind = match (raw >> 8) & 0xF {
+                    0x0 => Pause::Sleeping,
+                    0x1 => Pause::Waiting,
+                    0x2 => Pause::Blocked,
+                    0x3 => Pause::Paused,
+                    0x5 => Pause::HardPaused,
+                    _ => return Err(invalid_code(raw)),
+                };
+                State::Paused(kind, raw as u8)
+            }
+            0x1000_0000..=0x1000_FFFF => State::Defective(Defect::General(raw as u16)),
+            0x1100_0000..=0x110F_FFFF => State::Defective(Defect::Restart(raw & 0xF_FFFF)),
+            0x2000_0000..=0x2000_00FF => State::Errored(raw as u8),
+            0x8000_0000 => State::Critical(Critical::Unknown),
+            0x8000_0001..=0x8000_00FF => State::Critical(Critical::Killed(raw as u8)),
+            0x8000_0100..=0x8000_01FF => State::Critical(Critical::Signal(raw as u8)),
+            0x9000_0000..=0x9FFF_FFFF => State::Cascaded(raw & 0x0FFF_FFFF),
+            _ => {
+                let kind = match raw >> 24 {
+                    0xF0 => Shatter::Disposed,
+                    0xF1 => Shatter::Unhandled,
+                    0xF2 => Shatter::Empty,
+                    0xFF => Shatter::Debug,
+                    _ => return Err(invalid_code(raw)),
+                };
+                State::Shattered(kind, raw & 0xFF_FFFF)
+            }
+        };
+        Ok(state)
+    }
+
+    /// Whether the bottle may move from `self` to `to`. Nothing leaves a shattered state
+    /// except to [`Shatter::Disposed`], and a disposed bottle never changes again.
+    pub fn can_transition(&self, to: &State) -> bool {
+        use State::*;
+        match (self, to) {
+            (Shattered(Shatter::Disposed, _), _) => false,
+            (Shattered(..), Shattered(Shatter::Disposed, _)) => true,
+            (Shattered(..), _) => false,
+            (_, Shattered(..)) => true,
+            (Racked, Executing) | (Completed, Racked) => true,
+            (Executing | Paused(..), _) => true,
+            (
+                Defective(_) | Errored(_),
+                Racked | Executing | Defective(_) | Errored(_) | Critical(_) | Cascaded(_),
+            ) => true,
+            (Critical(_) | Cascaded(_), Racked | Critical(_) | Cascaded(_)) => true,
+            (from, to) => from == to,
+        }
+    }
+
+    /// Moves to `to`, or reports [`ILLEGAL_TRANSITION`] and leaves `self` untouched.
+    pub fn transition(&mut self, to: State) -> Result<(), InternalReport> {
+        if !self.can_transition(&to) {
+            return Err(InternalReport::InternalError(InternalError {
+                code: ILLEGAL_TRANSITION,
+                file: file!().to_owned(),
+                message: format!("illegal state transition from {} to {}", self, to),
+            }));
+        }
+        *self = to;
+        Ok(())
+    }
+
+    /// The bottle has stopped for good and can only be disposed.
+    pub fn is_shattered(&self) -> bool {
+        matches!(self, State::Shattered(..))
+    }
+
+    /// The bottle failed and cannot continue without outside help.
+    pub fn is_failed(&self) -> bool {
+        matches!(
+            self,
+            State::Defective(_)
+                | State::Errored(_)
+                | State::Critical(_)
+                | State::Cascaded(_)
+                | State::Shattered(..)
+        )
+    }
+}
+
+fn invalid_code(raw: u32) -> InternalReport {
+    InternalReport::InternalError(InternalError {
+        code: INVALID_STATE_CODE,
+        file: file!().to_owned(),
+        message: format!("{:#010x} is not a documented bottle state", raw),
+    })
+}
+
+impl From<State> for u32 {
+    fn from(state: State) -> u32 {
+        state.encode()
+    }
+}
+
+impl TryFrom<u32> for State {
+    type Error = InternalReport;
+    fn try_from(raw: u32) -> Result<State, InternalReport> {
+        State::decode(raw)
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            State::Completed => "Completed".to_owned(),
+            State::Racked => "Racked".to_owned(),
+            State::Executing => "Executing".to_owned(),
+            State::Paused(kind, _) => format!("{:?}", kind),
+            State::Defective(Defect::General(_)) => "Defective".to_owned(),
+            State::Defective(Defect::Restart(_)) => "Restarting".to_owned(),
+            State::Errored(_) => "Errored".to_owned(),
+            State::Critical(kind) => format!("Critical/{:?}", kind),
+            State::Cascaded(_) => "Cascaded".to_owned(),
+            State::Shattered(kind, _) => format!("Shattered/{:?}", kind),
+        };
+        write!(f, "{} ({:#010x})", name, self.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One state from every range in `docs/states.md`, with the code it encodes as.
+    const DOCUMENTED: &[(State, u32)] = &[
+        (State::Completed, 0x0000_0000),
+        (State::Racked, 0x0000_0001),
+        (State::Executing, 0x0000_0002),
+        (State::Paused(Pause::Sleeping, 0x12), 0x0000_1012),
+        (State::Paused(Pause::Waiting, 0x00), 0x0000_1100),
+        (State::Paused(Pause::Blocked, 0xFF), 0x0000_12FF),
+        (State::Paused(Pause::Paused, 0x01), 0x0000_1301),
+        (State::Paused(Pause::HardPaused, 0x7F), 0x0000_157F),
+        (State::Defective(Defect::General(0xBEEF)), 0x1000_BEEF),
+        (State::Defective(Defect::Restart(0xF_FFFF)), 0x110F_FFFF),
+        (State::Errored(0x2A), 0x2000_002A),
+        (State::Critical(Critical::Unknown), 0x8000_0000),
+        (State::Critical(Critical::Killed(9)), 0x8000_0009),
+        (State::Critical(Critical::Signal(15)), 0x8000_010F),
+        (State::Cascaded(0x0ABC_DEF0), 0x9ABC_DEF0),
+        (State::Shattered(Shatter::Disposed, 0), 0xF000_0000),
+        (State::Shattered(Shatter::Unhandled, 3004), 0xF100_0BBC),
+        (State::Shattered(Shatter::Empty, 0xFF_FFFF), 0xF2FF_FFFF),
+        (State::Shattered(Shatter::Debug, 1), 0xFF00_0001),
+    ];
+
+    #[test]
+    fn every_documented_range_round_trips() {
+        for &(state, raw) in DOCUMENTED {
+            assert_eq!(state.encode(), raw, "{:?}", state);
+            assert_eq!(State::decode(raw).ok(), Some(state), "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn range_bounds_round_trip() {
+        let bounds = [
+            0x0000_1000,
+            0x0000_15FF,
+            0x1000_0000,
+            0x1000_FFFF,
+            0x1100_0000,
+            0x2000_0000,
+            0x2000_00FF,
+            0x8000_00FF,
+            0x8000_0100,
+            0x8000_01FF,
+            0x9000_0000,
+            0x9FFF_FFFF,
+            0xF0FF_FFFF,
+            0xFFFF_FFFF,
+        ];
+        for raw in bounds {
+            let state = State::decode(raw).ok();
+            assert_eq!(state.map(|s| s.encode()), Some(raw), "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn undocumented_codes_are_rejected() {
+        let gaps = [
+            0x0000_0003,
+            0x0000_0FFF,
+            0x0000_1400,
+            0x0000_1600,
+            0x1001_0000,
+            0x1110_0000,
+            0x2000_0100,
+            0x3000_0000,
+            0x8000_0200,
+            0xA000_0000,
+            0xF300_0000,
+            0xFE00_0000,
+        ];
+        for raw in gaps {
+            assert!(State::decode(raw).is_err(), "{:#010x}", raw);
+        }
+    }
+
+    #[test]
+    fn detail_beyond_its_range_is_masked() {
+        assert_eq!(State::Cascaded(0xFFFF_FFFF).encode(), 0x9FFF_FFFF);
+        assert_eq!(
+            State::Shattered(Shatter::Unhandled, 0x0100_0001).encode(),
+            0xF100_0001
+        );
+        assert_eq!(
+            State::Defective(Defect::Restart(0x10_0002)).encode(),
+            0x1100_0002
+        );
+    }
+
+    #[test]
+    fn documented_transitions_are_allowed() {
+        let shatter = State::Shattered(Shatter::Unhandled, 1);
+        let dispose = State::Shattered(Shatter::Disposed, 0);
+        let allowed = [
+            (State::Racked, State::Executing),
+            (State::Completed, State::Racked),
+            (State::Executing, State::Completed),
+            (State::Executing, State::Paused(Pause::Blocked, 0)),
+            (State::Paused(Pause::Sleeping, 0), State::Executing),
+            (State::Paused(Pause::Waiting, 0), State::Cascaded(1)),
+            (State::Errored(1), State::Racked),
+            (
+                State::Defective(Defect::General(1)),
+                State::Critical(Critical::Unknown),
+            ),
+            (State::Errored(1), State::Cascaded(2)),
+            (State::Critical(Critical::Killed(1)), State::Racked),
+            (State::Cascaded(1), State::Critical(Critical::Signal(2))),
+            (State::Racked, State::Racked),
+            (State::Completed, State::Completed),
+            (State::Racked, shatter),
+            (State::Completed, shatter),
+            (State::Cascaded(1), shatter),
+            (shatter, dispose),
+        ];
+        for (from, to) in allowed {
+            let mut state = from;
+            assert!(state.transition(to).is_ok(), "{} -> {}", from, to);
+            assert_eq!(state, to);
+        }
+    }
+
+    #[test]
+    fn illegal_transitions_leave_the_state_alone() {
+        let shatter = State::Shattered(Shatter::Empty, 1);
+        let dispose = State::Shattered(Shatter::Disposed, 0);
+        let illegal = [
+            (State::Racked, State::Completed),
+            (State::Racked, State::Paused(Pause::Paused, 0)),
+            (State::Completed, State::Executing),
+            (State::Errored(1), State::Completed),
+            (
+                State::Defective(Defect::General(1)),
+                State::Paused(Pause::Blocked, 0),
+            ),
+            (State::Critical(Critical::Unknown), State::Executing),
+            (State::Cascaded(1), State::Completed),
+            (State::Cascaded(1), State::Errored(1)),
+            (shatter, State::Racked),
+            (shatter, State::Executing),
+            (shatter, State::Shattered(Shatter::Unhandled, 1)),
+            (dispose, dispose),
+            (dispose, State::Racked),
+        ];
+        for (from, to) in illegal {
+            let mut state = from;
+            let report = state
+                .transition(to)
+                .expect_err(&format!("{} -> {}", from, to));
+            let InternalReport::InternalError(e) = report else {
+                panic!("{} -> {} gave the wrong report", from, to);
+            };
+            assert_eq!(e.code, ILLEGAL_TRANSITION);
+            assert_eq!(state, from);
+        }
+    }
+}
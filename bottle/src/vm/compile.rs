@@ -0,0 +1,774 @@
+use super::{Chunk, Loc, Module, Op, Program, TOO_LARGE};
+use crate::deps::{self, DepKind, DependencyGraph, Edge};
+use crate::err::{self, Diagnostic};
+use crate::interp::{self, Value};
+use crate::intrinsic;
+use ast::{AstNode, NodeKind, AST};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Names one unit of a [`DependencyGraph`] gives its functions, globals and dependencies.
+struct ModuleScope {
+    file: String,
+    /// Prefix for chunk and global names, empty for the root.
+    prefix: String,
+    functions: HashMap<String, (u16, u8)>,
+    globals: HashMap<String, u16>,
+    deps: HashMap<String, (DepKind, Option<usize>)>,
+    init: usize,
+}
+
+/// A `try` the code being compiled is inside of.
+#[derive(Clone, Copy)]
+struct TryScope<'a> {
+    /// Handlers it still has installed at this point.
+    handlers: usize,
+    finally: &'a [&'a AstNode<'a>],
+}
+
+/// Lowers an [`AST`] into a [`Program`]. Names are resolved to slots at compile time, so
+/// undefined variables and functions, and arity mismatches, are reported before anything runs.
+pub struct Compiler<'a> {
+    file: String,
+    chunks: Vec<Chunk>,
+    modules: Vec<ModuleScope>,
+    global_names: Vec<String>,
+    /// Lexical scopes of the function being compiled. Empty while compiling an init chunk.
+    scopes: Vec<HashMap<String, u16>>,
+    current: usize,
+    module: usize,
+    /// Enclosing `try` statements, innermost last. A `return` leaves each of them.
+    tries: Vec<TryScope<'a>>,
+    /// Where the node being compiled is, for errors to point at.
+    at: Option<ast::Span>,
+    /// The return type of the function being compiled.
+    returns: String,
+    /// Local slots, by chunk, and global slots declared `f64`, so what is assigned to them is
+    /// widened as their declared value was.
+    float_locals: HashSet<(usize, u16)>,
+    float_globals: HashSet<u16>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(file: &str) -> Self {
+        Self {
+            file: file.to_owned(),
+            chunks: Vec::new(),
+            modules: Vec::new(),
+            global_names: Vec::new(),
+            scopes: Vec::new(),
+            current: 0,
+            module: 0,
+            tries: Vec::new(),
+            at: None,
+            returns: String::new(),
+            float_locals: HashSet::new(),
+            float_globals: HashSet::new(),
+        }
+    }
+
+    pub fn compile(self, ast: &AST<'a>) -> Result<Program, Diagnostic> {
+        let file = self.file.clone();
+        self.compile_units(&[(file, ast.head, Vec::new())])
+    }
+
+    /// Compiles every bottle in `graph` into one [`Program`] whose `main` is the root's.
+    pub fn compile_graph(self, graph: &DependencyGraph<'a>) -> Result<Program, Diagnostic> {
+        let units: Vec<_> = graph
+            .units
+            .iter()
+            .map(|unit| (unit.file(), unit.ast.head, unit.deps.clone()))
+            .collect();
+        self.compile_units(&units)
+    }
+
+    fn compile_units(
+        mut self,
+        units: &[(String, &'a AstNode<'a>, Vec<Edge>)],
+    ) -> Result<Program, Diagnostic> {
+        for (module, (file, head, deps)) in units.iter().enumerate() {
+            let NodeKind::Root { children } = &head.kind else {
+                return Err(self.error(interp::UNSUPPORTED, "expected a root node".to_owned()));
+            };
+            let prefix = match module {
+                0 => String::new(),
+                _ => Path::new(file)
+                    .file_stem()
+                    .map(|s| format!("{}.", s.to_string_lossy()))
+                    .unwrap_or_default(),
+            };
+            self.modules.push(ModuleScope {
+                file: file.clone(),
+                prefix,
+                functions: HashMap::new(),
+                globals: HashMap::new(),
+                deps: deps
+                    .iter()
+                    .map(|d| (d.name.clone(), (d.kind, d.target)))
+                    .collect(),
+                init: 0,
+            });
+            self.module = module;
+            for child in children {
+                match &child.kind {
+                    NodeKind::Function { name, params, .. } => {
+                        let idx = self.operand(self.chunks.len(), "functions")?;
+                        let arity = self.operand(params.len(), "parameters")?;
+                        let chunk = Chunk::new(&self.qualify(name), arity, module);
+                        self.modules[module]
+                            .functions
+                            .insert(name.clone(), (idx, arity));
+                        self.chunks.push(chunk);
+                    }
+                    _ => self.collect_globals(child)?,
+                }
+            }
+        }
+        for (module, (_, head, deps)) in units.iter().enumerate() {
+            let NodeKind::Root { children } = &head.kind else {
+                unreachable!("checked above");
+            };
+            self.module = module;
+            for child in children {
+                if let NodeKind::Function {
+                    name,
+                    params,
+                    body,
+                    return_type,
+                } = &child.kind
+                {
+                    self.current = self.modules[module].functions[name].0 as usize;
+                    self.returns = return_type.clone();
+                    self.scopes.push(HashMap::new());
+                    for param in params {
+                        if let NodeKind::Declaration {
+                            name, struct_type, ..
+                        } = &param.kind
+                        {
+                            let slot = self.declare_local(name)?;
+                            if struct_type == "f64" {
+                                self.float_locals.insert((self.current, slot));
+                                self.emit(Op::Load(slot));
+                                self.emit(Op::Widen);
+                                self.emit(Op::Store(slot));
+                            }
+                        }
+                    }
+                    self.block(body)?;
+                    self.scopes.pop();
+                    self.emit_const(Value::Void)?;
+                    self.emit(Op::Return);
+                }
+            }
+            let init = self.chunks.len();
+            self.modules[module].init = init;
+            self.chunks
+                .push(Chunk::new(&self.qualify("<init>"), 0, module));
+            self.current = init;
+            self.returns.clear();
+            for dep in deps.iter().filter(|d| d.kind == DepKind::Require) {
+                if let Some(target) = dep.target {
+                    let target = self.operand(target, "modules")?;
+                    self.emit(Op::Init(target));
+                    self.emit(Op::Pop);
+                }
+            }
+            // A final expression statement is what the top level returns, which a snippet
+            // run without `main` gives back; requires and imports drop it.
+            let statements: Vec<_> = children
+                .iter()
+                .filter(|child| !matches!(child.kind, NodeKind::Function { .. }))
+                .collect();
+            let last = statements.last().filter(|child| interp::is_expr(child));
+            let body = &statements[..statements.len() - last.is_some() as usize];
+            for child in body {
+                self.stmt(child)?;
+            }
+            match last {
+                Some(value) => self.expr(value)?,
+                None => self.emit_const(Value::Void)?,
+            }
+            self.emit(Op::Return);
+        }
+        let root = &self.modules[0];
+        let main = root.functions.get("main").map(|(idx, _)| *idx as usize);
+        Ok(Program {
+            file: self.file,
+            init: root.init,
+            modules: self
+                .modules
+                .iter()
+                .map(|m| Module {
+                    file: m.file.clone(),
+                    init: m.init,
+                })
+                .collect(),
+            chunks: self.chunks,
+            globals: self.global_names,
+            main,
+        })
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        format!("{}{}", self.modules[self.module].prefix, name)
+    }
+
+    /// Splits a dotted name into the module it lives in and its last segment. Reaching through
+    /// an import or lock first makes sure the module has been initialised; anything reached only
+    /// through requires already was, before this module's own init ran.
+    fn target<'n>(&mut self, name: &'n str) -> Result<(usize, &'n str), Diagnostic> {
+        let mut segments: Vec<&str> = name.split('.').collect();
+        let last = segments.pop().unwrap();
+        let mut module = self.module;
+        let mut lazy = false;
+        for segment in segments {
+            module = match self.modules[module].deps.get(segment) {
+                Some((kind, Some(target))) => {
+                    lazy |= *kind != DepKind::Require;
+                    *target
+                }
+                Some((kind, None)) => {
+                    return Err(self.error(
+                        deps::IMPORT_NOT_FOUND,
+                        format!("{} `{}` could not be loaded", kind, segment),
+                    ))
+                }
+                None => {
+                    return Err(self.error(
+                        interp::UNDEFINED_VARIABLE,
+                        format!("`{}` is not a dependency of this bottle", segment),
+                    ))
+                }
+            };
+        }
+        if lazy {
+            let module = self.operand(module, "modules")?;
+            self.emit(Op::Init(module));
+            self.emit(Op::Pop);
+        }
+        Ok((module, last))
+    }
+
+    /// Assigns a global slot to every declaration reachable from a top-level statement.
+    fn collect_globals(&mut self, node: &AstNode) -> Result<(), Diagnostic> {
+        match &node.kind {
+            NodeKind::Declaration {
+                name, struct_type, ..
+            } => {
+                let slot = match self.modules[self.module].globals.get(name) {
+                    Some(slot) => *slot,
+                    None => {
+                        let slot = self.operand(self.global_names.len(), "globals")?;
+                        self.modules[self.module].globals.insert(name.clone(), slot);
+                        self.global_names.push(self.qualify(name));
+                        slot
+                    }
+                };
+                if struct_type == "f64" {
+                    self.float_globals.insert(slot);
+                }
+                Ok(())
+            }
+            NodeKind::If {
+                then_body,
+                else_body,
+                ..
+            } => then_body
+                .iter()
+                .chain(else_body)
+                .try_for_each(|n| self.collect_globals(n)),
+            NodeKind::While { body, .. } => body.iter().try_for_each(|n| self.collect_globals(n)),
+            // Like blocks at the top level, these share the module's scope. Catch bodies don't.
+            NodeKind::Try { body, finally, .. } => body
+                .iter()
+                .chain(finally)
+                .try_for_each(|n| self.collect_globals(n)),
+            _ => Ok(()),
+        }
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.chunks[self.current]
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let loc = self.at.map(Loc::from);
+        let chunk = self.chunk();
+        chunk.locs.push(loc);
+        chunk.code.push(op);
+        chunk.code.len() - 1
+    }
+
+    fn constant(&mut self, v: Value) -> Result<u16, Diagnostic> {
+        let constants = &mut self.chunk().constants;
+        let idx = match constants.iter().position(|c| *c == v) {
+            Some(idx) => idx,
+            None => {
+                constants.push(v);
+                constants.len() - 1
+            }
+        };
+        self.operand(idx, "constants")
+    }
+
+    fn emit_const(&mut self, v: Value) -> Result<(), Diagnostic> {
+        let idx = self.constant(v)?;
+        self.emit(Op::Const(idx));
+        Ok(())
+    }
+
+    fn here(&mut self) -> Result<u32, Diagnostic> {
+        let len = self.chunk().code.len();
+        self.operand(len, "instructions")
+    }
+
+    /// `n` as an operand, or a [`TOO_LARGE`] error naming `what` there are too many of when it
+    /// doesn't fit.
+    fn operand<T: TryFrom<usize>>(&self, n: usize, what: &str) -> Result<T, Diagnostic> {
+        T::try_from(n).map_err(|_| {
+            self.error(
+                TOO_LARGE,
+                format!("too many {} for bytecode to address", what),
+            )
+        })
+    }
+
+    /// Points the jump at `at` to the current end of the chunk.
+    fn patch(&mut self, at: usize) -> Result<(), Diagnostic> {
+        let target = self.here()?;
+        match &mut self.chunk().code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) | Op::JumpIfTrue(t) | Op::PushHandler(t) => {
+                *t = target
+            }
+            op => unreachable!("patching non-jump {:?}", op),
+        }
+        Ok(())
+    }
+
+    fn declare_local(&mut self, name: &str) -> Result<u16, Diagnostic> {
+        let slot = self.operand(self.chunks[self.current].locals.len(), "locals")?;
+        self.chunk().locals.push(name.to_owned());
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_owned(), slot);
+        Ok(slot)
+    }
+
+    fn resolve(&self, name: &str) -> Option<Op> {
+        if let Some(slot) = self.scopes.iter().rev().find_map(|s| s.get(name)) {
+            return Some(Op::Load(*slot));
+        }
+        self.modules[self.module]
+            .globals
+            .get(name)
+            .map(|slot| Op::LoadGlobal(*slot))
+    }
+
+    fn block(&mut self, body: &'a [&'a AstNode<'a>]) -> Result<(), Diagnostic> {
+        let scoped = !self.scopes.is_empty();
+        if scoped {
+            self.scopes.push(HashMap::new());
+        }
+        let result = body.iter().try_for_each(|stmt| self.stmt(stmt));
+        if scoped {
+            self.scopes.pop();
+        }
+        result
+    }
+
+    /// Emits what a `return` does before it returns: uninstall the handlers of every enclosing
+    /// `try` and run their `finally` blocks, innermost first.
+    fn leave_tries(&mut self) -> Result<(), Diagnostic> {
+        let tries = std::mem::take(&mut self.tries);
+        for (i, scope) in tries.iter().enumerate().rev() {
+            for _ in 0..scope.handlers {
+                self.emit(Op::PopHandler);
+            }
+            if !scope.finally.is_empty() {
+                self.tries = tries[..i].to_vec();
+                self.block(scope.finally)?;
+            }
+        }
+        self.tries = tries;
+        Ok(())
+    }
+
+    /// Compiles a `try`. The error being handled sits on the stack while the handlers are
+    /// tried in order, and is rethrown if none of them takes it.
+    fn try_stmt(
+        &mut self,
+        body: &'a [&'a AstNode<'a>],
+        handlers: &'a [&'a AstNode<'a>],
+        finally: &'a [&'a AstNode<'a>],
+    ) -> Result<(), Diagnostic> {
+        let finally_handler = (!finally.is_empty()).then(|| self.emit(Op::PushHandler(0)));
+        let catch_handler = (!handlers.is_empty()).then(|| self.emit(Op::PushHandler(0)));
+        self.tries.push(TryScope {
+            handlers: finally_handler.is_some() as usize + catch_handler.is_some() as usize,
+            finally,
+        });
+        self.block(body)?;
+        if let Some(at) = catch_handler {
+            self.emit(Op::PopHandler);
+            let mut to_end = vec![self.emit(Op::Jump(0))];
+            self.patch(at)?;
+            self.tries.last_mut().unwrap().handlers -= 1;
+            for handler in handlers {
+                let NodeKind::Catch { kind, name, body } = &handler.kind else {
+                    unreachable!("the parser only puts catch nodes in handlers");
+                };
+                let next = match kind.is_empty() {
+                    true => None,
+                    false => {
+                        let kind = self.constant(Value::Str(kind.clone()))?;
+                        self.emit(Op::Matches(kind));
+                        Some(self.emit(Op::JumpIfFalse(0)))
+                    }
+                };
+                self.scopes.push(HashMap::new());
+                if name.is_empty() {
+                    self.emit(Op::Pop);
+                } else {
+                    let slot = self.declare_local(name)?;
+                    self.emit(Op::Store(slot));
+                }
+                let result = body.iter().try_for_each(|stmt| self.stmt(stmt));
+                self.scopes.pop();
+                result?;
+                to_end.push(self.emit(Op::Jump(0)));
+                if let Some(next) = next {
+                    self.patch(next)?;
+                }
+            }
+            self.emit(Op::Rethrow);
+            to_end.into_iter().try_for_each(|at| self.patch(at))?;
+        }
+        self.tries.pop();
+        if let Some(at) = finally_handler {
+            self.emit(Op::PopHandler);
+            self.block(finally)?;
+            let over = self.emit(Op::Jump(0));
+            self.patch(at)?;
+            self.block(finally)?;
+            self.emit(Op::Rethrow);
+            self.patch(over)?;
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, node: &'a AstNode<'a>) -> Result<(), Diagnostic> {
+        let outer = self.at.replace(node.span);
+        let result = self.stmt_node(node);
+        self.at = outer;
+        result
+    }
+
+    fn stmt_node(&mut self, node: &'a AstNode<'a>) -> Result<(), Diagnostic> {
+        match &node.kind {
+            NodeKind::Return { value } => {
+                self.expr(value)?;
+                if self.returns == "f64" {
+                    self.emit(Op::Widen);
+                }
+                self.leave_tries()?;
+                self.emit(Op::Return);
+            }
+            NodeKind::Declaration {
+                name,
+                value,
+                struct_type,
+            } => {
+                self.expr(value)?;
+                if struct_type == "f64" {
+                    self.emit(Op::Widen);
+                }
+                if self.scopes.is_empty() {
+                    let slot = self.modules[self.module].globals[name];
+                    self.emit(Op::StoreGlobal(slot));
+                } else {
+                    let slot = self.declare_local(name)?;
+                    if struct_type == "f64" {
+                        self.float_locals.insert((self.current, slot));
+                    }
+                    self.emit(Op::Store(slot));
+                }
+            }
+            NodeKind::Assignment { identifier, value } => {
+                let NodeKind::Identifier { name } = &identifier.kind else {
+                    return Err(
+                        self.error(interp::UNSUPPORTED, "invalid assignment target".to_owned())
+                    );
+                };
+                self.expr(value)?;
+                let store = match self.resolve(name) {
+                    Some(Op::Load(slot)) => Op::Store(slot),
+                    Some(Op::LoadGlobal(slot)) => Op::StoreGlobal(slot),
+                    _ => {
+                        return Err(self.error(
+                            interp::UNDEFINED_VARIABLE,
+                            format!("assignment to undeclared variable `{}`", name),
+                        ))
+                    }
+                };
+                let float = match store {
+                    Op::Store(slot) => self.float_locals.contains(&(self.current, slot)),
+                    Op::StoreGlobal(slot) => self.float_globals.contains(&slot),
+                    _ => false,
+                };
+                if float {
+                    self.emit(Op::Widen);
+                }
+                self.emit(store);
+            }
+            NodeKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                self.expr(cond)?;
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.block(then_body)?;
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_else)?;
+                self.block(else_body)?;
+                self.patch(to_end)?;
+            }
+            NodeKind::While { cond, body } => {
+                let start = self.here()?;
+                self.expr(cond)?;
+                let to_end = self.emit(Op::JumpIfFalse(0));
+                self.block(body)?;
+                self.emit(Op::Jump(start));
+                self.patch(to_end)?;
+            }
+            NodeKind::Try {
+                body,
+                handlers,
+                finally,
+            } => self.try_stmt(body, handlers, finally)?,
+            NodeKind::Spill { kind, value } => {
+                if !kind.is_empty() {
+                    self.emit_const(Value::Str(kind.clone()))?;
+                }
+                self.expr(value)?;
+                self.emit(Op::Spill(!kind.is_empty()));
+            }
+            NodeKind::Function { name, .. } => {
+                return Err(self.error(
+                    interp::UNSUPPORTED,
+                    format!("nested function `{}` is not supported", name),
+                ))
+            }
+            NodeKind::Skip
+            | NodeKind::None
+            | NodeKind::Eof
+            | NodeKind::Dependency { .. }
+            | NodeKind::Sharp { .. }
+            | NodeKind::At { .. } => {}
+            _ => {
+                self.expr(node)?;
+                self.emit(Op::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, node: &AstNode) -> Result<(), Diagnostic> {
+        let outer = self.at.replace(node.span);
+        let result = self.expr_node(node);
+        self.at = outer;
+        result
+    }
+
+    fn expr_node(&mut self, node: &AstNode) -> Result<(), Diagnostic> {
+        match &node.kind {
+            NodeKind::IntLiteral { value } => self.emit_const(Value::Int(*value))?,
+            NodeKind::FloatLiteral { value } => self.emit_const(Value::Float(*value))?,
+            NodeKind::BoolLiteral { value } => self.emit_const(Value::Bool(*value))?,
+            NodeKind::StrLiteral { value } => self.emit_const(Value::Str(value.clone()))?,
+            NodeKind::None => self.emit_const(Value::Void)?,
+            NodeKind::Variable { name } | NodeKind::Identifier { name } => {
+                let mut fields = name.split('.');
+                if let Some(load) = self.resolve(fields.next().unwrap()) {
+                    self.emit(load);
+                    for field in fields {
+                        let field = self.constant(Value::Str(field.to_owned()))?;
+                        self.emit(Op::Field(field));
+                    }
+                    return Ok(());
+                }
+                let (module, var) = self.target(name)?;
+                let load = match module == self.module {
+                    true => self.resolve(var),
+                    false => self.modules[module]
+                        .globals
+                        .get(var)
+                        .map(|s| Op::LoadGlobal(*s)),
+                };
+                match load {
+                    Some(load) => {
+                        self.emit(load);
+                    }
+                    None => {
+                        return Err(self.error(
+                            interp::UNDEFINED_VARIABLE,
+                            format!("undefined variable `{}`", name),
+                        ))
+                    }
+                }
+            }
+            NodeKind::UnaryExpr { op, expr } => {
+                self.expr(expr)?;
+                let op = static_op(parser::UNARY_OPS.iter().copied(), op);
+                self.emit(Op::Unary(op));
+            }
+            NodeKind::BinaryExpr { left, op, right } if op == "&&" || op == "||" => {
+                self.expr(left)?;
+                self.emit(Op::Dup);
+                let short = self.emit(if op == "&&" {
+                    Op::JumpIfFalse(0)
+                } else {
+                    Op::JumpIfTrue(0)
+                });
+                self.emit(Op::Pop);
+                self.expr(right)?;
+                self.emit(Op::CheckBool);
+                self.patch(short)?;
+            }
+            NodeKind::BinaryExpr { left, op, right } => {
+                self.expr(left)?;
+                self.expr(right)?;
+                let op = static_op(parser::BINARY_OPS.iter().map(|(op, _, _)| *op), op);
+                self.emit(Op::Binary(op));
+            }
+            NodeKind::Call { name, args } => {
+                let (module, function) = self.target(name)?;
+                let Some((idx, arity)) = self.modules[module].functions.get(function).copied()
+                else {
+                    return Err(self.error(
+                        interp::UNDEFINED_FUNCTION,
+                        format!("undefined function `{}`", name),
+                    ));
+                };
+                if args.len() != arity as usize {
+                    return Err(self.error(
+                        interp::ARITY_MISMATCH,
+                        format!(
+                            "`{}` takes {} argument(s) but {} were given",
+                            name,
+                            arity,
+                            args.len()
+                        ),
+                    ));
+                }
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                self.emit(Op::Call(idx, arity));
+            }
+            NodeKind::BangCall { name, args } => {
+                let Some(intrinsic) = intrinsic::find(name) else {
+                    return Err(self.error(
+                        interp::UNDEFINED_FUNCTION,
+                        format!("unknown intrinsic `{}!`", name),
+                    ));
+                };
+                let arity = intrinsic.signature.params.len();
+                if args.len() != arity {
+                    return Err(self.error(
+                        interp::ARITY_MISMATCH,
+                        format!(
+                            "`{}!` takes {} argument(s) but {} were given",
+                            name,
+                            arity,
+                            args.len()
+                        ),
+                    ));
+                }
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                let name = self.constant(Value::Str(name.clone()))?;
+                let arity = self.operand(arity, "arguments")?;
+                self.emit(Op::Bang(name, arity));
+            }
+            other => {
+                return Err(self.error(
+                    interp::UNSUPPORTED,
+                    format!("cannot compile `{}` as an expression", other),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, code: i32, message: String) -> Diagnostic {
+        let message = match self.chunks.get(self.current) {
+            Some(chunk) => format!("{} (in `{}`)", message, chunk.name),
+            None => message,
+        };
+        let d = Diagnostic::Error(err::Error {
+            code,
+            file: self
+                .modules
+                .get(self.module)
+                .map_or(&self.file, |m| &m.file)
+                .clone(),
+            message,
+            ..Default::default()
+        });
+        match self.at {
+            Some(span) => d.at(span),
+            None => d,
+        }
+    }
+}
+
+/// Finds the `'static` spelling of an operator the parser produced.
+fn static_op(mut table: impl Iterator<Item = &'static str>, op: &str) -> &'static str {
+    table
+        .find(|candidate| *candidate == op)
+        .expect("operator not produced by the parser")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use typed_arena::Arena;
+
+    fn compile(source: &str) -> Result<Program, Diagnostic> {
+        let arena = Arena::new();
+        let ast = crate::parse("limits.wg", source, &arena).expect("the source parses");
+        Compiler::new("limits.wg").compile(&ast)
+    }
+
+    #[test]
+    fn locals_past_what_an_operand_addresses_are_an_error() {
+        let locals = |n: usize| {
+            let lets: String = (0..n).map(|i| format!("    let l{} = 0;\n", i)).collect();
+            format!("fn main() {{\n{}}}\n", lets)
+        };
+        assert!(compile(&locals(u16::MAX as usize + 1)).is_ok());
+        let d = compile(&locals(u16::MAX as usize + 2)).expect_err("too many");
+        assert_eq!(d.get_code(), TOO_LARGE);
+        assert!(d.get_message().contains("locals"), "{}", d.get_message());
+        assert_eq!(d.get_idx().0, u16::MAX as usize + 2);
+    }
+
+    #[test]
+    fn parameters_past_what_an_operand_addresses_are_an_error() {
+        let params = |n: usize| {
+            let params: Vec<_> = (0..n).map(|i| format!("p{}: i64", i)).collect();
+            format!("fn wide({}) {{}}\n", params.join(", "))
+        };
+        assert!(compile(&params(u8::MAX as usize)).is_ok());
+        let d = compile(&params(u8::MAX as usize + 1)).expect_err("too many");
+        assert_eq!(d.get_code(), TOO_LARGE);
+        assert!(
+            d.get_message().contains("parameters"),
+            "{}",
+            d.get_message()
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use super::{Op, Program};
+use std::fmt::Write;
+
+/// Renders every chunk of `program` as a human readable listing.
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    if !program.globals.is_empty() {
+        writeln!(out, "globals: {}", program.globals.join(", ")).unwrap();
+    }
+    for (idx, chunk) in program.chunks.iter().enumerate() {
+        writeln!(
+            out,
+            "== #{} {} (arity {}, locals {}) ==",
+            idx,
+            chunk.name,
+            chunk.arity,
+            chunk.locals.len()
+        )
+        .unwrap();
+        for (offset, op) in chunk.code.iter().enumerate() {
+            let comment = match op {
+                Op::Const(i) | Op::Matches(i) | Op::Field(i) | Op::Bang(i, _) => {
+                    Some(format!("{:?}", chunk.constants[*i as usize]))
+                }
+                Op::Load(i) | Op::Store(i) => chunk.locals.get(*i as usize).cloned(),
+                Op::LoadGlobal(i) | Op::StoreGlobal(i) => program.globals.get(*i as usize).cloned(),
+                Op::Call(i, _) => program.chunks.get(*i as usize).map(|c| c.name.clone()),
+                Op::Init(i) => program.modules.get(*i as usize).map(|m| m.file.clone()),
+                _ => None,
+            };
+            let op = format!("{:?}", op);
+            match comment {
+                Some(comment) => writeln!(out, "{:04}  {:<20} ; {}", offset, op, comment),
+                None => writeln!(out, "{:04}  {}", offset, op),
+            }
+            .unwrap();
+        }
+    }
+    out
+}
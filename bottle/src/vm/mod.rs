@@ -0,0 +1,564 @@
+mod compile;
+mod disasm;
+mod verify;
+
+pub use compile::Compiler;
+pub use disasm::disassemble;
+pub use verify::verify;
+
+use crate::err::{self, Diagnostic};
+use crate::interp::{self, Value};
+use crate::intrinsic::{self, Runtime};
+use crate::sched::Interrupt;
+use log::*;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// A bottle needs more functions, constants, locals, globals or instructions than an [`Op`]'s
+/// operands can address.
+pub const TOO_LARGE: i32 = 9001;
+
+/// An operator's name from the parser's tables. An alias so the serde derives don't tie
+/// deserializing to `'static` input.
+pub type Operator = &'static str;
+
+/// A single VM instruction. Jump targets are absolute offsets into the owning [`Chunk`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Op {
+    /// Push `constants[i]`.
+    Const(u16),
+    /// Push local slot `i` of the current frame.
+    Load(u16),
+    /// Pop into local slot `i` of the current frame.
+    Store(u16),
+    LoadGlobal(u16),
+    StoreGlobal(u16),
+    /// Apply a prefix operator from `parser::UNARY_OPS` to the top of the stack.
+    Unary(#[serde(deserialize_with = "operator")] Operator),
+    /// Pop the right then the left operand and push the result of an operator from
+    /// `parser::BINARY_OPS`.
+    Binary(#[serde(deserialize_with = "operator")] Operator),
+    /// Fail unless the top of the stack is a bool. Used after the right side of `&&`/`||`.
+    CheckBool,
+    Dup,
+    Pop,
+    Jump(u32),
+    /// Pop a bool and jump if it is false.
+    JumpIfFalse(u32),
+    /// Like [`Op::JumpIfFalse`] but jumps when the bool is true.
+    JumpIfTrue(u32),
+    /// Call function `idx` with the top `argc` stack values as arguments.
+    Call(u16, u8),
+    /// Call the intrinsic named by `constants[i]` with the top `argc` stack values as
+    /// arguments.
+    Bang(u16, u8),
+    Return,
+    /// Install a `try` handler at `target` for errors raised until the matching
+    /// [`Op::PopHandler`]. When one is caught the stack is cut back to its height at this point
+    /// and the error value is pushed.
+    PushHandler(u32),
+    PopHandler,
+    /// Push whether the error on top of the stack is of the kind in `constants[i]`.
+    Matches(u16),
+    /// Replace the top of the stack with its field named by `constants[i]`.
+    Field(u16),
+    /// Pop a value and spill it. When true, the kind to spill it as is popped from beneath it.
+    Spill(bool),
+    /// Pop a caught error and let it carry on as if it had never been caught.
+    Rethrow,
+    /// Run the init chunk of module `i` unless it has already started. Always leaves one value
+    /// on the stack, which is popped straight after.
+    Init(u16),
+    /// Turn an `i64` on top of the stack into an `f64`, for a value going where an `f64` is
+    /// declared. Anything else is left as it is.
+    Widen,
+}
+
+/// Compiled code for one function.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub name: String,
+    pub arity: u8,
+    pub code: Vec<Op>,
+    /// Where the source of each op in `code` is, for runtime errors to point at.
+    pub locs: Vec<Option<Loc>>,
+    pub constants: Vec<Value>,
+    /// Name of every local slot, parameters first. Only used for disassembly.
+    pub locals: Vec<String>,
+    /// Index into [`Program::modules`] of the bottle this came from.
+    pub module: usize,
+}
+
+/// The zero-based lines and columns of the node an op was compiled from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loc {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl From<ast::Span> for Loc {
+    fn from(span: ast::Span) -> Self {
+        Self {
+            line: span.line,
+            column: span.column,
+            end_line: span.end_line,
+            end_column: span.end_column,
+        }
+    }
+}
+
+impl From<Loc> for ast::Span {
+    fn from(loc: Loc) -> Self {
+        Self {
+            line: loc.line,
+            column: loc.column,
+            end_line: loc.end_line,
+            end_column: loc.end_column,
+            ..Default::default()
+        }
+    }
+}
+
+impl Chunk {
+    pub fn new(name: &str, arity: u8, module: usize) -> Self {
+        Self {
+            name: name.to_owned(),
+            arity,
+            module,
+            code: Vec::new(),
+            locs: Vec::new(),
+            constants: Vec::new(),
+            locals: Vec::new(),
+        }
+    }
+}
+
+/// One bottle linked into a [`Program`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Module {
+    pub file: String,
+    /// Chunk that initialises this module's globals.
+    pub init: usize,
+}
+
+/// A compiled bottle and everything it depends on. `modules[0]` is the bottle itself and
+/// `chunks[init]` initialises it, along with what it requires, before `main` runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Program {
+    pub file: String,
+    pub chunks: Vec<Chunk>,
+    pub globals: Vec<String>,
+    pub modules: Vec<Module>,
+    pub init: usize,
+    pub main: Option<usize>,
+}
+
+/// Reads an operator back as the entry for it in the parser's tables, so loaded programs
+/// share the `'static` names compiled ones use.
+fn operator<'de, D: Deserializer<'de>>(d: D) -> Result<&'static str, D::Error> {
+    let op = String::deserialize(d)?;
+    parser::UNARY_OPS
+        .iter()
+        .copied()
+        .chain(parser::BINARY_OPS.iter().map(|(op, ..)| *op))
+        .find(|known| *known == op)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown operator `{}`", op)))
+}
+
+struct CallFrame {
+    chunk: usize,
+    ip: usize,
+    base: usize,
+}
+
+/// An installed `try` handler. `frame` is the frame count when it was installed, so it belongs
+/// to frame `frame - 1`.
+struct Handler {
+    frame: usize,
+    height: usize,
+    target: u32,
+}
+
+/// A stack machine executing a [`Program`]. Each frame's locals live on the value stack
+/// starting at the frame's base, with arguments occupying the first slots.
+pub struct Vm<'p> {
+    program: &'p Program,
+    stack: Vec<Value>,
+    globals: Vec<Value>,
+    /// Whether each module's init chunk has been entered.
+    started: Vec<bool>,
+    frames: Vec<CallFrame>,
+    interrupt: Option<Interrupt>,
+    /// What bang calls reach of the bottle.
+    runtime: Runtime,
+    /// Call trace captured where the error being propagated was raised.
+    error_trace: Vec<String>,
+    unwinding: bool,
+    handlers: Vec<Handler>,
+    /// The value of the `spill` being propagated, so `catch` gets it back intact.
+    spilled: Option<interp::ErrorValue>,
+}
+
+impl<'p> Vm<'p> {
+    pub fn new(program: &'p Program) -> Self {
+        Self {
+            program,
+            stack: Vec::new(),
+            globals: vec![Value::Void; program.globals.len()],
+            started: vec![false; program.modules.len()],
+            frames: Vec::new(),
+            interrupt: None,
+            runtime: Runtime::default(),
+            error_trace: Vec::new(),
+            unwinding: false,
+            handlers: Vec::new(),
+            spilled: None,
+        }
+    }
+
+    /// Runs the init chunk and then `main`, returning its result as an exit code.
+    pub fn run(&mut self) -> Result<i64, Diagnostic> {
+        if let Some(root) = self.started.first_mut() {
+            *root = true;
+        }
+        self.call(self.program.init, vec![])?;
+        let Some(main) = self.program.main else {
+            return Err(self.error(
+                interp::MISSING_MAIN,
+                "no `main` function defined".to_owned(),
+            ));
+        };
+        match self.call(main, vec![])? {
+            Value::Int(code) => Ok(code),
+            Value::Void => Ok(0),
+            v => Err(self.error(
+                interp::TYPE_ERROR,
+                format!("`main` must return i64 or void, found {}", v.type_name()),
+            )),
+        }
+    }
+
+    /// Runs only the init chunk, returning the value of its final expression statement, or void.
+    /// This is how a snippet without `main` is run.
+    pub fn run_top_level(&mut self) -> Result<Value, Diagnostic> {
+        if let Some(root) = self.started.first_mut() {
+            *root = true;
+        }
+        self.call(self.program.init, vec![])
+    }
+
+    /// Calls chunk `idx` with `args` and runs it to completion.
+    pub fn call(&mut self, idx: usize, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        let argc = args.len();
+        let height = self.stack.len();
+        self.stack.extend(args);
+        let depth = self.frames.len();
+        let result = self.push_frame(idx, argc).and_then(|_| self.execute(depth));
+        if result.is_err() {
+            if !std::mem::replace(&mut self.unwinding, false) {
+                self.error_trace = self.call_trace();
+            }
+            self.spilled = None;
+            self.handlers.retain(|h| h.frame <= depth);
+            self.frames.truncate(depth);
+            self.stack.truncate(height);
+        }
+        result
+    }
+
+    /// Names of the functions currently being executed, innermost last.
+    pub fn call_trace(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .map(|f| self.program.chunks[f.chunk].name.clone())
+            .collect()
+    }
+
+    /// The [`Vm::call_trace`] at the point the last error was raised.
+    pub fn error_trace(&self) -> &[String] {
+        &self.error_trace
+    }
+
+    /// Stops execution at the next call or jump once `interrupt` is raised.
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Runs bang calls in `runtime`, and stops once its interrupt is raised.
+    pub fn set_runtime(&mut self, runtime: Runtime) {
+        self.interrupt = Some(runtime.interrupt());
+        self.runtime = runtime;
+    }
+
+    fn poll(&self) -> Result<(), Diagnostic> {
+        match self.interrupt.as_ref().and_then(Interrupt::check) {
+            Some(message) => Err(self.error(interp::INTERRUPTED, message)),
+            None => Ok(()),
+        }
+    }
+
+    fn push_frame(&mut self, idx: usize, argc: usize) -> Result<(), Diagnostic> {
+        let chunk = &self.program.chunks[idx];
+        if argc != chunk.arity as usize {
+            return Err(self.error(
+                interp::ARITY_MISMATCH,
+                format!(
+                    "`{}` takes {} argument(s) but {} were given",
+                    chunk.name, chunk.arity, argc
+                ),
+            ));
+        }
+        if self.frames.len() >= interp::MAX_CALL_DEPTH {
+            return Err(self.error(
+                interp::STACK_OVERFLOW,
+                format!(
+                    "call depth exceeded {} in `{}`",
+                    interp::MAX_CALL_DEPTH,
+                    chunk.name
+                ),
+            ));
+        }
+        let base = self.stack.len() - argc;
+        self.stack.resize(base + chunk.locals.len(), Value::Void);
+        trace!("Entering {}", chunk.name);
+        self.frames.push(CallFrame {
+            chunk: idx,
+            ip: 0,
+            base,
+        });
+        Ok(())
+    }
+
+    /// Runs until the frame count drops back to `depth`, returning the value of that return.
+    fn execute(&mut self, depth: usize) -> Result<Value, Diagnostic> {
+        loop {
+            match self.step(depth) {
+                Ok(Some(v)) => return Ok(v),
+                Ok(None) => {}
+                Err(d) => {
+                    if !std::mem::replace(&mut self.unwinding, true) {
+                        self.error_trace = self.call_trace();
+                    }
+                    self.unwind(depth, d)?
+                }
+            }
+        }
+    }
+
+    /// Hands `d` to the innermost handler installed since [`Vm::execute`] was entered at
+    /// `depth`, dropping the frames and values above it, or gives it back if there is none.
+    fn unwind(&mut self, depth: usize, d: Diagnostic) -> Result<(), Diagnostic> {
+        match self.handlers.last() {
+            Some(h) if h.frame > depth && interp::catchable(d.get_code()) => {
+                let h = self.handlers.pop().unwrap();
+                self.unwinding = false;
+                self.frames.truncate(h.frame);
+                self.stack.truncate(h.height);
+                let mut error = interp::error_value(&d, self.spilled.take());
+                error.trace = std::mem::take(&mut self.error_trace);
+                self.stack.push(Value::Error(Box::new(error)));
+                self.jump(h.target);
+                Ok(())
+            }
+            _ => Err(d),
+        }
+    }
+
+    /// Executes one instruction. Returns the value once the frame at `depth` returns.
+    fn step(&mut self, depth: usize) -> Result<Option<Value>, Diagnostic> {
+        {
+            let frame = self.frames.last_mut().unwrap();
+            let chunk = &self.program.chunks[frame.chunk];
+            let Some(op) = chunk.code.get(frame.ip).copied() else {
+                return Err(self.error(
+                    interp::UNSUPPORTED,
+                    "fell off the end of a chunk".to_owned(),
+                ));
+            };
+            frame.ip += 1;
+            let base = frame.base;
+            match op {
+                Op::Const(i) => self.stack.push(chunk.constants[i as usize].clone()),
+                Op::Load(i) => self.stack.push(self.stack[base + i as usize].clone()),
+                Op::Store(i) => {
+                    let v = self.pop();
+                    self.stack[base + i as usize] = v;
+                }
+                Op::LoadGlobal(i) => self.stack.push(self.globals[i as usize].clone()),
+                Op::StoreGlobal(i) => self.globals[i as usize] = self.pop(),
+                Op::Unary(op) => {
+                    let v = self.pop();
+                    let v = Value::unary(op, v).map_err(|m| self.error(interp::TYPE_ERROR, m))?;
+                    self.stack.push(v);
+                }
+                Op::Binary(op) => {
+                    let r = self.pop();
+                    let l = self.pop();
+                    let v =
+                        Value::binary(op, l, r).map_err(|m| self.error(interp::TYPE_ERROR, m))?;
+                    self.stack.push(v);
+                }
+                Op::CheckBool => {
+                    let top = self.stack.last().unwrap();
+                    top.as_bool()
+                        .map_err(|m| self.error(interp::TYPE_ERROR, m))?;
+                }
+                Op::Dup => self.stack.push(self.stack.last().unwrap().clone()),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Jump(target) => {
+                    self.poll()?;
+                    self.jump(target);
+                }
+                Op::JumpIfFalse(target) | Op::JumpIfTrue(target) => {
+                    let cond = self
+                        .pop()
+                        .as_bool()
+                        .map_err(|m| self.error(interp::TYPE_ERROR, m))?;
+                    if cond == matches!(op, Op::JumpIfTrue(_)) {
+                        self.jump(target);
+                    }
+                }
+                Op::Call(idx, argc) => {
+                    self.poll()?;
+                    self.push_frame(idx as usize, argc as usize)?;
+                }
+                Op::Bang(i, argc) => {
+                    self.poll()?;
+                    let Value::Str(name) = &chunk.constants[i as usize] else {
+                        unreachable!("intrinsic names are compiled as strings");
+                    };
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let at = format!(
+                        "{} (in `{}`)",
+                        self.program.modules[chunk.module].file, chunk.name
+                    );
+                    let context = intrinsic::Context::new(at, &self.runtime);
+                    let v = intrinsic::call(name, &context, args)
+                        .map_err(|(code, message)| self.error(code, message))?;
+                    self.stack.push(v);
+                }
+                Op::Init(module) => {
+                    let module = module as usize;
+                    if std::mem::replace(&mut self.started[module], true) {
+                        self.stack.push(Value::Void);
+                    } else {
+                        self.push_frame(self.program.modules[module].init, 0)?;
+                    }
+                }
+                Op::Return => {
+                    let v = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    trace!("Leaving {}", self.program.chunks[frame.chunk].name);
+                    self.stack.truncate(frame.base);
+                    self.handlers.retain(|h| h.frame <= self.frames.len());
+                    if self.frames.len() == depth {
+                        return Ok(Some(v));
+                    }
+                    self.stack.push(v);
+                }
+                Op::Widen => {
+                    let v = self.pop();
+                    self.stack.push(v.widen("f64"));
+                }
+                Op::PushHandler(target) => self.handlers.push(Handler {
+                    frame: self.frames.len(),
+                    height: self.stack.len(),
+                    target,
+                }),
+                Op::PopHandler => {
+                    self.handlers.pop();
+                }
+                Op::Matches(i) => {
+                    let Value::Str(kind) = &chunk.constants[i as usize] else {
+                        unreachable!("error kinds are compiled as strings");
+                    };
+                    let matches =
+                        matches!(self.stack.last(), Some(Value::Error(e)) if e.kind == *kind);
+                    self.stack.push(Value::Bool(matches));
+                }
+                Op::Field(i) => {
+                    let Value::Str(name) = &chunk.constants[i as usize] else {
+                        unreachable!("field names are compiled as strings");
+                    };
+                    let v = self.pop();
+                    let v = v
+                        .field(name)
+                        .map_err(|m| self.error(interp::TYPE_ERROR, m))?;
+                    self.stack.push(v);
+                }
+                Op::Spill(typed) => {
+                    let value = self.pop();
+                    let kind = match typed {
+                        true => self.pop().to_string(),
+                        false => String::new(),
+                    };
+                    let mut error = interp::spill_value(&kind, value)
+                        .map_err(|m| self.error(interp::TYPE_ERROR, m))?;
+                    let d = match error.origin.take() {
+                        Some(origin) => *origin,
+                        None => self.error(error.code, error.to_string()),
+                    };
+                    self.spilled = Some(error);
+                    return Err(d);
+                }
+                Op::Rethrow => {
+                    // Verified bytecode pops only what is there, not only what was caught.
+                    let caught = match self.pop() {
+                        Value::Error(mut error) => error.origin.take().map(|o| (error, o)),
+                        _ => None,
+                    };
+                    let Some((mut error, origin)) = caught else {
+                        return Err(self.error(
+                            interp::UNSUPPORTED,
+                            "rethrew something that was never caught".to_owned(),
+                        ));
+                    };
+                    self.error_trace = std::mem::take(&mut error.trace);
+                    self.unwinding = true;
+                    self.spilled = Some(*error);
+                    return Err(*origin);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frames.last_mut().unwrap().ip = target as usize;
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("VM stack underflow")
+    }
+
+    /// An error raised by the op being executed, pointing at the source it was compiled from.
+    fn error(&self, code: i32, message: String) -> Diagnostic {
+        let (message, file, loc) = match self.frames.last() {
+            Some(frame) => {
+                let chunk = &self.program.chunks[frame.chunk];
+                (
+                    format!("{} (in `{}`)", message, chunk.name),
+                    self.program.modules[chunk.module].file.clone(),
+                    frame
+                        .ip
+                        .checked_sub(1)
+                        .and_then(|ip| chunk.locs.get(ip).copied().flatten()),
+                )
+            }
+            None => (message, self.program.file.clone(), None),
+        };
+        let d = Diagnostic::Error(err::Error {
+            code,
+            file,
+            message,
+            ..Default::default()
+        });
+        match loc {
+            Some(loc) => d.at(loc.into()),
+            None => d,
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use super::{Chunk, Op, Program};
+use crate::interp::Value;
+
+/// Checks that `program` is safe to run, as bytecode read from an archive may not be: every
+/// operand is in bounds, and every path through a chunk keeps the stack as deep as the ops
+/// on it need, at the same depth wherever paths meet. Says what is wrong when it isn't.
+pub fn verify(program: &Program) -> Result<(), String> {
+    let chunk_index = |idx: usize, what: &str| match idx < program.chunks.len() {
+        true => Ok(()),
+        false => Err(format!("{} chunk #{} does not exist", what, idx)),
+    };
+    chunk_index(program.init, "init")?;
+    if let Some(main) = program.main {
+        chunk_index(main, "main")?;
+        if program.chunks[main].arity != 0 {
+            return Err("`main` takes arguments".to_owned());
+        }
+    }
+    for module in &program.modules {
+        chunk_index(module.init, "init")?;
+    }
+    for (idx, chunk) in program.chunks.iter().enumerate() {
+        verify_chunk(program, chunk)
+            .map_err(|why| format!("chunk #{} `{}`: {}", idx, chunk.name, why))?;
+    }
+    Ok(())
+}
+
+fn verify_chunk(program: &Program, chunk: &Chunk) -> Result<(), String> {
+    if chunk.module >= program.modules.len() {
+        return Err(format!("module #{} does not exist", chunk.module));
+    }
+    if chunk.locals.len() < chunk.arity as usize {
+        return Err("fewer locals than parameters".to_owned());
+    }
+    if chunk.locs.len() != chunk.code.len() {
+        return Err("source locations don't match the code".to_owned());
+    }
+    for (offset, op) in chunk.code.iter().enumerate() {
+        operands(program, chunk, *op).map_err(|why| format!("{:04} {:?}: {}", offset, op, why))?;
+    }
+    // How many values each op finds above the frame's locals, from following every path.
+    let mut heights: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    let mut pending = vec![(0, 0)];
+    while let Some((offset, height)) = pending.pop() {
+        let Some(op) = chunk.code.get(offset) else {
+            return Err(format!("{:04}: runs off the end of the code", offset));
+        };
+        match heights[offset] {
+            Some(seen) if seen == height => continue,
+            Some(seen) => {
+                return Err(format!(
+                    "{:04} {:?}: reached with {} values on the stack and with {}",
+                    offset, op, seen, height
+                ))
+            }
+            None => heights[offset] = Some(height),
+        }
+        let (pops, pushes) = effect(*op);
+        let Some(rest) = height.checked_sub(pops) else {
+            return Err(format!(
+                "{:04} {:?}: needs {} values on the stack but has {}",
+                offset, op, pops, height
+            ));
+        };
+        let after = rest + pushes;
+        match *op {
+            Op::Return | Op::Rethrow | Op::Spill(_) => {}
+            Op::Jump(target) => pending.push((target as usize, after)),
+            Op::JumpIfFalse(target) | Op::JumpIfTrue(target) => {
+                pending.push((target as usize, after));
+                pending.push((offset + 1, after));
+            }
+            // A handler is entered with the stack cut back to here and the error pushed.
+            Op::PushHandler(target) => {
+                pending.push((target as usize, height + 1));
+                pending.push((offset + 1, after));
+            }
+            _ => pending.push((offset + 1, after)),
+        }
+    }
+    Ok(())
+}
+
+/// Checks what `op` refers to exists and is what it is used as.
+fn operands(program: &Program, chunk: &Chunk, op: Op) -> Result<(), String> {
+    let constant = |i: u16| {
+        chunk
+            .constants
+            .get(i as usize)
+            .ok_or_else(|| format!("constant #{} does not exist", i))
+    };
+    let name = |i: u16| match constant(i)? {
+        Value::Str(_) => Ok(()),
+        other => Err(format!(
+            "constant #{} is {}, not a name",
+            i,
+            other.type_name()
+        )),
+    };
+    let index = |i: u16, len: usize, what: &str| match (i as usize) < len {
+        true => Ok(()),
+        false => Err(format!("{} #{} does not exist", what, i)),
+    };
+    match op {
+        Op::Const(i) => constant(i).map(|_| ()),
+        Op::Matches(i) | Op::Field(i) | Op::Bang(i, _) => name(i),
+        Op::Load(i) | Op::Store(i) => index(i, chunk.locals.len(), "local"),
+        Op::LoadGlobal(i) | Op::StoreGlobal(i) => index(i, program.globals.len(), "global"),
+        Op::Init(i) => index(i, program.modules.len(), "module"),
+        Op::Call(i, argc) => {
+            index(i, program.chunks.len(), "chunk")?;
+            match program.chunks[i as usize].arity == argc {
+                true => Ok(()),
+                false => Err(format!(
+                    "passes {} argument(s) to a chunk taking a different number",
+                    argc
+                )),
+            }
+        }
+        Op::Jump(target)
+        | Op::JumpIfFalse(target)
+        | Op::JumpIfTrue(target)
+        | Op::PushHandler(target) => match (target as usize) < chunk.code.len() {
+            true => Ok(()),
+            false => Err(format!("offset {} is past the end of the code", target)),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// How many values `op` pops, then pushes, when it carries on to another op in its frame.
+fn effect(op: Op) -> (usize, usize) {
+    match op {
+        Op::Const(_) | Op::Load(_) | Op::LoadGlobal(_) | Op::Init(_) => (0, 1),
+        Op::Store(_) | Op::StoreGlobal(_) | Op::Pop | Op::JumpIfFalse(_) | Op::JumpIfTrue(_) => {
+            (1, 0)
+        }
+        Op::Unary(_) | Op::CheckBool | Op::Field(_) | Op::Widen => (1, 1),
+        Op::Binary(_) => (2, 1),
+        Op::Dup | Op::Matches(_) => (1, 2),
+        Op::Call(_, argc) | Op::Bang(_, argc) => (argc as usize, 1),
+        Op::Return | Op::Rethrow => (1, 0),
+        Op::Spill(typed) => (1 + typed as usize, 0),
+        Op::Jump(_) | Op::PushHandler(_) | Op::PopHandler => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Compiler, Loc};
+    use typed_arena::Arena;
+
+    const SOURCE: &str = "\
+let scale = 2;
+
+fn risky(n: i64) -> f64 {
+    try {
+        if n > 3 && n < 10 || n == 0 {
+            spill ValueError(\"out of range\");
+        }
+        return n * scale;
+    } catch e: ValueError {
+        let why = e.message;
+        return 0;
+    } finally {
+        pause!();
+    }
+    return 1;
+}
+
+fn main() -> i64 {
+    let i = 0;
+    while i < 5 {
+        risky(i);
+        i = i + 1;
+    }
+    return i;
+}
+";
+
+    fn compile(source: &str) -> Program {
+        let arena = Arena::new();
+        let ast = crate::parse("verify.wg", source, &arena).expect("the source parses");
+        Compiler::new("verify.wg")
+            .compile(&ast)
+            .expect("the source compiles")
+    }
+
+    #[test]
+    fn compiled_programs_verify() {
+        assert_eq!(verify(&compile(SOURCE)), Ok(()));
+        let control = include_str!("../../tests/format/control.wg");
+        assert_eq!(verify(&compile(control)), Ok(()));
+    }
+
+    #[test]
+    fn operands_out_of_bounds_are_found() {
+        let program = compile(SOURCE);
+        let main = program.main.unwrap();
+        let tamper = |op: Op| {
+            let mut program = program.clone();
+            program.chunks[main].code[0] = op;
+            verify(&program).expect_err("the op is out of bounds")
+        };
+        assert!(tamper(Op::Const(999)).contains("constant #999"));
+        assert!(tamper(Op::Load(999)).contains("local #999"));
+        assert!(tamper(Op::LoadGlobal(999)).contains("global #999"));
+        assert!(tamper(Op::Call(999, 0)).contains("chunk #999"));
+        assert!(tamper(Op::Init(999)).contains("module #999"));
+        assert!(tamper(Op::Jump(99999)).contains("past the end"));
+    }
+
+    #[test]
+    fn stack_misuse_is_found() {
+        let mut program = compile(SOURCE);
+        let main = program.main.unwrap();
+        let chunk = &mut program.chunks[main];
+        chunk.code.insert(0, Op::Pop);
+        chunk.locs.insert(0, None::<Loc>);
+        let why = verify(&program).expect_err("the stack underflows");
+        assert!(
+            why.contains("needs 1 values on the stack but has 0"),
+            "{}",
+            why
+        );
+    }
+}
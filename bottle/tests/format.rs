@@ -0,0 +1,67 @@
+//! Formats every sample in `tests/format` in several styles, checking that formatting again
+//! changes nothing and that every comment survives, in order and word for word.
+use bottle::format::{format, BraceStyle, Style};
+use bottle::lex::{lex, TokenKind};
+use std::fs;
+use std::path::Path;
+
+fn styles() -> Vec<Style> {
+    let default = Style::default();
+    vec![
+        default,
+        Style {
+            brace_style: BraceStyle::NextLine,
+            ..default
+        },
+        Style {
+            indent_width: 2,
+            ..default
+        },
+        Style {
+            line_width: 40,
+            ..default
+        },
+    ]
+}
+
+fn comments(source: &str) -> Vec<String> {
+    lex(source)
+        .iter()
+        .filter(|t| t.kind == TokenKind::Comment)
+        .map(|t| t.text.trim_end().to_owned())
+        .collect()
+}
+
+#[test]
+fn formatting_is_idempotent_and_keeps_comments() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/format");
+    let mut samples: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "wg"))
+        .collect();
+    samples.sort();
+    assert!(!samples.is_empty(), "no samples in {}", dir.display());
+    for path in samples {
+        let file = path.display().to_string();
+        let source = fs::read_to_string(&path).unwrap();
+        for style in styles() {
+            let once = format(&file, &source, &style)
+                .unwrap_or_else(|d| panic!("{} in {:?}: {}", file, style, d.get_message()));
+            let twice = format(&file, &once, &style)
+                .unwrap_or_else(|d| panic!("{} in {:?}: {}", file, style, d.get_message()));
+            assert_eq!(
+                once, twice,
+                "{} formats differently again in {:?}",
+                file, style
+            );
+            assert_eq!(
+                comments(&source),
+                comments(&once),
+                "{} lost or changed a comment in {:?}",
+                file,
+                style
+            );
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Drives the language server the way an editor would: a scripted session of JSON-RPC
+//! messages in, and the replies it writes checked against the documents it was given.
+use bottle::interp::Value as WgValue;
+use bottle::intrinsic::{self, Type};
+use bottle::lsp::{path_to_uri, read_message, write_message, Server};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Cursor;
+
+const MAIN: &str = "\
+require math
+
+fn double(x: i64) -> i64 {
+    return x * 2;
+}
+
+fn main() -> i64 {
+    let total = double(math.add(1, 2));
+
+    return total;
+}
+";
+
+const MATH: &str = "\
+fn add(a: i64, b: i64) -> i64 {
+    return a + b;
+}
+";
+
+/// A session of `messages`, each a method, its params and whether it is a request. Requests
+/// get ids in order, starting from 1. Returns everything the server wrote and its exit code.
+fn session(messages: &[(&str, Value, bool)]) -> (Vec<Value>, i32) {
+    let mut input = Vec::new();
+    let mut id = 0;
+    for (method, params, request) in messages {
+        let mut message = json!({"jsonrpc": "2.0", "method": method, "params": params});
+        if *request {
+            id += 1;
+            message["id"] = json!(id);
+        }
+        write_message(&mut input, &message).unwrap();
+    }
+    let mut output = Vec::new();
+    let code = Server::new().run(Cursor::new(input), &mut output).unwrap();
+    let mut output = Cursor::new(output);
+    let mut replies = Vec::new();
+    while let Some(body) = read_message(&mut output).unwrap() {
+        replies.push(serde_json::from_str(&body).unwrap());
+    }
+    (replies, code)
+}
+
+fn result(replies: &[Value], id: u64) -> &Value {
+    let reply = replies
+        .iter()
+        .find(|r| r["id"] == json!(id))
+        .unwrap_or_else(|| panic!("no reply to request {}", id));
+    assert!(
+        reply.get("error").is_none(),
+        "request {} failed: {}",
+        id,
+        reply
+    );
+    &reply["result"]
+}
+
+fn at(uri: &str, line: u64, character: u64) -> Value {
+    json!({"textDocument": {"uri": uri}, "position": {"line": line, "character": character}})
+}
+
+fn labels(items: &Value) -> Vec<&str> {
+    items
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn serves_a_scripted_session() {
+    intrinsic::register("answer", &[], Type::Int, |_, _| Ok(WgValue::Int(42))).unwrap();
+    let dir = std::env::temp_dir().join(format!("wineglass-lsp-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("math.wg"), MATH).unwrap();
+    let main = path_to_uri(&dir.join("main.wg"));
+    let math = path_to_uri(&dir.join("math.wg"));
+    let open = json!({"textDocument": {"uri": main, "languageId": "wineglass", "version": 1, "text": MAIN}});
+    let (replies, code) = session(&[
+        ("initialize", json!({"capabilities": {}}), true),
+        ("initialized", json!({}), false),
+        ("textDocument/didOpen", open, false),
+        // `double` where `main` calls it, and `add` in `math.add`.
+        ("textDocument/definition", at(&main, 7, 18), true),
+        ("textDocument/definition", at(&main, 7, 30), true),
+        ("textDocument/hover", at(&main, 7, 18), true),
+        ("textDocument/completion", at(&main, 8, 4), true),
+        ("textDocument/completion", at(&main, 7, 28), true),
+        ("shutdown", Value::Null, true),
+        ("exit", Value::Null, false),
+    ]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(code, 0);
+
+    let capabilities = &result(&replies, 1)["capabilities"];
+    assert_eq!(capabilities["definitionProvider"], json!(true));
+    assert_eq!(
+        capabilities["completionProvider"]["triggerCharacters"],
+        json!(["."])
+    );
+
+    let published = replies
+        .iter()
+        .find(|r| r["method"] == "textDocument/publishDiagnostics")
+        .expect("diagnostics are published when a document opens");
+    assert_eq!(published["params"]["uri"], json!(main));
+    assert_eq!(published["params"]["diagnostics"], json!([]));
+
+    let definition = result(&replies, 2);
+    assert_eq!(definition["uri"], json!(main));
+    assert_eq!(
+        definition["range"]["start"],
+        json!({"line": 2, "character": 3})
+    );
+    let definition = result(&replies, 3);
+    assert_eq!(definition["uri"], json!(math));
+    assert_eq!(
+        definition["range"]["start"],
+        json!({"line": 0, "character": 3})
+    );
+
+    let hover = result(&replies, 4)["contents"]["value"].as_str().unwrap();
+    assert!(hover.contains("fn double(x: i64) -> i64"), "{}", hover);
+
+    let items = result(&replies, 5);
+    let offered = labels(items);
+    for label in [
+        "total",
+        "double",
+        "math",
+        "while",
+        "i64",
+        "sleep!",
+        "answer!",
+        "TypeError",
+    ] {
+        assert!(
+            offered.contains(&label),
+            "`{}` not offered: {:?}",
+            label,
+            offered
+        );
+    }
+    let answer = items
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|item| item["label"] == "answer!")
+        .unwrap();
+    assert_eq!(answer["detail"], json!("fn answer!() -> i64"));
+
+    assert_eq!(labels(result(&replies, 6)), ["add"]);
+    assert_eq!(result(&replies, 7), &Value::Null);
+}
+
+#[test]
+fn requests_before_initialize_are_refused() {
+    let (replies, code) = session(&[("textDocument/hover", json!({}), true)]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        replies[0]["error"]["code"],
+        json!(bottle::lsp::SERVER_NOT_INITIALIZED)
+    );
+}
@@ -1,98 +1,475 @@
-use std::any::TypeId;
+use error::{ErrorLevel, Note, Position, Report, Span};
+use log::LevelFilter;
 use std::collections::HashMap;
 use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
+use toml::{Table, Value};
 
-pub(super) enum Arg {
+pub use crate::version::Version;
+
+/// An option that is not in the table.
+pub const UNKNOWN_ARGUMENT: i64 = 7001;
+/// An option that takes a value was given none.
+pub const MISSING_VALUE: i64 = 7002;
+/// A value that does not fit its option.
+pub const INVALID_VALUE: i64 = 7003;
+/// A positional argument the command has no use for.
+pub const UNEXPECTED_ARGUMENT: i64 = 7004;
+/// A config file exists but could not be read.
+pub const CONFIG_READ: i64 = 7005;
+/// A config file is not valid TOML.
+pub const CONFIG_SYNTAX: i64 = 7006;
+
+/// The project config, looked for in the working directory and its parents.
+pub const PROJECT_FILE: &str = "wineglass.toml";
+/// Environment variables starting with this override config files. `WINEGLASS_MAX_THREADS`
+/// sets `max-threads`; a double underscore separates folders, so `WINEGLASS_RUN__SEED` sets
+/// `run.seed`.
+pub const ENV_PREFIX: &str = "WINEGLASS_";
+/// Names the user config instead of `~/.config/wineglass/config.toml`.
+pub const USER_CONFIG_VAR: &str = "WINEGLASS_CONFIG";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arg {
     File(String),
     Int(usize),
     Bool(bool),
+    Str(String),
+}
+
+/// The kind of value an option takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A flag. `--key` sets it; `--key=false` clears it.
+    Bool,
+    Int,
+    File,
+    Str,
+}
+
+/// One option `Args` understands.
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub short: Option<char>,
+    pub kind: ArgKind,
+    /// Names the value in help, e.g. `<n>`.
+    pub value: &'static str,
+    /// The values a `Str` option may take. Empty allows any.
+    pub choices: &'static [&'static str],
+    pub default: Option<Arg>,
+    pub help: &'static str,
+}
+
+impl ArgSpec {
+    const fn flag(name: &'static str, short: Option<char>, help: &'static str) -> Self {
+        Self {
+            name,
+            short,
+            kind: ArgKind::Bool,
+            value: "",
+            choices: &[],
+            default: None,
+            help,
+        }
+    }
+
+    const fn valued(
+        name: &'static str,
+        short: Option<char>,
+        kind: ArgKind,
+        value: &'static str,
+        help: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            short,
+            kind,
+            value,
+            choices: &[],
+            default: None,
+            help,
+        }
+    }
+
+    /// Whether a value from a config file or the environment suits this option.
+    fn accepts(&self, value: &Value) -> Result<(), String> {
+        match (self.kind, value) {
+            (ArgKind::Bool, Value::Boolean(_)) => Ok(()),
+            (ArgKind::Int, Value::Integer(n)) if *n >= 0 => Ok(()),
+            (ArgKind::File, Value::String(s)) if !s.is_empty() => Ok(()),
+            (ArgKind::Str, Value::String(s))
+                if self.choices.is_empty() || self.choices.contains(&s.as_str()) =>
+            {
+                Ok(())
+            }
+            (ArgKind::Bool, _) => Err("expected `true` or `false`".to_owned()),
+            (ArgKind::Int, _) => Err("expected a whole number".to_owned()),
+            (ArgKind::File, _) => Err("expected a path".to_owned()),
+            (ArgKind::Str, _) if self.choices.is_empty() => Err("expected a string".to_owned()),
+            (ArgKind::Str, _) => Err(format!(
+                "expected one of `\"{}\"`",
+                self.choices.join("\"`, `\"")
+            )),
+        }
+    }
+
+    /// Turns the text given for this option into its value.
+    fn read(&self, text: &str) -> Result<Arg, ConfigError> {
+        let invalid = |expected: String| {
+            ConfigError::new(
+                INVALID_VALUE,
+                format!("invalid value `{}` for `--{}`", text, self.name),
+            )
+            .with_help(expected)
+        };
+        match self.kind {
+            ArgKind::Bool => match text {
+                "true" | "yes" | "on" => Ok(Arg::Bool(true)),
+                "false" | "no" | "off" => Ok(Arg::Bool(false)),
+                _ => Err(invalid("expected `true` or `false`".to_owned())),
+            },
+            ArgKind::Int => text
+                .parse()
+                .map(Arg::Int)
+                .map_err(|_| invalid("expected a whole number".to_owned())),
+            ArgKind::File if text.is_empty() => Err(invalid("expected a path".to_owned())),
+            ArgKind::File => Ok(Arg::File(text.to_owned())),
+            ArgKind::Str if !self.choices.is_empty() && !self.choices.contains(&text) => Err(
+                invalid(format!("expected one of `{}`", self.choices.join("`, `"))),
+            ),
+            ArgKind::Str => Ok(Arg::Str(text.to_owned())),
+        }
+    }
 }
 
-pub(super) struct AllowedArgument {
-    storeb: HashMap<String, bool>,
-    storei: HashMap<String, u64>,
+/// The table of options the command line accepts.
+pub struct AllowedArgument {
+    specs: Vec<ArgSpec>,
 }
+
+impl Default for AllowedArgument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AllowedArgument {
     pub fn new() -> Self {
         Self {
-            storeb: {
-                let mut storeb = HashMap::new();
-                storeb.insert("help".to_owned(), false);
-                storeb.insert("verbose".to_owned(), false);
-                storeb.insert("info".to_owned(), false);
-                storeb.insert("debug".to_owned(), false);
-                storeb.insert("version".to_owned(), false);
-                storeb.insert("trackerr".to_owned(), false);
-                storeb.insert("continue".to_owned(), false);
-                storeb.insert("log".to_owned(), false);
+            specs: vec![
+                ArgSpec::valued(
+                    "code",
+                    Some('e'),
+                    ArgKind::Str,
+                    "<code>",
+                    "Code for eval, instead of reading stdin",
+                ),
+                ArgSpec::valued(
+                    "output",
+                    Some('o'),
+                    ArgKind::File,
+                    "<path>",
+                    "Where pack writes the archive or unpack its contents",
+                ),
+                ArgSpec::valued(
+                    "registry",
+                    None,
+                    ArgKind::File,
+                    "<dir>",
+                    "Directory of .wgb archives to install bottles from",
+                ),
+                ArgSpec::valued(
+                    "cache",
+                    None,
+                    ArgKind::File,
+                    "<dir>",
+                    "Where installed archives are kept (default ~/.cache/wineglass)",
+                ),
+                ArgSpec::flag("dump-ast", None, "With parse, print the AST"),
+                ArgSpec::flag(
+                    "check",
+                    None,
+                    "With fmt, list the files that need formatting instead of rewriting them",
+                ),
+                ArgSpec {
+                    default: Some(Arg::Int(4)),
+                    ..ArgSpec::valued(
+                        "indent-width",
+                        None,
+                        ArgKind::Int,
+                        "<n>",
+                        "Spaces per level of nesting when formatting",
+                    )
+                },
+                ArgSpec {
+                    choices: &["same-line", "next-line"],
+                    default: Some(Arg::Str("same-line".to_owned())),
+                    ..ArgSpec::valued(
+                        "brace-style",
+                        None,
+                        ArgKind::Str,
+                        "<style>",
+                        "Whether fmt puts a block's `{` on the `same-line` or the `next-line`",
+                    )
+                },
+                ArgSpec {
+                    default: Some(Arg::Int(100)),
+                    ..ArgSpec::valued(
+                        "line-width",
+                        None,
+                        ArgKind::Int,
+                        "<n>",
+                        "How long a line fmt writes may get before it is broken",
+                    )
+                },
+                ArgSpec::flag(
+                    "disassemble",
+                    None,
+                    "With check, print the compiled bytecode",
+                ),
+                ArgSpec::flag(
+                    "bytecode",
+                    None,
+                    "Run on the bytecode VM instead of walking the AST; pack includes it",
+                ),
+                ArgSpec::valued(
+                    "grant",
+                    None,
+                    ArgKind::Str,
+                    "<caps>",
+                    "Capabilities bottles may ask for in bottle.toml, separated by commas",
+                ),
+                ArgSpec::valued(
+                    "refuse",
+                    None,
+                    ArgKind::Str,
+                    "<list>",
+                    "Directives, such as #loglevel, and capabilities bottles may not use here",
+                ),
+                ArgSpec {
+                    default: Some(Arg::Int(3)),
+                    ..ArgSpec::valued(
+                        "max-threads",
+                        None,
+                        ArgKind::Int,
+                        "<n>",
+                        "How many bottles may execute at once",
+                    )
+                },
+                ArgSpec {
+                    choices: &["human", "json"],
+                    default: Some(Arg::Str("human".to_owned())),
+                    ..ArgSpec::valued(
+                        "message-format",
+                        None,
+                        ArgKind::Str,
+                        "<fmt>",
+                        "Write diagnostics as `human` text or `json` lines",
+                    )
+                },
+                ArgSpec::flag("verbose", Some('v'), "Log what the bottles are doing"),
+                ArgSpec::flag("debug", None, "Log everything"),
+                ArgSpec::valued(
+                    "loglevel",
+                    None,
+                    ArgKind::Int,
+                    "<0-5>",
+                    "Log from nothing (0) up to tracing (5)",
+                ),
+                ArgSpec::flag("help", Some('h'), "Print this help"),
+                ArgSpec::flag("version", None, "Print the version"),
                 // Add more arguments here
-                storeb
-            },
-            storei: {
-                let mut storei = HashMap::new();
-                storei.insert("loglevel".to_owned(), 3);
-                storei.insert("max-threads".to_owned(), 3);
-                storei
-            },
+            ],
         }
     }
-    pub fn find(&self, key: &str) -> Result<TypeId, String> {
-        if self.storeb.contains_key(key) {
-            Ok(TypeId::of::<HashMap<String, bool>>())
-        } else if self.storei.contains_key(key) {
-            return Ok(TypeId::of::<HashMap<String, u64>>());
-        } else {
-            let mut e = "Expected a valid map key, instead received ".to_owned();
-            e.push_str(key);
-            return Err(e);
+
+    pub fn find(&self, key: &str) -> Option<&ArgSpec> {
+        self.specs.iter().find(|s| s.name == key)
+    }
+
+    pub fn find_short(&self, short: char) -> Option<&ArgSpec> {
+        self.specs.iter().find(|s| s.short == Some(short))
+    }
+
+    /// One line per option, aligned, for `--help`.
+    pub fn help(&self) -> String {
+        let usage: Vec<String> = self
+            .specs
+            .iter()
+            .map(|s| {
+                let short = match s.short {
+                    Some(c) => format!("-{}, ", c),
+                    None => String::new(),
+                };
+                match s.kind {
+                    ArgKind::Bool => format!("{}--{}", short, s.name),
+                    _ => format!("{}--{} {}", short, s.name, s.value),
+                }
+            })
+            .collect();
+        let width = usage.iter().map(String::len).max().unwrap_or(0);
+        let mut out = String::from("Options:");
+        for (spec, usage) in self.specs.iter().zip(usage) {
+            write!(out, "\n  {:<w$}  {}", usage, spec.help, w = width).unwrap();
+            match &spec.default {
+                Some(Arg::Int(n)) => write!(out, " (default {})", n).unwrap(),
+                Some(Arg::Str(s) | Arg::File(s)) => write!(out, " (default {})", s).unwrap(),
+                _ => {}
+            }
         }
+        out
     }
 }
 
-enum Argtype {
-    Bool,
-    Int,
-    String,
+/// Why the command line or a config file could not be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub code: i64,
+    pub message: String,
+    /// The config file at fault. Empty for the command line and the environment.
+    pub file: String,
+    /// Where in `file` the problem is. The default span means nowhere in particular.
+    pub span: Span,
+    pub notes: Box<[Note]>,
+    /// Text of `file`, kept so the error can point into it.
+    pub source: Option<Box<str>>,
 }
 
-pub struct Args {
-    ///takes care of the arguments, including parsing.
-    map: HashMap<String, Arg>,
+impl ConfigError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            file: String::new(),
+            span: Span::default(),
+            notes: Box::default(),
+            source: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        let mut notes = std::mem::take(&mut self.notes).into_vec();
+        notes.push(Note::Help(help.into()));
+        self.notes = notes.into_boxed_slice();
+        self
+    }
+
+    fn in_file(mut self, file: &Path) -> Self {
+        self.file = file.display().to_string();
+        self
+    }
 }
 
-impl Default for Args {
-    fn default() -> Self {
-        Self::new()
+impl Report for ConfigError {
+    fn level(&self) -> ErrorLevel {
+        ErrorLevel::ERROR
+    }
+    fn code(&self) -> i64 {
+        self.code
+    }
+    fn message(&self) -> String {
+        self.message.clone()
+    }
+    fn file(&self) -> String {
+        self.file.clone()
+    }
+    fn span(&self) -> Option<Span> {
+        (self.span != Span::default()).then_some(self.span)
+    }
+    fn notes(&self) -> &[Note] {
+        &self.notes
     }
 }
 
+/// The parsed command line: options by name and the positional arguments in order.
+#[derive(Debug, Default)]
+pub struct Args {
+    map: HashMap<String, Arg>,
+    positionals: Vec<String>,
+}
+
 impl Args {
-    pub fn new() -> Self {
-        let mut map: HashMap<String, Arg> = HashMap::new();
-        let allowed_args = AllowedArgument::new();
-        let v: Vec<String> = env::args().collect();
-        for i in 1..v.len() {
-            let current_arg = &v[i];
-            if current_arg.starts_with("--") {
-                let arg_content = current_arg[2..current_arg.len()].to_string();
-                if allowed_args.find(&arg_content).expect("invalid args!")
-                    == TypeId::of::<HashMap<String, bool>>()
-                {
-                    map.insert(arg_content, Arg::Bool(true));
-                } else if allowed_args.find(&arg_content).expect("invalid args!")
-                    == TypeId::of::<HashMap<String, u64>>()
-                {
-                    let tmp = Arg::Int(
-                        arg_content
-                            .parse::<usize>()
-                            .expect("Invalid integer argument"),
-                    );
-                    map.insert(arg_content, tmp);
-                }
+    /// Parses the arguments this process was started with against [`AllowedArgument::new`].
+    pub fn new() -> Result<Self, ConfigError> {
+        Self::parse(env::args().skip(1), &AllowedArgument::new())
+    }
+
+    /// Parses `args`, the arguments after the program name. Options are `--key value`,
+    /// `--key=value`, `-k value` or `-kvalue`; flags take no value unless written `--key=false`
+    /// or `-k=false`, so short flags can't be run together as `-ve`. Anything else, `-`
+    /// included, is positional, as is everything after `--`.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        allowed: &AllowedArgument,
+    ) -> Result<Self, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parsed.positionals.extend(args.by_ref());
+                break;
             }
+            let (spec, inline) = if let Some(long) = arg.strip_prefix("--") {
+                let (key, inline) = match long.split_once('=') {
+                    Some((key, value)) => (key, Some(value.to_owned())),
+                    None => (long, None),
+                };
+                match allowed.find(key) {
+                    Some(spec) => (spec, inline),
+                    None => {
+                        return Err(ConfigError::new(
+                            UNKNOWN_ARGUMENT,
+                            format!("unknown option `--{}`", key),
+                        )
+                        .with_help("`--help` lists the options"))
+                    }
+                }
+            } else if let Some(short) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+                let mut chars = short.chars();
+                let c = chars.next().unwrap_or_default();
+                let Some(spec) = allowed.find_short(c) else {
+                    return Err(ConfigError::new(
+                        UNKNOWN_ARGUMENT,
+                        format!("unknown option `-{}`", c),
+                    )
+                    .with_help("`--help` lists the options"));
+                };
+                let inline = match chars.as_str() {
+                    "" => None,
+                    rest if spec.kind == ArgKind::Bool && !rest.starts_with('=') => {
+                        return Err(ConfigError::new(
+                            INVALID_VALUE,
+                            format!("`-{}` takes no value, so `{}` can't be read", c, arg),
+                        )
+                        .with_help(format!("give `-{}` on its own", c)))
+                    }
+                    rest => Some(rest.strip_prefix('=').unwrap_or(rest).to_owned()),
+                };
+                (spec, inline)
+            } else {
+                parsed.positionals.push(arg);
+                continue;
+            };
+            let value = match (spec.kind, inline) {
+                (_, Some(text)) => spec.read(&text)?,
+                (ArgKind::Bool, None) => Arg::Bool(true),
+                (_, None) => match args.next() {
+                    Some(text) => spec.read(&text)?,
+                    None => {
+                        return Err(ConfigError::new(
+                            MISSING_VALUE,
+                            format!("`{}` needs a value", arg),
+                        )
+                        .with_help(format!("write `--{} {}`", spec.name, spec.value)))
+                    }
+                },
+            };
+            parsed.map.insert(spec.name.to_owned(), value);
         }
-        Args { map }
+        Ok(parsed)
     }
 }
 
@@ -101,115 +478,556 @@ impl Args {
         self.map.get(key)
     }
 
+    /// Whether the flag `key` was given and not turned off.
+    pub fn flag(&self, key: &str) -> bool {
+        matches!(self.get(key), Some(Arg::Bool(true)))
+    }
+
+    pub fn int(&self, key: &str) -> Option<usize> {
+        match self.get(key) {
+            Some(Arg::Int(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// The value of a `File` or `Str` option.
+    pub fn text(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(Arg::File(s) | Arg::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn positionals(&self) -> &[String] {
+        &self.positionals
+    }
+
+    /// How much to log, if `--loglevel`, `--debug` or `--verbose` asked for it, in that order.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        log_level(
+            self.int("loglevel"),
+            self.flag("debug"),
+            self.flag("verbose"),
+        )
+    }
+
     pub fn apply(&self) {
-        if match self.get("debug") {
-            Some(Arg::Bool(b)) => *b,
-            _ => false,
-        } {
-            log::set_max_level(log::LevelFilter::Debug);
+        if let Some(level) = self.log_level() {
+            log::set_max_level(level);
         }
     }
 }
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
+/// The shared config. Only defaults until [`Config::init`] has loaded the rest.
 pub fn get_config() -> &'static Config {
     CONFIG.get_or_init(Config::new)
 }
 
-pub(super) struct ConfigSettings {
-    name: String,
-    value: String,
+/// The log level asked for by `loglevel` (0 to 5), else `debug`, else `verbose`.
+fn log_level(loglevel: Option<usize>, debug: bool, verbose: bool) -> Option<LevelFilter> {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    match (loglevel, debug, verbose) {
+        (Some(n), _, _) => Some(LEVELS[n.min(LEVELS.len() - 1)]),
+        (None, true, _) => Some(LevelFilter::Debug),
+        (None, false, true) => Some(LevelFilter::Info),
+        _ => None,
+    }
 }
 
-pub struct Config {
-    name: String,
-    children: HashMap<String, &'static ConfigFolder>,
+/// A value and the dotted path it is found at, e.g. `run.max-threads`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigSettings {
+    pub name: String,
+    pub value: Value,
 }
 
-pub(super) struct ConfigFolder {
-    children: HashMap<String, &'static FolderContent>,
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigFolder {
+    children: HashMap<String, FolderContent>,
 }
 
-enum FolderContent {
+#[derive(Debug, Clone, PartialEq)]
+pub enum FolderContent {
     Folder(ConfigFolder),
     Setting(ConfigSettings),
 }
+
+impl ConfigFolder {
+    /// The folder for a TOML table whose own path is `prefix`.
+    fn from_table(table: Table, prefix: &str) -> Self {
+        let children = table
+            .into_iter()
+            .map(|(key, value)| {
+                let name = match prefix {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", prefix, key),
+                };
+                let content = match value {
+                    Value::Table(table) => FolderContent::Folder(Self::from_table(table, &name)),
+                    value => FolderContent::Setting(ConfigSettings { name, value }),
+                };
+                (key, content)
+            })
+            .collect();
+        Self { children }
+    }
+
+    /// Sets `path`, making the folders on the way to it.
+    fn set(&mut self, path: &str, value: Value) {
+        let mut folder = self;
+        let mut keys = path.split('.').peekable();
+        while let Some(key) = keys.next() {
+            if keys.peek().is_none() {
+                let setting = ConfigSettings {
+                    name: path.to_owned(),
+                    value,
+                };
+                folder
+                    .children
+                    .insert(key.to_owned(), FolderContent::Setting(setting));
+                return;
+            }
+            let entry = folder
+                .children
+                .entry(key.to_owned())
+                .or_insert_with(|| FolderContent::Folder(ConfigFolder::default()));
+            if let FolderContent::Setting(_) = entry {
+                *entry = FolderContent::Folder(ConfigFolder::default());
+            }
+            let FolderContent::Folder(next) = entry else {
+                unreachable!("replaced by a folder above")
+            };
+            folder = next;
+        }
+    }
+
+    /// Lays `other` over this folder. Its settings win; folders in both are merged.
+    fn merge(&mut self, other: ConfigFolder) {
+        for (key, theirs) in other.children {
+            match (self.children.get_mut(&key), theirs) {
+                (Some(FolderContent::Folder(ours)), FolderContent::Folder(theirs)) => {
+                    ours.merge(theirs)
+                }
+                (_, theirs) => {
+                    self.children.insert(key, theirs);
+                }
+            }
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Option<&FolderContent> {
+        let (first, rest) = match path.split_once('.') {
+            Some((first, rest)) => (first, Some(rest)),
+            None => (path, None),
+        };
+        match (self.children.get(first)?, rest) {
+            (content, None) => Some(content),
+            (FolderContent::Folder(folder), Some(rest)) => folder.lookup(rest),
+            (FolderContent::Setting(_), Some(_)) => None,
+        }
+    }
+
+    /// Checks the settings `allowed` knows about, which all live at the top.
+    fn validate(&self, allowed: &AllowedArgument) -> Result<(), ConfigError> {
+        for (key, content) in &self.children {
+            let (Some(spec), FolderContent::Setting(setting)) = (allowed.find(key), content) else {
+                continue;
+            };
+            if let Err(expected) = spec.accepts(&setting.value) {
+                return Err(ConfigError::new(
+                    INVALID_VALUE,
+                    format!("invalid value `{}` for `{}`", setting.value, key),
+                )
+                .with_help(expected));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    name: String,
+    root: ConfigFolder,
+}
+
 impl Config {
-    /// Loads the config file from the disk. This is not recommended to check config.
-    /// use get_config() instead.
-    pub fn load() -> Config {
+    /// Loads every layer from the disk and the environment, with `args` on top. This is not
+    /// recommended to check config; use [`get_config`] once [`Config::init`] has run.
+    ///
+    /// Later layers win: defaults, then the user config, then the project's
+    /// `wineglass.toml`, then `WINEGLASS_*` variables, then the command line.
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let files = [
+            user_config(),
+            find_project(&env::current_dir().unwrap_or_default()),
+        ];
+        Config::layered(files.into_iter().flatten(), env::vars(), args)
+    }
+
+    /// [`Config::load`] from the config files at `files`, lowest first, and the environment
+    /// variables `vars`.
+    fn layered(
+        files: impl IntoIterator<Item = PathBuf>,
+        vars: impl Iterator<Item = (String, String)>,
+        args: &Args,
+    ) -> Result<Config, ConfigError> {
+        let allowed = AllowedArgument::new();
         let mut config = Config::new();
         config.name = "config".to_string();
-
-        config
+        for path in files {
+            let folder = read_file(&path)?;
+            folder.validate(&allowed).map_err(|e| e.in_file(&path))?;
+            log::debug!("loaded config from {}", path.display());
+            config.root.merge(folder);
+        }
+        let environment = from_env(vars);
+        environment.validate(&allowed).map_err(|e| {
+            let help = format!("set through a `{}` environment variable", ENV_PREFIX);
+            e.with_help(help)
+        })?;
+        config.root.merge(environment);
+        for (key, arg) in &args.map {
+            let value = match arg {
+                Arg::Int(n) => Value::Integer(*n as i64),
+                Arg::Bool(b) => Value::Boolean(*b),
+                Arg::File(s) | Arg::Str(s) => Value::String(s.clone()),
+            };
+            config.root.set(key, value);
+        }
+        Ok(config)
     }
+
+    /// Just the defaults from the argument table.
     fn new() -> Self {
+        let mut root = ConfigFolder::default();
+        for spec in &AllowedArgument::new().specs {
+            let value = match &spec.default {
+                Some(Arg::Int(n)) => Value::Integer(*n as i64),
+                Some(Arg::Bool(b)) => Value::Boolean(*b),
+                Some(Arg::File(s) | Arg::Str(s)) => Value::String(s.clone()),
+                None => continue,
+            };
+            root.set(spec.name, value);
+        }
         Config {
             name: String::new(),
-            children: HashMap::new(),
+            root,
         }
     }
-    ///
-    pub fn init() -> &'static Config {
-        CONFIG.get_or_init(Config::load)
+
+    /// Loads the config on first use and returns the shared instance.
+    pub fn init(args: &Args) -> Result<&'static Config, ConfigError> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let config = Config::load(args)?;
+        Ok(CONFIG.get_or_init(|| config))
     }
-    /// Get all the subfolders in the root Cfg folder. Uses clone(), so watch out for time consumption.
-    fn get_subfolder(&self) -> Result<HashMap<String, &ConfigFolder>, String> {
-        Ok(self.children.clone())
+
+    /// Get all the subfolders in the root Cfg folder.
+    pub fn get_subfolder(&self) -> HashMap<String, &ConfigFolder> {
+        self.root
+            .children
+            .iter()
+            .filter_map(|(key, content)| match content {
+                FolderContent::Folder(folder) => Some((key.clone(), folder)),
+                FolderContent::Setting(_) => None,
+            })
+            .collect()
     }
 
-    /// Get all the settings that have the key given by DFS.
-    fn find_setting(&self, name: &str) -> Vec<ConfigSettings> {
-        let results = Vec::new();
-        let stack: Vec<(String, &ConfigFolder)> = Vec::new();
+    /// Get all the settings that have the key given by DFS, wherever they are nested.
+    pub fn find_setting(&self, name: &str) -> Vec<&ConfigSettings> {
+        let mut results = Vec::new();
+        let mut stack: Vec<&ConfigFolder> = vec![&self.root];
+        while let Some(folder) = stack.pop() {
+            for (key, content) in &folder.children {
+                match content {
+                    FolderContent::Folder(child) => stack.push(child),
+                    FolderContent::Setting(setting) if key == name => results.push(setting),
+                    FolderContent::Setting(_) => {}
+                }
+            }
+        }
+        results.sort_by(|a, b| a.name.cmp(&b.name));
         results
     }
+
+    /// The value at a dotted path such as `run.max-threads`.
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        match self.root.lookup(path)? {
+            FolderContent::Setting(setting) => Some(&setting.value),
+            FolderContent::Folder(_) => None,
+        }
+    }
+
+    /// `None` when `path` is missing or holds something other than an integer.
+    pub fn get_int(&self, path: &str) -> Option<i64> {
+        self.get(path)?.as_integer()
+    }
+
+    pub fn get_float(&self, path: &str) -> Option<f64> {
+        match self.get(path)? {
+            Value::Integer(n) => Some(*n as f64),
+            value => value.as_float(),
+        }
+    }
+
+    pub fn get_bool(&self, path: &str) -> Option<bool> {
+        self.get(path)?.as_bool()
+    }
+
+    pub fn get_str(&self, path: &str) -> Option<&str> {
+        self.get(path)?.as_str()
+    }
+
+    /// How much to log, from `loglevel`, `debug` or `verbose`, in that order.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        log_level(
+            self.get_int("loglevel").map(|n| n.max(0) as usize),
+            self.get_bool("debug").unwrap_or(false),
+            self.get_bool("verbose").unwrap_or(false),
+        )
+    }
+}
+
+/// `$WINEGLASS_CONFIG`, else `config.toml` under the user's config directory, if it exists.
+fn user_config() -> Option<PathBuf> {
+    let path = match env::var_os(USER_CONFIG_VAR) {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        }
+        .join("wineglass")
+        .join("config.toml"),
+    };
+    path.is_file().then_some(path)
 }
 
-pub struct Version {
-    major: i32,
-    minor: i32,
-    patch: i32,
+/// The nearest `wineglass.toml` in `dir` or above it.
+fn find_project(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
 }
 
-impl Version {
-    pub fn new(major: i32, minor: i32, patch: i32) -> Version {
-        Version {
-            major,
-            minor,
-            patch,
+fn read_file(path: &Path) -> Result<ConfigFolder, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|why| {
+        ConfigError::new(CONFIG_READ, format!("couldn't read config: {}", why)).in_file(path)
+    })?;
+    match text.parse::<Table>() {
+        Ok(table) => Ok(ConfigFolder::from_table(table, "")),
+        Err(why) => {
+            let mut lines = why.message().lines();
+            let message = lines.next().unwrap_or("invalid TOML").to_owned();
+            let mut e = ConfigError::new(CONFIG_SYNTAX, message).in_file(path);
+            e.notes = lines.map(|line| Note::Note(line.to_owned())).collect();
+            if let Some(range) = why.span() {
+                let start = position(&text, range.start);
+                // Errors at the end of a line take the line break with them.
+                let end = position(&text, range.end).min(Position::new(start.line, usize::MAX));
+                e.span = Span::new(start, end);
+            }
+            e.source = Some(text.into_boxed_str());
+            Err(e)
         }
     }
-    pub fn get_crate_ver() -> Version {
-        Version {
-            major: env!("CARGO_PKG_VERSION_MAJOR").parse::<i32>().unwrap(),
-            minor: env!("CARGO_PKG_VERSION_MINOR").parse::<i32>().unwrap(),
-            patch: env!("CARGO_PKG_VERSION_PATCH").parse::<i32>().unwrap(),
+}
+
+/// The line and column of byte `offset` in `text`.
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Position::new(
+        before.matches('\n').count(),
+        before[line_start..].chars().count(),
+    )
+}
+
+/// Settings from `WINEGLASS_*` variables. Values are read as booleans or numbers when they
+/// look like one and as strings otherwise.
+fn from_env(vars: impl Iterator<Item = (String, String)>) -> ConfigFolder {
+    let mut folder = ConfigFolder::default();
+    for (key, text) in vars {
+        let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if key == USER_CONFIG_VAR || name.is_empty() {
+            continue;
         }
+        let path = name.to_lowercase().replace("__", ".").replace('_', "-");
+        let value = if let Ok(b) = text.parse() {
+            Value::Boolean(b)
+        } else if let Ok(n) = text.parse() {
+            Value::Integer(n)
+        } else if let Ok(f) = text.parse() {
+            Value::Float(f)
+        } else {
+            Value::String(text)
+        };
+        folder.set(&path, value);
+    }
+    folder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ConfigError> {
+        let args = args.iter().map(|arg| arg.to_string());
+        Args::parse(args, &AllowedArgument::new())
+    }
+
+    fn code(args: &[&str]) -> i64 {
+        parse(args).expect_err("the arguments are refused").code
     }
-    pub fn major(&self) -> i32 {
-        self.major
+
+    #[test]
+    fn long_options_take_the_next_argument_or_an_attached_value() {
+        let args = parse(&["pack", "--output", "out.wgb", "--code=1 + 2", "main.wg"]).unwrap();
+        assert_eq!(args.text("output"), Some("out.wgb"));
+        assert_eq!(args.text("code"), Some("1 + 2"));
+        assert_eq!(args.positionals(), ["pack", "main.wg"]);
+        let args = parse(&["--indent-width=2", "--max-threads", "8"]).unwrap();
+        assert_eq!(
+            (args.int("indent-width"), args.int("max-threads")),
+            (Some(2), Some(8))
+        );
     }
-    pub fn minor(&self) -> i32 {
-        self.minor
+
+    #[test]
+    fn short_options_take_the_next_argument_or_the_rest_of_their_own() {
+        let args = parse(&["-o", "out.wgb", "-e1 + 2"]).unwrap();
+        assert_eq!(args.text("output"), Some("out.wgb"));
+        assert_eq!(args.text("code"), Some("1 + 2"));
+        assert_eq!(parse(&["-e=1"]).unwrap().text("code"), Some("1"));
     }
-    pub fn patch(&self) -> i32 {
-        self.patch
+
+    #[test]
+    fn flags_are_set_unless_turned_off() {
+        assert!(parse(&["--verbose"]).unwrap().flag("verbose"));
+        assert!(parse(&["-v"]).unwrap().flag("verbose"));
+        assert!(!parse(&["--verbose=false"]).unwrap().flag("verbose"));
+        assert!(!parse(&["-v=off"]).unwrap().flag("verbose"));
+        assert!(!parse(&[]).unwrap().flag("verbose"));
     }
-}
-impl From<&str> for Version {
-    fn from(s: &str) -> Version {
-        let v: Vec<&str> = s.split('.').collect();
-        Version {
-            major: v[0].parse::<i32>().unwrap(),
-            minor: v[1].parse::<i32>().unwrap(),
-            patch: v[2].parse::<i32>().unwrap(),
+
+    #[test]
+    fn short_flags_are_not_run_together() {
+        assert_eq!(code(&["-ve"]), INVALID_VALUE);
+        assert_eq!(code(&["-vtrue"]), INVALID_VALUE);
+        assert_eq!(code(&["-v=e"]), INVALID_VALUE);
+    }
+
+    #[test]
+    fn everything_after_a_double_dash_is_positional() {
+        let args = parse(&["eval", "--", "--verbose", "-x", "--"]).unwrap();
+        assert!(!args.flag("verbose"));
+        assert_eq!(args.positionals(), ["eval", "--verbose", "-x", "--"]);
+        assert_eq!(parse(&["-"]).unwrap().positionals(), ["-"]);
+    }
+
+    #[test]
+    fn options_without_their_value_are_refused() {
+        assert_eq!(code(&["--output"]), MISSING_VALUE);
+        assert_eq!(code(&["run", "-o"]), MISSING_VALUE);
+        assert_eq!(code(&["--indent-width", "wide"]), INVALID_VALUE);
+        assert_eq!(code(&["--brace-style=sideways"]), INVALID_VALUE);
+        assert_eq!(code(&["--output="]), INVALID_VALUE);
+    }
+
+    #[test]
+    fn unknown_options_are_refused() {
+        assert_eq!(code(&["--nope"]), UNKNOWN_ARGUMENT);
+        assert_eq!(code(&["-q"]), UNKNOWN_ARGUMENT);
+    }
+
+    /// Writes `text` to `name` in a directory of its own for `test`.
+    fn config_file(test: &str, name: &str, text: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wineglass-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        let vars: Vec<_> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        vars.into_iter()
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let user = "indent-width = 2\nbrace-style = \"next-line\"\nmax-threads = 5\nloglevel = 1\n";
+        let user = config_file("layers", "config.toml", user);
+        let project = "indent-width = 3\nmax-threads = 6\nloglevel = 2\n";
+        let project = config_file("layers", PROJECT_FILE, project);
+        let environment = vars(&[
+            ("WINEGLASS_MAX_THREADS", "7"),
+            ("WINEGLASS_LOGLEVEL", "3"),
+            ("WINEGLASS_RUN__SEED", "9"),
+            ("HOME", "/nowhere"),
+        ]);
+        let args = parse(&["--loglevel", "4"]).unwrap();
+        let config = Config::layered([user.clone(), project], environment, &args);
+        fs::remove_dir_all(user.parent().unwrap()).unwrap();
+        let config = config.expect("every layer is valid");
+        assert_eq!(config.get_int("line-width"), Some(100), "default");
+        assert_eq!(config.get_str("brace-style"), Some("next-line"), "user");
+        assert_eq!(config.get_int("indent-width"), Some(3), "project");
+        assert_eq!(config.get_int("max-threads"), Some(7), "environment");
+        assert_eq!(config.get_int("run.seed"), Some(9), "environment");
+        assert_eq!(config.get_int("loglevel"), Some(4), "command line");
+        assert_eq!(config.get("home"), None);
+    }
+
+    #[test]
+    fn bad_environment_values_are_refused() {
+        let args = Args::default();
+        for (key, value) in [
+            ("WINEGLASS_MAX_THREADS", "lots"),
+            ("WINEGLASS_MESSAGE_FORMAT", "xml"),
+            ("WINEGLASS_VERBOSE", "3"),
+        ] {
+            let e = Config::layered([], vars(&[(key, value)]), &args).expect_err(key);
+            assert_eq!(e.code, INVALID_VALUE, "{}", key);
+            assert!(e
+                .notes
+                .iter()
+                .any(|n| matches!(n, Note::Help(h) if h.contains(ENV_PREFIX))));
         }
     }
-}
-impl core::fmt::Display for Version {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+
+    #[test]
+    fn bad_config_files_are_refused() {
+        let args = Args::default();
+        let wrong = config_file("bad-values", PROJECT_FILE, "indent-width = \"wide\"\n");
+        let e = Config::layered([wrong.clone()], vars(&[]), &args).expect_err("not a number");
+        assert_eq!(
+            (e.code, e.file.as_str()),
+            (INVALID_VALUE, &*wrong.display().to_string())
+        );
+        let broken = config_file("bad-toml", PROJECT_FILE, "max-threads = 3\nline-width = \n");
+        let e = Config::layered([broken.clone()], vars(&[]), &args).expect_err("not TOML");
+        fs::remove_dir_all(wrong.parent().unwrap()).unwrap();
+        fs::remove_dir_all(broken.parent().unwrap()).unwrap();
+        assert_eq!(e.code, CONFIG_SYNTAX);
+        assert_eq!(e.span.start.line, 1);
+        assert!(e.source.is_some());
     }
 }
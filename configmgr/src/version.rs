@@ -0,0 +1,607 @@
+//! Semantic versions and the requirements dependencies put on them.
+//!
+//! Versions are `major.minor.patch`, optionally followed by `-pre.release` and `+build`
+//! metadata. Requirements are comma-separated comparators that must all match:
+//!
+//! | requirement       | matches                        |
+//! |-------------------|--------------------------------|
+//! | `1.2.3`, `^1.2.3` | `>=1.2.3, <2.0.0`              |
+//! | `^0.2.3`          | `>=0.2.3, <0.3.0`              |
+//! | `~1.2.3`          | `>=1.2.3, <1.3.0`              |
+//! | `=1.2`            | `>=1.2.0, <1.3.0`              |
+//! | `1.*`, `1.x`      | `>=1.0.0, <2.0.0`              |
+//! | `>=1, <2`         | `>=1.0.0, <2.0.0`              |
+//!
+//! A pre-release such as `1.3.0-beta` only satisfies a requirement that names a pre-release
+//! of the same `1.3.0`, so nobody is moved onto one without asking for it.
+use crate::config::ConfigError;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Text that is not a version.
+pub const INVALID_VERSION: i64 = 7007;
+/// Text that is not a version requirement.
+pub const INVALID_REQUIREMENT: i64 = 7008;
+
+/// One dot-separated part of a pre-release. Numeric parts sort before alphanumeric ones.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Identifier {
+    Numeric(u64),
+    Alpha(String),
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier::Numeric(n) => write!(f, "{}", n),
+            Identifier::Alpha(s) => f.write_str(s),
+        }
+    }
+}
+
+/// A semantic version. Build metadata is kept but ignored when comparing, as semver requires.
+#[derive(Debug, Clone)]
+pub struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+    pre: Vec<Identifier>,
+    build: String,
+}
+
+impl Version {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+            pre: Vec::new(),
+            build: String::new(),
+        }
+    }
+    pub fn get_crate_ver() -> Version {
+        Version::parse(env!("CARGO_PKG_VERSION")).expect("Cargo checks package versions")
+    }
+
+    /// Parses `1.2.3`, `1.2.3-rc.1` or `1.2.3-rc.1+build.5`.
+    pub fn parse(text: &str) -> Result<Version, ConfigError> {
+        let invalid = |why: &str| {
+            ConfigError::new(
+                INVALID_VERSION,
+                format!("`{}` is not a version: {}", text, why),
+            )
+            .with_help("versions look like `1.2.3`, `1.2.3-beta.1` or `1.2.3+build`")
+        };
+        let (rest, build) = match text.trim().split_once('+') {
+            Some((rest, build)) if identifiers(build) => (rest, build),
+            Some(_) => return Err(invalid("bad build metadata")),
+            None => (text.trim(), ""),
+        };
+        let (core, pre) = match rest.split_once('-') {
+            Some((core, pre)) if identifiers(pre) => (core, Some(pre)),
+            Some(_) => return Err(invalid("bad pre-release")),
+            None => (rest, None),
+        };
+        let parts = core
+            .split('.')
+            .map(number)
+            .collect::<Option<Vec<u64>>>()
+            .ok_or_else(|| invalid("expected numbers without leading zeros"))?;
+        let [major, minor, patch] = parts[..] else {
+            return Err(invalid("expected major.minor.patch"));
+        };
+        let mut pre_release = Vec::new();
+        for part in pre.into_iter().flat_map(|p| p.split('.')) {
+            pre_release.push(match part.bytes().all(|b| b.is_ascii_digit()) {
+                true => Identifier::Numeric(
+                    number(part).ok_or_else(|| invalid("leading zero in pre-release"))?,
+                ),
+                false => Identifier::Alpha(part.to_owned()),
+            });
+        }
+        Ok(Version {
+            major,
+            minor,
+            patch,
+            pre: pre_release,
+            build: build.to_owned(),
+        })
+    }
+    pub fn major(&self) -> u64 {
+        self.major
+    }
+    pub fn minor(&self) -> u64 {
+        self.minor
+    }
+    pub fn patch(&self) -> u64 {
+        self.patch
+    }
+    pub fn pre(&self) -> &[Identifier] {
+        &self.pre
+    }
+    pub fn build(&self) -> &str {
+        &self.build
+    }
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+
+    fn triple(&self) -> (u64, u64, u64) {
+        (self.major, self.minor, self.patch)
+    }
+}
+
+/// A number in a version: digits only, and no leading zero unless it is `0`.
+fn number(text: &str) -> Option<u64> {
+    let digits = !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+    match digits && (text == "0" || !text.starts_with('0')) {
+        true => text.parse().ok(),
+        false => None,
+    }
+}
+
+/// Dot-separated, non-empty runs of ASCII letters, digits and hyphens.
+fn identifiers(text: &str) -> bool {
+    text.split('.').all(|part| {
+        !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+impl FromStr for Version {
+    type Err = ConfigError;
+    fn from_str(s: &str) -> Result<Version, ConfigError> {
+        Version::parse(s)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Hash for Version {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.triple().hash(state);
+        self.pre.hash(state);
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    /// A pre-release comes before the release it leads up to; otherwise pre-releases compare
+    /// part by part, and one that runs out of parts first is the smaller.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.triple().cmp(&other.triple()).then_with(|| {
+            match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            }
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            let pre: Vec<String> = self.pre.iter().map(Identifier::to_string).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+    /// `*`, `1.*` or `1.2.x`.
+    Wildcard,
+}
+
+/// One comparator of a requirement. Missing parts are wildcards: `>=1.2` is `minor = Some(2)`
+/// and `patch = None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparator {
+    pub op: Op,
+    pub major: Option<u64>,
+    pub minor: Option<u64>,
+    pub patch: Option<u64>,
+    pub pre: Vec<Identifier>,
+}
+
+impl Comparator {
+    fn parse(text: &str, whole: &str) -> Result<Comparator, ConfigError> {
+        let invalid = |why: String| {
+            ConfigError::new(
+                INVALID_REQUIREMENT,
+                format!("`{}` is not a version requirement: {}", whole, why),
+            )
+            .with_help("write requirements like `^1.2`, `~0.3.1` or `>=1, <2`")
+        };
+        let text = text.trim();
+        let (op, rest) = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Exact),
+            ("~", Op::Tilde),
+            ("^", Op::Caret),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (op, rest.trim())))
+        .unwrap_or((Op::Caret, text));
+        if rest.is_empty() {
+            return Err(invalid("expected a version".to_owned()));
+        }
+        let (core, pre) = match rest.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (rest.split_once('+').map_or(rest, |(core, _)| core), None),
+        };
+        let mut parts = [None; 3];
+        let mut wild = false;
+        let pieces: Vec<&str> = core.split('.').collect();
+        if pieces.len() > 3 {
+            return Err(invalid(format!("`{}` has too many parts", core)));
+        }
+        for (slot, piece) in parts.iter_mut().zip(&pieces) {
+            match *piece {
+                "*" | "x" | "X" => wild = true,
+                _ if wild => return Err(invalid(format!("`{}` follows a wildcard", piece))),
+                _ => {
+                    *slot = Some(
+                        number(piece)
+                            .ok_or_else(|| invalid(format!("`{}` is not a number", piece)))?,
+                    )
+                }
+            }
+        }
+        let pre = match pre {
+            Some(_) if parts.iter().any(Option::is_none) => {
+                return Err(invalid("a pre-release needs a full version".to_owned()))
+            }
+            Some(pre) => {
+                let full = format!("{}-{}", core, pre);
+                Version::parse(&full).map_err(|e| invalid(e.message))?.pre
+            }
+            None => Vec::new(),
+        };
+        let op = match (wild, op) {
+            (true, Op::Caret) => Op::Wildcard,
+            (true, _) => {
+                return Err(invalid(
+                    "wildcards can't be used with an operator".to_owned(),
+                ))
+            }
+            (false, op) => op,
+        };
+        Ok(Comparator {
+            op,
+            major: parts[0],
+            minor: parts[1],
+            patch: parts[2],
+            pre,
+        })
+    }
+
+    pub fn matches(&self, v: &Version) -> bool {
+        let lower = Version {
+            major: self.major.unwrap_or(0),
+            minor: self.minor.unwrap_or(0),
+            patch: self.patch.unwrap_or(0),
+            pre: self.pre.clone(),
+            build: String::new(),
+        };
+        // The first version past everything this comparator's given parts cover.
+        let after = || {
+            self.major
+                .and_then(|major| successor(major, self.minor, self.patch))
+        };
+        let below = |bound: Option<Version>| bound.is_none_or(|b| v.triple() < b.triple());
+        match self.op {
+            Op::Exact => match self.patch {
+                Some(_) => *v == lower,
+                None => v >= &lower && below(after()),
+            },
+            Op::Greater => match self.patch {
+                Some(_) => v > &lower,
+                None => after().is_some_and(|b| v.triple() >= b.triple()),
+            },
+            Op::GreaterEq => v >= &lower,
+            Op::Less => v < &lower,
+            Op::LessEq => match self.patch {
+                Some(_) => v <= &lower,
+                None => below(after()),
+            },
+            Op::Tilde => {
+                let upper = match (self.major, self.minor) {
+                    (Some(major), Some(minor)) => successor(major, Some(minor), None),
+                    _ => after(),
+                };
+                v >= &lower && below(upper)
+            }
+            Op::Caret => {
+                let upper = match (self.major, self.minor, self.patch) {
+                    (Some(0), Some(0), Some(patch)) => successor(0, Some(0), Some(patch)),
+                    (Some(0), Some(minor), _) => successor(0, Some(minor), None),
+                    (Some(major), ..) => successor(major, None, None),
+                    (None, ..) => None,
+                };
+                v >= &lower && below(upper)
+            }
+            Op::Wildcard => self.major.is_none() || (v >= &lower && below(after())),
+        }
+    }
+}
+
+/// The first version past every one starting with the given parts, carrying into the part
+/// above when one is already at its largest. `None` when nothing comes after them.
+fn successor(major: u64, minor: Option<u64>, patch: Option<u64>) -> Option<Version> {
+    match (minor, patch) {
+        (Some(minor), Some(patch)) => match patch.checked_add(1) {
+            Some(patch) => Some(Version::new(major, minor, patch)),
+            None => successor(major, Some(minor), None),
+        },
+        (Some(minor), None) => match minor.checked_add(1) {
+            Some(minor) => Some(Version::new(major, minor, 0)),
+            None => successor(major, None, None),
+        },
+        (None, _) => major.checked_add(1).map(|major| Version::new(major, 0, 0)),
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Op::Exact => "=",
+            Op::Greater => ">",
+            Op::GreaterEq => ">=",
+            Op::Less => "<",
+            Op::LessEq => "<=",
+            Op::Tilde => "~",
+            Op::Caret => "^",
+            Op::Wildcard => "",
+        };
+        f.write_str(op)?;
+        let mut parts = Vec::new();
+        for part in [self.major, self.minor, self.patch] {
+            match part {
+                Some(n) => parts.push(n.to_string()),
+                None if self.op == Op::Wildcard => {
+                    parts.push("*".to_owned());
+                    break;
+                }
+                None => break,
+            }
+        }
+        write!(f, "{}", parts.join("."))?;
+        if !self.pre.is_empty() {
+            let pre: Vec<String> = self.pre.iter().map(Identifier::to_string).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+/// Comparators that must all match, such as `>=1.2, <2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    pub comparators: Vec<Comparator>,
+}
+
+impl VersionReq {
+    /// Matches every release.
+    pub fn any() -> VersionReq {
+        VersionReq {
+            comparators: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<VersionReq, ConfigError> {
+        if text.trim() == "*" {
+            return Ok(VersionReq::any());
+        }
+        let comparators = text
+            .split(',')
+            .map(|part| Comparator::parse(part, text))
+            .collect::<Result<_, _>>()?;
+        Ok(VersionReq { comparators })
+    }
+
+    /// Whether `v` satisfies every comparator. Pre-releases only match when a comparator names
+    /// a pre-release of the same version.
+    pub fn matches(&self, v: &Version) -> bool {
+        if !self.comparators.iter().all(|c| c.matches(v)) {
+            return false;
+        }
+        !v.is_prerelease()
+            || self.comparators.iter().any(|c| {
+                !c.pre.is_empty()
+                    && (c.major, c.minor, c.patch) == (Some(v.major), Some(v.minor), Some(v.patch))
+            })
+    }
+
+    /// The highest of `candidates` that satisfies this requirement, judged by `version`.
+    pub fn best_match<T>(
+        &self,
+        candidates: impl IntoIterator<Item = T>,
+        version: impl Fn(&T) -> &Version,
+    ) -> Option<T> {
+        candidates
+            .into_iter()
+            .filter(|c| self.matches(version(c)))
+            .max_by(|a, b| version(a).cmp(version(b)))
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = ConfigError;
+    fn from_str(s: &str) -> Result<VersionReq, ConfigError> {
+        VersionReq::parse(s)
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.comparators.is_empty() {
+            return f.write_str("*");
+        }
+        let parts: Vec<String> = self.comparators.iter().map(|c| c.to_string()).collect();
+        f.write_str(&parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    fn req(text: &str) -> VersionReq {
+        VersionReq::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parses_versions() {
+        let full = v("1.2.3-rc.1+build.5");
+        assert_eq!((full.major(), full.minor(), full.patch()), (1, 2, 3));
+        assert_eq!(
+            full.pre(),
+            &[Identifier::Alpha("rc".to_owned()), Identifier::Numeric(1)]
+        );
+        assert_eq!(full.build(), "build.5");
+        assert_eq!(full.to_string(), "1.2.3-rc.1+build.5");
+        assert_eq!(v(" 0.0.0 ").to_string(), "0.0.0");
+        for bad in [
+            "",
+            "1",
+            "1.2",
+            "1.2.3.4",
+            "01.2.3",
+            "1.2.x",
+            "1.2.3-",
+            "1.2.3-rc.01",
+            "1.2.3+",
+            "18446744073709551616.0.0",
+        ] {
+            assert!(Version::parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn orders_versions() {
+        let ordered = [
+            "0.9.9",
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.1.0",
+            "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(v("1.0.0+a"), v("1.0.0+b"));
+    }
+
+    #[test]
+    fn parses_requirements() {
+        assert_eq!(req("*"), VersionReq::any());
+        assert_eq!(req(">=1.2, <2").to_string(), ">=1.2, <2");
+        assert_eq!(req("1.2").comparators[0].op, Op::Caret);
+        assert_eq!(req("1.*").comparators[0].op, Op::Wildcard);
+        for bad in ["", ">=", "1.2.3.4", "1.*.3", ">1.*", "1.2-rc", "a.b"] {
+            assert!(VersionReq::parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let cases = [
+            ("^1.2.3", "1.2.3", true),
+            ("^1.2.3", "1.9.0", true),
+            ("^1.2.3", "2.0.0", false),
+            ("^1.2.3", "1.2.2", false),
+            ("^0.2.3", "0.2.9", true),
+            ("^0.2.3", "0.3.0", false),
+            ("^0.0.3", "0.0.4", false),
+            ("~1.2.3", "1.2.9", true),
+            ("~1.2.3", "1.3.0", false),
+            ("~1", "1.9.9", true),
+            ("=1.2", "1.2.7", true),
+            ("=1.2", "1.3.0", false),
+            (">1.2", "1.2.9", false),
+            (">1.2", "1.3.0", true),
+            ("<=1.2", "1.2.9", true),
+            (">=1, <2", "1.5.0", true),
+            (">=1, <2", "2.0.0", false),
+            ("1.*", "1.4.0", true),
+            ("1.*", "2.0.0", false),
+            ("*", "9.9.9", true),
+            ("^1.2.3", "1.3.0-rc.1", false),
+            ("^1.3.0-rc.1", "1.3.0-rc.2", true),
+            ("^1.3.0-rc.1", "1.3.0-beta", false),
+        ];
+        for (requirement, version, expected) in cases {
+            assert_eq!(
+                req(requirement).matches(&v(version)),
+                expected,
+                "{} against {}",
+                requirement,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn bounds_at_the_largest_numbers_do_not_overflow() {
+        let max = u64::MAX;
+        let top = v(&format!("{}.{}.{}", max, max, max));
+        assert!(req(&format!("^{}", max)).matches(&top));
+        assert!(req(&format!("^0.{}", max)).matches(&v(&format!("0.{}.5", max))));
+        assert!(!req(&format!("^0.{}", max)).matches(&v("1.0.0")));
+        assert!(req(&format!("~{}.{}", max, max)).matches(&top));
+        assert!(req(&format!("^0.0.{}", max)).matches(&v(&format!("0.0.{}", max))));
+        assert!(!req(&format!("^0.0.{}", max)).matches(&v("0.1.0")));
+        assert!(req(&format!("={}.{}", max, max)).matches(&top));
+        assert!(!req(&format!(">{}", max)).matches(&top));
+        assert!(req(&format!("<={}", max)).matches(&top));
+        assert!(req(&format!("{}.*", max)).matches(&top));
+    }
+
+    #[test]
+    fn picks_the_best_match() {
+        let versions = [v("1.0.0"), v("1.4.2"), v("2.0.0"), v("1.5.0-rc.1")];
+        let best = req("^1").best_match(versions.iter(), |v| v);
+        assert_eq!(best, Some(&v("1.4.2")));
+        assert_eq!(req("^3").best_match(versions.iter(), |v| v), None);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{Note, Renderer, Report, Span};
+use serde::Serialize;
+
+/// Where a diagnostic is written for a person to read, or one JSON object per line for tools.
+///
+/// JSON lines and columns are one-based, like the `-->` locations of human output, and ranges
+/// end just past their last character:
+///
+/// ```text
+/// {"code":3004,"level":"error","file":"main.wg","message":"division by zero","span":null,
+///  "labels":[],"notes":[{"kind":"note","message":"in main > div"}],"fixes":[]}
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emitter {
+    Human(Renderer),
+    Json,
+}
+
+#[derive(Serialize)]
+struct Json<'r> {
+    code: i64,
+    level: String,
+    file: String,
+    message: String,
+    span: Option<JsonSpan>,
+    labels: Vec<JsonLabel<'r>>,
+    notes: Vec<JsonNote<'r>>,
+    fixes: Vec<JsonFix<'r>>,
+}
+
+#[derive(Serialize)]
+struct JsonSpan {
+    line: usize,
+    column: usize,
+    end_line: usize,
+    end_column: usize,
+}
+
+#[derive(Serialize)]
+struct JsonLabel<'r> {
+    span: JsonSpan,
+    message: &'r str,
+    primary: bool,
+}
+
+#[derive(Serialize)]
+struct JsonNote<'r> {
+    kind: &'static str,
+    message: &'r str,
+}
+
+#[derive(Serialize)]
+struct JsonFix<'r> {
+    message: &'r str,
+    span: JsonSpan,
+    replacement: &'r str,
+}
+
+impl From<Span> for JsonSpan {
+    fn from(span: Span) -> Self {
+        Self {
+            line: span.start.line + 1,
+            column: span.start.column + 1,
+            end_line: span.end.line + 1,
+            end_column: span.end.column + 1,
+        }
+    }
+}
+
+impl Emitter {
+    /// `json` picks [`Emitter::Json`]; otherwise people get colour when stderr supports it.
+    pub fn new(json: bool) -> Self {
+        match json {
+            true => Emitter::Json,
+            false => Emitter::Human(Renderer::stderr()),
+        }
+    }
+
+    /// Formats `report`. Human output quotes `source`; JSON output is a single line.
+    pub fn emit(&self, report: &dyn Report, source: Option<&str>) -> String {
+        match self {
+            Emitter::Human(renderer) => renderer.render(report, source),
+            Emitter::Json => json(report),
+        }
+    }
+}
+
+fn json(report: &dyn Report) -> String {
+    let mut notes = Vec::new();
+    let mut fixes = Vec::new();
+    for note in report.notes() {
+        match note {
+            Note::Note(message) => notes.push(JsonNote {
+                kind: "note",
+                message,
+            }),
+            Note::Help(message) => notes.push(JsonNote {
+                kind: "help",
+                message,
+            }),
+            Note::Fix {
+                message,
+                span,
+                replacement,
+            } => fixes.push(JsonFix {
+                message,
+                span: (*span).into(),
+                replacement,
+            }),
+        }
+    }
+    let json = Json {
+        code: report.code(),
+        level: report.level().to_string(),
+        file: report.file(),
+        message: report.message(),
+        span: report.span().map(JsonSpan::from),
+        labels: report
+            .labels()
+            .iter()
+            .map(|l| JsonLabel {
+                span: l.span.into(),
+                message: &l.message,
+                primary: l.primary,
+            })
+            .collect(),
+        notes,
+        fixes,
+    };
+    serde_json::to_string(&json).expect("diagnostics only hold strings and numbers")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::tests::Diag;
+    use crate::{ErrorLevel, Label, Position};
+
+    #[test]
+    fn reports_are_one_json_line_with_one_based_positions() {
+        let report = Diag {
+            level: ErrorLevel::WARN,
+            code: 5001,
+            span: Some(Span::at(7, 11, 5)),
+            labels: vec![Label::secondary(
+                Span::new(Position::new(1, 4), Position::new(2, 0)),
+                "declared here",
+            )],
+            notes: vec![
+                Note::Note("in main".to_owned()),
+                Note::Help("names are \"case\" sensitive".to_owned()),
+                Note::Fix {
+                    message: "did you mean".to_owned(),
+                    span: Span::at(7, 11, 5),
+                    replacement: "totals".to_owned(),
+                },
+            ],
+            ..Diag::error("undefined name `total`")
+        };
+        let expected = concat!(
+            r#"{"code":5001,"level":"warning","file":"main.wg","message":"undefined name `total`","#,
+            r#""span":{"line":8,"column":12,"end_line":8,"end_column":17},"#,
+            r#""labels":[{"span":{"line":2,"column":5,"end_line":3,"end_column":1},"#,
+            r#""message":"declared here","primary":false}],"#,
+            r#""notes":[{"kind":"note","message":"in main"},"#,
+            r#"{"kind":"help","message":"names are \"case\" sensitive"}],"#,
+            r#""fixes":[{"message":"did you mean","#,
+            r#""span":{"line":8,"column":12,"end_line":8,"end_column":17},"replacement":"totals"}]}"#,
+        );
+        assert_eq!(Emitter::Json.emit(&report, Some("ignored")), expected);
+    }
+
+    #[test]
+    fn reports_without_a_place_have_a_null_span() {
+        let report = Diag {
+            file: "",
+            ..Diag::error("no command\ngiven")
+        };
+        let expected = concat!(
+            r#"{"code":0,"level":"error","file":"","message":"no command\ngiven","#,
+            r#""span":null,"labels":[],"notes":[],"fixes":[]}"#,
+        );
+        let emitted = Emitter::Json.emit(&report, None);
+        assert_eq!(emitted, expected);
+        assert!(!emitted.contains('\n'));
+    }
+
+    #[test]
+    fn people_get_the_renderer() {
+        let report = Diag::error("bad");
+        let human = Emitter::Human(Renderer::plain()).emit(&report, None);
+        assert_eq!(human, Renderer::plain().render(&report, None));
+        assert_eq!(Emitter::new(true), Emitter::Json);
+    }
+}
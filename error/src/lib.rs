@@ -1,140 +1,177 @@
+//! Diagnostics shared by the parser, checker and runtime.
+//!
+//! Anything that implements [`Report`] can be shown by a [`Renderer`], which points into the
+//! source it is given in memory with carets, labels, notes and help text, or written as JSON
+//! by an [`Emitter`].
+mod emit;
+mod render;
+
+pub use emit::Emitter;
+pub use render::Renderer;
+
+use nom_locate::LocatedSpan;
 use std::fmt::{self, Display, Formatter};
 
-pub struct ErrorLogger {
-    errors: Vec<Box<dyn Error>>,
-}
-
-trait Error: std::fmt::Display {
-    fn get_position(&self) -> Position;
-    fn get_errorlevel(&self) -> ErrorLevel;
-}
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorLevel {
     INFO,
     WARN,
     ERROR,
     FATAL,
 }
-#[derive(Debug, Clone)]
-pub struct Position {
-    pub line: usize,
-    pub column: usize,
-}
 
-pub struct Parse {
-    pub errorlevel: ErrorLevel,
-    pub message: String,
-    pub position: Position,
+impl Display for ErrorLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorLevel::INFO => "info",
+            ErrorLevel::WARN => "warning",
+            ErrorLevel::ERROR => "error",
+            ErrorLevel::FATAL => "fatal",
+        })
+    }
 }
 
-pub struct Runtime {
-    pub errorlevel: ErrorLevel,
-    pub message: String,
-    pub position: Position,
+/// A zero-based line and column. Columns count characters, not bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
 }
 
-pub struct Syntax {
-    pub errorlevel: ErrorLevel,
-    pub message: String,
-    pub position: Position,
-}
+impl Position {
+    pub fn new(line: usize, column: usize) -> Self {
+        Self { line, column }
+    }
 
-impl Error for Parse {
-    fn get_position(&self) -> Position {
-        self.position.clone()
+    /// Where `span` starts.
+    pub fn of<X>(span: &LocatedSpan<&str, X>) -> Self {
+        Self {
+            line: span.location_line() as usize - 1,
+            column: span.get_utf8_column() - 1,
+        }
     }
 
-    fn get_errorlevel(&self) -> ErrorLevel {
-        self.errorlevel.clone()
+    /// Where `text` ends if it starts here.
+    fn after(self, text: &str) -> Self {
+        match text.rsplit_once('\n') {
+            Some((before, last)) => Self {
+                line: self.line + before.matches('\n').count() + 1,
+                column: last.chars().count(),
+            },
+            None => Self {
+                line: self.line,
+                column: self.column + text.chars().count(),
+            },
+        }
     }
 }
 
-impl Display for Parse {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} at line {}, column {}",
-            self.message, self.position.line, self.position.column
-        )
-    }
+/// A range of source from `start` up to, but not including, `end`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
 }
 
-impl Error for Runtime {
-    fn get_position(&self) -> Position {
-        self.position.clone()
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Self { start, end }
     }
 
-    fn get_errorlevel(&self) -> ErrorLevel {
-        self.errorlevel.clone()
+    /// `len` characters on one line.
+    pub fn at(line: usize, column: usize, len: usize) -> Self {
+        Self {
+            start: Position::new(line, column),
+            end: Position::new(line, column + len),
+        }
     }
-}
 
-impl Display for Runtime {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} at line {}, column {}",
-            self.message, self.position.line, self.position.column
-        )
+    /// The text `span` covers.
+    pub fn of<X>(span: &LocatedSpan<&str, X>) -> Self {
+        let start = Position::of(span);
+        Self {
+            start,
+            end: start.after(span.fragment()),
+        }
     }
-}
 
-impl Error for Syntax {
-    fn get_position(&self) -> Position {
-        self.position.clone()
+    /// From where `start` begins to where `end` begins, such as the input a parser consumed
+    /// between the two.
+    pub fn between<X>(start: &LocatedSpan<&str, X>, end: &LocatedSpan<&str, X>) -> Self {
+        Self {
+            start: Position::of(start),
+            end: Position::of(end),
+        }
     }
 
-    fn get_errorlevel(&self) -> ErrorLevel {
-        self.errorlevel.clone()
+    pub fn is_multiline(&self) -> bool {
+        self.end.line > self.start.line
     }
 }
 
-impl Display for Syntax {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} at line {}, column {}",
-            self.message, self.position.line, self.position.column
-        )
-    }
+/// Marks a span with a message. Primary labels show where the problem is; secondary ones
+/// point at what is related to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
 }
 
-macro_rules! parse_error {
-    ($position:expr, $message:expr) => {
-        Parse {
-            errorlevel: ErrorLevel::ERROR,
-            message: $message.to_string(),
-            position: $position,
+impl Label {
+    pub fn primary(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+            primary: true,
         }
-    };
-}
+    }
 
-macro_rules! parse_warning {
-    ($position:expr, $message:expr) => {
-        Parse {
-            errorlevel: ErrorLevel::WARN,
-            message: $message.to_string(),
-            position: $position,
+    pub fn secondary(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+            primary: false,
         }
-    };
+    }
 }
 
-macro_rules! parse_info {
-    ($position:expr, $message:expr) => {
-        Parse {
-            errorlevel: ErrorLevel::INFO,
-            message: $message.to_string(),
-            position: $position,
-        }
-    };
+/// Text printed under the source snippet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Note {
+    Note(String),
+    Help(String),
+    /// Replacing `span` with `replacement` would fix the problem.
+    Fix {
+        message: String,
+        span: Span,
+        replacement: String,
+    },
+}
+
+/// Something that can be rendered as a diagnostic.
+pub trait Report {
+    fn level(&self) -> ErrorLevel;
+    /// Zero when the problem has no code of its own.
+    fn code(&self) -> i64;
+    fn message(&self) -> String;
+    /// Name of the source the spans point into. Empty when there is none.
+    fn file(&self) -> String;
+    /// Where the problem is, underlined even without a label of its own.
+    fn span(&self) -> Option<Span> {
+        None
+    }
+    fn labels(&self) -> &[Label] {
+        &[]
+    }
+    fn notes(&self) -> &[Note] {
+        &[]
+    }
 }
 
-macro_rules! parse_fatal {
-    ($position:expr, $message:expr) => {
-        Parse {
-            errorlevel: ErrorLevel::FATAL,
-            message: $message.to_string(),
-            position: $position,
-        }
-    };
+/// A source file kept in memory so diagnostics can be rendered against it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
 }
@@ -0,0 +1,554 @@
+use crate::{ErrorLevel, Label, Note, Report, Span};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const CYAN: &str = "\x1b[1;36m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Tabs are shown as this many spaces so carets line up under them.
+const TAB_WIDTH: usize = 4;
+/// Multi-line labels longer than this only show their first and last lines.
+const MAX_MULTILINE: usize = 5;
+
+/// Turns a [`Report`] into the text shown to a user:
+///
+/// ```text
+/// error[1001]: unexpected input `fn (`
+///  --> main.wg:3:1
+///   |
+/// 3 | fn (
+///   | ^^^^ the parser stopped here
+///   |
+///   = help: functions need a name
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Renderer {
+    color: bool,
+}
+
+enum Block<'l> {
+    Line(usize, Vec<&'l Label>),
+    Multi(&'l Label),
+}
+
+impl Block<'_> {
+    fn first(&self) -> usize {
+        match self {
+            Block::Line(line, _) => *line,
+            Block::Multi(label) => label.span.start.line,
+        }
+    }
+
+    fn last(&self) -> usize {
+        match self {
+            Block::Line(line, _) => *line,
+            Block::Multi(label) => label.span.end.line,
+        }
+    }
+}
+
+impl Renderer {
+    pub fn new(color: bool) -> Self {
+        Self { color }
+    }
+
+    pub fn plain() -> Self {
+        Self::new(false)
+    }
+
+    /// Colours output when stderr is a terminal and `NO_COLOR` is not set.
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none())
+    }
+
+    /// Renders `report`, quoting `source` wherever its spans point. Spans outside `source`,
+    /// or every span when there is no source, are left out of the snippet.
+    pub fn render(&self, report: &dyn Report, source: Option<&str>) -> String {
+        let level = report.level();
+        let mut out = String::new();
+        let head = match report.code() {
+            0 => level.to_string(),
+            code => format!("{}[{}]", level, code),
+        };
+        let message = format!(": {}", report.message());
+        writeln!(
+            out,
+            "{}{}",
+            self.paint(style(level), &head),
+            self.paint(BOLD, &message)
+        )
+        .unwrap();
+
+        let mut labels = report.labels().to_vec();
+        if let Some(span) = report.span() {
+            if !labels.iter().any(|l| l.span == span) {
+                labels.insert(0, Label::primary(span, ""));
+            }
+        }
+        let at = report
+            .span()
+            .or_else(|| labels.iter().find(|l| l.primary).map(|l| l.span));
+        let lines: Vec<&str> = match source {
+            Some(source) => source
+                .split('\n')
+                .map(|l| l.strip_suffix('\r').unwrap_or(l))
+                .collect(),
+            None => Vec::new(),
+        };
+        labels.retain(|l| l.span.start.line < lines.len() && l.span.start <= l.span.end);
+        labels.sort_by_key(|l| (l.span.start, !l.primary));
+
+        let blocks = blocks(&labels);
+        let last = blocks.iter().map(|b| b.last().min(lines.len() - 1)).max();
+        let pad = " ".repeat(last.map_or(1, |l| (l + 1).to_string().len()));
+        let file = report.file();
+        if !file.is_empty() {
+            let arrow = self.paint(BLUE, "-->");
+            match at {
+                Some(at) => writeln!(
+                    out,
+                    "{}{} {}:{}:{}",
+                    pad,
+                    arrow,
+                    file,
+                    at.start.line + 1,
+                    at.start.column + 1
+                ),
+                None => writeln!(out, "{}{} {}", pad, arrow, file),
+            }
+            .unwrap();
+        }
+
+        if !blocks.is_empty() {
+            let margin = labels.iter().any(|l| l.span.is_multiline());
+            writeln!(out, "{} {}", pad, self.paint(BLUE, "|")).unwrap();
+            let mut previous: Option<usize> = None;
+            for block in &blocks {
+                if previous.is_some_and(|p| block.first() > p + 1) {
+                    writeln!(out, "{}", self.paint(BLUE, "...")).unwrap();
+                }
+                match block {
+                    Block::Line(line, labels) => {
+                        self.line(
+                            &mut out,
+                            &pad,
+                            *line,
+                            lines[*line],
+                            if margin { "  " } else { "" },
+                        );
+                        for label in labels {
+                            self.underline(&mut out, &pad, lines[*line], label, level, margin);
+                        }
+                    }
+                    Block::Multi(label) => self.multiline(&mut out, &pad, &lines, label, level),
+                }
+                previous = Some(block.last());
+            }
+        }
+
+        let notes = report.notes();
+        if !notes.is_empty() {
+            if !blocks.is_empty() {
+                writeln!(out, "{} {}", pad, self.paint(BLUE, "|")).unwrap();
+            }
+            for note in notes {
+                let (kind, text) = match note {
+                    Note::Note(text) => ("note", text.clone()),
+                    Note::Help(text) => ("help", text.clone()),
+                    Note::Fix {
+                        message,
+                        replacement,
+                        ..
+                    } => ("help", format!("{}: `{}`", message, replacement)),
+                };
+                let indent = format!("\n{}   {}", pad, " ".repeat(kind.len() + 2));
+                writeln!(
+                    out,
+                    "{} {} {}: {}",
+                    pad,
+                    self.paint(BLUE, "="),
+                    self.paint(BOLD, kind),
+                    text.replace('\n', &indent)
+                )
+                .unwrap();
+            }
+        }
+        out.truncate(out.trim_end().len());
+        out
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        match self.color && !text.is_empty() {
+            true => format!("{}{}{}", style, text, RESET),
+            false => text.to_owned(),
+        }
+    }
+
+    fn line(&self, out: &mut String, pad: &str, line: usize, text: &str, margin: &str) {
+        let gutter = format!("{:>w$} |", line + 1, w = pad.len());
+        writeln!(
+            out,
+            "{} {}{}",
+            self.paint(BLUE, &gutter),
+            margin,
+            expand(text)
+        )
+        .unwrap();
+    }
+
+    /// Carets under the part of `text` a single-line label covers.
+    fn underline(
+        &self,
+        out: &mut String,
+        pad: &str,
+        text: &str,
+        label: &Label,
+        level: ErrorLevel,
+        margin: bool,
+    ) {
+        let chars: Vec<char> = text.chars().collect();
+        let start = label.span.start.column.min(chars.len());
+        let end = label.span.end.column.clamp(start, chars.len());
+        let caret = if label.primary { "^" } else { "-" };
+        let marks = caret.repeat(width(&chars[start..end]).max(1));
+        let style = label_style(label, level);
+        let mut row = format!(
+            "{}{}{}",
+            if margin { "  " } else { "" },
+            " ".repeat(width(&chars[..start])),
+            self.paint(style, &marks)
+        );
+        if !label.message.is_empty() {
+            write!(row, " {}", self.paint(style, &label.message)).unwrap();
+        }
+        writeln!(out, "{} {} {}", pad, self.paint(BLUE, "|"), row).unwrap();
+    }
+
+    /// A label spanning several lines, drawn as a bracket in the margin.
+    fn multiline(
+        &self,
+        out: &mut String,
+        pad: &str,
+        lines: &[&str],
+        label: &Label,
+        level: ErrorLevel,
+    ) {
+        let Span { start, end } = label.span;
+        let (end_line, end_column) = match lines.get(end.line) {
+            Some(_) => (end.line, end.column),
+            None => (lines.len() - 1, usize::MAX),
+        };
+        let style = label_style(label, level);
+        let bar = self.paint(style, "|");
+        let first: Vec<char> = lines[start.line].chars().collect();
+        let start_column = start.column.min(first.len());
+        let indent = first.iter().take_while(|c| c.is_whitespace()).count();
+        if start_column <= indent {
+            self.line(
+                out,
+                pad,
+                start.line,
+                lines[start.line],
+                &format!("{} ", self.paint(style, "/")),
+            );
+        } else {
+            self.line(out, pad, start.line, lines[start.line], "  ");
+            let to = format!(" {}^", "_".repeat(width(&first[..start_column]) + 1));
+            writeln!(
+                out,
+                "{} {} {}",
+                pad,
+                self.paint(BLUE, "|"),
+                self.paint(style, &to)
+            )
+            .unwrap();
+        }
+        let inner: Vec<usize> = (start.line + 1..end_line).collect();
+        let shown: Vec<Option<usize>> = match inner.len() > MAX_MULTILINE {
+            true => vec![Some(inner[0]), None, Some(inner[inner.len() - 1])],
+            false => inner.into_iter().map(Some).collect(),
+        };
+        for line in shown {
+            match line {
+                Some(line) => self.line(out, pad, line, lines[line], &format!("{} ", bar)),
+                None => writeln!(out, "{}", self.paint(BLUE, "...")).unwrap(),
+            }
+        }
+        let last: Vec<char> = lines[end_line].chars().collect();
+        let end_column = end_column.min(last.len()).max(1) - 1;
+        self.line(out, pad, end_line, lines[end_line], &format!("{} ", bar));
+        let mut row = self.paint(
+            style,
+            &format!("|{}^", "_".repeat(width(&last[..end_column]) + 1)),
+        );
+        if !label.message.is_empty() {
+            write!(row, " {}", self.paint(style, &label.message)).unwrap();
+        }
+        writeln!(out, "{} {} {}", pad, self.paint(BLUE, "|"), row).unwrap();
+    }
+}
+
+/// Groups single-line labels by line and gives every multi-line label a block of its own,
+/// in source order.
+fn blocks<'l>(labels: &'l [Label]) -> Vec<Block<'l>> {
+    let mut single: BTreeMap<usize, Vec<&Label>> = BTreeMap::new();
+    let mut blocks = Vec::new();
+    for label in labels {
+        match label.span.is_multiline() {
+            true => blocks.push(Block::Multi(label)),
+            false => single.entry(label.span.start.line).or_default().push(label),
+        }
+    }
+    blocks.extend(
+        single
+            .into_iter()
+            .map(|(line, labels)| Block::Line(line, labels)),
+    );
+    blocks.sort_by_key(|b| b.first());
+    blocks
+}
+
+/// The style of a label's marks and message.
+fn label_style(label: &Label, level: ErrorLevel) -> &'static str {
+    match label.primary {
+        true => style(level),
+        false => BLUE,
+    }
+}
+
+fn style(level: ErrorLevel) -> &'static str {
+    match level {
+        ErrorLevel::INFO => CYAN,
+        ErrorLevel::WARN => YELLOW,
+        ErrorLevel::ERROR | ErrorLevel::FATAL => RED,
+    }
+}
+
+fn width(chars: &[char]) -> usize {
+    chars
+        .iter()
+        .map(|c| if *c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+fn expand(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::Position;
+
+    /// A report built up in place, standing in for the crates that implement [`Report`].
+    pub(crate) struct Diag {
+        pub level: ErrorLevel,
+        pub code: i64,
+        pub message: &'static str,
+        pub file: &'static str,
+        pub span: Option<Span>,
+        pub labels: Vec<Label>,
+        pub notes: Vec<Note>,
+    }
+
+    impl Diag {
+        pub fn error(message: &'static str) -> Self {
+            Self {
+                level: ErrorLevel::ERROR,
+                code: 0,
+                message,
+                file: "main.wg",
+                span: None,
+                labels: Vec::new(),
+                notes: Vec::new(),
+            }
+        }
+    }
+
+    impl Report for Diag {
+        fn level(&self) -> ErrorLevel {
+            self.level
+        }
+        fn code(&self) -> i64 {
+            self.code
+        }
+        fn message(&self) -> String {
+            self.message.to_owned()
+        }
+        fn file(&self) -> String {
+            self.file.to_owned()
+        }
+        fn span(&self) -> Option<Span> {
+            self.span
+        }
+        fn labels(&self) -> &[Label] {
+            &self.labels
+        }
+        fn notes(&self) -> &[Note] {
+            &self.notes
+        }
+    }
+
+    const SOURCE: &str = "\
+fn main() -> i64 {
+\tlet total = 0;
+    let i = 0;
+    while i < 10 {
+        total = total + i;
+        i = i + 1;
+    }
+    return total / 0;
+}
+";
+
+    fn plain(report: &Diag, source: Option<&str>) -> String {
+        Renderer::plain().render(report, source)
+    }
+
+    #[test]
+    fn single_line_spans_are_underlined() {
+        let report = Diag {
+            code: 3004,
+            span: Some(Span::at(7, 11, 9)),
+            notes: vec![
+                Note::Note("in main".to_owned()),
+                Note::Help("check the divisor first".to_owned()),
+            ],
+            ..Diag::error("division by zero")
+        };
+        let expected = "\
+error[3004]: division by zero
+ --> main.wg:8:12
+  |
+8 |     return total / 0;
+  |            ^^^^^^^^^
+  |
+  = note: in main
+  = help: check the divisor first";
+        assert_eq!(plain(&report, Some(SOURCE)), expected);
+    }
+
+    #[test]
+    fn labels_on_distant_lines_are_split_by_an_ellipsis() {
+        let report = Diag {
+            code: 5004,
+            level: ErrorLevel::WARN,
+            labels: vec![
+                Label::primary(Span::at(1, 5, 5), "never read"),
+                Label::secondary(Span::at(7, 11, 5), "this is a different `total`"),
+                Label::secondary(Span::at(4, 8, 5), "only written"),
+            ],
+            ..Diag::error("`total` is never read")
+        };
+        // The tab on line 2 is drawn as four spaces, and the carets line up with it.
+        let expected = "\
+warning[5004]: `total` is never read
+ --> main.wg:2:6
+  |
+2 |     let total = 0;
+  |         ^^^^^ never read
+...
+5 |         total = total + i;
+  |         ----- only written
+...
+8 |     return total / 0;
+  |            ----- this is a different `total`";
+        assert_eq!(plain(&report, Some(SOURCE)), expected);
+    }
+
+    #[test]
+    fn multiline_labels_are_bracketed() {
+        let body = Span::new(Position::new(3, 4), Position::new(6, 5));
+        let report = Diag {
+            labels: vec![Label::primary(body, "this loop never ends")],
+            ..Diag::error("infinite loop")
+        };
+        let expected = "\
+error: infinite loop
+ --> main.wg:4:5
+  |
+4 | /     while i < 10 {
+5 | |         total = total + i;
+6 | |         i = i + 1;
+7 | |     }
+  | |_____^ this loop never ends";
+        assert_eq!(plain(&report, Some(SOURCE)), expected);
+    }
+
+    #[test]
+    fn long_multiline_labels_keep_their_ends() {
+        let source: String = (0..12).map(|n| format!("line {}\n", n)).collect();
+        let all = Span::new(Position::new(0, 0), Position::new(11, 7));
+        let report = Diag {
+            labels: vec![Label::primary(all, "")],
+            ..Diag::error("too long")
+        };
+        let expected = "\
+error: too long
+  --> main.wg:1:1
+   |
+ 1 | / line 0
+ 2 | | line 1
+...
+11 | | line 10
+12 | | line 11
+   | |_______^";
+        assert_eq!(plain(&report, Some(&source)), expected);
+    }
+
+    #[test]
+    fn fixes_are_shown_as_help() {
+        let report = Diag {
+            code: 5001,
+            span: Some(Span::at(7, 11, 5)),
+            notes: vec![Note::Fix {
+                message: "did you mean".to_owned(),
+                span: Span::at(7, 11, 5),
+                replacement: "totals".to_owned(),
+            }],
+            ..Diag::error("undefined name `total`")
+        };
+        let rendered = plain(&report, Some(SOURCE));
+        assert!(rendered.ends_with("  |\n  = help: did you mean: `totals`"));
+    }
+
+    #[test]
+    fn reports_without_source_have_no_snippet() {
+        let report = Diag {
+            span: Some(Span::at(7, 11, 9)),
+            notes: vec![Note::Help("first line\nsecond line".to_owned())],
+            ..Diag::error("couldn't read source")
+        };
+        let expected = "\
+error: couldn't read source
+ --> main.wg:8:12
+  = help: first line
+          second line";
+        assert_eq!(plain(&report, None), expected);
+        let report = Diag {
+            file: "",
+            ..Diag::error("no command given")
+        };
+        assert_eq!(plain(&report, None), "error: no command given");
+    }
+
+    #[test]
+    fn colour_wraps_each_part_and_resets() {
+        let report = Diag {
+            span: Some(Span::at(0, 3, 4)),
+            ..Diag::error("bad name")
+        };
+        let rendered = Renderer::new(true).render(&report, Some(SOURCE));
+        assert!(rendered.starts_with(&format!(
+            "{}error{}{}: bad name{}\n",
+            RED, RESET, BOLD, RESET
+        )));
+        assert!(rendered.contains(&format!("{}^^^^{}", RED, RESET)));
+        assert!(!plain(&report, Some(SOURCE)).contains('\x1b'));
+    }
+}
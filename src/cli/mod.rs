@@ -0,0 +1,666 @@
+//! The `wineglass` command line: picks a command and its options out of the arguments, loads
+//! the source from a file or stdin and turns how the bottle ended into an exit code.
+use bottle::cellar::Site;
+use bottle::err::{Diagnostic, Emitter};
+use bottle::format::{self, BraceStyle, Style};
+use bottle::interp::Value;
+use bottle::lsp;
+use bottle::pack::{Archive, ARCHIVE_EXTENSION};
+use bottle::pkg::{PackageManager, BOTTLES_DIR};
+use bottle::repl::Repl;
+use bottle::sched::{self, BottleId, Scheduler};
+use bottle::{Backend, Bottle, Shatter, State};
+use configmgr::config::{
+    AllowedArgument, Args, Config, ConfigError, ENV_PREFIX, UNEXPECTED_ARGUMENT,
+};
+use log::LevelFilter;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use typed_arena::Arena;
+
+const COMMANDS: &str = "\
+Usage: wineglass <command> [options] [file]
+
+Commands:
+  run <file>      Run a bottle and exit with what its `main` returns
+  check <file>    Load and compile a bottle and its dependencies without running it
+  parse <file>    Parse a single file; --dump-ast prints the tree
+  eval -e <code>  Run code given inline and print what its `main` returns, or its last
+                  expression when it has no `main`
+  repl [file]     Start an interactive session, after loading what file defines
+  lsp             Serve the language server protocol on stdin and stdout
+  fmt [<path>...] Format sources in place, or stdin to stdout; --check only lists them
+  pack <file>     Bundle a bottle and its dependencies into a .wgb archive
+  unpack <file>   Verify a .wgb archive and unpack it into a directory
+  add <name>[@<req>]  Depend on a bottle from the registry and install it
+  remove <name>   Drop a dependency and uninstall what only it needed
+  install         Install the project's dependencies as bottle.lock records them
+  update [<name>...]  Move dependencies to the newest versions bottle.toml allows
+
+run and check take .wgb archives as well as sources.
+
+A file of `-`, or none at all, reads the source from stdin. A file given without a
+command is run. fmt formats every .wg file in a directory it is given, and with --check
+exits with 1 when any would change.";
+
+const EXIT_STATUS: &str = "\
+Exit status is what `main` returned when the bottle completed, otherwise:
+  1 it spilled at runtime   2 bad usage   3 it could not be loaded
+  4 a bottle it requires spilled   5 anything else";
+
+/// The emitter for errors in `args` themselves, which stop [`Cli::parse`] before it knows
+/// which was asked for: `json` when the last `--message-format` among them says so, or failing
+/// one, `WINEGLASS_MESSAGE_FORMAT` does.
+pub fn usage_emitter(args: &[String]) -> Emitter {
+    let given = args.iter().enumerate().rev().find_map(|(i, arg)| {
+        match arg.strip_prefix("--message-format") {
+            Some("") => args.get(i + 1).cloned(),
+            Some(value) => value.strip_prefix('=').map(str::to_owned),
+            None => None,
+        }
+    });
+    let format = given.or_else(|| env::var(format!("{}MESSAGE_FORMAT", ENV_PREFIX)).ok());
+    Emitter::new(format.as_deref() == Some("json"))
+}
+
+/// The `--help` text, with the options taken from configmgr's table.
+pub fn usage() -> String {
+    format!(
+        "{}\n\n{}\n\n{}",
+        COMMANDS,
+        AllowedArgument::new().help(),
+        EXIT_STATUS
+    )
+}
+
+/// A bottle failed while running.
+pub const EXIT_RUNTIME: u8 = 1;
+/// The command line could not be understood.
+pub const EXIT_USAGE: u8 = 2;
+/// The bottle shattered before it ran: it could not be read, parsed or linked.
+pub const EXIT_LOAD: u8 = 3;
+/// A bottle this one requires spilled.
+pub const EXIT_CASCADED: u8 = 4;
+/// The bottle ended in any other state, such as critical or defective.
+pub const EXIT_OTHER: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Check,
+    Parse,
+    Eval,
+    Pack,
+    Unpack,
+    Add,
+    Remove,
+    Install,
+    Update,
+    Repl,
+    Lsp,
+    Fmt,
+}
+
+pub struct Cli {
+    pub command: Command,
+    /// Where the source comes from. `None` and `-` read stdin.
+    pub file: Option<String>,
+    /// Inline code for `eval -e`.
+    pub code: Option<String>,
+    /// Where pack and unpack write to.
+    pub output: Option<String>,
+    /// What follows the command: the bottles add, remove and update work on, or the paths fmt
+    /// formats.
+    pub operands: Vec<String>,
+    /// The directory add, install and update take bottles from.
+    pub registry: Option<PathBuf>,
+    pub cache: PathBuf,
+    /// Where the REPL keeps its history, if anywhere.
+    pub history: Option<PathBuf>,
+    /// Layout for fmt.
+    pub style: Style,
+    /// fmt only reports what it would change.
+    pub check: bool,
+    pub dump_ast: bool,
+    pub disassemble: bool,
+    pub backend: Backend,
+    /// What bottles run here may do.
+    pub site: Site,
+    pub max_threads: usize,
+    pub emitter: Emitter,
+    pub log_level: LevelFilter,
+}
+
+/// What the arguments asked for.
+pub enum Invocation {
+    Execute(Box<Cli>),
+    Help,
+    Version,
+}
+
+impl Cli {
+    /// Reads the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Invocation, ConfigError> {
+        let args = Args::parse(args, &AllowedArgument::new())?;
+        if args.flag("help") {
+            return Ok(Invocation::Help);
+        }
+        if args.flag("version") {
+            return Ok(Invocation::Version);
+        }
+        let mut positionals = args.positionals().iter().map(String::as_str);
+        let (command, file) = match positionals.next() {
+            Some("run") => (Command::Run, positionals.next()),
+            Some("check") => (Command::Check, positionals.next()),
+            Some("parse") => (Command::Parse, positionals.next()),
+            Some("eval") => (Command::Eval, positionals.next()),
+            Some("pack") => (Command::Pack, positionals.next()),
+            Some("unpack") => (Command::Unpack, positionals.next()),
+            Some("add") => (Command::Add, None),
+            Some("remove") => (Command::Remove, None),
+            Some("install") => (Command::Install, None),
+            Some("update") => (Command::Update, None),
+            Some("repl") => (Command::Repl, positionals.next()),
+            Some("lsp") => (Command::Lsp, None),
+            Some("fmt") => (Command::Fmt, None),
+            Some(file) => (Command::Run, Some(file)),
+            None => {
+                return Err(ConfigError::new(UNEXPECTED_ARGUMENT, "no command given")
+                    .with_help("`--help` lists the commands"))
+            }
+        };
+        let operands: Vec<String> = positionals.map(str::to_owned).collect();
+        let wanted = match command {
+            Command::Add | Command::Remove => 1..=1,
+            Command::Update | Command::Fmt => 0..=usize::MAX,
+            _ => 0..=0,
+        };
+        if !wanted.contains(&operands.len()) {
+            return Err(match operands.get(*wanted.end()) {
+                Some(extra) => ConfigError::new(
+                    UNEXPECTED_ARGUMENT,
+                    format!("unexpected argument `{}`", extra),
+                ),
+                None => ConfigError::new(UNEXPECTED_ARGUMENT, "which bottle?")
+                    .with_help("name the bottle after the command"),
+            });
+        }
+        let code = args.text("code").map(str::to_owned);
+        if code.is_some() && command != Command::Eval {
+            return Err(ConfigError::new(
+                UNEXPECTED_ARGUMENT,
+                "`-e` only works with eval",
+            ));
+        }
+        if command == Command::Eval && file.is_some() {
+            return Err(ConfigError::new(
+                UNEXPECTED_ARGUMENT,
+                "eval takes code with `-e` or on stdin, not a file",
+            ));
+        }
+        // Options can also come from wineglass.toml, the user config and WINEGLASS_* variables.
+        let config = Config::init(&args)?;
+        let emitter = Emitter::new(config.get_str("message-format") == Some("json"));
+        // Keep stderr parseable unless logging was asked for.
+        let log_level = config.log_level().unwrap_or(match emitter {
+            Emitter::Json => LevelFilter::Off,
+            Emitter::Human(_) => LevelFilter::Error,
+        });
+        Ok(Invocation::Execute(Box::new(Cli {
+            command,
+            file: file.map(str::to_owned),
+            code,
+            output: args.text("output").map(str::to_owned),
+            operands,
+            registry: config.get_str("registry").map(PathBuf::from),
+            cache: match config.get_str("cache") {
+                Some(cache) => PathBuf::from(cache),
+                None => default_cache(),
+            },
+            history: config
+                .get_str("history")
+                .map(PathBuf::from)
+                .or_else(default_history),
+            style: Style {
+                indent_width: config.get_int("indent-width").map_or(4, |n| n as usize),
+                brace_style: config
+                    .get_str("brace-style")
+                    .and_then(BraceStyle::parse)
+                    .unwrap_or_default(),
+                line_width: config.get_int("line-width").map_or(100, |n| n as usize),
+            },
+            check: args.flag("check"),
+            dump_ast: args.flag("dump-ast"),
+            disassemble: args.flag("disassemble"),
+            backend: match config.get_bool("bytecode") {
+                Some(true) => Backend::Bytecode,
+                _ => Backend::TreeWalk,
+            },
+            site: Site::granting(config.get_str("grant").unwrap_or_default())
+                .refusing(config.get_str("refuse").unwrap_or_default()),
+            max_threads: config
+                .get_int("max-threads")
+                .map_or(sched::DEFAULT_MAX_THREADS, |n| n as usize),
+            emitter,
+            log_level,
+        })))
+    }
+
+    /// Starts logging at the requested level. `RUST_LOG` still overrides it, and a bottle's
+    /// `#loglevel` may only lower it once the bottle is running.
+    pub fn init_logger(&self) {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+        if env::var_os("RUST_LOG").is_none() {
+            log::set_max_level(self.log_level);
+        }
+    }
+
+    pub fn execute(self) -> ExitCode {
+        match self.command {
+            Command::Parse => return self.parse_only(),
+            Command::Repl => return self.repl(),
+            Command::Lsp => return self.lsp(),
+            Command::Fmt => return self.fmt(),
+            Command::Add | Command::Remove | Command::Install | Command::Update => {
+                return self.manage()
+            }
+            _ => {}
+        }
+        let bottle = match self.bottle() {
+            Ok(mut bottle) => {
+                bottle.site = self.site.clone();
+                bottle.emitter = Some(self.emitter);
+                bottle
+            }
+            Err(d) => return self.failed(&d),
+        };
+        match self.command {
+            Command::Check => self.check(bottle),
+            Command::Pack => self.pack(bottle),
+            Command::Unpack => {
+                println!("{}", bottle.path.display());
+                ExitCode::SUCCESS
+            }
+            _ => self.run(bottle),
+        }
+    }
+
+    /// The bottle to work on, read from its file, an archive or stdin. Archives are unpacked,
+    /// for unpack where `--output` says and otherwise into the temporary directory.
+    fn bottle(&self) -> Result<Bottle, Diagnostic> {
+        if self.command == Command::Eval {
+            let code = match &self.code {
+                Some(code) => code.clone(),
+                None => read_stdin().map_err(|why| self.read_error(why))?,
+            };
+            return Ok(Bottle::from_snippet("<eval>", code));
+        }
+        let file = match self.file.as_deref() {
+            None | Some("-") if self.command == Command::Unpack => {
+                return Err(self.read_error("unpack needs an archive".to_owned()))
+            }
+            None | Some("-") => {
+                let text = read_stdin().map_err(|why| self.read_error(why))?;
+                return Ok(Bottle::from_source("<stdin>", text));
+            }
+            Some(file) => Path::new(file),
+        };
+        let archive = self.command == Command::Unpack
+            || file.extension().is_some_and(|e| e == ARCHIVE_EXTENSION);
+        if !archive || self.command == Command::Pack {
+            return Bottle::new(file, None, None, None).map_err(|why| self.read_error(why));
+        }
+        let archive = Archive::read(file)?;
+        let dir = match (&self.output, self.command) {
+            (Some(dir), Command::Unpack) => PathBuf::from(dir),
+            (None, Command::Unpack) => file.with_extension(""),
+            _ => env::temp_dir().join("wineglass").join(&archive.hash),
+        };
+        archive.unpack(&dir)
+    }
+
+    /// Reads entries from stdin until it ends, prompting when it is a terminal.
+    fn repl(&self) -> ExitCode {
+        let (sources, nodes) = (Arena::new(), Arena::new());
+        let mut repl = Repl::new(&sources, &nodes, self.emitter);
+        if let Some(history) = &self.history {
+            repl = repl.with_history(history.clone());
+        }
+        repl.prompt = io::stdin().is_terminal();
+        let (mut out, mut err) = (io::stdout(), io::stderr());
+        if repl.prompt {
+            eprintln!(
+                "wineglass {}, :help for commands",
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        let loaded = match &self.file {
+            Some(file) => repl.load(Path::new(file), &mut out, &mut err),
+            None => Ok(()),
+        };
+        match loaded.and_then(|_| repl.run(io::stdin().lock(), &mut out, &mut err)) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(why) => {
+                eprintln!(
+                    "{}",
+                    self.emitter.emit(&self.read_error(why.to_string()), None)
+                );
+                ExitCode::from(EXIT_OTHER)
+            }
+        }
+    }
+
+    /// Serves editors until they ask the server to exit.
+    fn lsp(&self) -> ExitCode {
+        match lsp::Server::new().run(io::stdin().lock(), &mut io::stdout()) {
+            Ok(code) => ExitCode::from(code as u8),
+            Err(why) => {
+                eprintln!(
+                    "{}",
+                    self.emitter.emit(&self.read_error(why.to_string()), None)
+                );
+                ExitCode::from(EXIT_OTHER)
+            }
+        }
+    }
+
+    /// Runs one of the package commands on the project around the working directory.
+    fn manage(&self) -> ExitCode {
+        let dir = env::current_dir().unwrap_or_default();
+        let result = PackageManager::open(&dir, self.registry.clone(), self.cache.clone())
+            .and_then(|manager| match self.command {
+                Command::Add => {
+                    let (name, req) = match self.operands[0].split_once('@') {
+                        Some((name, req)) => (name, Some(req)),
+                        None => (self.operands[0].as_str(), None),
+                    };
+                    manager.add(name, req)
+                }
+                Command::Remove => manager.remove(&self.operands[0]),
+                Command::Update => manager.update(&self.operands),
+                _ => manager.install(),
+            });
+        match result {
+            Ok(locked) => {
+                for bottle in locked {
+                    println!("{} {}", bottle.name, bottle.version);
+                }
+                ExitCode::SUCCESS
+            }
+            Err(d) => self.failed(&d),
+        }
+    }
+
+    fn pack(&self, bottle: Bottle) -> ExitCode {
+        let archive = match Archive::pack(&bottle, self.backend == Backend::Bytecode) {
+            Ok(archive) => archive,
+            Err(d) => return self.failed(&d),
+        };
+        let output = match &self.output {
+            Some(output) => PathBuf::from(output),
+            None => PathBuf::from(format!(
+                "{}-{}.{}",
+                archive.manifest.name.trim_end_matches(".wg"),
+                archive.manifest.version,
+                ARCHIVE_EXTENSION
+            )),
+        };
+        match archive.write(&output) {
+            Ok(()) => {
+                println!("{}", output.display());
+                ExitCode::SUCCESS
+            }
+            Err(d) => self.failed(&d),
+        }
+    }
+
+    fn run(&self, mut bottle: Bottle) -> ExitCode {
+        // Unpacked archives with bytecode already run on the VM.
+        if self.backend == Backend::Bytecode {
+            bottle.backend = Backend::Bytecode;
+        }
+        let scheduler = Scheduler::new(self.max_threads);
+        let id = scheduler.submit(bottle);
+        let result = scheduler.wait(id).expect("the bottle was just submitted");
+        scheduler.join();
+        for (name, state) in scheduler.states() {
+            log::debug!("{}: {}", name, state);
+        }
+        let value = match result {
+            Ok(value) => value,
+            Err(d) => {
+                self.spilled(&scheduler, id, &d);
+                0
+            }
+        };
+        let state = scheduler.state(id).unwrap_or(State::Completed);
+        if self.command == Command::Eval && state == State::Completed {
+            match scheduler.returned(id) {
+                Some(Value::Void) => {}
+                Some(returned) => println!("{}", returned),
+                None => println!("{}", value),
+            }
+            return ExitCode::SUCCESS;
+        }
+        ExitCode::from(exit_code(state, value))
+    }
+
+    fn check(&self, mut bottle: Bottle) -> ExitCode {
+        let result = bottle.check();
+        for notice in bottle.notices() {
+            eprintln!("{}", notice.emit(self.emitter));
+        }
+        if let Err(d) = result {
+            match bottle.spill() {
+                Some(spill) => eprintln!("{}", spill.emit(self.emitter)),
+                None => eprintln!("{}", self.emitter.emit(&d, None)),
+            }
+            return ExitCode::from(exit_code(bottle.state(), 0));
+        }
+        if self.disassemble {
+            match bottle.compile() {
+                Ok(program) => {
+                    return write_out(format_args!("{}", bottle::vm::disassemble(&program)))
+                }
+                Err(d) => {
+                    eprintln!("{}", self.emitter.emit(&d, None));
+                    return ExitCode::from(EXIT_LOAD);
+                }
+            }
+        }
+        ExitCode::SUCCESS
+    }
+
+    /// Formats the files and directories named, or stdin to stdout when there are none.
+    fn fmt(&self) -> ExitCode {
+        if self.operands.is_empty() || self.operands == ["-"] {
+            let text = match read_stdin() {
+                Ok(text) => text,
+                Err(why) => return self.failed(&self.read_error(why)),
+            };
+            return match format::format("<stdin>", &text, &self.style) {
+                Ok(formatted) if self.check && formatted != text => {
+                    println!("<stdin>");
+                    ExitCode::from(EXIT_RUNTIME)
+                }
+                Ok(_) if self.check => ExitCode::SUCCESS,
+                Ok(formatted) => write_out(format_args!("{}", formatted)),
+                Err(d) => {
+                    eprintln!("{}", self.emitter.emit(&d, Some(&text)));
+                    ExitCode::from(EXIT_LOAD)
+                }
+            };
+        }
+        let mut files = Vec::new();
+        for operand in &self.operands {
+            sources(Path::new(operand), &mut files);
+        }
+        let (mut failed, mut unformatted) = (false, false);
+        for file in files {
+            let name = file.display().to_string();
+            let text = match fs::read_to_string(&file) {
+                Ok(text) => text,
+                Err(why) => {
+                    let why = format!("couldn't read {}: {}", name, why);
+                    eprintln!("{}", self.emitter.emit(&self.read_error(why), None));
+                    failed = true;
+                    continue;
+                }
+            };
+            let formatted = match format::format(&name, &text, &self.style) {
+                Ok(formatted) if formatted == text => continue,
+                Ok(formatted) => formatted,
+                Err(d) => {
+                    eprintln!("{}", self.emitter.emit(&d, Some(&text)));
+                    failed = true;
+                    continue;
+                }
+            };
+            if self.check {
+                println!("{}", name);
+                unformatted = true;
+            } else if let Err(why) = fs::write(&file, formatted) {
+                eprintln!("couldn't write {}: {}", name, why);
+                failed = true;
+            }
+        }
+        match (failed, unformatted) {
+            (true, _) => ExitCode::from(EXIT_LOAD),
+            (false, true) => ExitCode::from(EXIT_RUNTIME),
+            (false, false) => ExitCode::SUCCESS,
+        }
+    }
+
+    /// Parses one file on its own, without following its dependencies.
+    fn parse_only(&self) -> ExitCode {
+        let (name, text) = match self.file.as_deref() {
+            None | Some("-") => ("<stdin>".to_owned(), read_stdin()),
+            Some(file) => (
+                file.to_owned(),
+                fs::read_to_string(file).map_err(|why| format!("couldn't read {}: {}", file, why)),
+            ),
+        };
+        let text = match text {
+            Ok(text) => text,
+            Err(why) => return self.failed(&self.read_error(why)),
+        };
+        let arena = Arena::new();
+        match bottle::parse(&name, &text, &arena) {
+            Ok(ast) => match self.dump_ast {
+                true => write_out(format_args!("{:?}\n", ast)),
+                false => ExitCode::SUCCESS,
+            },
+            Err(d) => {
+                eprintln!("{}", self.emitter.emit(&d, Some(&text)));
+                let code = d.get_code();
+                let state = State::Shattered(bottle::shatter_kind(code), code as u32);
+                ExitCode::from(exit_code(state, 0))
+            }
+        }
+    }
+
+    /// Reports the spill of bottle `id` and of every bottle it cascaded from.
+    fn spilled(&self, scheduler: &Scheduler, id: BottleId, d: &Diagnostic) {
+        match scheduler.spill(id) {
+            Some(spill) => eprintln!("{}", spill.emit(self.emitter)),
+            None => eprintln!("{}", self.emitter.emit(d, None)),
+        }
+        for cause in scheduler.causes(id).into_iter().skip(1) {
+            if let Some(spill) = scheduler.spill(cause) {
+                eprintln!("{}", spill.emit(self.emitter));
+            }
+        }
+    }
+
+    fn read_error(&self, why: String) -> Diagnostic {
+        Diagnostic::Fatal(bottle::err::Fatal {
+            code: bottle::READ_ERROR,
+            file: self.file.clone().unwrap_or_default(),
+            message: why,
+            ..Default::default()
+        })
+    }
+
+    /// Reports a bottle that could not be loaded at all.
+    fn failed(&self, d: &Diagnostic) -> ExitCode {
+        eprintln!("{}", self.emitter.emit(d, None));
+        ExitCode::from(EXIT_LOAD)
+    }
+}
+
+/// Collects `path` if it is a file, or the `.wg` files under it if it is a directory. Hidden
+/// directories and installed bottles are skipped.
+fn sources(path: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(path) else {
+        files.push(path.to_owned());
+        return;
+    };
+    let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+    entries.sort();
+    for entry in entries {
+        let name = entry.file_name().unwrap_or_default().to_string_lossy();
+        if entry.is_dir() {
+            if !name.starts_with('.') && name != BOTTLES_DIR {
+                sources(&entry, files);
+            }
+        } else if entry.extension().is_some_and(|e| e == "wg") {
+            files.push(entry);
+        }
+    }
+}
+
+/// `$XDG_CACHE_HOME/wineglass`, else `~/.cache/wineglass`, else under the temporary directory.
+fn default_cache() -> PathBuf {
+    match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+        (Some(dir), _) => PathBuf::from(dir).join("wineglass"),
+        (None, Some(home)) => PathBuf::from(home).join(".cache").join("wineglass"),
+        (None, None) => env::temp_dir().join("wineglass").join("cache"),
+    }
+}
+
+/// `$XDG_STATE_HOME/wineglass/history`, else `~/.local/state/wineglass/history`.
+fn default_history() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Some(dir.join("wineglass").join("history"))
+}
+
+/// The exit code for a bottle that ended in `state`. A completed bottle exits with `value`,
+/// what its `main` returned, truncated to a byte.
+pub fn exit_code(state: State, value: i64) -> u8 {
+    match state {
+        State::Completed => value as u8,
+        State::Errored(_) | State::Shattered(Shatter::Unhandled, _) => EXIT_RUNTIME,
+        State::Shattered(Shatter::Empty, _) => EXIT_LOAD,
+        State::Cascaded(_) => EXIT_CASCADED,
+        _ => EXIT_OTHER,
+    }
+}
+
+/// Writes `text` to stdout. A reader that stops early, as `| head` does, is not an error.
+fn write_out(text: std::fmt::Arguments) -> ExitCode {
+    let mut out = io::stdout().lock();
+    match out.write_fmt(text).and_then(|_| out.flush()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(why) if why.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(why) => {
+            eprintln!("couldn't write to stdout: {}", why);
+            ExitCode::from(EXIT_OTHER)
+        }
+    }
+}
+
+fn read_stdin() -> Result<String, String> {
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .map_err(|why| format!("couldn't read stdin: {}", why))?;
+    Ok(text)
+}
@@ -1,36 +1,28 @@
+mod cli;
+
+use cli::{Cli, Invocation};
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-use std::path::MAIN_SEPARATOR_STR;
+use std::process::ExitCode;
 
-fn main() {
-    env::set_var("RUST_BACKTRACE", "1");
-    env_logger::init();
-    let target: Vec<String> = vec![
-        env::current_dir().unwrap().to_str().unwrap().to_string(),
-        "interpret\\main.wg".to_string(),
-    ];
-    println!("Target: {:?}", target.join(MAIN_SEPARATOR_STR));
-    let sep: &str = MAIN_SEPARATOR_STR;
-    let target_path_str = target.join(sep);
-    let target_path = Path::new(&target_path_str);
-    let mut file: File;
-    match target_path.exists() {
-        true => {
-            file = match File::open(target_path) {
-                Err(why) => panic!("FATAL! Couldn't open {}: {}", target_path.display(), why),
-                Ok(file) => file,
-            };
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match Cli::parse(args.clone()) {
+        Ok(Invocation::Execute(cli)) => {
+            cli.init_logger();
+            cli.execute()
+        }
+        Ok(Invocation::Help) => {
+            println!("{}", cli::usage());
+            ExitCode::SUCCESS
+        }
+        Ok(Invocation::Version) => {
+            println!("wineglass {}", env!("CARGO_PKG_VERSION"));
+            ExitCode::SUCCESS
+        }
+        Err(why) => {
+            let emitter = cli::usage_emitter(&args);
+            eprintln!("{}", emitter.emit(&why, why.source.as_deref()));
+            ExitCode::from(cli::EXIT_USAGE)
         }
-        false => panic!("FATAL! Target file not found: {}", target_path.display()),
-    };
-    let mut contents = String::new();
-    match file.read_to_string(&mut contents) {
-        Err(why) => panic!("FATAL! Couldn't read {}: {}", target_path.display(), why),
-        Ok(_) => println!("Contents: {}", contents),
-    };
-    let arena = typed_arena::Arena::new();
-    let mut parser = parser::Parser::new(nom_locate::LocatedSpan::new(&contents), &arena);
-    println!("Parsed: {:#?}", parser.parse());
+    }
 }